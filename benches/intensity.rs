@@ -0,0 +1,53 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use webcalculation::analysis::{accumulate_parallel, accumulate_sequential};
+use webcalculation::simd::{self, sum_bytes, sum_bytes_scalar};
+
+/// Deterministic pseudo-random RGB buffer so every run measures the same data.
+fn synthetic_rgb(pixels: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_u32;
+    (0..pixels * 3)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+fn bench_pixel_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("accumulate_intensity");
+    group.sample_size(20);
+
+    for megapixels in [1, 12, 50] {
+        let rgb = synthetic_rgb(megapixels * 1_000_000);
+        assert_eq!(accumulate_sequential(&rgb, 3, 0), accumulate_parallel(&rgb, 3));
+
+        group.throughput(Throughput::Bytes(rgb.len() as u64));
+        group.bench_with_input(BenchmarkId::new("sequential", megapixels), &rgb, |b, rgb| {
+            b.iter(|| accumulate_sequential(black_box(rgb), 3, 0))
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", megapixels), &rgb, |b, rgb| {
+            b.iter(|| accumulate_parallel(black_box(rgb), 3))
+        });
+    }
+
+    group.finish();
+}
+
+/// Compares the byte-sum kernel with and without the vector path. Build with
+/// `--features simd` to exercise AVX2; without it both variants are scalar.
+fn bench_sum_bytes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sum_bytes");
+    let rgb = synthetic_rgb(12_000_000);
+    group.throughput(Throughput::Bytes(rgb.len() as u64));
+    group.bench_function("scalar", |b| b.iter(|| sum_bytes_scalar(black_box(&rgb))));
+    group.bench_function(if simd::accelerated() { "avx2" } else { "dispatch (scalar)" }, |b| {
+        b.iter(|| sum_bytes(black_box(&rgb)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_pixel_loop, bench_sum_bytes);
+criterion_main!(benches);
@@ -0,0 +1,77 @@
+//! Records the git commit and build time for `GET /version`, and embeds the
+//! vendored Swagger UI assets.
+//!
+//! Both are optional: a build from a source tarball without git simply
+//! reports no commit. `SOURCE_DATE_EPOCH` overrides the build time for
+//! reproducible builds, and `GIT_COMMIT` the commit, e.g. in CI images that
+//! are built without the `.git` directory.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The Swagger UI files `/swagger-ui` needs, from `swagger-ui-dist`'s
+/// `dist/` directory; `scripts/vendor-swagger-ui.sh` downloads them.
+const SWAGGER_UI_ASSETS: [&str; 3] = ["swagger-ui.css", "swagger-ui-bundle.js", "swagger-ui-standalone-preset.js"];
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let commit = std::env::var("GIT_COMMIT").ok().filter(|commit| !commit.is_empty()).or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+        let commit = String::from_utf8(output.stdout).ok()?;
+        (output.status.success() && !commit.trim().is_empty()).then(|| commit.trim().to_string())
+    });
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=WEBCALCULATION_GIT_COMMIT={commit}");
+    }
+
+    let epoch = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()));
+    println!("cargo:rustc-env=WEBCALCULATION_BUILD_TIMESTAMP={}", rfc3339(epoch));
+
+    embed_swagger_ui();
+}
+
+/// Writes `swagger_ui_assets.rs`, a table of the vendored assets, into
+/// `OUT_DIR`. It is left empty unless every asset is there, and the server
+/// then serves a plain endpoint list instead of Swagger UI.
+fn embed_swagger_ui() {
+    let dir = Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("assets/swagger-ui");
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let paths = SWAGGER_UI_ASSETS.map(|name| dir.join(name));
+    let table = if paths.iter().all(|path| path.is_file()) {
+        let entries: Vec<String> = SWAGGER_UI_ASSETS
+            .iter()
+            .zip(&paths)
+            .map(|(name, path)| format!("    ({name:?}, include_bytes!({:?})),\n", path.display().to_string()))
+            .collect();
+        format!("&[\n{}]", entries.concat())
+    } else {
+        println!("cargo:warning=Swagger UI assets are not vendored; run scripts/vendor-swagger-ui.sh to embed them");
+        "&[]".to_string()
+    };
+    let out = Path::new(&std::env::var("OUT_DIR").unwrap()).join("swagger_ui_assets.rs");
+    std::fs::write(out, table + "\n").unwrap();
+}
+
+/// `seconds` after the Unix epoch as `YYYY-MM-DDTHH:MM:SSZ`.
+fn rfc3339(seconds: u64) -> String {
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    // Civil date from a day count, after Howard Hinnant's days_from_civil inverse
+    let shifted = days + 719_468;
+    let era = shifted / 146_097;
+    let day_of_era = shifted % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", time / 3600, time % 3600 / 60, time % 60)
+}
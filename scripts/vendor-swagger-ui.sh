@@ -0,0 +1,17 @@
+#!/bin/sh
+# Downloads the Swagger UI files that build.rs embeds for /swagger-ui into
+# assets/swagger-ui/. Run it once with network access, then commit the files;
+# builds need no network afterwards. VERSION picks another swagger-ui-dist.
+set -eu
+
+VERSION="${VERSION:-5.17.14}"
+DEST="$(cd "$(dirname "$0")/.." && pwd)/assets/swagger-ui"
+TMP="$(mktemp -d)"
+trap 'rm -rf "$TMP"' EXIT
+
+curl -fsSL "https://registry.npmjs.org/swagger-ui-dist/-/swagger-ui-dist-$VERSION.tgz" | tar -xz -C "$TMP"
+for file in swagger-ui.css swagger-ui-bundle.js swagger-ui-standalone-preset.js; do
+    # Without the trailing source map comments, browsers do not ask for .map files
+    sed '/^\/\/# sourceMappingURL=/d; s#/\*\# sourceMappingURL=[^*]*\*/##' "$TMP/package/$file" > "$DEST/$file"
+done
+echo "swagger-ui-dist $VERSION vendored into $DEST"
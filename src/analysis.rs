@@ -0,0 +1,2062 @@
+//! Decoding and per-pixel statistics.
+//!
+//! Pixel intensity throughout is the unweighted channel mean `(r + g + b) / 3`;
+//! gray images use the gray value directly and alpha is ignored. Statistics
+//! are reported on a 0-255 scale whatever the image's bit depth.
+
+use crate::simd;
+use image::{
+    error::{EncodingError, ImageFormatHint, LimitErrorKind, UnsupportedErrorKind},
+    DynamicImage, GrayImage, ImageError, ImageFormat, ImageReader, Limits, Luma, Rgb, RgbImage,
+};
+use rayon::prelude::*;
+use std::{borrow::Cow, collections::HashSet, fmt, io::Cursor, sync::OnceLock};
+
+/// Images with more pixels than this are accumulated in parallel; below it the
+/// thread-pool overhead outweighs the gain.
+pub const PARALLEL_THRESHOLD_PIXELS: usize = 4_000_000;
+
+/// Pixels handed to each rayon task on the parallel path.
+const PIXELS_PER_CHUNK: usize = 256 * 1024;
+
+/// Caps applied while decoding, guarding against decompression bombs.
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeLimits {
+    /// Largest accepted `width * height`
+    pub max_pixels: u64,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Largest buffer the decoder may allocate
+    pub max_alloc_bytes: u64,
+}
+
+impl Default for DecodeLimits {
+    /// The service defaults: 100 megapixels, 12000 pixels per side and 512 MiB
+    /// of decoder allocations.
+    fn default() -> Self {
+        DecodeLimits {
+            max_pixels: 100_000_000,
+            max_width: 12_000,
+            max_height: 12_000,
+            max_alloc_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// Why an image could not be analysed.
+#[derive(Debug)]
+pub enum AnalysisError {
+    /// The declared dimensions exceed the configured pixel cap
+    TooManyPixels { width: u32, height: u32, max_pixels: u64 },
+    /// The declared width or height exceeds the configured maximum
+    DimensionsExceeded { width: u32, height: u32, max_width: u32, max_height: u32 },
+    /// Decoding would allocate more than the configured maximum
+    AllocationExceeded { max_alloc_bytes: u64 },
+    /// The bytes are a recognised format whose decoder is not compiled in
+    UnsupportedFormat(ImageFormat),
+    /// A four-channel JPEG without the Adobe marker that says how its CMYK
+    /// channels are stored, so converting them to RGB would be a guess
+    AmbiguousCmyk,
+    /// The bytes could not be decoded as an image
+    Decode(ImageError),
+    /// The image decoded to zero pixels
+    Empty,
+}
+
+impl fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalysisError::TooManyPixels { width, height, max_pixels } => write!(
+                f,
+                "image dimensions {width}x{height} exceed the maximum of {max_pixels} pixels"
+            ),
+            AnalysisError::DimensionsExceeded { width, height, max_width, max_height } => write!(
+                f,
+                "image dimensions {width}x{height} exceed the configured maximum of {max_width}x{max_height}"
+            ),
+            AnalysisError::AllocationExceeded { max_alloc_bytes } => write!(
+                f,
+                "image exceeds the configured decode memory limit of {max_alloc_bytes} bytes"
+            ),
+            AnalysisError::UnsupportedFormat(format) => {
+                write!(f, "{} format not supported in this build", format_name(*format))?;
+                match decoder_feature(*format) {
+                    Some(feature) => write!(f, " (enable the `{feature}` cargo feature)"),
+                    None => Ok(()),
+                }
+            }
+            AnalysisError::AmbiguousCmyk => f.write_str(
+                "CMYK JPEG without an Adobe APP14 marker: its channel convention is ambiguous, \
+                 so it cannot be converted to RGB reliably; re-export it as RGB or with Adobe markers",
+            ),
+            AnalysisError::Decode(err) => write!(f, "unsupported or corrupt image: {err}"),
+            AnalysisError::Empty => write!(f, "No pixels found in image"),
+        }
+    }
+}
+
+impl std::error::Error for AnalysisError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AnalysisError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ImageError> for AnalysisError {
+    fn from(err: ImageError) -> Self {
+        if let ImageError::Unsupported(unsupported) = &err
+            && let UnsupportedErrorKind::Format(ImageFormatHint::Exact(format)) = unsupported.kind()
+        {
+            return AnalysisError::UnsupportedFormat(format);
+        }
+        AnalysisError::Decode(err)
+    }
+}
+
+/// Whether this build can decode `format`. `ImageFormat::reading_enabled`
+/// keys AVIF on image's encoder feature and never reports DDS, so both are
+/// answered from the decoder features enabled in `Cargo.toml` instead.
+///
+/// ```
+/// use image::ImageFormat;
+/// use webcalculation::analysis::can_decode;
+///
+/// assert!(can_decode(ImageFormat::Png));
+/// assert!(!can_decode(ImageFormat::Pcx));
+/// ```
+pub fn can_decode(format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Avif => cfg!(feature = "avif"),
+        ImageFormat::Dds => true,
+        format => format.reading_enabled(),
+    }
+}
+
+/// The cargo feature that compiles in the decoder for `format`, for the
+/// formats that are optional.
+///
+/// ```
+/// use image::ImageFormat;
+/// use webcalculation::analysis::decoder_feature;
+///
+/// assert_eq!(decoder_feature(ImageFormat::Avif), Some("avif"));
+/// assert_eq!(decoder_feature(ImageFormat::Png), None);
+/// ```
+pub fn decoder_feature(format: ImageFormat) -> Option<&'static str> {
+    match format {
+        ImageFormat::OpenExr => Some("exr"),
+        ImageFormat::Hdr => Some("hdr"),
+        ImageFormat::WebP => Some("webp"),
+        ImageFormat::Avif => Some("avif"),
+        _ => None,
+    }
+}
+
+/// Human-readable name of a format, e.g. `AVIF`.
+fn format_name(format: ImageFormat) -> String {
+    format.extensions_str().first().map_or_else(|| format!("{format:?}"), |ext| ext.to_uppercase())
+}
+
+/// Decodes an image, first reading only its header so oversized images are
+/// rejected before the pixel buffer is allocated. The decoder itself also runs
+/// under `limits`, catching formats whose header understates the work.
+///
+/// CMYK JPEGs are converted to RGB following the Adobe convention (inverted
+/// channels, as written by Photoshop); ones lacking the Adobe marker are
+/// rejected with [`AnalysisError::AmbiguousCmyk`].
+///
+/// ```
+/// use webcalculation::analysis::{decode_image, AnalysisError, DecodeLimits};
+///
+/// let err = decode_image(b"not an image", &DecodeLimits::default()).unwrap_err();
+/// assert!(matches!(err, AnalysisError::Decode(_)));
+/// ```
+pub fn decode_image(image_data: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, AnalysisError> {
+    let _span = tracing::info_span!("decode").entered();
+    let reader = || {
+        ImageReader::new(Cursor::new(image_data))
+            .with_guessed_format()
+            .map_err(ImageError::IoError)
+    };
+
+    let limit_error = |err: ImageError, width: u32, height: u32| match &err {
+        ImageError::Limits(limit) => match limit.kind() {
+            LimitErrorKind::DimensionError => AnalysisError::DimensionsExceeded {
+                width,
+                height,
+                max_width: limits.max_width,
+                max_height: limits.max_height,
+            },
+            LimitErrorKind::InsufficientMemory => AnalysisError::AllocationExceeded {
+                max_alloc_bytes: limits.max_alloc_bytes,
+            },
+            _ => AnalysisError::Decode(err),
+        },
+        _ => AnalysisError::from(err),
+    };
+
+    let (width, height) = reader()?.into_dimensions()?;
+    if u64::from(width) * u64::from(height) > limits.max_pixels {
+        return Err(AnalysisError::TooManyPixels { width, height, max_pixels: limits.max_pixels });
+    }
+    if width > limits.max_width || height > limits.max_height {
+        return Err(AnalysisError::DimensionsExceeded {
+            width,
+            height,
+            max_width: limits.max_width,
+            max_height: limits.max_height,
+        });
+    }
+
+    if image::guess_format(image_data).ok() == Some(ImageFormat::Jpeg) && is_unmarked_cmyk_jpeg(image_data) {
+        return Err(AnalysisError::AmbiguousCmyk);
+    }
+
+    let mut decoder_limits = Limits::default();
+    decoder_limits.max_image_width = Some(limits.max_width);
+    decoder_limits.max_image_height = Some(limits.max_height);
+    decoder_limits.max_alloc = Some(limits.max_alloc_bytes);
+
+    let mut decoder = reader()?;
+    decoder.limits(decoder_limits);
+    decoder.decode().map_err(|err| limit_error(err, width, height))
+}
+
+/// Whether a JPEG has four components but no Adobe APP14 segment. Only the
+/// header segments before the first scan are walked.
+fn is_unmarked_cmyk_jpeg(data: &[u8]) -> bool {
+    let mut components = None;
+    let mut adobe = false;
+    let mut pos = 2; // past SOI
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            // Fill byte before the marker
+            pos += 1;
+            continue;
+        }
+        let length = usize::from(u16::from_be_bytes([data[pos + 2], data[pos + 3]]));
+        let Some(segment) = data.get(pos + 4..pos + 2 + length) else {
+            break;
+        };
+        match marker {
+            0xEE => adobe |= segment.starts_with(b"Adobe"),
+            // SOF0-SOF15, except DHT (C4), JPG (C8) and DAC (CC)
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => components = segment.get(5).copied(),
+            0xDA => break,
+            _ => {}
+        }
+        pos += 2 + length;
+    }
+    components == Some(4) && !adobe
+}
+
+/// Location and intensity of a single pixel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PixelExtreme {
+    pub x: u32,
+    pub y: u32,
+    pub intensity: f64,
+}
+
+/// Summary statistics returned by [`intensity_stats`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IntensityStats {
+    pub average_intensity: f64,
+    /// Median intensity, at histogram (integer) resolution
+    pub median_intensity: f64,
+    /// Population standard deviation of pixel intensity, on the same scale as
+    /// `average_intensity`
+    pub std_dev: f64,
+    /// Geometric mean intensity `exp(mean(ln(intensity + 1))) - 1`, which
+    /// weighs dark regions more than the arithmetic mean does; the `+ 1` keeps
+    /// black pixels finite
+    pub log_mean_intensity: f64,
+    /// Mean HSP perceived brightness (0-255): each pixel contributes
+    /// `sqrt(0.299·R² + 0.587·G² + 0.114·B²)`, weighting the channels by Rec.
+    /// 601 luma in the squared domain. Saturated colors read brighter than
+    /// with plain luma; gray pixels read their value
+    pub hsp_brightness: f64,
+    /// Mean HSV saturation (0-1)
+    pub mean_saturation: f64,
+    /// Pixel count by the largest difference between two of the pixel's
+    /// channels, `max(r, g, b) - min(r, g, b)` at 8-bit resolution; gray
+    /// pixels are in bin 0
+    pub chroma_histogram: [u64; 256],
+    pub brightest_pixel: PixelExtreme,
+    pub darkest_pixel: PixelExtreme,
+    /// Pixel count per intensity, rounded to the nearest integer
+    pub histogram: [u64; 256],
+    /// Bits per channel the statistics were computed at: 16 for 16-bit and
+    /// floating-point images, 8 otherwise
+    pub bit_depth: u8,
+    /// Width of the analysed image, in pixels
+    pub width: u32,
+    /// Height of the analysed image, in pixels
+    pub height: u32,
+}
+
+impl IntensityStats {
+    /// Fraction (0-1) of pixels counting as colored, with a difference
+    /// between two of their channels above `threshold` (0-255).
+    pub fn color_fraction(&self, threshold: f64) -> f64 {
+        let pixels: u64 = self.chroma_histogram.iter().sum();
+        let colored: u64 = (0u8..=255)
+            .zip(self.chroma_histogram)
+            .filter(|&(chroma, _)| f64::from(chroma) > threshold)
+            .map(|(_, count)| count)
+            .sum();
+        if pixels == 0 { 0.0 } else { colored as f64 / pixels as f64 }
+    }
+
+    /// Whether less than `max_percent` of the pixels are colored by
+    /// [`color_fraction`](Self::color_fraction)'s `threshold`, as for a
+    /// black-and-white document scanned in color.
+    pub fn is_monochrome(&self, threshold: f64, max_percent: f64) -> bool {
+        100.0 * self.color_fraction(threshold) < max_percent
+    }
+
+    /// Whether the image is effectively an all-black frame: its average is
+    /// below `max_average` and even its brightest pixel is below `max_peak`
+    /// (both 0-255). The peak test keeps dim images with real content, such as
+    /// a night sky with a few stars, from being flagged.
+    pub fn is_black_frame(&self, max_average: f64, max_peak: f64) -> bool {
+        self.average_intensity < max_average && self.brightest_pixel.intensity < max_peak
+    }
+
+    /// RMS contrast: the standard deviation relative to the mean, so images
+    /// of any size and brightness compare directly. 0 for a flat image, and
+    /// for an all-black one where the ratio is undefined.
+    pub fn contrast_rms(&self) -> f64 {
+        if self.average_intensity > 0.0 { self.std_dev / self.average_intensity } else { 0.0 }
+    }
+
+    /// Michelson contrast `(max - min) / (max + min)` of the brightest and
+    /// darkest pixels, from 0 (flat) to 1 (some pixel is black). `None` for an
+    /// all-black image, where the ratio is undefined.
+    pub fn contrast_michelson(&self) -> Option<f64> {
+        let (max, min) = (self.brightest_pixel.intensity, self.darkest_pixel.intensity);
+        (max + min > 0.0).then(|| (max - min) / (max + min))
+    }
+
+    /// Exposure estimate with advice. The mean's distance from middle gray
+    /// (in units of 50) and the imbalance between highlight and shadow
+    /// clipping (in units of 5%) are added up; once the sum reaches 1 either
+    /// way, the frame is called over- or underexposed. A slightly dark mean
+    /// with crushed shadows is thus flagged where either alone would not be,
+    /// while clipping at both ends, as in a high-contrast scene, cancels out.
+    pub fn exposure(&self) -> Exposure {
+        let pixels: u64 = self.histogram.iter().sum();
+        let percent = |count: u64| if pixels == 0 { 0.0 } else { 100.0 * count as f64 / pixels as f64 };
+        let shadow_clipping = percent(self.histogram[..=SHADOW_CLIP_MAX].iter().sum());
+        let highlight_clipping = percent(self.histogram[HIGHLIGHT_CLIP_MIN..].iter().sum());
+
+        let brightness = (self.average_intensity - MIDDLE_GRAY) / MIDTONE_TOLERANCE
+            + (highlight_clipping - shadow_clipping) / CLIPPING_TOLERANCE;
+        let suggestion = if brightness <= -1.0 {
+            ExposureSuggestion::IncreaseExposure
+        } else if brightness >= 1.0 {
+            ExposureSuggestion::DecreaseExposure
+        } else {
+            ExposureSuggestion::WellExposed
+        };
+        Exposure {
+            ev_offset: (self.average_intensity.max(1.0) / MIDDLE_GRAY).log2(),
+            shadow_clipping,
+            highlight_clipping,
+            suggestion,
+        }
+    }
+}
+
+/// Middle gray on the 0-255 scale, the mean a well-exposed frame sits near.
+pub const MIDDLE_GRAY: f64 = 118.0;
+/// Histogram bins at or below this intensity count as clipped shadows.
+const SHADOW_CLIP_MAX: usize = 2;
+/// Histogram bins at or above this intensity count as clipped highlights.
+const HIGHLIGHT_CLIP_MIN: usize = 253;
+/// Distance of the mean from [`MIDDLE_GRAY`] that alone is enough for advice.
+const MIDTONE_TOLERANCE: f64 = 50.0;
+/// Percentage of one-sided clipping that alone is enough for advice.
+const CLIPPING_TOLERANCE: f64 = 5.0;
+
+/// Advice derived from an image's [`Exposure`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExposureSuggestion {
+    IncreaseExposure,
+    DecreaseExposure,
+    WellExposed,
+}
+
+impl ExposureSuggestion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExposureSuggestion::IncreaseExposure => "increase exposure",
+            ExposureSuggestion::DecreaseExposure => "decrease exposure",
+            ExposureSuggestion::WellExposed => "well exposed",
+        }
+    }
+}
+
+/// Exposure estimate returned by [`IntensityStats::exposure`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exposure {
+    /// `log2(mean / MIDDLE_GRAY)`: stops the frame sits above (positive) or
+    /// below middle gray, with the mean floored at 1 so black stays finite
+    pub ev_offset: f64,
+    /// Percentage of pixels at intensity 2 or below
+    pub shadow_clipping: f64,
+    /// Percentage of pixels at intensity 253 or above
+    pub highlight_clipping: f64,
+    pub suggestion: ExposureSuggestion,
+}
+
+/// An 8- or 16-bit channel value.
+pub trait Sample: Copy + Into<u32> + Send + Sync {
+    /// Largest value, standing for full intensity
+    const MAX: u32;
+
+    /// Histogram bin (0-255) of a pixel's channel sum, rounded to nearest.
+    fn histogram_bin(channel_sum: u32) -> usize;
+
+    /// Vectorised [`contiguous_channel_total`](simd::contiguous_channel_total),
+    /// where one exists for this sample type.
+    fn contiguous_channel_total(samples: &[Self], channels: usize) -> Option<u64>;
+
+    /// Linear-light value (0-1) of every sample value, indexed by the value.
+    fn linear_lut() -> &'static [f64];
+
+    /// `ln(intensity + 1)` of every pixel channel sum, in [`LOG_FIXED_POINT`]
+    /// units, indexed by the channel sum.
+    fn log_lut() -> &'static [u64];
+}
+
+impl Sample for u8 {
+    const MAX: u32 = 255;
+
+    fn histogram_bin(channel_sum: u32) -> usize {
+        ((channel_sum + 1) / 3) as usize
+    }
+
+    fn contiguous_channel_total(samples: &[u8], channels: usize) -> Option<u64> {
+        simd::contiguous_channel_total(samples, channels)
+    }
+
+    fn linear_lut() -> &'static [f64] {
+        static LUT: OnceLock<Vec<f64>> = OnceLock::new();
+        LUT.get_or_init(|| linear_lut(<Self as Sample>::MAX))
+    }
+
+    fn log_lut() -> &'static [u64] {
+        static LUT: OnceLock<Vec<u64>> = OnceLock::new();
+        LUT.get_or_init(|| log_lut(<Self as Sample>::MAX))
+    }
+}
+
+impl Sample for u16 {
+    const MAX: u32 = 65_535;
+
+    fn histogram_bin(channel_sum: u32) -> usize {
+        let full_scale = 3 * u64::from(Self::MAX);
+        ((u64::from(channel_sum) * 255 + full_scale / 2) / full_scale) as usize
+    }
+
+    fn contiguous_channel_total(_samples: &[u16], _channels: usize) -> Option<u64> {
+        None
+    }
+
+    fn linear_lut() -> &'static [f64] {
+        static LUT: OnceLock<Vec<f64>> = OnceLock::new();
+        LUT.get_or_init(|| linear_lut(<Self as Sample>::MAX))
+    }
+
+    fn log_lut() -> &'static [u64] {
+        static LUT: OnceLock<Vec<u64>> = OnceLock::new();
+        LUT.get_or_init(|| log_lut(<Self as Sample>::MAX))
+    }
+}
+
+/// The sRGB electro-optical transfer function: encoded value (0-1) to linear light.
+pub fn srgb_to_linear(encoded: f64) -> f64 {
+    if encoded <= 0.04045 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The inverse of [`srgb_to_linear`]: linear light (0-1) to an encoded value.
+///
+/// ```
+/// use webcalculation::analysis::{linear_to_srgb, srgb_to_linear};
+///
+/// assert!((linear_to_srgb(srgb_to_linear(0.5)) - 0.5).abs() < 1e-12);
+/// ```
+pub fn linear_to_srgb(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn linear_lut(max: u32) -> Vec<f64> {
+    (0..=max).map(|value| srgb_to_linear(f64::from(value) / f64::from(max))).collect()
+}
+
+/// Scale of the fixed-point logarithms summed for the geometric mean. Summing
+/// integers keeps the total independent of how pixels are split across
+/// threads; 2^32 leaves the rounding far below any reported precision.
+pub const LOG_FIXED_POINT: f64 = 4_294_967_296.0;
+
+/// Scale of the fixed-point per-pixel HSP brightness (0-255) and HSV
+/// saturation (0-1) summed for [`IntensityStats`], for the same reasons as
+/// [`LOG_FIXED_POINT`].
+const FIXED_POINT: f64 = 4_294_967_296.0;
+
+fn log_lut(max: u32) -> Vec<u64> {
+    let full_scale = 3.0 * f64::from(max);
+    (0..=3 * max)
+        .map(|channel_sum| ((255.0 * f64::from(channel_sum) / full_scale).ln_1p() * LOG_FIXED_POINT).round() as u64)
+        .collect()
+}
+
+/// Interleaved samples of a decoded image, at the precision they are analysed.
+enum Samples<'a> {
+    Eight(&'a [u8]),
+    Sixteen(&'a [u16]),
+}
+
+/// Running totals over a run of pixels. Pixel values are tracked as the sum of
+/// the three channels (`3 * gray` for gray images) so extrema compare exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntensityAccumulator {
+    /// Sum of every pixel's channel sum; divided by `3 * pixel_count` only at
+    /// the end so no per-pixel rounding biases the mean
+    pub total_channel_sum: u64,
+    pub pixel_count: u64,
+    /// Sum of every pixel's squared channel sum, for the variance; wide enough
+    /// that no image within the decode limits overflows it
+    pub squared_channel_sum: u128,
+    /// Sum of every pixel's [`Sample::log_lut`] entry, for the geometric mean
+    pub log_sum: u128,
+    /// Sum of every pixel's HSP brightness, in fixed point
+    pub hsp_sum: u128,
+    /// Sum of every pixel's HSV saturation, in fixed point
+    pub saturation_sum: u128,
+    /// Row-major index and channel sum of the first brightest pixel
+    pub brightest: (usize, u32),
+    /// Row-major index and channel sum of the first darkest pixel
+    pub darkest: (usize, u32),
+    /// Pixel count per intensity, rounded to the nearest integer
+    pub histogram: [u64; 256],
+    /// Pixel count per channel difference `max - min`, see
+    /// [`IntensityStats::chroma_histogram`]
+    pub chroma_histogram: [u64; 256],
+}
+
+impl IntensityAccumulator {
+    fn new(first_pixel: usize) -> Self {
+        IntensityAccumulator {
+            total_channel_sum: 0,
+            pixel_count: 0,
+            squared_channel_sum: 0,
+            log_sum: 0,
+            hsp_sum: 0,
+            saturation_sum: 0,
+            brightest: (first_pixel, 0),
+            darkest: (first_pixel, u32::MAX),
+            histogram: [0; 256],
+            chroma_histogram: [0; 256],
+        }
+    }
+
+    /// Records one pixel and returns its channel sum; the caller keeps
+    /// `total_channel_sum` up to date and passes in [`Sample::log_lut`],
+    /// looked up once per run.
+    fn add<S: Sample>(&mut self, index: usize, pixel: &[S], log_lut: &[u64]) -> u32 {
+        let channel_sum = channel_sum(pixel);
+        self.pixel_count += 1;
+        self.squared_channel_sum += u128::from(u64::from(channel_sum).pow(2));
+        self.log_sum += u128::from(log_lut[channel_sum as usize]);
+        self.hsp_sum += u128::from(hsp_fixed(pixel));
+        let (chroma_bin, saturation) = chroma_saturation(pixel);
+        self.chroma_histogram[chroma_bin] += 1;
+        self.saturation_sum += u128::from(saturation);
+        self.histogram[S::histogram_bin(channel_sum)] += 1;
+        if channel_sum > self.brightest.1 {
+            self.brightest = (index, channel_sum);
+        }
+        if channel_sum < self.darkest.1 {
+            self.darkest = (index, channel_sum);
+        }
+        channel_sum
+    }
+
+    /// Combines with the totals of the pixels that directly follow these ones.
+    /// Ties keep `self`'s extrema, preserving first-occurrence semantics.
+    fn merge(mut self, later: IntensityAccumulator) -> Self {
+        self.total_channel_sum += later.total_channel_sum;
+        self.pixel_count += later.pixel_count;
+        self.squared_channel_sum += later.squared_channel_sum;
+        self.log_sum += later.log_sum;
+        self.hsp_sum += later.hsp_sum;
+        self.saturation_sum += later.saturation_sum;
+        for (bin, count) in self.histogram.iter_mut().zip(later.histogram) {
+            *bin += count;
+        }
+        for (bin, count) in self.chroma_histogram.iter_mut().zip(later.chroma_histogram) {
+            *bin += count;
+        }
+        if later.brightest.1 > self.brightest.1 {
+            self.brightest = later.brightest;
+        }
+        if later.darkest.1 < self.darkest.1 {
+            self.darkest = later.darkest;
+        }
+        self
+    }
+}
+
+/// Decodes `image_data` under `limits` and computes its [`IntensityStats`].
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::{calculate_image_intensity, encode_png, DecodeLimits};
+///
+/// let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([255, 255, 254])));
+/// let png = encode_png(&img).unwrap();
+///
+/// let stats = calculate_image_intensity(&png, &DecodeLimits::default()).unwrap();
+/// assert!((stats.average_intensity - 764.0 / 3.0).abs() < 1e-9);
+/// ```
+pub fn calculate_image_intensity(image_data: &[u8], limits: &DecodeLimits) -> Result<IntensityStats, AnalysisError> {
+    intensity_stats(&decode_image(image_data, limits)?)
+}
+
+/// Computes the [`IntensityStats`] of an already decoded image. Extrema report
+/// the first pixel in row-major order when several share the same intensity.
+///
+/// ```
+/// use image::{DynamicImage, GrayImage, Luma};
+/// use webcalculation::analysis::intensity_stats;
+///
+/// let mut gray = GrayImage::new(8, 8);
+/// gray.put_pixel(5, 2, Luma([255]));
+///
+/// let stats = intensity_stats(&DynamicImage::ImageLuma8(gray)).unwrap();
+/// assert_eq!((stats.brightest_pixel.x, stats.brightest_pixel.y), (5, 2));
+/// assert_eq!(stats.median_intensity, 0.0);
+/// ```
+pub fn intensity_stats(img: &DynamicImage) -> Result<IntensityStats, AnalysisError> {
+    fn accumulate<S: Sample>(samples: &[S], channels: usize) -> IntensityAccumulator {
+        if samples.len() / channels > PARALLEL_THRESHOLD_PIXELS {
+            accumulate_parallel(samples, channels)
+        } else {
+            accumulate_sequential(samples, channels, 0)
+        }
+    }
+
+    let width = img.width() as usize;
+    let (totals, max, bit_depth) = with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => (accumulate(samples, channels), <u8 as Sample>::MAX, 8),
+        Samples::Sixteen(samples) => (accumulate(samples, channels), <u16 as Sample>::MAX, 16),
+    });
+    if totals.pixel_count == 0 {
+        return Err(AnalysisError::Empty);
+    }
+
+    // Dividing a channel sum by 3 * max / 255 puts it on the 0-255 intensity
+    // scale; the divisor is exactly 3 for 8-bit images, leaving them unaffected.
+    let to_byte_scale = 3.0 * f64::from(max) / 255.0;
+    // n^2 * variance = n * sum(x^2) - sum(x)^2, exact in integers, so a flat
+    // image comes out at exactly 0 rather than at cancellation noise
+    let count = u128::from(totals.pixel_count);
+    let spread = count * totals.squared_channel_sum - u128::from(totals.total_channel_sum).pow(2);
+    let std_dev = (spread as f64).sqrt() / (to_byte_scale * totals.pixel_count as f64);
+
+    let extreme = |(index, channel_sum): (usize, u32)| PixelExtreme {
+        x: (index % width) as u32,
+        y: (index / width) as u32,
+        intensity: f64::from(channel_sum) / to_byte_scale,
+    };
+
+    Ok(IntensityStats {
+        average_intensity: totals.total_channel_sum as f64 / (to_byte_scale * totals.pixel_count as f64),
+        median_intensity: f64::from(histogram_median(&totals.histogram)),
+        std_dev,
+        log_mean_intensity: (totals.log_sum as f64 / (LOG_FIXED_POINT * totals.pixel_count as f64)).exp_m1(),
+        hsp_brightness: totals.hsp_sum as f64 / (FIXED_POINT * totals.pixel_count as f64),
+        mean_saturation: totals.saturation_sum as f64 / (FIXED_POINT * totals.pixel_count as f64),
+        chroma_histogram: totals.chroma_histogram,
+        brightest_pixel: extreme(totals.brightest),
+        darkest_pixel: extreme(totals.darkest),
+        histogram: totals.histogram,
+        bit_depth,
+        width: img.width(),
+        height: img.height(),
+    })
+}
+
+/// Common aspect ratios, landscape side first.
+const COMMON_ASPECT_RATIOS: [(u32, u32); 9] = [(1, 1), (5, 4), (4, 3), (3, 2), (16, 10), (5, 3), (16, 9), (2, 1), (21, 9)];
+
+/// Relative difference from a common ratio still reported under its label;
+/// covers panel sizes such as 1366x768 that are 16:9 only approximately.
+const ASPECT_TOLERANCE: f64 = 0.01;
+
+/// A `"W:H"` label for the common aspect ratio `width x height` matches, with
+/// portrait images labelled the other way round (`"9:16"`). `None` when the
+/// ratio is not close to any common one or a side is zero.
+///
+/// ```
+/// use webcalculation::analysis::aspect_label;
+///
+/// assert_eq!(aspect_label(1920, 1080).as_deref(), Some("16:9"));
+/// assert_eq!(aspect_label(768, 1024).as_deref(), Some("3:4"));
+/// assert_eq!(aspect_label(1000, 123), None);
+/// ```
+pub fn aspect_label(width: u32, height: u32) -> Option<String> {
+    fn gcd(a: u32, b: u32) -> u32 {
+        if b == 0 { a } else { gcd(b, a % b) }
+    }
+
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let divisor = gcd(width, height);
+    let reduced = (width.max(height) / divisor, width.min(height) / divisor);
+    let ratio = f64::from(reduced.0) / f64::from(reduced.1);
+    let close = || {
+        COMMON_ASPECT_RATIOS
+            .iter()
+            .find(|&&(long, short)| (ratio * f64::from(short) / f64::from(long) - 1.0).abs() <= ASPECT_TOLERANCE)
+    };
+    let &(long, short) = COMMON_ASPECT_RATIOS.iter().find(|&&common| common == reduced).or_else(close)?;
+    Some(if width >= height { format!("{long}:{short}") } else { format!("{short}:{long}") })
+}
+
+/// The first bin at which the cumulative count reaches half of all pixels.
+///
+/// ```
+/// let mut bins = [0u64; 256];
+/// bins[10] = 3;
+/// bins[200] = 2;
+/// assert_eq!(webcalculation::analysis::histogram_median(&bins), 10);
+/// ```
+pub fn histogram_median(bins: &[u64; 256]) -> u8 {
+    let total: u64 = bins.iter().sum();
+    let mut cumulative = 0;
+    for (value, &count) in bins.iter().enumerate() {
+        cumulative += count;
+        if cumulative * 2 >= total {
+            return value as u8;
+        }
+    }
+    255
+}
+
+/// Mean of a histogram after discarding the lowest and highest `percent`% of
+/// pixels. Cut-offs that fall inside a bin remove only part of its count.
+///
+/// ```
+/// let mut bins = [0u64; 256];
+/// bins[0] = 1;
+/// bins[100] = 8;
+/// bins[255] = 1;
+/// assert_eq!(webcalculation::analysis::trimmed_mean(&bins, 10.0), 100.0);
+/// ```
+pub fn trimmed_mean(bins: &[u64; 256], percent: f64) -> f64 {
+    let total: u64 = bins.iter().sum();
+    let cut = total as f64 * percent / 100.0;
+
+    let mut kept: Vec<f64> = bins.iter().map(|&count| count as f64).collect();
+    let mut remove_low = cut;
+    for count in kept.iter_mut() {
+        let removed = count.min(remove_low);
+        *count -= removed;
+        remove_low -= removed;
+    }
+    let mut remove_high = cut;
+    for count in kept.iter_mut().rev() {
+        let removed = count.min(remove_high);
+        *count -= removed;
+        remove_high -= removed;
+    }
+
+    let kept_total: f64 = kept.iter().sum();
+    let weighted: f64 = kept.iter().enumerate().map(|(value, count)| value as f64 * count).sum();
+    weighted / kept_total
+}
+
+/// Hands `f` the image's interleaved samples and channel count. The decoder's
+/// own buffer is used where the layout is already 8- or 16-bit; other sample
+/// formats pay for a converted copy, floating point at 16-bit precision.
+fn with_samples<R>(img: &DynamicImage, f: impl FnOnce(Samples<'_>, usize) -> R) -> R {
+    match img {
+        DynamicImage::ImageLuma8(buffer) => f(Samples::Eight(buffer.as_raw()), 1),
+        DynamicImage::ImageLumaA8(buffer) => f(Samples::Eight(buffer.as_raw()), 2),
+        DynamicImage::ImageRgb8(buffer) => f(Samples::Eight(buffer.as_raw()), 3),
+        DynamicImage::ImageRgba8(buffer) => f(Samples::Eight(buffer.as_raw()), 4),
+        DynamicImage::ImageLuma16(buffer) => f(Samples::Sixteen(buffer.as_raw()), 1),
+        DynamicImage::ImageLumaA16(buffer) => f(Samples::Sixteen(buffer.as_raw()), 2),
+        DynamicImage::ImageRgb16(buffer) => f(Samples::Sixteen(buffer.as_raw()), 3),
+        DynamicImage::ImageRgba16(buffer) => f(Samples::Sixteen(buffer.as_raw()), 4),
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => f(Samples::Sixteen(img.to_rgb16().as_raw()), 3),
+        other => f(Samples::Eight(other.to_rgb8().as_raw()), 3),
+    }
+}
+
+/// Per-pixel `r + g + b` (`3 * gray` for gray images) of an interleaved buffer.
+fn channel_sums<S: Sample>(samples: &[S], channels: usize) -> impl Iterator<Item = u32> + '_ {
+    samples.chunks_exact(channels).map(channel_sum)
+}
+
+/// `r + g + b` of one pixel, or `3 * gray` for a one- or two-channel pixel.
+fn channel_sum<S: Sample>(pixel: &[S]) -> u32 {
+    match pixel.len() {
+        1 | 2 => 3 * pixel[0].into(),
+        _ => pixel[0].into() + pixel[1].into() + pixel[2].into(),
+    }
+}
+
+/// HSP brightness of one pixel on the 0-255 scale, in [`FIXED_POINT`]
+/// units. A gray pixel's is its value, with no square root to take.
+fn hsp_fixed<S: Sample>(pixel: &[S]) -> u64 {
+    let byte_scale = |value: S| 255.0 * f64::from(value.into()) / f64::from(S::MAX);
+    let hsp = match pixel.len() {
+        1 | 2 => byte_scale(pixel[0]),
+        _ => (0.299 * byte_scale(pixel[0]).powi(2)
+            + 0.587 * byte_scale(pixel[1]).powi(2)
+            + 0.114 * byte_scale(pixel[2]).powi(2))
+        .sqrt(),
+    };
+    (hsp * FIXED_POINT).round() as u64
+}
+
+/// Chroma histogram bin of one pixel, its `max - min` channel difference at
+/// 8-bit resolution, and its HSV saturation in [`FIXED_POINT`] units. Both
+/// are 0 for a gray pixel.
+fn chroma_saturation<S: Sample>(pixel: &[S]) -> (usize, u64) {
+    if pixel.len() < 3 {
+        return (0, 0);
+    }
+    let channel_values: [u32; 3] = [pixel[0].into(), pixel[1].into(), pixel[2].into()];
+    let (max, min) = (channel_values.into_iter().max().unwrap(), channel_values.into_iter().min().unwrap());
+    let chroma = max - min;
+    if chroma == 0 {
+        return (0, 0);
+    }
+    let bin = (u64::from(chroma) * 255 + u64::from(S::MAX) / 2) / u64::from(S::MAX);
+    (bin as usize, (f64::from(chroma) / f64::from(max) * FIXED_POINT).round() as u64)
+}
+
+/// Accumulates the per-pixel channel sums `r + g + b` over an interleaved
+/// buffer with `channels` samples per pixel, whose first pixel has row-major
+/// index `first_pixel`. One- and two-channel buffers are gray (optionally with
+/// alpha), counted as `3 * gray`; any alpha channel is ignored.
+pub fn accumulate_sequential<S: Sample>(samples: &[S], channels: usize, first_pixel: usize) -> IntensityAccumulator {
+    let mut totals = IntensityAccumulator::new(first_pixel);
+    let log_lut = S::log_lut();
+    match S::contiguous_channel_total(samples, channels) {
+        // The vectorised byte sum covers the total; the scalar pass only has
+        // to maintain the histogram and extrema.
+        Some(channel_total) => {
+            for (offset, pixel) in samples.chunks_exact(channels).enumerate() {
+                totals.add(first_pixel + offset, pixel, log_lut);
+            }
+            totals.total_channel_sum = channel_total;
+        }
+        None => {
+            for (offset, pixel) in samples.chunks_exact(channels).enumerate() {
+                let channel_sum = totals.add(first_pixel + offset, pixel, log_lut);
+                totals.total_channel_sum += u64::from(channel_sum);
+            }
+        }
+    }
+    totals
+}
+
+/// Same as [`accumulate_sequential`], with chunks of whole pixels processed on
+/// the rayon pool. Partial results are merged in buffer order, never in the
+/// order the chunks finish, and hold only integers, so the outcome is
+/// identical to the sequential pass and to every other run.
+pub fn accumulate_parallel<S: Sample>(samples: &[S], channels: usize) -> IntensityAccumulator {
+    let partials: Vec<_> = samples
+        .par_chunks(PIXELS_PER_CHUNK * channels)
+        .enumerate()
+        .map(|(chunk, chunk_samples)| accumulate_sequential(chunk_samples, channels, chunk * PIXELS_PER_CHUNK))
+        .collect();
+
+    partials
+        .into_iter()
+        .reduce(IntensityAccumulator::merge)
+        .unwrap_or_else(|| IntensityAccumulator::new(0))
+}
+
+/// Mean linear-light intensity (0-1): every channel is decoded from sRGB with
+/// [`srgb_to_linear`] before the `(r + g + b) / 3` average, so the result is
+/// proportional to physical brightness. Returns `None` for an empty image.
+///
+/// ```
+/// use image::{DynamicImage, GrayImage, Luma};
+/// use webcalculation::analysis::linear_intensity;
+///
+/// let mid_gray = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])));
+/// let linear = linear_intensity(&mid_gray).unwrap();
+/// assert!((linear - 0.2158).abs() < 1e-4);
+/// ```
+pub fn linear_intensity(img: &DynamicImage) -> Option<f64> {
+    fn mean<S: Sample>(samples: &[S], channels: usize) -> Option<f64> {
+        let lut = S::linear_lut();
+        let linear = |value: S| lut[Into::<u32>::into(value) as usize];
+        let mut total = 0.0;
+        let mut pixels = 0u64;
+        for pixel in samples.chunks_exact(channels) {
+            total += match channels {
+                1 | 2 => 3.0 * linear(pixel[0]),
+                _ => linear(pixel[0]) + linear(pixel[1]) + linear(pixel[2]),
+            };
+            pixels += 1;
+        }
+        (pixels > 0).then(|| total / (3.0 * pixels as f64))
+    }
+
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => mean(samples, channels),
+        Samples::Sixteen(samples) => mean(samples, channels),
+    })
+}
+
+/// CIE 1976 lightness L* (0-100) of a relative luminance `y` (0-1), against
+/// a white of luminance 1.
+///
+/// ```
+/// use webcalculation::analysis::cie_lightness;
+///
+/// assert!((cie_lightness(1.0) - 100.0).abs() < 1e-9);
+/// assert!((cie_lightness(0.18) - 49.5).abs() < 0.01);
+/// ```
+pub fn cie_lightness(y: f64) -> f64 {
+    const EPSILON: f64 = 216.0 / 24389.0;
+    const KAPPA: f64 = 24389.0 / 27.0;
+    if y > EPSILON { 116.0 * y.cbrt() - 16.0 } else { KAPPA * y }
+}
+
+/// Segments of the L* lookup table over luminance; interpolating between its
+/// entries stays within 0.001 of [`cie_lightness`].
+const LIGHTNESS_LUT_STEPS: usize = 4096;
+
+fn lightness_lut() -> &'static [f64] {
+    static LUT: OnceLock<Vec<f64>> = OnceLock::new();
+    LUT.get_or_init(|| {
+        (0..=LIGHTNESS_LUT_STEPS).map(|step| cie_lightness(step as f64 / LIGHTNESS_LUT_STEPS as f64)).collect()
+    })
+}
+
+/// Mean CIELAB lightness L* (0-100) of the pixels. Each pixel is decoded from
+/// sRGB to linear light, weighted into the luminance `Y` of CIE XYZ with the
+/// Rec. 709 primaries (`0.2126 R + 0.7152 G + 0.0722 B`, D65 white), and
+/// mapped to L*. The decode and the cube root both go through lookup tables.
+/// Returns `None` for an empty image.
+///
+/// ```
+/// use image::{DynamicImage, GrayImage, Luma};
+/// use webcalculation::analysis::lab_lightness;
+///
+/// let white = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([255])));
+/// assert!((lab_lightness(&white).unwrap() - 100.0).abs() < 1e-9);
+/// ```
+pub fn lab_lightness(img: &DynamicImage) -> Option<f64> {
+    fn mean<S: Sample>(samples: &[S], channels: usize) -> Option<f64> {
+        let (linear, lightness) = (S::linear_lut(), lightness_lut());
+        let linear = |value: S| linear[Into::<u32>::into(value) as usize];
+        let mut total = 0.0;
+        let mut pixels = 0u64;
+        for pixel in samples.chunks_exact(channels) {
+            let y = match channels {
+                1 | 2 => linear(pixel[0]),
+                _ => 0.2126 * linear(pixel[0]) + 0.7152 * linear(pixel[1]) + 0.0722 * linear(pixel[2]),
+            };
+            let position = y.clamp(0.0, 1.0) * LIGHTNESS_LUT_STEPS as f64;
+            let step = (position as usize).min(LIGHTNESS_LUT_STEPS - 1);
+            let fraction = position - step as f64;
+            total += lightness[step] + (lightness[step + 1] - lightness[step]) * fraction;
+            pixels += 1;
+        }
+        (pixels > 0).then(|| total / pixels as f64)
+    }
+
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => mean(samples, channels),
+        Samples::Sixteen(samples) => mean(samples, channels),
+    })
+}
+
+/// Mean intensity (0-255) with each pixel weighted by a radial Gaussian
+/// centred on the image, `exp(-r² / 2σ²)` where `r` is the pixel's distance
+/// from the centre and `σ` is `sigma_fraction` of the image diagonal, so
+/// the subject in the middle of a frame counts for more than its corners.
+/// A very large `sigma_fraction` approaches the plain mean. Returns `None`
+/// for an empty image.
+///
+/// ```
+/// use image::{DynamicImage, GrayImage, Luma};
+/// use webcalculation::analysis::center_weighted_intensity;
+///
+/// let flat = DynamicImage::ImageLuma8(GrayImage::from_pixel(9, 9, Luma([80])));
+/// assert!((center_weighted_intensity(&flat, 0.2).unwrap() - 80.0).abs() < 1e-9);
+/// ```
+pub fn center_weighted_intensity(img: &DynamicImage, sigma_fraction: f64) -> Option<f64> {
+    fn mean<S: Sample>(samples: &[S], channels: usize, width: u32, height: u32, sigma_fraction: f64) -> Option<f64> {
+        let sigma = sigma_fraction * f64::from(width).hypot(f64::from(height));
+        // The weight is separable: exp(-(dx² + dy²) / 2σ²) = wx · wy
+        let weights = |length: u32| -> Vec<f64> {
+            let centre = (f64::from(length) - 1.0) / 2.0;
+            (0..length).map(|i| (-(f64::from(i) - centre).powi(2) / (2.0 * sigma * sigma)).exp()).collect()
+        };
+        let (column_weights, row_weights) = (weights(width), weights(height));
+
+        let mut weighted = 0.0;
+        let mut total_weight = 0.0;
+        for (offset, channel_sum) in channel_sums(samples, channels).enumerate() {
+            let (x, y) = (offset % width as usize, offset / width as usize);
+            let weight = column_weights[x] * row_weights[y];
+            weighted += weight * f64::from(channel_sum);
+            total_weight += weight;
+        }
+        (total_weight > 0.0).then(|| weighted / total_weight / 3.0 * 255.0 / f64::from(S::MAX))
+    }
+
+    let (width, height) = (img.width(), img.height());
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => mean(samples, channels, width, height, sigma_fraction),
+        Samples::Sixteen(samples) => mean(samples, channels, width, height, sigma_fraction),
+    })
+}
+
+/// Mean intensities (0-255) of the four quadrants of an image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quadrants {
+    pub top_left: f64,
+    pub top_right: f64,
+    pub bottom_left: f64,
+    pub bottom_right: f64,
+}
+
+/// Mean intensity of each quadrant, split at `width / 2` and `height / 2`
+/// (rounded down), so on odd dimensions the middle column belongs to the
+/// right quadrants and the middle row to the bottom ones. Returns `None`
+/// unless the image is at least 2x2, as a quadrant would be empty otherwise.
+///
+/// ```
+/// use image::{DynamicImage, GrayImage, Luma};
+/// use webcalculation::analysis::quadrant_intensity;
+///
+/// // 3 wide: the bright middle column is on the right
+/// let img = DynamicImage::ImageLuma8(GrayImage::from_fn(3, 2, |x, _| Luma([if x == 0 { 0 } else { 200 }])));
+/// let quadrants = quadrant_intensity(&img).unwrap();
+/// assert_eq!((quadrants.top_left, quadrants.top_right), (0.0, 200.0));
+/// assert!(quadrant_intensity(&DynamicImage::ImageLuma8(GrayImage::new(1, 4))).is_none());
+/// ```
+pub fn quadrant_intensity(img: &DynamicImage) -> Option<Quadrants> {
+    fn means<S: Sample>(samples: &[S], channels: usize, width: u32, height: u32) -> Quadrants {
+        let (split_x, split_y) = ((width / 2) as usize, (height / 2) as usize);
+        // Indexed top-left, top-right, bottom-left, bottom-right
+        let mut sums = [0u64; 4];
+        let mut counts = [0u64; 4];
+        for (offset, channel_sum) in channel_sums(samples, channels).enumerate() {
+            let (x, y) = (offset % width as usize, offset / width as usize);
+            let quadrant = usize::from(x >= split_x) + 2 * usize::from(y >= split_y);
+            sums[quadrant] += u64::from(channel_sum);
+            counts[quadrant] += 1;
+        }
+        let mean = |quadrant: usize| sums[quadrant] as f64 / counts[quadrant] as f64 / 3.0 * 255.0 / f64::from(S::MAX);
+        Quadrants { top_left: mean(0), top_right: mean(1), bottom_left: mean(2), bottom_right: mean(3) }
+    }
+
+    let (width, height) = (img.width(), img.height());
+    if width < 2 || height < 2 {
+        return None;
+    }
+    Some(with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => means(samples, channels, width, height),
+        Samples::Sixteen(samples) => means(samples, channels, width, height),
+    }))
+}
+
+/// Counts pixels whose intensity `(r + g + b) / 3` is strictly above
+/// `threshold` (on the 0-255 scale), returning `(pixels_above, total_pixels)`.
+pub fn count_above_threshold(img: &DynamicImage, threshold: f64) -> (u64, u64) {
+    fn count<S: Sample>(samples: &[S], channels: usize, threshold: f64) -> (u64, u64) {
+        let cut_off = 3.0 * threshold * f64::from(S::MAX) / 255.0;
+        channel_sums(samples, channels).fold((0, 0), |(above, total), channel_sum| {
+            (above + u64::from(f64::from(channel_sum) > cut_off), total + 1)
+        })
+    }
+
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => count(samples, channels, threshold),
+        Samples::Sixteen(samples) => count(samples, channels, threshold),
+    })
+}
+
+/// Pearson correlation matrix of the R, G and B values across all pixels,
+/// indexed `[row][column]` in that channel order. Gray images correlate
+/// perfectly (the three channels are the same value); an entry involving a
+/// channel that never varies is `None`, being undefined. Returns `None` for an
+/// empty image.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::channel_correlation;
+///
+/// let img = RgbImage::from_fn(8, 1, |x, _| Rgb([x as u8 * 30, 255 - x as u8 * 30, 7]));
+/// let matrix = channel_correlation(&DynamicImage::ImageRgb8(img)).unwrap();
+/// assert!((matrix[0][1].unwrap() + 1.0).abs() < 1e-12);
+/// assert_eq!(matrix[2][0], None);
+/// ```
+pub fn channel_correlation(img: &DynamicImage) -> Option<[[Option<f64>; 3]; 3]> {
+    fn correlate<S: Sample>(samples: &[S], channels: usize) -> Option<[[Option<f64>; 3]; 3]> {
+        // Exact integer sums, cross-products included, gathered in one pass
+        let mut pixels = 0u128;
+        let mut sums = [0u128; 3];
+        let mut products = [[0u128; 3]; 3];
+        for pixel in samples.chunks_exact(channels) {
+            let rgb: [u128; 3] = match channels {
+                1 | 2 => [pixel[0].into().into(); 3],
+                _ => [pixel[0].into().into(), pixel[1].into().into(), pixel[2].into().into()],
+            };
+            pixels += 1;
+            for (row, &value) in rgb.iter().enumerate() {
+                sums[row] += value;
+                for column in row..3 {
+                    products[row][column] += value * rgb[column];
+                }
+            }
+        }
+        if pixels == 0 {
+            return None;
+        }
+
+        // n² times the covariance, exact in signed arithmetic
+        let covariance = |a: usize, b: usize| {
+            let (low, high) = (a.min(b), a.max(b));
+            (pixels * products[low][high]) as i128 - (sums[a] * sums[b]) as i128
+        };
+        let mut matrix = [[None; 3]; 3];
+        for (row, entries) in matrix.iter_mut().enumerate() {
+            for (column, entry) in entries.iter_mut().enumerate() {
+                let spread = covariance(row, row) as f64 * covariance(column, column) as f64;
+                if spread > 0.0 {
+                    *entry = Some((covariance(row, column) as f64 / spread.sqrt()).clamp(-1.0, 1.0));
+                }
+            }
+        }
+        Some(matrix)
+    }
+
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => correlate(samples, channels),
+        Samples::Sixteen(samples) => correlate(samples, channels),
+    })
+}
+
+/// Per-channel and intensity histograms returned by [`rgb_histograms`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RgbHistograms {
+    pub red: [u64; 256],
+    pub green: [u64; 256],
+    pub blue: [u64; 256],
+    /// Histogram of the per-pixel intensity `(r + g + b) / 3`, the same bins
+    /// as [`IntensityStats::histogram`]
+    pub luminance: [u64; 256],
+}
+
+/// Histograms of the R, G and B channels and of the intensity, all four
+/// gathered in one pass. 16-bit values are rounded to the nearest of 256
+/// bins; gray images count each value in all three channel histograms.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::rgb_histograms;
+///
+/// let img = RgbImage::from_pixel(2, 2, Rgb([255, 0, 30]));
+/// let histograms = rgb_histograms(&DynamicImage::ImageRgb8(img));
+/// assert_eq!((histograms.red[255], histograms.green[0], histograms.blue[30]), (4, 4, 4));
+/// assert_eq!(histograms.luminance[95], 4);
+/// ```
+pub fn rgb_histograms(img: &DynamicImage) -> RgbHistograms {
+    fn accumulate<S: Sample>(samples: &[S], channels: usize) -> RgbHistograms {
+        let mut histograms = RgbHistograms { red: [0; 256], green: [0; 256], blue: [0; 256], luminance: [0; 256] };
+        for pixel in samples.chunks_exact(channels) {
+            let [r, g, b]: [u32; 3] = match channels {
+                1 | 2 => [pixel[0].into(); 3],
+                _ => [pixel[0].into(), pixel[1].into(), pixel[2].into()],
+            };
+            // A single value's bin is that of a gray pixel's channel sum
+            histograms.red[S::histogram_bin(3 * r)] += 1;
+            histograms.green[S::histogram_bin(3 * g)] += 1;
+            histograms.blue[S::histogram_bin(3 * b)] += 1;
+            histograms.luminance[S::histogram_bin(r + g + b)] += 1;
+        }
+        histograms
+    }
+
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => accumulate(samples, channels),
+        Samples::Sixteen(samples) => accumulate(samples, channels),
+    })
+}
+
+/// Hue and saturation summary returned by [`hue_stats`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HueStats {
+    /// Circular mean of the HSV hue in degrees (0-360), each pixel weighted
+    /// by its saturation; `None` when the hues cancel out or no pixel has any
+    pub mean_hue: Option<f64>,
+    /// Mean HSV saturation (0-1)
+    pub mean_saturation: f64,
+}
+
+/// Coarse color family of an image, see [`HueStats::color_family`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorFamily {
+    Neutral,
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Cyan,
+    Blue,
+    Purple,
+    Magenta,
+}
+
+impl ColorFamily {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColorFamily::Neutral => "neutral",
+            ColorFamily::Red => "red",
+            ColorFamily::Orange => "orange",
+            ColorFamily::Yellow => "yellow",
+            ColorFamily::Green => "green",
+            ColorFamily::Cyan => "cyan",
+            ColorFamily::Blue => "blue",
+            ColorFamily::Purple => "purple",
+            ColorFamily::Magenta => "magenta",
+        }
+    }
+}
+
+/// Upper hue bound (exclusive, degrees) of each family; red also covers the
+/// hues from the last bound up to 360.
+const HUE_FAMILIES: [(f64, ColorFamily); 8] = [
+    (15.0, ColorFamily::Red),
+    (45.0, ColorFamily::Orange),
+    (70.0, ColorFamily::Yellow),
+    (165.0, ColorFamily::Green),
+    (195.0, ColorFamily::Cyan),
+    (255.0, ColorFamily::Blue),
+    (285.0, ColorFamily::Purple),
+    (345.0, ColorFamily::Magenta),
+];
+
+/// Resultant length, relative to the total saturation, below which the hues
+/// are taken to cancel out and the mean hue is undefined.
+const MIN_HUE_CONCENTRATION: f64 = 1e-6;
+
+impl HueStats {
+    /// `Neutral` when the mean saturation is below `saturation_threshold`
+    /// (0-1) or the hues have no mean, otherwise the family of the mean hue.
+    pub fn color_family(&self, saturation_threshold: f64) -> ColorFamily {
+        match self.mean_hue {
+            Some(hue) if self.mean_saturation >= saturation_threshold => HUE_FAMILIES
+                .iter()
+                .find(|(bound, _)| hue < *bound)
+                .map_or(ColorFamily::Red, |&(_, family)| family),
+            _ => ColorFamily::Neutral,
+        }
+    }
+}
+
+/// Mean saturation and saturation-weighted circular mean hue of the pixels,
+/// in HSV. Weighting by saturation lets nearly gray pixels, whose hue is
+/// mostly noise, count for little. Returns `None` for an empty image.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::{hue_stats, ColorFamily};
+///
+/// let img = RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([255, 0, 0]) } else { Rgb([255, 255, 0]) });
+/// let stats = hue_stats(&DynamicImage::ImageRgb8(img)).unwrap();
+/// assert!((stats.mean_hue.unwrap() - 30.0).abs() < 1e-9);
+/// assert_eq!(stats.color_family(0.1), ColorFamily::Orange);
+/// ```
+pub fn hue_stats(img: &DynamicImage) -> Option<HueStats> {
+    fn accumulate<S: Sample>(samples: &[S], channels: usize) -> Option<HueStats> {
+        if channels < 3 {
+            // Gray: no saturation, no hue
+            return (!samples.is_empty()).then_some(HueStats { mean_hue: None, mean_saturation: 0.0 });
+        }
+        let (mut pixels, mut saturation_sum, mut sin_sum, mut cos_sum) = (0u64, 0.0, 0.0, 0.0);
+        for pixel in samples.chunks_exact(channels) {
+            pixels += 1;
+            let Some((hue, saturation)) = hue_saturation(rgb(pixel)) else { continue };
+            let hue = hue.to_radians();
+            saturation_sum += saturation;
+            sin_sum += saturation * hue.sin();
+            cos_sum += saturation * hue.cos();
+        }
+        if pixels == 0 {
+            return None;
+        }
+        let concentrated = sin_sum.hypot(cos_sum) > MIN_HUE_CONCENTRATION * saturation_sum;
+        Some(HueStats {
+            mean_hue: (saturation_sum > 0.0 && concentrated).then(|| sin_sum.atan2(cos_sum).to_degrees().rem_euclid(360.0)),
+            mean_saturation: saturation_sum / pixels as f64,
+        })
+    }
+
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => accumulate(samples, channels),
+        Samples::Sixteen(samples) => accumulate(samples, channels),
+    })
+}
+
+/// [`HueStats::color_family`] of an image whose `stats` are already known.
+/// The mean saturation in `stats` settles `Neutral` without looking at the
+/// hues, so the [`hue_stats`] pass only runs when the hue decides the family.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::{color_family, intensity_stats, ColorFamily};
+///
+/// let pale_teal = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([150, 200, 200])));
+/// let stats = intensity_stats(&pale_teal).unwrap();
+/// assert_eq!(color_family(&pale_teal, &stats, 0.2), ColorFamily::Cyan);
+/// assert_eq!(color_family(&pale_teal, &stats, 0.3), ColorFamily::Neutral);
+/// ```
+pub fn color_family(img: &DynamicImage, stats: &IntensityStats, saturation_threshold: f64) -> ColorFamily {
+    if stats.mean_saturation < saturation_threshold {
+        return ColorFamily::Neutral;
+    }
+    hue_stats(img).map_or(ColorFamily::Neutral, |hues| hues.color_family(saturation_threshold))
+}
+
+/// The first three samples of a pixel, as floats.
+fn rgb<S: Sample>(pixel: &[S]) -> [f64; 3] {
+    [pixel[0].into(), pixel[1].into(), pixel[2].into()].map(f64::from)
+}
+
+/// HSV hue in degrees (0-360) and saturation (0-1) of a pixel; `None` for a
+/// gray pixel, which has neither.
+fn hue_saturation([r, g, b]: [f64; 3]) -> Option<(f64, f64)> {
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    if max == min {
+        return None;
+    }
+    let chroma = max - min;
+    let sector = if max == r {
+        (g - b) / chroma
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    Some(((sector * 60.0).rem_euclid(360.0), chroma / max))
+}
+
+/// CIE 1931 chromaticity of the D65 white point, which sRGB gray has.
+const D65_CHROMATICITY: (f64, f64) = (0.3127, 0.3290);
+/// Correlated color temperatures below this (kelvin) read as warm.
+const WARM_BELOW_KELVIN: f64 = 5500.0;
+/// Correlated color temperatures above this (kelvin) read as cool.
+const COOL_ABOVE_KELVIN: f64 = 7500.0;
+/// Kelvin range over which McCamy's cubic follows the Planckian locus.
+const MCCAMY_RANGE_KELVIN: (f64, f64) = (2000.0, 12500.0);
+/// Mean chroma (0-1) below which an image counts as grayscale.
+const GRAY_CHROMA_MAX: f64 = 0.02;
+/// Mean luminance (0-1, linear) below which an image is too dark to judge.
+const DARK_LUMINANCE_MAX: f64 = 0.01;
+
+/// Color cast of a [`ColorTemperature`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorCast {
+    Warm,
+    Neutral,
+    Cool,
+}
+
+impl ColorCast {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColorCast::Warm => "warm",
+            ColorCast::Neutral => "neutral",
+            ColorCast::Cool => "cool",
+        }
+    }
+}
+
+/// Color temperature estimate returned by [`color_temperature`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorTemperature {
+    /// Correlated color temperature in kelvin, from McCamy's approximation
+    pub kelvin: f64,
+    /// CIE 1931 (x, y) chromaticity of the mean linear-light color
+    pub chromaticity: (f64, f64),
+    /// Relative luminance Y of the mean color (0-1)
+    pub luminance: f64,
+    /// Mean of each pixel's largest minus smallest channel (0-1), 0 for gray
+    pub mean_chroma: f64,
+}
+
+impl ColorTemperature {
+    /// `Warm` below 5500 K, `Cool` above 7500 K, `Neutral` in between;
+    /// sRGB white is 6504 K.
+    pub fn cast(&self) -> ColorCast {
+        if self.kelvin < WARM_BELOW_KELVIN {
+            ColorCast::Warm
+        } else if self.kelvin > COOL_ABOVE_KELVIN {
+            ColorCast::Cool
+        } else {
+            ColorCast::Neutral
+        }
+    }
+
+    /// Whether the estimate means anything: not for a near-grayscale or very
+    /// dark image, whose mean color is just the white point or noise, nor
+    /// outside the range McCamy's formula was fitted to.
+    pub fn is_reliable(&self) -> bool {
+        self.mean_chroma >= GRAY_CHROMA_MAX
+            && self.luminance >= DARK_LUMINANCE_MAX
+            && (MCCAMY_RANGE_KELVIN.0..=MCCAMY_RANGE_KELVIN.1).contains(&self.kelvin)
+    }
+}
+
+/// McCamy's (1992) cubic approximation of the correlated color temperature,
+/// in kelvin, of a CIE 1931 chromaticity.
+///
+/// ```
+/// use webcalculation::analysis::mccamy_cct;
+///
+/// // D65 and illuminant A
+/// assert!((mccamy_cct(0.3127, 0.3290) - 6504.0).abs() < 5.0);
+/// assert!((mccamy_cct(0.4476, 0.4074) - 2856.0).abs() < 5.0);
+/// ```
+pub fn mccamy_cct(x: f64, y: f64) -> f64 {
+    let n = (x - 0.3320) / (0.1858 - y);
+    ((449.0 * n + 3525.0) * n + 6823.3) * n + 5520.33
+}
+
+/// Rough correlated color temperature of an image: its pixels are decoded to
+/// linear light and averaged, the mean converted to CIE XYZ with the sRGB (D65)
+/// matrix, and [`mccamy_cct`] applied to its `xy` chromaticity. A black image,
+/// which has no chromaticity, is taken to be white-point gray. Returns `None`
+/// for an empty image.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::{color_temperature, ColorCast};
+///
+/// let candle = color_temperature(&DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([255, 190, 120])))).unwrap();
+/// assert_eq!(candle.cast(), ColorCast::Warm);
+/// assert!(candle.is_reliable());
+/// ```
+pub fn color_temperature(img: &DynamicImage) -> Option<ColorTemperature> {
+    fn means<S: Sample>(samples: &[S], channels: usize) -> Option<([f64; 3], f64)> {
+        let lut = S::linear_lut();
+        let (mut linear_sums, mut chroma_sum, mut pixels) = ([0.0; 3], 0.0, 0u64);
+        for pixel in samples.chunks_exact(channels) {
+            let color = if channels < 3 { [pixel[0]; 3] } else { [pixel[0], pixel[1], pixel[2]] };
+            let values = color.map(Into::<u32>::into);
+            for (sum, value) in linear_sums.iter_mut().zip(values) {
+                *sum += lut[value as usize];
+            }
+            chroma_sum += f64::from(values.iter().max().unwrap() - values.iter().min().unwrap());
+            pixels += 1;
+        }
+        (pixels > 0).then(|| (linear_sums.map(|sum| sum / pixels as f64), chroma_sum / (pixels as f64 * f64::from(S::MAX))))
+    }
+
+    let ([r, g, b], mean_chroma) = with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => means(samples, channels),
+        Samples::Sixteen(samples) => means(samples, channels),
+    })?;
+    let x = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+    let total = x + y + z;
+    let chromaticity = if total > 0.0 { (x / total, y / total) } else { D65_CHROMATICITY };
+    Some(ColorTemperature {
+        kelvin: mccamy_cct(chromaticity.0, chromaticity.1),
+        chromaticity,
+        luminance: y,
+        mean_chroma,
+    })
+}
+
+/// Buckets of [`HsvStats::hue_histogram`], 30° each.
+pub const HUE_BUCKETS: usize = 12;
+
+/// HSV summary returned by [`hsv_stats`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HsvStats {
+    /// Mean saturation (0-1)
+    pub mean_saturation: f64,
+    /// Population standard deviation of the saturation
+    pub std_saturation: f64,
+    /// Mean value, the brightest channel (0-1)
+    pub mean_value: f64,
+    /// Population standard deviation of the value
+    pub std_value: f64,
+    /// Share of the total saturation in each 30° hue bucket, bucket `i`
+    /// centered on `30 * i` degrees so that red (0°), yellow (60°), green
+    /// (120°), cyan (180°), blue (240°) and magenta (300°) sit mid-bucket.
+    /// Sums to 1, or all zero when no pixel has any saturation.
+    pub hue_histogram: [f64; HUE_BUCKETS],
+}
+
+/// Saturation and value statistics and a saturation-weighted hue histogram
+/// in HSV, so nearly gray pixels, whose hue is mostly noise, count for
+/// little. Gray images have zero saturation and an all-zero histogram.
+/// Returns `None` for an empty image.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::hsv_stats;
+///
+/// let img = RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) });
+/// let stats = hsv_stats(&DynamicImage::ImageRgb8(img)).unwrap();
+/// assert_eq!((stats.mean_saturation, stats.mean_value), (1.0, 1.0));
+/// assert_eq!((stats.hue_histogram[0], stats.hue_histogram[8]), (0.5, 0.5));
+/// ```
+pub fn hsv_stats(img: &DynamicImage) -> Option<HsvStats> {
+    fn accumulate<S: Sample>(samples: &[S], channels: usize) -> Option<HsvStats> {
+        let max = f64::from(S::MAX);
+        let (mut pixels, mut saturation, mut value) = (0u64, [0.0; 2], [0.0; 2]);
+        let mut buckets = [0.0; HUE_BUCKETS];
+        for pixel in samples.chunks_exact(channels) {
+            pixels += 1;
+            let channels = match channels {
+                1 | 2 => [f64::from(pixel[0].into()); 3],
+                _ => rgb(pixel),
+            };
+            let brightest = channels[0].max(channels[1]).max(channels[2]) / max;
+            value[0] += brightest;
+            value[1] += brightest * brightest;
+            let Some((hue, pixel_saturation)) = hue_saturation(channels) else { continue };
+            saturation[0] += pixel_saturation;
+            saturation[1] += pixel_saturation * pixel_saturation;
+            let bucket = ((hue + 15.0) / 30.0) as usize % HUE_BUCKETS;
+            buckets[bucket] += pixel_saturation;
+        }
+        if pixels == 0 {
+            return None;
+        }
+        let moments = |[sum, squares]: [f64; 2]| {
+            let mean = sum / pixels as f64;
+            (mean, (squares / pixels as f64 - mean * mean).max(0.0).sqrt())
+        };
+        let ((mean_saturation, std_saturation), (mean_value, std_value)) = (moments(saturation), moments(value));
+        let total = saturation[0];
+        Some(HsvStats {
+            mean_saturation,
+            std_saturation,
+            mean_value,
+            std_value,
+            hue_histogram: buckets.map(|bucket| if total > 0.0 { bucket / total } else { 0.0 }),
+        })
+    }
+
+    with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => accumulate(samples, channels),
+        Samples::Sixteen(samples) => accumulate(samples, channels),
+    })
+}
+
+/// Counts distinct RGB values, stopping once `max_colors` have been seen so the
+/// set can't grow without bound on huge photographic images. Returns the count
+/// and whether it was truncated at `max_colors`, which is only when a further
+/// color turned up; an image with exactly `max_colors` colors is counted in full.
+pub fn count_unique_colors(
+    image_data: &[u8],
+    limits: &DecodeLimits,
+    max_colors: usize,
+) -> Result<(usize, bool), AnalysisError> {
+    Ok(unique_colors(&decode_image(image_data, limits)?, max_colors))
+}
+
+/// [`count_unique_colors`] for an already decoded image.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::unique_colors;
+///
+/// let img = RgbImage::from_fn(4, 4, |x, _| Rgb([x as u8, 0, 0]));
+/// assert_eq!(unique_colors(&DynamicImage::ImageRgb8(img), 1 << 20), (4, false));
+/// ```
+pub fn unique_colors(img: &DynamicImage, max_colors: usize) -> (usize, bool) {
+    let rgb_img = img.to_rgb8();
+
+    let mut colors = HashSet::new();
+    for pixel in rgb_img.pixels() {
+        let packed = u32::from(pixel[0]) << 16 | u32::from(pixel[1]) << 8 | u32::from(pixel[2]);
+        if colors.len() >= max_colors && !colors.contains(&packed) {
+            return (colors.len(), true);
+        }
+        colors.insert(packed);
+    }
+
+    (colors.len(), false)
+}
+
+/// Side of the intensity image the perceptual hash transforms.
+const PHASH_SIZE: usize = 32;
+
+/// Side of the block of lowest DCT frequencies that become the hash bits.
+const PHASH_BLOCK: usize = 8;
+
+/// 64-bit DCT perceptual hash (pHash). The image is area-averaged to 32x32
+/// regardless of its aspect ratio and reduced to intensity; each bit of the
+/// hash, most significant first and row by row, says whether one of the 8x8
+/// lowest-frequency DCT coefficients is above their median. Rescaling,
+/// recompression and small edits flip few bits, so images are compared by the
+/// [`hash_distance`] of their hashes.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage, Rgb};
+/// use webcalculation::analysis::{hash_distance, perceptual_hash};
+///
+/// // A bright disc on the left of a dark frame, and the same frame mirrored
+/// let disc = |flip: bool| {
+///     DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+///         let x = if flip { 127 - x } else { x };
+///         Rgb([if (x as i32 - 36).pow(2) + (y as i32 - 48).pow(2) < 24 * 24 { 230 } else { 20 }; 3])
+///     }))
+/// };
+/// let hash = perceptual_hash(&disc(false));
+/// assert!(hash_distance(hash, perceptual_hash(&disc(false).thumbnail(64, 48))) <= 8);
+/// assert!(hash_distance(hash, perceptual_hash(&disc(true))) >= 16);
+/// ```
+pub fn perceptual_hash(img: &DynamicImage) -> u64 {
+    let gray = intensity_image(&img.thumbnail_exact(PHASH_SIZE as u32, PHASH_SIZE as u32));
+    let cosines: Vec<[f64; PHASH_SIZE]> = (0..PHASH_BLOCK)
+        .map(|frequency| {
+            std::array::from_fn(|x| {
+                (std::f64::consts::PI * frequency as f64 * (2 * x + 1) as f64 / (2 * PHASH_SIZE) as f64).cos()
+            })
+        })
+        .collect();
+
+    // The 2-D DCT-II as a transform of the rows, then of the columns, for the
+    // low frequencies only
+    let rows: Vec<[f64; PHASH_BLOCK]> = gray
+        .as_raw()
+        .chunks_exact(PHASH_SIZE)
+        .map(|row| std::array::from_fn(|u| row.iter().zip(&cosines[u]).map(|(&value, cos)| f64::from(value) * cos).sum()))
+        .collect();
+    let coefficients: Vec<f64> = (0..PHASH_BLOCK)
+        .flat_map(|v| {
+            let (rows, cosines) = (&rows, &cosines);
+            (0..PHASH_BLOCK).map(move |u| rows.iter().zip(&cosines[v]).map(|(row, cos)| row[u] * cos).sum())
+        })
+        .collect();
+
+    let mut sorted = coefficients.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0;
+    coefficients.iter().fold(0, |hash, &coefficient| hash << 1 | u64::from(coefficient > median))
+}
+
+/// Number of bits in which two [`perceptual_hash`]es differ, 0 for
+/// identical hashes and at most 64.
+pub fn hash_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Resamples `img` so its longest side is at most `max_side` pixels, keeping
+/// the aspect ratio, with an area-averaging filter: each output pixel is the
+/// mean of the block of source pixels it covers, at the source bit depth.
+/// Images already within the bound are returned as they are.
+///
+/// ```
+/// use image::{DynamicImage, RgbImage};
+/// use webcalculation::analysis::downscale;
+///
+/// let img = DynamicImage::ImageRgb8(RgbImage::new(4000, 3000));
+/// let small = downscale(&img, 400);
+/// assert_eq!((small.width(), small.height()), (400, 300));
+/// ```
+pub fn downscale(img: &DynamicImage, max_side: u32) -> Cow<'_, DynamicImage> {
+    if img.width().max(img.height()) <= max_side {
+        Cow::Borrowed(img)
+    } else {
+        Cow::Owned(img.thumbnail(max_side, max_side))
+    }
+}
+
+/// Converts to an 8-bit gray image using the same per-pixel intensity
+/// `(r + g + b) / 3` as the statistics, rounded to the histogram bin that
+/// `median_intensity` is read from.
+pub fn intensity_image(img: &DynamicImage) -> GrayImage {
+    if let DynamicImage::ImageLuma8(gray) = img {
+        return gray.clone();
+    }
+
+    fn bins<S: Sample>(samples: &[S], channels: usize) -> Vec<u8> {
+        channel_sums(samples, channels).map(|channel_sum| S::histogram_bin(channel_sum) as u8).collect()
+    }
+    let pixels = with_samples(img, |samples, channels| match samples {
+        Samples::Eight(samples) => bins(samples, channels),
+        Samples::Sixteen(samples) => bins(samples, channels),
+    });
+    GrayImage::from_raw(img.width(), img.height(), pixels).expect("one bin per pixel")
+}
+
+/// 256-bin histogram of a gray image.
+pub fn histogram(gray: &GrayImage) -> [u64; 256] {
+    let mut bins = [0u64; 256];
+    for &value in gray.as_raw() {
+        bins[usize::from(value)] += 1;
+    }
+    bins
+}
+
+/// Result of Otsu's method on a histogram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Otsu {
+    /// Pixels at or below this value form the background class
+    pub threshold: u8,
+    /// `w0 * w1 * (mu0 - mu1)^2` with class weights as fractions of all pixels
+    pub between_class_variance: f64,
+    /// Fraction of pixels at or below the threshold
+    pub background_fraction: f64,
+}
+
+/// Otsu's method: the threshold `t` maximising the between-class variance of
+/// the classes `[0, t]` and `(t, 255]`.
+///
+/// ```
+/// let mut bins = [0u64; 256];
+/// bins[20] = 50;
+/// bins[220] = 50;
+/// let split = webcalculation::analysis::otsu(&bins);
+/// assert!((20..220).contains(&split.threshold));
+/// assert_eq!(split.background_fraction, 0.5);
+/// ```
+pub fn otsu(bins: &[u64; 256]) -> Otsu {
+    let total: u64 = bins.iter().sum();
+    let weighted_total: f64 = bins.iter().enumerate().map(|(value, &count)| value as f64 * count as f64).sum();
+
+    let mut best = Otsu {
+        threshold: 0,
+        between_class_variance: 0.0,
+        background_fraction: if total == 0 { 0.0 } else { bins[0] as f64 / total as f64 },
+    };
+    let mut background_count = 0u64;
+    let mut background_sum = 0.0;
+
+    for (value, &count) in bins.iter().enumerate() {
+        background_count += count;
+        if background_count == 0 {
+            continue;
+        }
+        let foreground_count = total - background_count;
+        if foreground_count == 0 {
+            break;
+        }
+
+        background_sum += value as f64 * count as f64;
+        let background_weight = background_count as f64 / total as f64;
+        let background_mean = background_sum / background_count as f64;
+        let foreground_mean = (weighted_total - background_sum) / foreground_count as f64;
+        let variance = background_weight * (1.0 - background_weight) * (background_mean - foreground_mean).powi(2);
+
+        if variance > best.between_class_variance {
+            best = Otsu {
+                threshold: value as u8,
+                between_class_variance: variance,
+                background_fraction: background_weight,
+            };
+        }
+    }
+
+    best
+}
+
+/// Maps pixels above `threshold` to 255 and the rest to 0.
+pub fn binarize(gray: &GrayImage, threshold: u8) -> GrayImage {
+    let mut mask = gray.clone();
+    for value in mask.iter_mut() {
+        *value = if *value > threshold { 255 } else { 0 };
+    }
+    mask
+}
+
+/// Quantizes to black and white with Floyd-Steinberg error diffusion: each
+/// pixel becomes 0 or 255, whichever is nearer, and its error is carried to
+/// the unvisited neighbours (7/16 right, 3/16 below left, 5/16 below, 1/16
+/// below right), so areas keep their average brightness.
+///
+/// ```
+/// use image::{GrayImage, Luma};
+/// use webcalculation::analysis::floyd_steinberg;
+///
+/// // 100 rounds down to 0, and 7/16 of its error pushes the next pixel past 127
+/// let dithered = floyd_steinberg(&GrayImage::from_pixel(2, 1, Luma([100])));
+/// assert_eq!(dithered.as_raw(), &[0, 255]);
+/// ```
+pub fn floyd_steinberg(gray: &GrayImage) -> GrayImage {
+    let (width, height) = (gray.width() as usize, gray.height() as usize);
+    let mut values: Vec<f32> = gray.as_raw().iter().map(|&value| f32::from(value)).collect();
+    let mut out = GrayImage::new(gray.width(), gray.height());
+
+    for y in 0..height {
+        for x in 0..width {
+            let old = values[y * width + x];
+            let new = if old >= 127.5 { 255.0 } else { 0.0 };
+            out.as_mut()[y * width + x] = new as u8;
+
+            let error = old - new;
+            let mut spread = |dx: isize, dy: usize, weight: f32| {
+                let nx = x as isize + dx;
+                if (0..width as isize).contains(&nx) && y + dy < height {
+                    values[(y + dy) * width + nx as usize] += error * weight;
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
+    out
+}
+
+/// 8x8 Bayer matrix: the order in which a tile's pixels turn on as the gray level rises.
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Quantizes to black and white by comparing each pixel with the threshold
+/// its position gets from a tiled 8x8 Bayer matrix. Unlike error diffusion,
+/// a pixel's output depends only on its own value and position.
+///
+/// ```
+/// use image::{GrayImage, Luma};
+/// use webcalculation::analysis::ordered_dither;
+///
+/// let dithered = ordered_dither(&GrayImage::from_pixel(8, 8, Luma([64])));
+/// assert_eq!(dithered.iter().filter(|&&value| value == 255).count(), 16);
+/// ```
+pub fn ordered_dither(gray: &GrayImage) -> GrayImage {
+    GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+        let rank = BAYER_8X8[y as usize % 8][x as usize % 8];
+        // Thresholds sit midway between the 64 levels a tile can show
+        let threshold = (f32::from(rank) + 0.5) * 4.0;
+        Luma([if f32::from(gray.get_pixel(x, y).0[0]) > threshold { 255 } else { 0 }])
+    })
+}
+
+/// The `percent`th and `(100 - percent)`th percentiles of a histogram: the
+/// darkest and brightest values left once `percent`% of the pixels are
+/// discarded at each end. With 0 they are the darkest and brightest values
+/// present. `None` for an empty histogram.
+///
+/// ```
+/// let mut bins = [0u64; 256];
+/// bins[10] = 1;
+/// bins[60] = 98;
+/// bins[250] = 1;
+/// let percentiles = webcalculation::analysis::percentile_bounds;
+/// assert_eq!(percentiles(&bins, 0.0), Some((10, 250)));
+/// assert_eq!(percentiles(&bins, 1.0), Some((60, 60)));
+/// ```
+pub fn percentile_bounds(bins: &[u64; 256], percent: f64) -> Option<(u8, u8)> {
+    let total: u64 = bins.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let clipped = total as f64 * percent / 100.0;
+    // The first value, walking from one end, past the clipped pixels
+    let first_past = |mut values: Box<dyn Iterator<Item = usize>>| {
+        let mut seen = 0;
+        values.find(|&value| {
+            seen += bins[value];
+            seen as f64 > clipped
+        })
+    };
+    let low = first_past(Box::new(0..256))?;
+    let high = first_past(Box::new((0..256).rev()))?;
+    Some((low as u8, high.max(low) as u8))
+}
+
+/// Stretches the levels of every color channel linearly so that `low` maps
+/// to 0 and `high` to 255, clamping values outside. Alpha is kept as it is;
+/// the result has 8 bits per channel and the image's gray or color layout.
+/// Returns the image unchanged in 8 bits when `high <= low`, as there is no
+/// range to stretch.
+///
+/// ```
+/// use image::{DynamicImage, GrayImage, Luma};
+/// use webcalculation::analysis::stretch_levels;
+///
+/// let img = DynamicImage::ImageLuma8(GrayImage::from_fn(3, 1, |x, _| Luma([[100, 120, 140][x as usize]])));
+/// assert_eq!(stretch_levels(&img, 100, 140).as_bytes(), &[0, 128, 255]);
+/// ```
+pub fn stretch_levels(img: &DynamicImage, low: u8, high: u8) -> DynamicImage {
+    let mut lut = [0u8; 256];
+    for (value, level) in lut.iter_mut().enumerate() {
+        *level = if high > low {
+            let stretched = (value as f64 - f64::from(low)) * 255.0 / f64::from(high - low);
+            stretched.round().clamp(0.0, 255.0) as u8
+        } else {
+            value as u8
+        };
+    }
+    let mut out = eight_bit(img);
+    for_each_color(&mut out, |colors| colors.iter_mut().for_each(|sample| *sample = lut[usize::from(*sample)]));
+    out
+}
+
+/// Histogram equalization of the HSV value, the brightest channel: values are
+/// remapped through their cumulative distribution so they spread as evenly
+/// as possible over 0-255. Every color channel of a pixel is scaled by the
+/// same factor, which keeps its hue and saturation, so colors do not shift.
+/// Alpha is kept as it is; the result has 8 bits per channel and the image's
+/// gray or color layout. A single-valued image is returned unchanged.
+///
+/// ```
+/// use image::{DynamicImage, GrayImage, Luma};
+/// use webcalculation::analysis::equalize;
+///
+/// let img = DynamicImage::ImageLuma8(GrayImage::from_fn(4, 1, |x, _| Luma([[100, 101, 102, 103][x as usize]])));
+/// assert_eq!(equalize(&img).as_bytes(), &[0, 85, 170, 255]);
+/// ```
+pub fn equalize(img: &DynamicImage) -> DynamicImage {
+    let mut out = eight_bit(img);
+    let value = |colors: &[u8]| colors.iter().copied().max().unwrap_or_default();
+    let mut bins = [0u64; 256];
+    for_each_color(&mut out, |colors| bins[usize::from(value(colors))] += 1);
+
+    let total: u64 = bins.iter().sum();
+    let darkest = bins.iter().copied().find(|&count| count > 0).unwrap_or_default();
+    if total == darkest {
+        return out;
+    }
+    let mut lut = [0u8; 256];
+    let mut cumulative = 0;
+    for (level, &count) in lut.iter_mut().zip(&bins) {
+        cumulative += count;
+        // The darkest value present maps to 0 and the brightest to 255
+        *level = ((cumulative.saturating_sub(darkest)) as f64 * 255.0 / (total - darkest) as f64).round() as u8;
+    }
+    for_each_color(&mut out, |colors| {
+        let old = value(colors);
+        if old == 0 {
+            return;
+        }
+        let gain = f64::from(lut[usize::from(old)]) / f64::from(old);
+        for sample in colors {
+            *sample = (f64::from(*sample) * gain).round().min(255.0) as u8;
+        }
+    });
+    out
+}
+
+/// `img` at 8 bits per channel, keeping its gray or color layout and any alpha.
+fn eight_bit(img: &DynamicImage) -> DynamicImage {
+    let color = img.color();
+    match (color.has_color(), color.has_alpha()) {
+        (false, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+        (false, true) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        (true, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+        (true, true) => DynamicImage::ImageRgba8(img.to_rgba8()),
+    }
+}
+
+/// Calls `f` with the color samples of every pixel of an [`eight_bit`]
+/// image, one for gray and three for color, leaving alpha out.
+fn for_each_color(img: &mut DynamicImage, mut f: impl FnMut(&mut [u8])) {
+    let channels = usize::from(img.color().channel_count());
+    let colors = if img.color().has_color() { 3 } else { 1 };
+    let samples: &mut [u8] = match img {
+        DynamicImage::ImageLuma8(buffer) => buffer,
+        DynamicImage::ImageLumaA8(buffer) => buffer,
+        DynamicImage::ImageRgb8(buffer) => buffer,
+        DynamicImage::ImageRgba8(buffer) => buffer,
+        _ => unreachable!("eight_bit images have one of these layouts"),
+    };
+    for pixel in samples.chunks_exact_mut(channels) {
+        f(&mut pixel[..colors]);
+    }
+}
+
+/// The per-channel absolute difference `|a - b|` of two images, at 8 bits
+/// per channel and multiplied by `gain`, clamping at 255. Alpha is ignored.
+/// `None` when the sizes differ.
+///
+/// ```
+/// use image::{DynamicImage, Rgb, RgbImage};
+/// use webcalculation::analysis::difference_image;
+///
+/// let a = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([100, 50, 200])));
+/// let b = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([90, 60, 200])));
+/// assert_eq!(difference_image(&a, &b, 1.0).unwrap().get_pixel(0, 0).0, [10, 10, 0]);
+/// assert_eq!(difference_image(&a, &b, 30.0).unwrap().get_pixel(0, 0).0, [255, 255, 0]);
+/// ```
+pub fn difference_image(a: &DynamicImage, b: &DynamicImage, gain: f64) -> Option<RgbImage> {
+    if (a.width(), a.height()) != (b.width(), b.height()) {
+        return None;
+    }
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    let amplify = |a: u8, b: u8| (f64::from(a.abs_diff(b)) * gain).round().min(255.0) as u8;
+    Some(RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (Rgb(a), Rgb(b)) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        Rgb([amplify(a[0], b[0]), amplify(a[1], b[1]), amplify(a[2], b[2])])
+    }))
+}
+
+/// Encodes `img` as PNG.
+pub fn encode_png(img: &DynamicImage) -> Result<Vec<u8>, ImageError> {
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
+
+/// Encodes a black-and-white image as a 1-bit grayscale PNG: values of 128
+/// and up are written as white, the rest as black.
+pub fn encode_bilevel_png(gray: &GrayImage) -> Result<Vec<u8>, ImageError> {
+    let encoding_error = |err: png::EncodingError| ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), err));
+
+    let mut packed = Vec::with_capacity(gray.height() as usize * gray.width().div_ceil(8) as usize);
+    // Rows are packed most significant bit first, each starting on a new byte
+    for row in gray.as_raw().chunks(gray.width().max(1) as usize) {
+        for pixels in row.chunks(8) {
+            let byte = pixels
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (bit, &value)| if value >= 128 { byte | 0x80 >> bit } else { byte });
+            packed.push(byte);
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, gray.width(), gray.height());
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    let mut writer = encoder.write_header().map_err(encoding_error)?;
+    writer.write_image_data(&packed).map_err(encoding_error)?;
+    writer.finish().map_err(encoding_error)?;
+    Ok(out)
+}
@@ -0,0 +1,132 @@
+//! Extracting the images from a ZIP archive, for `POST /calculate-intensity/zip`.
+//!
+//! Archives are untrusted, so reading is bounded: an archive listing more
+//! than a maximum number of entries is refused before anything is
+//! decompressed, and decompression stops as soon as the bytes produced reach
+//! a total limit, whatever sizes the entries declare. Entries are taken as
+//! images by their extension or, failing that, by their first bytes; the rest
+//! are skipped and only counted.
+
+use image::ImageFormat;
+use std::{
+    fmt,
+    io::{Cursor, Read},
+};
+use zip::ZipArchive;
+
+/// Bytes of an entry without an image extension read to sniff its format.
+const SNIFF_BYTES: u64 = 64;
+
+/// Bounds on what one archive may make the server do.
+#[derive(Clone, Copy, Debug)]
+pub struct ArchiveLimits {
+    /// Most entries, directories included, the archive may list
+    pub max_entries: usize,
+    /// Most bytes decompressed over all entries
+    pub max_uncompressed_bytes: u64,
+}
+
+/// An image entry of an archive.
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    /// Path of the entry within the archive
+    pub name: String,
+    /// The decompressed bytes, or why the entry could not be read
+    pub data: Result<Vec<u8>, String>,
+}
+
+/// The image entries of an archive, in archive order.
+#[derive(Debug)]
+pub struct ArchiveImages {
+    pub entries: Vec<ArchiveEntry>,
+    /// Directories and entries that are not images
+    pub skipped: usize,
+}
+
+/// Why an archive could not be read.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArchiveError {
+    /// Not a ZIP archive, or one whose directory is damaged
+    Invalid(String),
+    /// The archive lists `entries` entries, more than `max`
+    TooManyEntries { entries: usize, max: usize },
+    /// Decompressing the images would produce more than this many bytes
+    TooLarge(u64),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Invalid(reason) => write!(f, "not a readable ZIP archive: {reason}"),
+            ArchiveError::TooManyEntries { entries, max } => {
+                write!(f, "the archive has {entries} entries, more than the maximum of {max}")
+            }
+            ArchiveError::TooLarge(max) => write!(f, "the archive's images decompress to more than {max} bytes"),
+        }
+    }
+}
+
+/// Decompresses the image entries of the ZIP archive `data` under `limits`.
+/// A damaged or encrypted entry is returned with its error rather than
+/// failing the archive. macOS `__MACOSX/` resource forks are skipped along
+/// with the other non-images.
+pub fn image_entries(data: &[u8], limits: ArchiveLimits) -> Result<ArchiveImages, ArchiveError> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|err| ArchiveError::Invalid(err.to_string()))?;
+    if archive.len() > limits.max_entries {
+        return Err(ArchiveError::TooManyEntries { entries: archive.len(), max: limits.max_entries });
+    }
+
+    let mut images = ArchiveImages { entries: Vec::new(), skipped: 0 };
+    let mut used = 0;
+    for index in 0..archive.len() {
+        let name = archive.name_for_index(index).unwrap_or_default().to_string();
+        let mut entry = match archive.by_index(index) {
+            Ok(entry) => entry,
+            Err(err) => {
+                images.entries.push(ArchiveEntry { name, data: Err(err.to_string()) });
+                continue;
+            }
+        };
+        if entry.is_dir() || name.starts_with("__MACOSX/") {
+            images.skipped += 1;
+            continue;
+        }
+
+        let mut bytes = Vec::new();
+        let mut outcome = Ok(());
+        if ImageFormat::from_path(&name).is_err() {
+            outcome = read_bounded(&mut entry, &mut bytes, SNIFF_BYTES, &mut used, limits.max_uncompressed_bytes)?;
+            if outcome.is_ok() && image::guess_format(&bytes).is_err() {
+                images.skipped += 1;
+                continue;
+            }
+        }
+        if outcome.is_ok() {
+            outcome = read_bounded(&mut entry, &mut bytes, u64::MAX, &mut used, limits.max_uncompressed_bytes)?;
+        }
+        images.entries.push(ArchiveEntry { name, data: outcome.map(|()| bytes) });
+    }
+    Ok(images)
+}
+
+/// Appends up to `most` more bytes of `entry` to `bytes`, adding them to the
+/// `used` total. Fails the archive once that total passes `max`, and just the
+/// entry when it cannot be decompressed.
+fn read_bounded(
+    entry: &mut impl Read,
+    bytes: &mut Vec<u8>,
+    most: u64,
+    used: &mut u64,
+    max: u64,
+) -> Result<Result<(), String>, ArchiveError> {
+    // One byte past the limit is enough to tell that it was passed
+    let allowed = most.min((max - *used).saturating_add(1));
+    match entry.take(allowed).read_to_end(bytes) {
+        Ok(read) => *used += read as u64,
+        Err(err) => return Ok(Err(err.to_string())),
+    }
+    if *used > max {
+        return Err(ArchiveError::TooLarge(max));
+    }
+    Ok(Ok(()))
+}
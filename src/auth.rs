@@ -0,0 +1,75 @@
+//! API-key and upload-signature verification.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConstantTimeEq};
+
+/// The set of accepted API keys. Keys are held as SHA-256 digests, so every
+/// comparison covers the same 32 bytes whatever the length of the presented key.
+pub struct ApiKeys {
+    digests: Vec<[u8; 32]>,
+}
+
+impl ApiKeys {
+    /// Returns `None` when `keys` is empty, i.e. authentication is disabled.
+    pub fn new(keys: &[String]) -> Option<Self> {
+        if keys.is_empty() {
+            return None;
+        }
+        Some(ApiKeys {
+            digests: keys.iter().map(|key| digest(key)).collect(),
+        })
+    }
+
+    /// Whether `candidate` is one of the accepted keys. Every key is compared
+    /// in constant time, without stopping at the first match.
+    pub fn verify(&self, candidate: &str) -> bool {
+        let candidate = digest(candidate);
+        self.digests
+            .iter()
+            .fold(Choice::from(0), |matched, key| matched | key.ct_eq(&candidate))
+            .into()
+    }
+}
+
+/// A short, stable label for `key` that does not reveal it: the first eight
+/// hex digits of its SHA-256, e.g. for naming clients in `/stats`.
+pub fn fingerprint(key: &str) -> String {
+    digest(key)[..4].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// The hex HMAC-SHA256 of `body` under `secret`, as sent in `X-Signature`.
+///
+/// ```
+/// // RFC 4231, test case 2
+/// let signature = webcalculation::auth::sign(b"Jefe", b"what do ya want for nothing?");
+/// assert_eq!(signature, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+/// ```
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    mac(secret, body).finalize().into_bytes().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Whether `signature`, hex with an optional `sha256=` prefix, is the
+/// HMAC-SHA256 of `body` under `secret`. The comparison takes constant time.
+pub fn signature_matches(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let signature = signature.trim();
+    let hex = signature.strip_prefix("sha256=").unwrap_or(signature);
+    decode_hex(hex).is_some_and(|expected| mac(secret, body).verify_slice(&expected).is_ok())
+}
+
+fn mac(secret: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(body);
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok()).collect()
+}
@@ -0,0 +1,76 @@
+use image::{GrayImage, Rgb, RgbImage};
+use serde::Deserialize;
+use std::sync::OnceLock;
+use utoipa::ToSchema;
+
+/// False-color maps for rendering intensity.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Colormap {
+    #[default]
+    Viridis,
+    Turbo,
+}
+
+type Lut = [[u8; 3]; 256];
+
+impl Colormap {
+    /// The 256-entry lookup table, built on first use.
+    pub fn lut(self) -> &'static Lut {
+        static VIRIDIS: OnceLock<Lut> = OnceLock::new();
+        static TURBO: OnceLock<Lut> = OnceLock::new();
+
+        match self {
+            Colormap::Viridis => VIRIDIS.get_or_init(|| build_lut(viridis)),
+            Colormap::Turbo => TURBO.get_or_init(|| build_lut(turbo)),
+        }
+    }
+}
+
+/// Maps every gray value through `colormap`, keeping the input dimensions.
+pub fn apply_colormap(gray: &GrayImage, colormap: Colormap) -> RgbImage {
+    let lut = colormap.lut();
+    RgbImage::from_fn(gray.width(), gray.height(), |x, y| {
+        Rgb(lut[usize::from(gray.get_pixel(x, y)[0])])
+    })
+}
+
+fn build_lut(colormap: fn(f64) -> [f64; 3]) -> Lut {
+    let mut lut = [[0; 3]; 256];
+    for (index, entry) in lut.iter_mut().enumerate() {
+        let rgb = colormap(index as f64 / 255.0);
+        *entry = rgb.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+    }
+    lut
+}
+
+/// Polynomial fit of matplotlib's viridis (Matt Zucker, CC0).
+fn viridis(t: f64) -> [f64; 3] {
+    const COEFFICIENTS: [[f64; 3]; 7] = [
+        [0.277_727_327_223_417_7, 0.005_407_344_544_966_578, 0.334_099_805_335_306_1],
+        [0.105_093_043_108_577_4, 1.404_613_529_898_575, 1.384_590_162_594_685],
+        [-0.330_861_828_725_556_3, 0.214_847_559_468_213, 0.095_095_163_028_236_59],
+        [-4.634_230_498_983_486, -5.799_100_973_351_585, -19.332_440_956_279_87],
+        [6.228_269_936_347_081, 14.179_933_366_805_09, 56.690_552_600_681_05],
+        [4.776_384_997_670_288, -13.745_145_377_746_01, -65.353_032_633_372_34],
+        [-5.435_455_855_934_631, 4.645_852_612_178_535, 26.312_435_249_583_2],
+    ];
+
+    let mut rgb = [0.0; 3];
+    for coefficients in COEFFICIENTS.iter().rev() {
+        for (channel, coefficient) in rgb.iter_mut().zip(coefficients) {
+            *channel = *channel * t + coefficient;
+        }
+    }
+    rgb
+}
+
+/// Google's polynomial approximation of the Turbo colormap (Apache-2.0).
+fn turbo(t: f64) -> [f64; 3] {
+    const RED: [f64; 6] = [0.135_721_38, 4.615_392_60, -42.660_322_58, 132.131_082_34, -152.942_393_96, 59.286_379_43];
+    const GREEN: [f64; 6] = [0.091_402_61, 2.194_188_39, 4.842_966_58, -14.185_033_33, 4.277_298_57, 2.829_566_04];
+    const BLUE: [f64; 6] = [0.106_673_30, 12.641_946_08, -60.582_048_36, 110.362_767_71, -89.903_109_12, 27.348_249_73];
+
+    let polynomial = |coefficients: &[f64; 6]| coefficients.iter().rev().fold(0.0, |acc, c| acc * t + c);
+    [polynomial(&RED), polynomial(&GREEN), polynomial(&BLUE)]
+}
@@ -0,0 +1,104 @@
+//! Cross-origin resource sharing policy.
+
+use axum::http::{header, request::Parts, HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+
+/// Response headers browser code may read from listed origins, besides the
+/// CORS-safelisted ones.
+const EXPOSED_HEADERS: [HeaderName; 4] = [
+    HeaderName::from_static("x-request-id"),
+    HeaderName::from_static("x-threshold"),
+    HeaderName::from_static("x-average-intensity"),
+    header::RETRY_AFTER,
+];
+
+/// An allowed origin: either exact, like `https://app.example.com`, or every
+/// subdomain of a host, like `https://*.example.com`. Ports are part of the
+/// origin, so `http://localhost:5173` does not match `http://localhost`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum OriginPattern {
+    Exact(String),
+    /// `scheme://` and the `.host[:port]` every matching origin ends with
+    Subdomains { scheme: String, suffix: String },
+}
+
+impl OriginPattern {
+    /// Whether the `Origin` header value `origin` is allowed. Scheme and host
+    /// compare case-insensitively.
+    pub fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            OriginPattern::Exact(allowed) => origin == *allowed,
+            OriginPattern::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|subdomain| !subdomain.is_empty() && !subdomain.contains(['/', ':', '@'])),
+        }
+    }
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid origin {value:?}; expected e.g. https://app.example.com or https://*.example.com");
+        let origin = value.trim().trim_end_matches('/').to_ascii_lowercase();
+        let (scheme, host) = origin.split_once("://").ok_or_else(invalid)?;
+        if scheme.is_empty() || host.is_empty() || host.contains(['/', '@']) {
+            return Err(invalid());
+        }
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && suffix.len() > 1 && !suffix.contains('*') => {
+                Ok(OriginPattern::Subdomains { scheme: format!("{scheme}://"), suffix: suffix.to_string() })
+            }
+            Some(_) => Err(invalid()),
+            None if host.contains('*') => Err(invalid()),
+            None => Ok(OriginPattern::Exact(origin)),
+        }
+    }
+}
+
+impl TryFrom<String> for OriginPattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        value.parse()
+    }
+}
+
+impl From<OriginPattern> for String {
+    fn from(pattern: OriginPattern) -> String {
+        pattern.to_string()
+    }
+}
+
+impl fmt::Display for OriginPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OriginPattern::Exact(origin) => f.write_str(origin),
+            OriginPattern::Subdomains { scheme, suffix } => write!(f, "{scheme}*{suffix}"),
+        }
+    }
+}
+
+/// The CORS layer for `origins`; with none configured every origin is
+/// allowed, as before CORS became configurable. Request headers are mirrored
+/// back in preflight responses, and the response headers clients need, such
+/// as `X-Request-Id`, are exposed by name; both stay valid alongside credentials.
+pub fn cors_layer(origins: &[OriginPattern], methods: &[Method], allow_credentials: bool) -> CorsLayer {
+    if origins.is_empty() {
+        return CorsLayer::permissive();
+    }
+    let origins = origins.to_vec();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _: &Parts| {
+            origin.to_str().is_ok_and(|origin| origins.iter().any(|pattern| pattern.matches(origin)))
+        }))
+        .allow_methods(methods.to_vec())
+        .allow_headers(AllowHeaders::mirror_request())
+        .expose_headers(EXPOSED_HEADERS)
+        .allow_credentials(allow_credentials)
+}
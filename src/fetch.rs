@@ -0,0 +1,230 @@
+//! Fetching images by URL, for `GET /calculate-intensity?url=`.
+//!
+//! Only `http` and `https` URLs are fetched and, unless private targets are
+//! allowed, only from public addresses: names are checked as they are
+//! resolved for each connection, redirects included, so neither a redirect
+//! nor a DNS answer can point the server at loopback, private, link-local or
+//! other special-purpose ranges. Bodies are read up to a byte limit.
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{header, redirect, Url};
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+/// Most redirects followed for one fetch.
+const MAX_REDIRECTS: usize = 5;
+
+/// An image fetched from a URL.
+#[derive(Debug)]
+pub struct Fetched {
+    pub data: Vec<u8>,
+    /// The `Content-Type` the server sent, if any
+    pub content_type: Option<String>,
+}
+
+/// Why an image could not be fetched.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FetchError {
+    /// Not an absolute `http` or `https` URL
+    InvalidUrl(String),
+    /// The URL leads to an address that may not be fetched
+    Forbidden(String),
+    /// The body exceeds the byte limit, which is given
+    TooLarge(usize),
+    /// The server could not be reached or did not answer with a success
+    Failed(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl(reason) | FetchError::Forbidden(reason) | FetchError::Failed(reason) => {
+                f.write_str(reason)
+            }
+            FetchError::TooLarge(limit) => write!(f, "the image is larger than {limit} bytes"),
+        }
+    }
+}
+
+/// Fetches images, sharing connections between requests.
+pub struct Fetcher {
+    client: reqwest::Client,
+    max_bytes: usize,
+    allow_private: bool,
+}
+
+impl Fetcher {
+    /// Fetches bodies of at most `max_bytes`, giving up after `timeout`.
+    /// Private and other non-public addresses are refused unless
+    /// `allow_private`.
+    pub fn new(max_bytes: usize, timeout: Duration, allow_private: bool) -> Self {
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error(Refused(format!("more than {MAX_REDIRECTS} redirects")))
+            } else if let Err(refused) = check_literal_host(attempt.url(), allow_private) {
+                attempt.error(refused)
+            } else {
+                attempt.follow()
+            }
+        });
+        // A proxy would resolve names itself, out of reach of `PublicOnly`
+        let mut builder = reqwest::Client::builder().timeout(timeout).redirect(policy).no_proxy();
+        if !allow_private {
+            builder = builder.dns_resolver(std::sync::Arc::new(PublicOnly));
+        }
+        Fetcher {
+            client: builder.build().expect("the HTTP client builds with the default TLS settings"),
+            max_bytes,
+            allow_private,
+        }
+    }
+
+    /// Fetches `url`, which must be an absolute `http` or `https` URL.
+    pub async fn fetch(&self, url: &str) -> Result<Fetched, FetchError> {
+        let url = Url::parse(url).map_err(|err| FetchError::InvalidUrl(format!("invalid url {url:?}: {err}")))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::InvalidUrl(format!("url must be http or https, got {}:", url.scheme())));
+        }
+        check_literal_host(&url, self.allow_private).map_err(|Refused(reason)| FetchError::Forbidden(reason))?;
+
+        let mut response = self.client.get(url).send().await.map_err(failure)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(FetchError::Failed(format!("the image URL answered {status}")));
+        }
+        if response.content_length().is_some_and(|length| length > self.max_bytes as u64) {
+            return Err(FetchError::TooLarge(self.max_bytes));
+        }
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(failure)? {
+            if data.len() + chunk.len() > self.max_bytes {
+                return Err(FetchError::TooLarge(self.max_bytes));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Fetched { data, content_type })
+    }
+}
+
+/// Whether `ip` is a public unicast address, the only kind fetched by default.
+/// IPv6 addresses that carry an IPv4 one (mapped, NAT64 and 6to4) are judged
+/// by the IPv4 address they lead to.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, third, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Shared address space for carrier-grade NAT, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64)
+                // IETF protocol assignments, 192.0.0.0/24
+                || (first == 192 && second == 0 && third == 0)
+                // Benchmarking, 198.18.0.0/15
+                || (first == 198 && second & 0xfe == 18)
+                // Reserved, 240.0.0.0/4, which includes the broadcast address
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(embedded) = embedded_ipv4(ip) {
+                return is_public(embedded.into());
+            }
+            let segments = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Deprecated site-local, fec0::/10
+                || segments[0] & 0xffc0 == 0xfec0
+                // Local-use NAT64, 64:ff9b:1::/48
+                || segments[..3] == [0x64, 0xff9b, 1])
+        }
+    }
+}
+
+/// The IPv4 address an IPv4-mapped (`::ffff:0:0/96`), NAT64
+/// (`64:ff9b::/96`) or 6to4 (`2002::/16`) address leads to.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(mapped) = ip.to_ipv4_mapped() {
+        return Some(mapped);
+    }
+    let octets = ip.octets();
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    } else if segments[0] == 0x2002 {
+        Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5]))
+    } else {
+        None
+    }
+}
+
+/// A connection the fetcher refused to make, wrapped inside reqwest's error.
+#[derive(Debug)]
+struct Refused(String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Refused {}
+
+/// Refuses URLs whose host is a non-public IP literal, which never reaches
+/// the resolver.
+fn check_literal_host(url: &Url, allow_private: bool) -> Result<(), Refused> {
+    let host = url.host_str().unwrap_or_default();
+    let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() else {
+        return Ok(());
+    };
+    if allow_private || is_public(ip) {
+        Ok(())
+    } else {
+        Err(Refused(format!("url points at the non-public address {ip}")))
+    }
+}
+
+fn failure(err: reqwest::Error) -> FetchError {
+    let mut source = err.source();
+    while let Some(cause) = source {
+        if let Some(Refused(reason)) = cause.downcast_ref::<Refused>() {
+            return FetchError::Forbidden(reason.clone());
+        }
+        source = cause.source();
+    }
+    let what = if err.is_timeout() { "timed out fetching the image" } else { "cannot fetch the image" };
+    FetchError::Failed(format!("{what}: {err}"))
+}
+
+/// Resolves names to their public addresses only.
+struct PublicOnly;
+
+impl Resolve for PublicOnly {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let public: Vec<SocketAddr> =
+                tokio::net::lookup_host((host, 0)).await?.filter(|addr| is_public(addr.ip())).collect();
+            if public.is_empty() {
+                return Err(Box::new(Refused(format!("{host} has no public address"))) as Box<dyn Error + Send + Sync>);
+            }
+            Ok(Box::new(public.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,259 @@
+//! Bearer JWT verification, against an HS256 secret or the keys published at
+//! a JWKS URL.
+//!
+//! Tokens must be signed by a configured key and carry an `exp` in the
+//! future; audiences are not checked. JWKS keys are fetched on first use,
+//! kept for [`JWKS_MAX_AGE`], and fetched again early when a token names a
+//! `kid` the cached set lacks, at most once per refresh interval so tokens
+//! naming made-up keys cannot flood the issuer.
+
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// How long a fetched key set is used before it is fetched again.
+pub const JWKS_MAX_AGE: Duration = Duration::from_secs(15 * 60);
+
+/// Least time between two JWKS fetches, unless changed with
+/// [`JwtVerifier::min_refresh_interval`].
+pub const DEFAULT_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time allowed for one JWKS request.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Algorithms accepted from JWKS keys. Keeping HMAC out means a public key can
+/// never be misused as a shared secret.
+const ASYMMETRIC: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// The claims of a verified token that the service uses.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Claims {
+    /// Subject, recorded on the request's span for audit logging
+    #[serde(default)]
+    pub sub: Option<String>,
+    /// Space-separated scopes, as in RFC 8693
+    #[serde(default)]
+    scope: Option<String>,
+    /// Scopes as some issuers send them, a list or a space-separated string
+    #[serde(default)]
+    scp: Option<Scopes>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum Scopes {
+    List(Vec<String>),
+    Joined(String),
+}
+
+impl Claims {
+    /// Whether the token grants `wanted`, through `scope` or `scp`.
+    pub fn has_scope(&self, wanted: &str) -> bool {
+        let listed: Vec<&str> = match &self.scp {
+            Some(Scopes::List(scopes)) => scopes.iter().map(String::as_str).collect(),
+            Some(Scopes::Joined(scopes)) => scopes.split_whitespace().collect(),
+            None => Vec::new(),
+        };
+        self.scope.iter().flat_map(|scopes| scopes.split_whitespace()).chain(listed).any(|scope| scope == wanted)
+    }
+}
+
+/// Why a token was not accepted.
+#[derive(Debug, PartialEq, Eq)]
+pub enum JwtError {
+    /// Malformed, expired, badly signed, or signed by an unknown key
+    Invalid(String),
+    /// Valid, but without the required scope, which is given
+    MissingScope(String),
+    /// The key set could not be fetched and no cached key applies
+    KeysUnavailable(String),
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtError::Invalid(reason) | JwtError::KeysUnavailable(reason) => f.write_str(reason),
+            JwtError::MissingScope(scope) => write!(f, "the token lacks the required scope {scope:?}"),
+        }
+    }
+}
+
+/// Checks bearer tokens. Cheap to share; the JWKS cache lives inside.
+pub struct JwtVerifier {
+    keys: Keys,
+    required_scope: Option<String>,
+}
+
+enum Keys {
+    Secret(DecodingKey),
+    Jwks(Jwks),
+}
+
+struct Jwks {
+    url: reqwest::Url,
+    client: reqwest::Client,
+    min_refresh_interval: Duration,
+    cached: RwLock<Option<KeySet>>,
+    /// Held while fetching, so concurrent misses share one request; holds
+    /// the time of the last attempt, successful or not
+    fetching: Mutex<Option<Instant>>,
+}
+
+struct KeySet {
+    keys: Vec<(Option<String>, DecodingKey)>,
+    fetched: Instant,
+}
+
+impl JwtVerifier {
+    /// Accepts HS256 tokens signed with `secret`.
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtVerifier {
+            keys: Keys::Secret(DecodingKey::from_secret(secret)),
+            required_scope: None,
+        }
+    }
+
+    /// Accepts tokens signed by a key from the JWKS at `url`, with an
+    /// asymmetric algorithm. Nothing is fetched until the first token arrives.
+    pub fn jwks(url: reqwest::Url) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .expect("the HTTP client builds with the default TLS settings");
+        JwtVerifier {
+            keys: Keys::Jwks(Jwks {
+                url,
+                client,
+                min_refresh_interval: DEFAULT_MIN_REFRESH_INTERVAL,
+                cached: RwLock::new(None),
+                fetching: Mutex::new(None),
+            }),
+            required_scope: None,
+        }
+    }
+
+    /// Also requires every token to grant `scope`.
+    pub fn required_scope(mut self, scope: impl Into<String>) -> Self {
+        self.required_scope = Some(scope.into());
+        self
+    }
+
+    /// Changes the least time between JWKS fetches (no effect with a secret).
+    pub fn min_refresh_interval(mut self, interval: Duration) -> Self {
+        if let Keys::Jwks(jwks) = &mut self.keys {
+            jwks.min_refresh_interval = interval;
+        }
+        self
+    }
+
+    /// Verifies `token`, returning its claims.
+    pub async fn verify(&self, token: &str) -> Result<Claims, JwtError> {
+        let header = decode_header(token).map_err(|err| JwtError::Invalid(format!("malformed token: {err}")))?;
+        let claims = match &self.keys {
+            Keys::Secret(key) => checked(token, key, Algorithm::HS256)?,
+            Keys::Jwks(jwks) => {
+                if !ASYMMETRIC.contains(&header.alg) {
+                    return Err(JwtError::Invalid(format!("tokens signed with {:?} are not accepted", header.alg)));
+                }
+                checked(token, &jwks.key_for(header.kid.as_deref()).await?, header.alg)?
+            }
+        };
+
+        match &self.required_scope {
+            Some(scope) if !claims.has_scope(scope) => Err(JwtError::MissingScope(scope.clone())),
+            _ => Ok(claims),
+        }
+    }
+}
+
+/// Checks the signature, with `algorithm` only, and the expiry.
+fn checked(token: &str, key: &DecodingKey, algorithm: Algorithm) -> Result<Claims, JwtError> {
+    let mut validation = Validation::new(algorithm);
+    validation.validate_aud = false;
+    decode::<Claims>(token, key, &validation)
+        .map(|data| data.claims)
+        .map_err(|err| JwtError::Invalid(format!("invalid token: {err}")))
+}
+
+impl Jwks {
+    /// The key named `kid` or, for tokens without one, the only key in the set.
+    async fn key_for(&self, kid: Option<&str>) -> Result<DecodingKey, JwtError> {
+        if let Some(key) = self.cached_key(kid, false) {
+            return Ok(key);
+        }
+
+        let mut last_attempt = self.fetching.lock().await;
+        // Another request may have fetched the set while this one waited
+        if let Some(key) = self.cached_key(kid, false) {
+            return Ok(key);
+        }
+        if last_attempt.is_none_or(|attempt| attempt.elapsed() >= self.min_refresh_interval) {
+            *last_attempt = Some(Instant::now());
+            if let Err(err) = self.fetch().await {
+                let stale = self.cached_key(kid, true);
+                if stale.is_some() {
+                    tracing::warn!(%err, "JWKS refresh failed; using the cached keys");
+                }
+                return stale.ok_or(err);
+            }
+        }
+
+        self.cached_key(kid, true).ok_or_else(|| match kid {
+            Some(kid) => JwtError::Invalid(format!("the token is signed by an unknown key {kid:?}")),
+            None => JwtError::Invalid("the token names no key (kid) and the key set does not have exactly one".to_string()),
+        })
+    }
+
+    /// Looks `kid` up in the cached set, which must be fresh unless `stale_ok`.
+    fn cached_key(&self, kid: Option<&str>, stale_ok: bool) -> Option<DecodingKey> {
+        let cached = self.cached.read().unwrap();
+        let set = cached.as_ref().filter(|set| stale_ok || set.fetched.elapsed() < JWKS_MAX_AGE)?;
+        match kid {
+            Some(kid) => set.keys.iter().find(|(id, _)| id.as_deref() == Some(kid)),
+            None => match set.keys.as_slice() {
+                [only] => Some(only),
+                _ => None,
+            },
+        }
+        .map(|(_, key)| key.clone())
+    }
+
+    /// Replaces the cached set with the one published now. Keys of types the
+    /// verifier cannot use are left out.
+    async fn fetch(&self) -> Result<(), JwtError> {
+        let unavailable = |err: reqwest::Error| JwtError::KeysUnavailable(format!("cannot fetch the JWKS from {}: {err}", self.url));
+        let set: JwkSet = self
+            .client
+            .get(self.url.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+
+        let keys = set
+            .keys
+            .iter()
+            .filter_map(|jwk| Some((jwk.common.key_id.clone(), DecodingKey::from_jwk(jwk).ok()?)))
+            .collect();
+        *self.cached.write().unwrap() = Some(KeySet { keys, fetched: Instant::now() });
+        Ok(())
+    }
+}
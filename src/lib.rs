@@ -0,0 +1,32 @@
+//! Image intensity analysis behind the `webcalculation` service.
+//!
+//! [`analysis`] decodes images under configurable limits and computes the
+//! statistics the HTTP endpoints report; [`colormap`] renders intensity as
+//! false color; [`metadata`] reads EXIF capture settings; [`archive`] unpacks
+//! uploaded ZIPs; [`server`] wires them into the axum router returned by
+//! [`server::app`], served over TCP or a [`unix_socket`], logged through
+//! [`logging`] and optionally traced through [`telemetry`]. The analysis modules are usable without the server:
+//!
+//! ```
+//! use image::{DynamicImage, GrayImage, Luma};
+//! use webcalculation::analysis::intensity_stats;
+//!
+//! let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])));
+//! assert_eq!(intensity_stats(&img).unwrap().average_intensity, 128.0);
+//! ```
+
+pub mod analysis;
+pub mod archive;
+pub mod auth;
+pub mod colormap;
+pub mod cors;
+pub mod fetch;
+pub mod jwt;
+pub mod logging;
+pub mod metadata;
+pub mod rate_limit;
+pub mod server;
+pub mod simd;
+pub mod telemetry;
+#[cfg(unix)]
+pub mod unix_socket;
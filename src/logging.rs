@@ -0,0 +1,128 @@
+//! Log output, filtered by `RUST_LOG` and written for people to read or as
+//! JSON objects for a log aggregator. The same spans can also be exported
+//! as traces, see [`telemetry`](crate::telemetry).
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, Request, Response},
+};
+use std::{fmt, io::IsTerminal, str::FromStr, time::Duration};
+use tracing::{field::Empty, Span, Subscriber};
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter};
+
+use crate::server::X_REQUEST_ID;
+use crate::telemetry::{self, Telemetry};
+
+/// Filter used when `RUST_LOG` is unset.
+const DEFAULT_FILTER: &str = "info";
+
+/// How log events are written, from `LOG_FORMAT`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Multi-line events, each field and the enclosing span on its own line
+    #[default]
+    Pretty,
+    /// One compact human-readable line per event
+    Text,
+    /// One JSON object per line, request fields included
+    Json,
+}
+
+impl LogFormat {
+    /// The format named by `LOG_FORMAT`, pretty when unset.
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("LOG_FORMAT") {
+            Ok(value) => value.parse().map_err(|err| format!("LOG_FORMAT: {err}")),
+            Err(_) => Ok(LogFormat::default()),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "pretty" | "" => Ok(LogFormat::Pretty),
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("invalid log format {value:?}; expected pretty, text or json")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Pretty => "pretty",
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+/// A subscriber writing events in `format` to `writer`, filtered by
+/// `RUST_LOG` (default `info`), without terminal colors. Spans that pass the
+/// filter also go to `telemetry`.
+pub fn subscriber<W>(format: LogFormat, writer: W, telemetry: &Telemetry) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    build(format, writer, false, telemetry)
+}
+
+/// Installs the process-wide subscriber, writing to stdout, in color when
+/// that is a terminal.
+pub fn init(format: LogFormat, telemetry: &Telemetry) {
+    let subscriber = build(format, std::io::stdout, std::io::stdout().is_terminal(), telemetry);
+    tracing::subscriber::set_global_default(subscriber).expect("the global subscriber is set once, at startup");
+}
+
+fn build<W>(format: LogFormat, writer: W, ansi: bool, telemetry: &Telemetry) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Pretty => Box::new(builder.pretty().finish().with(telemetry.layer())),
+        LogFormat::Text => Box::new(builder.finish().with(telemetry.layer())),
+        LogFormat::Json => {
+            Box::new(builder.json().flatten_event(true).with_span_list(false).finish().with(telemetry.layer()))
+        }
+    }
+}
+
+/// The span every request is handled in, part of the caller's trace when it
+/// sent a `traceparent`. Analysis endpoints fill in the upload fields once
+/// the image has been read, and `sub` once a bearer JWT is accepted.
+pub(crate) fn request_span(request: &Request<Body>) -> Span {
+    let request_id = request.headers().get(X_REQUEST_ID).and_then(|id| id.to_str().ok());
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        request_id,
+        sub = Empty,
+        upload_bytes = Empty,
+        detected_format = Empty,
+    );
+    telemetry::join_trace(&span, request.headers());
+    span
+}
+
+/// Logs the outcome of a request inside its span. The response size is left
+/// out for streamed bodies, whose length is not known up front.
+pub(crate) fn log_response<B: HttpBody>(response: &Response<B>, latency: Duration, _span: &Span) {
+    let response_bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    tracing::info!(
+        status = response.status().as_u16(),
+        latency_ms = latency.as_secs_f64() * 1000.0,
+        response_bytes,
+        "request finished"
+    );
+}
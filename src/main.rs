@@ -1,174 +1,203 @@
-use axum::{
-    extract::Multipart,
-    http::StatusCode,
-    response::{Html, Json},
-    routing::{get, post},
-    Router,
+#[cfg(unix)]
+use webcalculation::unix_socket::UnixSocket;
+use std::path::PathBuf;
+use webcalculation::logging::{self, LogFormat};
+use webcalculation::telemetry::{self, Telemetry};
+use webcalculation::server::{
+    parse_bind_addr, serve_until, AppState, Config, Listeners, DEFAULT_BIND_ADDR, LEGACY_SUNSET, SWAGGER_UI_EMBEDDED,
 };
-use bytes::Bytes;
-use serde::Serialize;
-use tower_http::cors::CorsLayer;
-use utoipa::{OpenApi, ToSchema};
 
-#[derive(Serialize, ToSchema)]
-struct IntensityResponse {
-    /// The calculated average intensity value (0-255)
-    average_intensity: f64,
-    /// Success message with formatted intensity value
-    message: String,
-}
+/// Routes listed at startup.
+const ROUTES: &[(&str, &str)] = &[
+    ("POST /v1/calculate-intensity", "Upload an image to calculate average intensity"),
+    ("GET  /v1/calculate-intensity?url=", "Fetch the image at a URL to calculate average intensity"),
+    ("POST /v1/calculate-intensity/stream", "Upload many images and stream one NDJSON result per image"),
+    ("POST /v1/calculate-intensity/sse", "Same as /stream, as Server-Sent Events with a final summary"),
+    ("POST /v1/calculate-intensity/batch/summary", "Upload many images to get every result plus cross-image statistics"),
+    ("POST /v1/calculate-intensity/zip", "Upload a ZIP archive to analyse every image inside it"),
+    ("POST /v1/unique-colors", "Upload an image to count its distinct colors"),
+    ("POST /v1/threshold", "Upload an image to get a thresholded black/white PNG mask"),
+    ("POST /v1/coverage", "Upload an image to get the fraction of pixels above ?threshold=T"),
+    ("POST /v1/segment-stats", "Upload an image to get Otsu foreground/background statistics"),
+    ("POST /v1/hsv-stats", "Upload an image to get HSV saturation and value statistics and a hue histogram"),
+    ("POST /v1/color-temperature", "Upload an image to get its estimated color temperature and warm/cool cast"),
+    ("POST /v1/channel-correlation", "Upload an image to get the correlation matrix of its color channels"),
+    ("POST /v1/histogram/rgb", "Upload an image to get its red, green, blue and intensity histograms"),
+    ("POST /v1/heatmap", "Upload an image to get a false-color intensity heatmap PNG"),
+    ("POST /v1/grayscale", "Upload an image to get its intensity as a gray PNG, optionally dithered to 1 bit"),
+    ("POST /v1/normalize", "Upload an image to get it back as a PNG with its levels stretched to the full range"),
+    ("POST /v1/equalize", "Upload an image to get it back as a PNG with its histogram equalized"),
+    ("POST /v1/diff-image", "Upload two images of the same size to get their per-pixel difference as a PNG"),
+    ("POST /v1/check-duplicate", "Upload an image to compare its perceptual hash with known hashes"),
+    ("POST /v1/metadata", "Upload an image to get its EXIF capture settings"),
+    ("GET  /v1/supported-formats", "Image formats this build can decode"),
+    ("GET  /v1/version", "Version and build information"),
+    ("GET  /v1/stats", "Uptime and request counters"),
+    ("GET  /live", "Liveness probe"),
+    ("GET  /ready", "Readiness probe"),
+    ("GET  /health", "Health check endpoint"),
+    ("GET  /swagger-ui", "Swagger documentation UI"),
+    ("GET  /api-docs/openapi.yaml", "OpenAPI document as YAML"),
+];
 
-#[derive(Serialize, ToSchema)]
-struct ErrorResponse {
-    /// Error description
-    error: String,
-}
+#[tokio::main]
+async fn main() {
+    let log_format = LogFormat::from_env().unwrap_or_else(|err| {
+        eprintln!("configuration error: {err}");
+        std::process::exit(1);
+    });
+    let telemetry = Telemetry::from_env().unwrap_or_else(|err| {
+        eprintln!("configuration error: {err}");
+        std::process::exit(1);
+    });
+    logging::init(log_format, &telemetry);
+    if telemetry.is_exporting() {
+        tracing::info!("Exporting traces to {}", std::env::var(telemetry::ENDPOINT_VAR).unwrap_or_default());
+    } else if std::env::var_os(telemetry::ENDPOINT_VAR).is_some() {
+        tracing::warn!("{} is ignored: this build lacks the otel feature", telemetry::ENDPOINT_VAR);
+    }
 
-#[derive(OpenApi)]
-#[openapi(
-    paths(calculate_intensity, health_check),
-    components(schemas(IntensityResponse, ErrorResponse)),
-    tags(
-        (name = "Image Processing", description = "Image intensity calculation API")
-    ),
-    info(
-        title = "Web Image Intensity Calculator API",
-        description = "A REST API for calculating the average intensity of uploaded images",
-        version = "1.0.0"
-    )
-)]
-struct ApiDoc;
+    let config = Args::parse(std::env::args().skip(1))
+        .and_then(Args::load)
+        .unwrap_or_else(|err| fatal(&format!("configuration error: {err}")));
+    // Loaded before binding, so a bad certificate stops startup right away
+    let tls = config.tls.as_ref().map(|tls| tls.load()).transpose().unwrap_or_else(|err| fatal(&format!("configuration error: {err}")));
+    let state = AppState::new(config);
+    let config = state.config.clone();
 
-#[utoipa::path(
-    post,
-    path = "/calculate-intensity",
-    tag = "Image Processing",
-    request_body(
-        content = String,
-        description = "Image file uploaded as multipart/form-data with field name 'image'",
-        content_type = "multipart/form-data"
-    ),
-    responses(
-        (status = 200, description = "Successfully calculated image intensity", body = IntensityResponse),
-        (status = 400, description = "Bad request - invalid or missing image data"),
-        (status = 422, description = "Unprocessable entity - invalid image format")
-    )
-)]
-async fn calculate_intensity(mut multipart: Multipart) -> Result<Json<IntensityResponse>, StatusCode> {
-    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
-        if field.name() == Some("image") {
-            let data = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-            
-            match calculate_image_intensity(data) {
-                Ok(intensity) => {
-                    return Ok(Json(IntensityResponse {
-                        average_intensity: intensity,
-                        message: format!("Average intensity calculated: {:.2}", intensity),
-                    }));
-                }
-                Err(_) => return Err(StatusCode::UNPROCESSABLE_ENTITY),
-            }
-        }
+    let mut listeners = Listeners::default();
+    if let Some(addr) = config.tcp_addr() {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|err| fatal(&format!("failed to listen on {addr}: {err}")));
+        // Reports the port actually assigned when binding to port 0
+        let local_addr = listener.local_addr().expect("bound listener has an address");
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        tracing::info!("Server running on {scheme}://{local_addr}");
+        listeners.tcp = Some(listener);
+        listeners.tls = tls;
+    } else if tls.is_some() {
+        tracing::warn!("TLS_CERT_PATH/TLS_KEY_PATH are ignored without a TCP listener");
     }
-    
-    Err(StatusCode::BAD_REQUEST)
-}
-
-fn calculate_image_intensity(image_data: Bytes) -> Result<f64, Box<dyn std::error::Error>> {
-    let img = image::load_from_memory(&image_data)?;
-    let rgb_img = img.to_rgb8();
-    
-    let mut total_intensity = 0u64;
-    let mut pixel_count = 0u64;
-    
-    for pixel in rgb_img.pixels() {
-        let r = pixel[0] as u64;
-        let g = pixel[1] as u64;
-        let b = pixel[2] as u64;
-        
-        let intensity = (r + g + b) / 3;
-        total_intensity += intensity;
-        pixel_count += 1;
+    #[cfg(unix)]
+    if let Some(path) = &config.uds_path {
+        let socket = UnixSocket::bind(path)
+            .unwrap_or_else(|err| fatal(&format!("failed to listen on {}: {err}", path.display())));
+        tracing::info!("Server running on unix:{}", socket.path().display());
+        listeners.unix = Some(socket);
+    }
+    #[cfg(not(unix))]
+    if config.uds_path.is_some() {
+        fatal("configuration error: UDS_PATH needs a Unix platform");
+    }
+    for (route, description) in ROUTES {
+        tracing::info!("{route} - {description}");
+    }
+    tracing::info!("The /v1 routes also answer without the prefix, deprecated until {LEGACY_SUNSET}");
+    tracing::info!("Effective configuration:\n{}", config.to_redacted_toml().trim_end());
+    if config.cors_allowed_origins.is_empty() {
+        tracing::warn!("CORS_ALLOWED_ORIGINS is not set, so browsers on any origin may call the API");
+    }
+    if !SWAGGER_UI_EMBEDDED {
+        tracing::warn!("Swagger UI is not embedded in this build, so /swagger-ui only lists the endpoints");
+    }
+    if config.api_keys.is_empty() && !config.jwt_enabled() {
+        tracing::warn!(
+            "neither API_KEYS nor JWT_HS256_SECRET/JWT_JWKS_URL is set, so every endpoint is reachable without authentication"
+        );
     }
-    
-    if pixel_count == 0 {
-        return Err("No pixels found in image".into());
+
+    let served = serve_until(listeners, state, shutdown_signal()).await;
+    // Flushing blocks on the exporter, which needs the runtime to keep running
+    tokio::task::spawn_blocking(|| telemetry.shutdown()).await.expect("flushing trace spans does not panic");
+    if let Err(err) = served {
+        tracing::error!(%err, "server error");
+        std::process::exit(1);
     }
-    
-    Ok(total_intensity as f64 / pixel_count as f64)
 }
 
-#[utoipa::path(
-    get,
-    path = "/health",
-    tag = "Health",
-    responses(
-        (status = 200, description = "Service is healthy", body = String)
-    )
-)]
-async fn health_check() -> &'static str {
-    "OK"
+/// Logs a startup failure and exits.
+fn fatal(message: &str) -> ! {
+    tracing::error!("{message}");
+    std::process::exit(1);
 }
 
-async fn serve_swagger() -> Html<&'static str> {
-    Html(r#"
-<!DOCTYPE html>
-<html>
-<head>
-    <title>API Documentation</title>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1">
-    <link rel="stylesheet" type="text/css" href="https://unpkg.com/swagger-ui-dist@5.9.0/swagger-ui.css" />
-    <style>
-        html { box-sizing: border-box; overflow: -moz-scrollbars-vertical; overflow-y: scroll; }
-        *, *:before, *:after { box-sizing: inherit; }
-        body { margin:0; background: #fafafa; }
-    </style>
-</head>
-<body>
-    <div id="swagger-ui"></div>
-    <script src="https://unpkg.com/swagger-ui-dist@5.9.0/swagger-ui-bundle.js"></script>
-    <script src="https://unpkg.com/swagger-ui-dist@5.9.0/swagger-ui-standalone-preset.js"></script>
-    <script>
-        window.onload = function() {
-            const ui = SwaggerUIBundle({
-                url: '/api-docs/openapi.json',
-                dom_id: '#swagger-ui',
-                deepLinking: true,
-                presets: [
-                    SwaggerUIBundle.presets.apis,
-                    SwaggerUIStandalonePreset
-                ],
-                plugins: [
-                    SwaggerUIBundle.plugins.DownloadUrl
-                ],
-                layout: "StandaloneLayout"
-            });
-        };
-    </script>
-</body>
-</html>
-    "#)
+/// Resolves on ctrl-c or, on Unix, SIGTERM (what Kubernetes and Docker send).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("install ctrl-c handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => tracing::info!("shutdown: received ctrl-c"),
+        () = terminate => tracing::info!("shutdown: received SIGTERM"),
+    }
 }
 
-async fn serve_openapi() -> Json<utoipa::openapi::OpenApi> {
-    Json(ApiDoc::openapi())
+/// Command-line flags. `--config FILE` names a TOML configuration file;
+/// `--bind ADDR` takes precedence over `BIND_ADDR` and `PORT`, and `--uds
+/// PATH` over `UDS_PATH`. Each also accepts the `--flag=value` form.
+#[derive(Default)]
+struct Args {
+    config: Option<PathBuf>,
+    bind: Option<String>,
+    uds: Option<PathBuf>,
 }
 
-#[tokio::main]
-async fn main() {
-    let app = Router::new()
-        .route("/calculate-intensity", post(calculate_intensity))
-        .route("/health", get(health_check))
-        .route("/swagger-ui", get(serve_swagger))
-        .route("/api-docs/openapi.json", get(serve_openapi))
-        .layer(CorsLayer::permissive());
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        const USAGE: &str = "usage: webcalculation [--config FILE] [--bind ADDR] [--uds PATH]";
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = |example: &str| {
+                inline.clone().or_else(|| args.next()).ok_or(format!("{flag} needs a value, e.g. {flag} {example}"))
+            };
+            match flag.as_str() {
+                "--config" => parsed.config = Some(value("config.toml")?.into()),
+                "--bind" => parsed.bind = Some(value("127.0.0.1:8080")?),
+                "--uds" => parsed.uds = Some(value("/run/webcalculation.sock")?.into()),
+                _ => return Err(format!("unknown argument {flag:?}; {USAGE}")),
+            }
+        }
+        Ok(parsed)
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("Server running on http://localhost:3000");
-    println!("POST /calculate-intensity - Upload an image to calculate average intensity");
-    println!("GET  /health - Health check endpoint");
-    println!("GET  /swagger-ui - Swagger documentation UI");
-    
-    axum::serve(listener, app).await.unwrap();
+    /// Builds the configuration: defaults, then the file, then environment
+    /// variables, then these flags.
+    fn load(self) -> Result<Config, String> {
+        let mut config = match &self.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+                let (config, unknown) = Config::from_toml(&text).map_err(|err| format!("{}: {err}", path.display()))?;
+                for key in unknown {
+                    tracing::warn!("{}: unknown key {key:?} ignored", path.display());
+                }
+                config
+            }
+            None => Config::default(),
+        }
+        .with_env()?;
+
+        if let Some(value) = self.bind {
+            let default_port = config.bind_addr.unwrap_or(DEFAULT_BIND_ADDR).port();
+            config.bind_addr = Some(parse_bind_addr(&value, default_port).map_err(|err| format!("--bind: {err}"))?);
+        }
+        if let Some(path) = self.uds {
+            config.uds_path = Some(path);
+        }
+        Ok(config)
+    }
 }
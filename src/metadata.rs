@@ -0,0 +1,122 @@
+//! Capture metadata read from an image's EXIF block, for `POST /metadata`.
+//!
+//! Only a handful of tags are reported, each as a plain value rather than in
+//! its raw EXIF encoding. The GPS position is read only when asked for, so a
+//! default request never returns where a photo was taken.
+
+use exif::{Field, In, Reader, Tag, Value};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use utoipa::ToSchema;
+
+/// Capture settings from EXIF. Tags the image does not carry are left out,
+/// so an image without EXIF gives an empty object.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct CaptureMetadata {
+    /// Camera manufacturer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Canon")]
+    pub make: Option<String>,
+    /// Camera model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Canon EOS R6")]
+    pub model: Option<String>,
+    /// ISO speed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 400, minimum = 0)]
+    pub iso: Option<u32>,
+    /// Exposure time, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 0.008, minimum = 0.0)]
+    pub exposure_time: Option<f64>,
+    /// Aperture as an f-number, e.g. 2.8 for f/2.8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 2.8, minimum = 0.0)]
+    pub f_number: Option<f64>,
+    /// Lens focal length, in millimetres
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 50.0, minimum = 0.0)]
+    pub focal_length: Option<f64>,
+    /// EXIF orientation: 1 is upright, 3 rotated 180°, 6 and 8 rotated a quarter turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 1, minimum = 1, maximum = 8)]
+    pub orientation: Option<u16>,
+    /// Where the photo was taken (only with `?include_gps=true`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gps: Option<GpsPosition>,
+}
+
+/// A position from the EXIF GPS tags.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct GpsPosition {
+    /// Degrees north of the equator, negative for south
+    #[schema(example = 48.8584, minimum = -90.0, maximum = 90.0)]
+    pub latitude: f64,
+    /// Degrees east of Greenwich, negative for west
+    #[schema(example = 2.2945, minimum = -180.0, maximum = 180.0)]
+    pub longitude: f64,
+    /// Metres above sea level, negative below
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 35.0)]
+    pub altitude: Option<f64>,
+}
+
+/// Reads the capture metadata from an image's EXIF, in any container
+/// `kamadak-exif` understands (JPEG, TIFF, PNG, WebP, HEIF). EXIF that is
+/// absent, or too damaged to parse, gives an empty result; so do tags with an
+/// unexpected type. The GPS position is read only if `include_gps`.
+pub fn capture_metadata(data: &[u8], include_gps: bool) -> CaptureMetadata {
+    let Ok(exif) = Reader::new().read_from_container(&mut Cursor::new(data)) else {
+        return CaptureMetadata::default();
+    };
+    let field = |tag| exif.get_field(tag, In::PRIMARY);
+
+    CaptureMetadata {
+        make: field(Tag::Make).and_then(text),
+        model: field(Tag::Model).and_then(text),
+        iso: field(Tag::PhotographicSensitivity).and_then(|field| field.value.get_uint(0)),
+        exposure_time: field(Tag::ExposureTime).and_then(|field| rational(field, 0)),
+        f_number: field(Tag::FNumber).and_then(|field| rational(field, 0)),
+        focal_length: field(Tag::FocalLength).and_then(|field| rational(field, 0)),
+        orientation: field(Tag::Orientation)
+            .and_then(|field| field.value.get_uint(0))
+            .and_then(|orientation| u16::try_from(orientation).ok()),
+        gps: include_gps.then(|| gps_position(&field)).flatten(),
+    }
+}
+
+fn gps_position<'a>(field: &impl Fn(Tag) -> Option<&'a Field>) -> Option<GpsPosition> {
+    // Degrees, minutes and seconds, signed by the N/S or E/W reference
+    let coordinate = |tag, reference, negative: u8| {
+        let dms = field(tag)?;
+        let degrees = rational(dms, 0)? + rational(dms, 1)? / 60.0 + rational(dms, 2)? / 3600.0;
+        let negated = field(reference).and_then(text).is_some_and(|reference| reference.as_bytes() == [negative]);
+        Some(if negated { -degrees } else { degrees })
+    };
+    let altitude = field(Tag::GPSAltitude).and_then(|altitude| rational(altitude, 0)).map(|altitude| {
+        // GPSAltitudeRef 1 means below sea level
+        match field(Tag::GPSAltitudeRef).and_then(|reference| reference.value.get_uint(0)) {
+            Some(1) => -altitude,
+            _ => altitude,
+        }
+    });
+    Some(GpsPosition {
+        latitude: coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')?,
+        longitude: coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')?,
+        altitude,
+    })
+}
+
+/// The first string of an ASCII field, without the padding some cameras add.
+fn text(field: &Field) -> Option<String> {
+    let Value::Ascii(strings) = &field.value else { return None };
+    let text = String::from_utf8_lossy(strings.first()?);
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// The `index`th value of a rational field, if it has a nonzero denominator.
+fn rational(field: &Field, index: usize) -> Option<f64> {
+    let Value::Rational(values) = &field.value else { return None };
+    values.get(index).filter(|value| value.denom != 0).map(|value| value.to_f64())
+}
@@ -0,0 +1,140 @@
+//! Per-client token buckets and in-flight caps.
+
+use lru::LruCache;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Keys tracked by default before idle ones are evicted.
+pub const DEFAULT_MAX_KEYS: usize = 10_000;
+
+/// Token-bucket limiter: each key may spend up to `capacity` requests at once,
+/// refilled continuously at `per_minute` requests per minute. At most
+/// `max_keys` buckets are kept, least recently used first out, so a flood of
+/// new keys costs constant time per request.
+pub struct RateLimiter<K: Eq + Hash> {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<LruCache<K, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// A limiter allowing `per_minute` requests per minute per key, all of
+    /// which may arrive in a single burst. `per_minute` must be non-zero.
+    pub fn new(per_minute: u32) -> Self {
+        Self::with_burst(per_minute, per_minute)
+    }
+
+    /// A limiter allowing `per_minute` requests per minute per key, at most
+    /// `burst` of them at once. Both must be non-zero.
+    pub fn with_burst(per_minute: u32, burst: u32) -> Self {
+        RateLimiter {
+            capacity: f64::from(burst),
+            refill_per_sec: f64::from(per_minute) / 60.0,
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(DEFAULT_MAX_KEYS).expect("non-zero"))),
+        }
+    }
+
+    /// Keeps at most `max_keys` buckets (at least one).
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.buckets.get_mut().unwrap().resize(NonZeroUsize::new(max_keys).unwrap_or(NonZeroUsize::MIN));
+        self
+    }
+
+    /// Number of keys currently tracked.
+    pub fn tracked_keys(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    /// Takes a token for `key` at time `now`, or returns how long until one
+    /// becomes available.
+    pub fn check(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(key, || Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec))
+        }
+    }
+}
+
+/// Caps how much work each key may have running at once. A slot is held by
+/// the [`InFlightGuard`] it was acquired with and given back when the guard
+/// drops, so it is released however the work ends: finished, failed,
+/// panicked or abandoned by a disconnected client. Keys with nothing in
+/// flight are not stored.
+pub struct InFlightLimiter<K> {
+    max_per_key: usize,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+/// One slot of an [`InFlightLimiter`], released on drop.
+pub struct InFlightGuard<K: Eq + Hash> {
+    key: Option<K>,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+impl<K: Eq + Hash + Clone> InFlightLimiter<K> {
+    /// A limiter allowing `max_per_key` slots per key. `max_per_key` must be non-zero.
+    pub fn new(max_per_key: usize) -> Self {
+        InFlightLimiter {
+            max_per_key,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Takes a slot for `key`, or returns how many it already holds when
+    /// that is the maximum.
+    pub fn try_acquire(&self, key: K) -> Result<InFlightGuard<K>, usize> {
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = counts.entry(key.clone()).or_insert(0);
+        if *count >= self.max_per_key {
+            return Err(*count);
+        }
+        *count += 1;
+        Ok(InFlightGuard {
+            key: Some(key),
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// The slots held by each key that has any.
+    pub fn in_flight(&self) -> Vec<(K, usize)> {
+        let counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        counts.iter().map(|(key, &count)| (key.clone(), count)).collect()
+    }
+}
+
+impl<K: Eq + Hash> Drop for InFlightGuard<K> {
+    fn drop(&mut self) {
+        // Poisoning is ignored: a slot must come back even while unwinding
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(key) = self.key.take()
+            && let Some(count) = counts.get_mut(&key)
+        {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&key);
+            }
+        }
+    }
+}
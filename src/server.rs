@@ -0,0 +1,4477 @@
+//! HTTP layer: configuration, shared state, handlers and the [`app`] router.
+
+use crate::analysis::{
+    aspect_label, binarize, can_decode, center_weighted_intensity, decoder_feature, channel_correlation, count_above_threshold, count_unique_colors,
+    decode_image, difference_image, downscale, encode_bilevel_png, hash_distance, perceptual_hash, encode_png, floyd_steinberg, histogram, color_family, intensity_image, lab_lightness, intensity_stats, linear_intensity, linear_to_srgb, ordered_dither,
+    otsu, percentile_bounds, quadrant_intensity, rgb_histograms, stretch_levels, trimmed_mean, AnalysisError, DecodeLimits, IntensityStats, PixelExtreme, Quadrants,
+};
+use axum::{
+    async_trait,
+    body::{Body, HttpBody},
+    extract::{
+        multipart::{Field, MultipartError, MultipartRejection},
+        rejection::QueryRejection,
+        ConnectInfo, DefaultBodyLimit, FromRequest, FromRequestParts, MatchedPath, Multipart, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Json, Response,
+    },
+    routing::{get, post, MethodRouter},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use bytes::Bytes;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use crate::archive::{image_entries, ArchiveEntry, ArchiveError, ArchiveImages, ArchiveLimits};
+use crate::auth::{fingerprint, signature_matches, ApiKeys};
+use crate::colormap::{apply_colormap, Colormap};
+use crate::cors::{cors_layer, OriginPattern};
+use crate::fetch::{FetchError, Fetcher};
+use crate::jwt::{JwtError, JwtVerifier};
+use crate::logging::{log_response, request_span};
+use crate::metadata::{capture_metadata, CaptureMetadata, GpsPosition};
+use crate::rate_limit::{InFlightLimiter, RateLimiter};
+#[cfg(unix)]
+use crate::unix_socket::UnixSocket;
+use image::{DynamicImage, ImageFormat};
+use lru::LruCache;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt,
+    future::Future,
+    hash::{BuildHasher, Hasher, RandomState},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+/// Seconds clients are told to wait before retrying a request that could not be scheduled.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Header identifying a request across the client's logs and ours.
+pub(crate) const X_REQUEST_ID: &str = "x-request-id";
+
+/// Header repeating `average_intensity` on `/calculate-intensity` responses.
+const X_AVERAGE_INTENSITY: &str = "x-average-intensity";
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct IntensityResponse {
+    /// The calculated average intensity value (0-255, or 0-1 with `?scale=unit`). 16-bit images
+    /// are analysed at full precision and normalized onto the same scale.
+    #[schema(example = 128.75, minimum = 0.0, maximum = 255.0)]
+    pub average_intensity: f64,
+    /// Range in which all intensity fields of this response are expressed
+    pub scale: IntensityScale,
+    /// How pixels were weighted in `average_intensity`; the other statistics are always unweighted
+    pub weighting: Weighting,
+    /// Success message with formatted intensity value
+    #[schema(example = "Average intensity calculated: 128.75")]
+    pub message: String,
+    /// Median pixel intensity, at 8-bit integer resolution
+    #[schema(example = 131.0, minimum = 0.0, maximum = 255.0)]
+    pub median_intensity: f64,
+    /// Population standard deviation of pixel intensity, on the same scale as `average_intensity`
+    #[schema(example = 52.4, minimum = 0.0, maximum = 127.5)]
+    pub std_dev: f64,
+    /// Geometric (log-average) mean intensity `exp(mean(ln(I + 1))) - 1`, computed on the 0-255 scale
+    /// and reported on the same scale as `average_intensity`; at most the arithmetic mean
+    #[schema(example = 112.9, minimum = 0.0, maximum = 255.0)]
+    pub log_mean_intensity: f64,
+    /// HSP perceived brightness: the mean of `sqrt(0.299 R² + 0.587 G² + 0.114 B²)` over pixels, on the
+    /// same scale as `average_intensity`. Higher than Rec. 601 luma for saturated colors; equal to the value for gray
+    #[schema(example = 131.6, minimum = 0.0, maximum = 255.0)]
+    pub hsp_brightness: f64,
+    /// RMS contrast `std_dev / average_intensity`, independent of scale and resolution (0 for a black image)
+    #[schema(example = 0.407, minimum = 0.0)]
+    pub contrast_rms: f64,
+    /// Michelson contrast `(max - min) / (max + min)` of the brightest and darkest pixels (0-1)
+    #[schema(example = 0.99, minimum = 0.0, maximum = 1.0)]
+    pub contrast_michelson: f64,
+    /// Whether the image is all black, so both contrast ratios are undefined and reported as 0
+    pub contrast_undefined: bool,
+    /// Exposure estimate and advice for photographers
+    pub exposure: ExposureResponse,
+    /// Coarse color family of the saturation-weighted mean hue: `red`,
+    /// `orange`, `yellow`, `green`, `cyan`, `blue`, `purple` or `magenta`, or
+    /// `neutral` when the mean saturation is below `?saturation_threshold=`
+    #[schema(example = "neutral")]
+    pub color_family: String,
+    /// Fraction of pixels with a difference above `?color_threshold=` (0-255) between two of their channels
+    #[schema(example = 0.004, minimum = 0.0, maximum = 1.0)]
+    pub color_fraction: f64,
+    /// Whether `color_fraction` is below `?monochrome_percent=` percent, as for a black-and-white
+    /// document scanned in color
+    pub is_monochrome: bool,
+    /// Wall-clock time spent decoding the image and computing its intensity, in milliseconds (0 when served from cache)
+    #[schema(example = 4.21, minimum = 0.0)]
+    pub processing_ms: f64,
+    /// Whether the result was served from the result cache without decoding the image
+    pub cached: bool,
+    /// First pixel (row-major) with the highest intensity
+    pub brightest_pixel: PixelLocation,
+    /// First pixel (row-major) with the lowest intensity
+    pub darkest_pixel: PixelLocation,
+    /// Bits per channel the image was analysed at: 16 for 16-bit and floating-point images, 8 otherwise
+    #[schema(example = 8)]
+    pub bit_depth: u8,
+    /// Mean intensity after discarding the top and bottom `trim` percent of pixels (only with `?trim=`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 127.9, minimum = 0.0, maximum = 255.0)]
+    pub trimmed_mean_intensity: Option<f64>,
+    /// Average intensity in linear light: each channel decoded from sRGB before averaging, on the
+    /// same scale as `average_intensity`, which stays in gamma-encoded space (only with `?colorspace=linear`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 55.04, minimum = 0.0, maximum = 255.0)]
+    pub linear_average_intensity: Option<f64>,
+    /// `linear_average_intensity` encoded back through the sRGB transfer function, on the same scale:
+    /// the gray level whose light matches the image's average (only with `?colorspace=linear`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 128.0, minimum = 0.0, maximum = 255.0)]
+    pub linear_average_intensity_srgb: Option<f64>,
+    /// Mean CIELAB lightness L* of the pixels, 0-100 whatever `scale` (only with `?metric=lab_lightness`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 53.39, minimum = 0.0, maximum = 100.0)]
+    pub mean_lab_lightness: Option<f64>,
+    /// Average intensity of each quadrant, on the same scale as `average_intensity` (only with
+    /// `?quadrants=true`, for images at least 2x2)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quadrants: Option<QuadrantIntensities>,
+    /// Image size in megapixels, `width * height / 1e6`
+    #[schema(example = 2.0736, minimum = 0.0)]
+    pub megapixels: f64,
+    /// `width / height`; above 1 for landscape images
+    #[schema(example = 1.7777777777777777, exclusive_minimum = 0.0)]
+    pub aspect_ratio: f64,
+    /// Matching common aspect ratio such as `16:9` or `3:4`, within 1% (omitted when none matches)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "16:9")]
+    pub aspect_label: Option<String>,
+    /// Size the statistics were computed at, after resampling (only with `?downscale_to=`); pixel
+    /// locations refer to this size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_dimensions: Option<Dimensions>,
+    /// Whether the image is effectively black: the average is below `?black_average=` (default 2)
+    /// and the brightest pixel below `?black_peak=` (default 16), both on the 0-255 scale
+    pub is_black_frame: bool,
+    /// Format sniffed from the image's leading bytes, e.g. `png`, regardless of the declared content type
+    #[schema(example = "jpeg")]
+    pub detected_format: String,
+    /// Problems with the upload that did not stop the analysis, e.g. a content type contradicting the detected format
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct PixelLocation {
+    /// Column of the pixel, starting at 0 on the left
+    #[schema(example = 412)]
+    pub x: u32,
+    /// Row of the pixel, starting at 0 at the top
+    #[schema(example = 87)]
+    pub y: u32,
+    /// Intensity of the pixel `(R + G + B) / 3`
+    #[schema(example = 255.0, minimum = 0.0, maximum = 255.0)]
+    pub intensity: f64,
+}
+
+impl PixelLocation {
+    fn new(pixel: PixelExtreme, scale: IntensityScale) -> Self {
+        PixelLocation {
+            x: pixel.x,
+            y: pixel.y,
+            intensity: scale.apply(pixel.intensity),
+        }
+    }
+}
+
+/// Average intensities of the four quadrants, split at `width / 2` and
+/// `height / 2`: on odd dimensions the middle column counts as right and the
+/// middle row as bottom.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct QuadrantIntensities {
+    #[schema(example = 141.2, minimum = 0.0, maximum = 255.0)]
+    pub top_left: f64,
+    #[schema(example = 150.6, minimum = 0.0, maximum = 255.0)]
+    pub top_right: f64,
+    #[schema(example = 108.3, minimum = 0.0, maximum = 255.0)]
+    pub bottom_left: f64,
+    #[schema(example = 114.9, minimum = 0.0, maximum = 255.0)]
+    pub bottom_right: f64,
+}
+
+impl QuadrantIntensities {
+    fn new(quadrants: Quadrants, scale: IntensityScale) -> Self {
+        QuadrantIntensities {
+            top_left: scale.apply(quadrants.top_left),
+            top_right: scale.apply(quadrants.top_right),
+            bottom_left: scale.apply(quadrants.bottom_left),
+            bottom_right: scale.apply(quadrants.bottom_right),
+        }
+    }
+}
+
+// The multipart bodies only exist to be described in the OpenAPI document,
+// so Swagger UI offers a file picker and generated clients send a file part;
+// the handlers read the parts themselves.
+
+/// An image uploaded as multipart/form-data.
+#[derive(ToSchema)]
+pub struct ImageUpload {
+    /// The image file. A part named `file`, or the only file part, is accepted too
+    #[schema(value_type = String, format = Binary)]
+    pub image: Vec<u8>,
+}
+
+/// Any number of images uploaded as multipart/form-data.
+#[derive(ToSchema)]
+pub struct ImageBatchUpload {
+    /// Image files, analysed in order whatever their part names
+    #[schema(value_type = Vec<String>, format = Binary)]
+    pub images: Vec<Vec<u8>>,
+}
+
+/// A ZIP archive of images uploaded as multipart/form-data.
+#[derive(ToSchema)]
+pub struct ArchiveUpload {
+    /// The ZIP file, in a part named `image` or `file`, or as the only file part
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+/// An image and the perceptual hashes to compare it with, uploaded as
+/// multipart/form-data.
+#[derive(ToSchema)]
+pub struct DuplicateCheckUpload {
+    /// The image file. A part named `file`, or the only file part, is accepted too
+    #[schema(value_type = String, format = Binary)]
+    pub image: Vec<u8>,
+    /// JSON array of known pHashes, each 16 hex digits
+    #[schema(example = r#"["c3e1f0b8a4d29e71", "0f0f0f0f0f0f0f0f"]"#)]
+    pub known_hashes: String,
+}
+
+/// Two images to compare, uploaded as multipart/form-data.
+#[derive(ToSchema)]
+pub struct ImagePairUpload {
+    /// The first image file
+    #[schema(value_type = String, format = Binary)]
+    pub image_a: Vec<u8>,
+    /// The second image file, the same size as the first
+    #[schema(value_type = String, format = Binary)]
+    pub image_b: Vec<u8>,
+}
+
+/// Width and height of an image, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct Dimensions {
+    #[schema(example = 1920)]
+    pub width: u32,
+    #[schema(example = 1080)]
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ExposureResponse {
+    /// Mean pixel intensity, on the same scale as `average_intensity`
+    #[schema(example = 128.75, minimum = 0.0, maximum = 255.0)]
+    pub mean_luminance: f64,
+    /// Stops above (positive) or below (negative) middle gray, 118 on the 0-255 scale
+    #[schema(example = 0.126)]
+    pub ev_offset: f64,
+    /// Percentage of pixels at or below intensity 2 (0-255 scale)
+    #[schema(example = 0.8, minimum = 0.0, maximum = 100.0)]
+    pub shadow_clipping_percent: f64,
+    /// Percentage of pixels at or above intensity 253 (0-255 scale)
+    #[schema(example = 1.2, minimum = 0.0, maximum = 100.0)]
+    pub highlight_clipping_percent: f64,
+    /// `increase exposure`, `decrease exposure` or `well exposed`, weighing the mean's
+    /// distance from middle gray together with the clipping
+    #[schema(example = "well exposed")]
+    pub suggestion: String,
+}
+
+impl ExposureResponse {
+    fn new(stats: &IntensityStats, scale: IntensityScale) -> Self {
+        let exposure = stats.exposure();
+        ExposureResponse {
+            mean_luminance: scale.apply(stats.average_intensity),
+            ev_offset: exposure.ev_offset,
+            shadow_clipping_percent: exposure.shadow_clipping,
+            highlight_clipping_percent: exposure.highlight_clipping,
+            suggestion: exposure.suggestion.as_str().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct UniqueColorsResponse {
+    /// Number of distinct RGB values found (a lower bound when truncated)
+    pub unique_color_count: usize,
+    /// Whether counting stopped early because the configured limit was reached
+    pub truncated: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct CoverageResponse {
+    /// The requested intensity threshold (0-255)
+    #[schema(example = 128.0, minimum = 0.0, maximum = 255.0)]
+    pub threshold: f64,
+    /// Fraction of pixels whose intensity is strictly above the threshold
+    #[schema(example = 0.42, minimum = 0.0, maximum = 1.0)]
+    pub fraction_above: f64,
+    /// Fraction of pixels at or below the threshold
+    #[schema(example = 0.58, minimum = 0.0, maximum = 1.0)]
+    pub fraction_below: f64,
+    /// Number of pixels strictly above the threshold
+    #[schema(example = 870912)]
+    pub pixels_above: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ChannelCorrelationResponse {
+    /// Channel order of the matrix rows and columns: `["r", "g", "b"]`
+    pub channels: Vec<String>,
+    /// 3x3 Pearson correlation matrix (-1 to 1). An entry involving a channel
+    /// with no variation is `null`; values near 1 off the diagonal mean a
+    /// desaturated, nearly gray image
+    pub correlation: Vec<Vec<Option<f64>>>,
+    /// Number of pixels the correlations were computed over
+    pub pixel_count: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct RgbHistogramResponse {
+    /// Pixel counts of each red value (256 bins, 0-255; 16-bit values are
+    /// rounded to the nearest bin)
+    pub red: Vec<u64>,
+    /// Pixel counts of each green value (256 bins)
+    pub green: Vec<u64>,
+    /// Pixel counts of each blue value (256 bins)
+    pub blue: Vec<u64>,
+    /// Pixel counts of each intensity `(r + g + b) / 3`, rounded (256 bins)
+    pub luminance: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct SegmentStatsResponse {
+    /// Otsu threshold; pixels at or below it are background, above it foreground
+    #[schema(example = 117)]
+    pub threshold: u8,
+    /// Between-class variance at the threshold; higher values indicate a more clearly bimodal image
+    pub between_class_variance: f64,
+    /// Fraction of pixels in the background (dark) class
+    #[schema(example = 0.61, minimum = 0.0, maximum = 1.0)]
+    pub background_fraction: f64,
+    /// Fraction of pixels in the foreground (bright) class
+    #[schema(example = 0.39, minimum = 0.0, maximum = 1.0)]
+    pub foreground_fraction: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct HsvStatsResponse {
+    /// Mean HSV saturation (0-1); 0 for a gray image
+    #[schema(example = 0.34, minimum = 0.0, maximum = 1.0)]
+    pub mean_saturation: f64,
+    /// Population standard deviation of the saturation
+    #[schema(example = 0.21, minimum = 0.0)]
+    pub std_saturation: f64,
+    /// Mean HSV value, each pixel's brightest channel (0-1)
+    #[schema(example = 0.62, minimum = 0.0, maximum = 1.0)]
+    pub mean_value: f64,
+    /// Population standard deviation of the value
+    #[schema(example = 0.18, minimum = 0.0)]
+    pub std_value: f64,
+    /// Share of the total saturation in each of 12 hue buckets of 30°, bucket `i` centered on
+    /// `30 * i` degrees (0 red, 2 yellow, 4 green, 6 cyan, 8 blue, 10 magenta). Sums to 1, or is
+    /// all zero when no pixel is saturated
+    #[schema(example = json!([0.3, 0.1, 0.05, 0.0, 0.1, 0.0, 0.05, 0.1, 0.2, 0.05, 0.05, 0.0]))]
+    pub hue_histogram: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ColorTemperatureResponse {
+    /// Estimated correlated color temperature, to the nearest kelvin
+    #[schema(example = 5120.0, minimum = 0.0)]
+    pub cct_kelvin: f64,
+    /// `warm` below 5500 K, `cool` above 7500 K, otherwise `neutral`
+    #[schema(example = "warm")]
+    pub label: String,
+    /// `low` when the image is near-grayscale or very dark, or the estimate falls outside
+    /// 2000-12500 K, where `cct_kelvin` says little about the lighting; otherwise `high`
+    #[schema(example = "high")]
+    pub confidence: String,
+    /// CIE 1931 x chromaticity of the mean linear-light color
+    #[schema(example = 0.341, minimum = 0.0, maximum = 1.0)]
+    pub chromaticity_x: f64,
+    /// CIE 1931 y chromaticity of the mean linear-light color
+    #[schema(example = 0.352, minimum = 0.0, maximum = 1.0)]
+    pub chromaticity_y: f64,
+}
+
+/// Body of `/check-duplicate`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct DuplicateCheckResponse {
+    /// 64-bit DCT perceptual hash of the upload, as 16 lowercase hex digits
+    #[schema(example = "c3e1f0b8a4d29671")]
+    pub hash: String,
+    /// The known hash nearest the upload's; ties go to the earliest (absent when none were sent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closest_match: Option<HashMatch>,
+    /// Whether `closest_match` is within `max_distance` bits of the upload's hash
+    pub is_duplicate: bool,
+    /// The threshold `is_duplicate` was decided with
+    #[schema(example = 10, maximum = 64)]
+    pub max_distance: u32,
+}
+
+/// A known hash compared against an upload's.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct HashMatch {
+    /// Position of the hash in `known_hashes`, starting at 0
+    pub index: usize,
+    /// The hash as sent
+    #[schema(example = "c3e1f0b8a4d29e71")]
+    pub hash: String,
+    /// Hamming distance to the upload's hash: the number of differing bits, 0-64
+    #[schema(example = 2, maximum = 64)]
+    pub distance: u32,
+}
+
+/// One line of the `/calculate-intensity/stream` response: either `result`
+/// or `error` is set.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct IntensityStreamLine {
+    /// Position of the part in the upload, starting at 0
+    pub index: usize,
+    /// Multipart field name of the part (absent for archive entries, and when the body itself could not be read)
+    pub field: Option<String>,
+    /// File name the part was uploaded with, if any, or the entry's path in the archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// The analysis, as returned by /calculate-intensity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<IntensityResponse>,
+    /// Why the part could not be analysed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+/// Final `done` event of `/calculate-intensity/sse`.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct BatchSummary {
+    /// Number of parts processed
+    pub total: usize,
+    /// Parts analysed successfully
+    pub succeeded: usize,
+    /// Parts that could not be analysed
+    pub failed: usize,
+    /// Mean of the successful parts' `average_intensity` (absent when none succeeded)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean_intensity: Option<f64>,
+}
+
+/// Body of `/calculate-intensity/batch/summary`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct BatchSummaryResponse {
+    /// Part counts and mean, as in the `done` event of `/calculate-intensity/sse`
+    pub summary: BatchSummary,
+    /// Statistics across the successfully analysed images (absent when none succeeded)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<BatchAggregate>,
+    /// Every part's result or error, in upload order
+    pub images: Vec<IntensityStreamLine>,
+}
+
+/// Body of `/calculate-intensity/zip`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ArchiveIntensityResponse {
+    /// Image entry counts and mean, as for `/calculate-intensity/batch/summary`
+    pub summary: BatchSummary,
+    /// Statistics across the successfully analysed images (absent when none succeeded)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<BatchAggregate>,
+    /// Every image entry's result or error, in archive order, with its path in `filename`
+    pub images: Vec<IntensityStreamLine>,
+    /// Directories and entries that are not images, left out of `images`
+    #[schema(example = 2)]
+    pub skipped: usize,
+}
+
+/// Cross-image statistics over a batch's `average_intensity` values. Ties go
+/// to the earliest image.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct BatchAggregate {
+    /// Mean of the images' average intensities
+    pub mean_of_means: f64,
+    /// Lowest image average intensity
+    pub min_intensity: f64,
+    /// Highest image average intensity
+    pub max_intensity: f64,
+    /// Index of the brightest image among the parts
+    pub brightest_index: usize,
+    /// File name of the brightest image, if it was uploaded with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightest_filename: Option<String>,
+    /// Index of the darkest image among the parts
+    pub darkest_index: usize,
+    /// File name of the darkest image, if it was uploaded with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub darkest_filename: Option<String>,
+}
+
+impl BatchAggregate {
+    /// Reduces over the successful parts of `images`; `None` when there are none.
+    fn over(images: &[IntensityStreamLine]) -> Option<Self> {
+        let analysed: Vec<(&IntensityStreamLine, f64)> = images
+            .iter()
+            .filter_map(|image| Some((image, image.result.as_ref()?.average_intensity)))
+            .collect();
+        let &(first, first_intensity) = analysed.first()?;
+        let (mut brightest, mut darkest) = ((first, first_intensity), (first, first_intensity));
+        for &(image, intensity) in &analysed[1..] {
+            if intensity > brightest.1 {
+                brightest = (image, intensity);
+            }
+            if intensity < darkest.1 {
+                darkest = (image, intensity);
+            }
+        }
+        Some(BatchAggregate {
+            mean_of_means: analysed.iter().map(|(_, intensity)| intensity).sum::<f64>() / analysed.len() as f64,
+            min_intensity: darkest.1,
+            max_intensity: brightest.1,
+            brightest_index: brightest.0.index,
+            brightest_filename: brightest.0.filename.clone(),
+            darkest_index: darkest.0.index,
+            darkest_filename: darkest.0.filename.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct SupportedFormat {
+    /// Format name as reported in `detected_format`, e.g. `png`
+    pub format: String,
+    /// File extensions commonly used for the format
+    pub extensions: Vec<String>,
+    /// MIME types accepted for the format (empty when it has none registered)
+    pub mime_types: Vec<String>,
+    /// Whether this build can decode the format; uploads of disabled formats get `415`
+    pub enabled: bool,
+    /// Cargo feature that compiles the decoder in, for optional formats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct VersionResponse {
+    /// Crate version, e.g. `0.1.0`
+    pub version: String,
+    /// Abbreviated git commit the binary was built from (absent without git)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    /// Build time in UTC, e.g. `2024-05-01T12:00:00Z`
+    pub build_timestamp: String,
+    /// Optional cargo features compiled in, e.g. `simd` or `webp`
+    pub features: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct StatsResponse {
+    /// Seconds since the service started
+    pub uptime_seconds: u64,
+    /// Requests received on each route, keyed by its path pattern
+    pub requests_by_route: BTreeMap<String, u64>,
+    /// Sum of `requests_by_route`
+    pub requests_total: u64,
+    /// Bytes of uploaded images read
+    pub image_bytes_processed: u64,
+    /// Uploads that could not be decoded, batch parts included
+    pub decode_failures: u64,
+    /// Analysis requests being handled right now
+    pub analyses_in_flight: usize,
+    /// Analysis requests in flight per client with any, keyed `key:<fingerprint>`
+    /// or `ip:<address>` (empty when MAX_IN_FLIGHT_PER_CLIENT is 0)
+    pub in_flight_by_client: BTreeMap<String, usize>,
+    /// Results held in the cache (absent when caching is disabled)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_entries: Option<usize>,
+}
+
+/// Body of `/live` and `/ready`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ProbeResponse {
+    /// `live`, `ready` or `not_ready`
+    pub status: String,
+    /// Why the service is not ready, e.g. `draining` (`not_ready` only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// Stable machine-readable error code, e.g. `missing_field` or `decode_error`
+    #[schema(example = "missing_field")]
+    pub code: String,
+    /// Error description
+    #[schema(example = "no image found; send it in a field named 'image' or 'file', or as the only file part")]
+    pub error: String,
+    /// Identifier of the failure in the server log (`internal` errors only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Multipart field names the image is read from (`missing_field` errors only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted_fields: Option<Vec<String>>,
+    /// The request's `X-Request-Id`, as sent or generated, to quote when
+    /// reporting the failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "6f1c2a7e-3f4b-4c1d-9a51-0c8e2d7b9e10")]
+    pub request_id: Option<String>,
+}
+
+/// A failed request. Each variant maps to a status code and a stable
+/// [`code`](ApiError::code), sent together with the message as an
+/// [`ErrorResponse`] body.
+#[derive(Debug)]
+pub enum ApiError {
+    /// No multipart part holding the image was sent; lists the accepted and
+    /// the received field names
+    MissingField { accepted: Vec<String>, received: Vec<String> },
+    /// An endpoint taking several images did not get all of them; lists the
+    /// required and the received field names
+    MissingImages { required: Vec<String>, received: Vec<String> },
+    /// The request body could not be read as multipart form data
+    BodyReadError(String),
+    /// A query parameter is malformed or out of range
+    InvalidParameter(String),
+    /// No valid API key was presented
+    Unauthorized(String),
+    /// `UPLOAD_HMAC_SECRET` is set and the upload's `X-Signature` is missing or wrong
+    InvalidSignature(String),
+    /// The bearer token is valid but lacks the required scope
+    InsufficientScope(String),
+    /// Credentials cannot be checked right now, e.g. the JWKS is unreachable
+    AuthUnavailable(String),
+    /// The request did not finish within the time budget
+    Timeout(Duration),
+    /// The upload exceeds the size limit, in bytes
+    TooLarge(usize),
+    /// The client sent too many requests; retry after the given delay
+    RateLimited(Duration),
+    /// The client already has this many analysis requests in flight, the per-client maximum
+    ClientBusy(usize),
+    /// The upload's declared content type is not an image type
+    UnsupportedContentType(String),
+    /// The declared content type contradicts the detected format (only with `?strict=true`)
+    ContentTypeMismatch(String),
+    /// The image format is recognised but not compiled into this build
+    UnsupportedFormat(String),
+    /// The image data is unrecognised or corrupt
+    DecodeError(String),
+    /// The image exceeds the configured dimension or memory limits
+    ImageTooLarge(String),
+    /// Images that must be the same size are not
+    DimensionMismatch(String),
+    /// The image has no pixels
+    EmptyImage,
+    /// A batch upload had no parts at all
+    EmptyBatch,
+    /// `known_hashes` is not a JSON array of 64-bit hex pHashes
+    InvalidHash(String),
+    /// The upload is not a readable ZIP archive
+    InvalidArchive(String),
+    /// The archive has too many entries or decompresses to too many bytes
+    ArchiveTooLarge(String),
+    /// The image at the requested URL could not be fetched
+    FetchFailed(String),
+    /// No capacity to serve the request right now
+    ServerBusy,
+    /// An unexpected failure, such as a panic while processing; the id ties
+    /// the response to the logged details
+    Internal { correlation_id: String },
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::MissingField { .. }
+            | ApiError::MissingImages { .. }
+            | ApiError::BodyReadError(_)
+            | ApiError::InvalidParameter(_)
+            | ApiError::InvalidHash(_)
+            | ApiError::DimensionMismatch(_)
+            | ApiError::EmptyBatch => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) | ApiError::InvalidSignature(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            ApiError::TooLarge(_) | ApiError::ArchiveTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::RateLimited(_) | ApiError::ClientBusy(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::UnsupportedContentType(_) | ApiError::UnsupportedFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::ContentTypeMismatch(_)
+            | ApiError::DecodeError(_)
+            | ApiError::ImageTooLarge(_)
+            | ApiError::InvalidArchive(_)
+            | ApiError::EmptyImage => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::InsufficientScope(_) => StatusCode::FORBIDDEN,
+            ApiError::FetchFailed(_) => StatusCode::BAD_GATEWAY,
+            ApiError::ServerBusy | ApiError::AuthUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier clients can match on; never changes once published.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::MissingField { .. } | ApiError::MissingImages { .. } => "missing_field",
+            ApiError::BodyReadError(_) => "body_read_error",
+            ApiError::InvalidParameter(_) => "invalid_parameter",
+            ApiError::InvalidHash(_) => "invalid_hash",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::InvalidSignature(_) => "invalid_signature",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::TooLarge(_) => "too_large",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::ClientBusy(_) => "client_busy",
+            ApiError::UnsupportedContentType(_) => "unsupported_content_type",
+            ApiError::ContentTypeMismatch(_) => "content_type_mismatch",
+            ApiError::UnsupportedFormat(_) => "unsupported_format",
+            ApiError::DecodeError(_) => "decode_error",
+            ApiError::ImageTooLarge(_) => "image_too_large",
+            ApiError::DimensionMismatch(_) => "dimension_mismatch",
+            ApiError::EmptyImage => "empty_image",
+            ApiError::EmptyBatch => "empty_batch",
+            ApiError::InvalidArchive(_) => "invalid_archive",
+            ApiError::ArchiveTooLarge(_) => "archive_too_large",
+            ApiError::FetchFailed(_) => "fetch_failed",
+            ApiError::InsufficientScope(_) => "insufficient_scope",
+            ApiError::AuthUnavailable(_) => "auth_unavailable",
+            ApiError::ServerBusy => "server_busy",
+            ApiError::Internal { .. } => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quoted = |names: &[String]| names.iter().map(|name| format!("'{name}'")).collect::<Vec<_>>();
+        let received_fields = |f: &mut fmt::Formatter<'_>, received: &[String]| match received {
+            [] => f.write_str("; the request had no fields"),
+            received => write!(f, "; received fields {}", quoted(received).join(", ")),
+        };
+        match self {
+            ApiError::MissingField { accepted, received } => {
+                match quoted(accepted).as_slice() {
+                    [] => f.write_str("no image found; send it as the only file part")?,
+                    [only] => write!(f, "no image found; send it in a field named {only}, or as the only file part")?,
+                    [rest @ .., last] => write!(
+                        f,
+                        "no image found; send it in a field named {} or {last}, or as the only file part",
+                        rest.join(", ")
+                    )?,
+                }
+                received_fields(f, received)
+            }
+            ApiError::MissingImages { required, received } => {
+                write!(f, "send the images in fields named {}", quoted(required).join(" and "))?;
+                received_fields(f, received)
+            }
+            ApiError::BodyReadError(message)
+            | ApiError::InvalidParameter(message)
+            | ApiError::InvalidHash(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::InvalidSignature(message)
+            | ApiError::InsufficientScope(message)
+            | ApiError::AuthUnavailable(message)
+            | ApiError::FetchFailed(message)
+            | ApiError::ContentTypeMismatch(message)
+            | ApiError::UnsupportedFormat(message)
+            | ApiError::DecodeError(message)
+            | ApiError::ImageTooLarge(message)
+            | ApiError::InvalidArchive(message)
+            | ApiError::ArchiveTooLarge(message)
+            | ApiError::DimensionMismatch(message) => f.write_str(message),
+            ApiError::Timeout(timeout) => write!(f, "request did not complete within {}s", timeout.as_secs()),
+            ApiError::TooLarge(max_bytes) => write!(f, "upload exceeds the maximum of {max_bytes} bytes"),
+            ApiError::UnsupportedContentType(content_type) => write!(
+                f,
+                "unsupported content type '{content_type}'; send image/*, application/octet-stream or no content type"
+            ),
+            ApiError::ClientBusy(in_flight) => write!(
+                f,
+                "{in_flight} analysis requests from this client are already in flight; wait for one to finish"
+            ),
+            ApiError::RateLimited(retry_after) => write!(
+                f,
+                "rate limit exceeded, retry in {}s",
+                retry_after_secs(*retry_after)
+            ),
+            ApiError::EmptyImage => f.write_str("No pixels found in image"),
+            ApiError::EmptyBatch => f.write_str("the batch has no parts; send at least one image"),
+            ApiError::ServerBusy => f.write_str("server is busy, please retry later"),
+            ApiError::Internal { correlation_id } => {
+                write!(f, "internal processing error (correlation id {correlation_id})")
+            }
+        }
+    }
+}
+
+impl ApiError {
+    /// The JSON body describing this error.
+    pub fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            code: self.code().to_string(),
+            error: self.to_string(),
+            correlation_id: match self {
+                ApiError::Internal { correlation_id } => Some(correlation_id.clone()),
+                _ => None,
+            },
+            accepted_fields: match self {
+                ApiError::MissingField { accepted, .. } | ApiError::MissingImages { required: accepted, .. } => {
+                    Some(accepted.clone())
+                }
+                _ => None,
+            },
+            request_id: REQUEST_ID.try_with(Clone::clone).ok().flatten(),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// `X-Request-Id` of the request being handled, for [`ApiError::body`].
+    /// Unset outside a request, e.g. for errors built in tests.
+    static REQUEST_ID: Option<String>;
+}
+
+/// Makes the request's `X-Request-Id`, set by [`SetRequestIdLayer`] before
+/// this runs, available to the error bodies built while handling it.
+async fn scope_request_id(request: Request, next: Next) -> Response {
+    let id = request.headers().get(X_REQUEST_ID).and_then(|id| id.to_str().ok()).map(str::to_string);
+    REQUEST_ID.scope(id, next.run(request)).await
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        response.extensions_mut().insert(ErrorCode(self.code()));
+        match self {
+            // A transient overload the client should retry
+            ApiError::ServerBusy => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+            }
+            ApiError::RateLimited(retry_after) => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs(retry_after)));
+            }
+            ApiError::Unauthorized(_) => {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            // The client gets the same message; the log keeps it with the request
+            ApiError::DecodeError(reason) => tracing::warn!(%reason, "image could not be decoded"),
+            _ => {}
+        }
+        response
+    }
+}
+
+/// The [`ApiError::code`] of an error response, for middleware to inspect.
+#[derive(Clone, Copy, Debug)]
+struct ErrorCode(&'static str);
+
+/// Whole seconds to advertise in `Retry-After`, rounded up so a client that
+/// waits exactly that long succeeds.
+fn retry_after_secs(delay: Duration) -> u64 {
+    delay.as_secs() + u64::from(delay.subsec_nanos() > 0)
+}
+
+impl From<AnalysisError> for ApiError {
+    fn from(err: AnalysisError) -> Self {
+        let message = err.to_string();
+        match err {
+            AnalysisError::TooManyPixels { .. }
+            | AnalysisError::DimensionsExceeded { .. }
+            | AnalysisError::AllocationExceeded { .. } => ApiError::ImageTooLarge(message),
+            AnalysisError::UnsupportedFormat(_) => {
+                ApiError::UnsupportedFormat(format!("{message}; see /supported-formats"))
+            }
+            AnalysisError::Decode(_) => ApiError::DecodeError(format!("{message}; see /supported-formats")),
+            AnalysisError::AmbiguousCmyk => ApiError::DecodeError(message),
+            AnalysisError::Empty => ApiError::EmptyImage,
+        }
+    }
+}
+
+impl From<ArchiveError> for ApiError {
+    fn from(err: ArchiveError) -> Self {
+        let message = err.to_string();
+        match err {
+            ArchiveError::Invalid(_) => ApiError::InvalidArchive(message),
+            ArchiveError::TooManyEntries { .. } | ArchiveError::TooLarge(_) => ApiError::ArchiveTooLarge(message),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::InvalidParameter(format!("invalid query parameters: {}", rejection.body_text()))
+    }
+}
+
+impl From<MultipartRejection> for ApiError {
+    fn from(rejection: MultipartRejection) -> Self {
+        ApiError::BodyReadError(rejection.body_text())
+    }
+}
+
+/// [`Query`] whose rejection is an [`ApiError`].
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(ApiError))]
+struct ApiQuery<T>(T);
+
+/// [`Multipart`] whose rejection is an [`ApiError`].
+struct ApiMultipart(Multipart);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for ApiMultipart {
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ApiMultipart(Multipart::from_request(request, state).await?))
+    }
+}
+
+/// TCP address served when no other is configured.
+pub const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3000);
+
+/// Service configuration, read from an optional [TOML file](Config::from_toml)
+/// and overridable through [environment variables](Config::with_env).
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Maximum number of images decoded and analysed at the same time
+    pub max_concurrent_decodes: usize,
+    /// How long a request may wait for a decode slot before giving up
+    #[serde(rename = "decode_queue_timeout_secs", with = "secs")]
+    pub decode_queue_timeout: Duration,
+    /// Number of results kept in the content-hash result cache (0 disables caching)
+    pub cache_capacity: usize,
+    /// Largest `width * height` accepted before the image is fully decoded
+    pub max_image_pixels: u64,
+    /// Largest accepted image width
+    pub max_image_width: u32,
+    /// Largest accepted image height
+    pub max_image_height: u32,
+    /// Largest buffer the image decoder may allocate
+    pub max_decode_alloc_bytes: u64,
+    /// Upper bound on the number of distinct colors tracked by `/unique-colors`
+    pub max_unique_colors: usize,
+    /// Largest accepted request body, in bytes
+    pub max_upload_bytes: usize,
+    /// Most entries, directories included, an archive sent to
+    /// `/calculate-intensity/zip` may list
+    pub max_archive_entries: usize,
+    /// Most bytes the images of one archive may decompress to
+    pub max_archive_uncompressed_bytes: u64,
+    /// Overall time budget for an analysis request, including upload and decode
+    #[serde(rename = "request_timeout_secs", with = "secs")]
+    pub request_timeout: Duration,
+    /// Analysis requests admitted at once; further requests are shed with a 503
+    pub max_in_flight_requests: usize,
+    /// Analysis requests each client (API key, or IP without one) may have in
+    /// flight; further ones get a 429. 0 disables the cap
+    pub max_in_flight_per_client: usize,
+    /// Keys accepted by [`authenticate`]; with no keys and no JWT settings,
+    /// authentication is disabled
+    pub api_keys: Vec<String>,
+    /// Secret HS256 bearer JWTs are verified with (exclusive with `jwt_jwks_url`)
+    pub jwt_hs256_secret: Option<String>,
+    /// URL of the JWKS bearer JWTs are verified against
+    pub jwt_jwks_url: Option<String>,
+    /// Scope every JWT must grant, e.g. `intensity:write`
+    pub jwt_required_scope: Option<String>,
+    /// Secret analysis uploads must be signed with, see [`verify_signature`];
+    /// `None` accepts unsigned uploads
+    pub upload_hmac_secret: Option<String>,
+    /// Requests per minute allowed for each client IP; 0 disables rate limiting
+    pub rate_limit_per_minute: u32,
+    /// Requests each client IP may send at once before the per-minute rate applies
+    pub rate_limit_burst: u32,
+    /// Take the client IP from `Forwarded` or `X-Forwarded-For` instead of
+    /// the connection, for deployments behind a reverse proxy
+    pub trust_proxy: bool,
+    /// Let `GET /calculate-intensity?url=` fetch from loopback, private and
+    /// other non-public addresses, which are refused by default
+    pub allow_private_fetch_urls: bool,
+    /// TCP address to listen on, port 0 picking a free port; `None` means
+    /// [`DEFAULT_BIND_ADDR`] unless a Unix socket is configured (see
+    /// [`Config::tcp_addr`])
+    #[serde(deserialize_with = "deserialize_bind_addr")]
+    pub bind_addr: Option<SocketAddr>,
+    /// Unix domain socket to listen on, alongside or instead of TCP
+    pub uds_path: Option<PathBuf>,
+    /// How long a shutdown waits for in-flight requests and decodes before exiting anyway
+    #[serde(rename = "shutdown_timeout_secs", with = "secs")]
+    pub shutdown_timeout: Duration,
+    /// Multipart field names read as the image upload, in no particular order
+    pub upload_field_names: Vec<String>,
+    /// Certificate and key to serve HTTPS with; plain HTTP when `None`
+    pub tls: Option<TlsPaths>,
+    /// Origins allowed to call the API from a browser; empty allows every
+    /// origin (without credentials)
+    pub cors_allowed_origins: Vec<OriginPattern>,
+    /// Methods allowed in cross-origin requests to the listed origins
+    #[serde(with = "methods")]
+    pub cors_allowed_methods: Vec<Method>,
+    /// Let the listed origins send cookies and `Authorization` headers
+    pub cors_allow_credentials: bool,
+    /// Path prefix a reverse proxy serves the API under, e.g. `/intensity`;
+    /// empty at the root. Used for the OpenAPI `servers` entry and the Swagger
+    /// UI's spec URL (see [`parse_base_path`])
+    #[serde(deserialize_with = "deserialize_base_path")]
+    pub base_path: String,
+    /// Serve the routes under `base_path` too, for proxies that forward the
+    /// prefix instead of stripping it
+    pub nest_base_path: bool,
+}
+
+/// PEM files for serving HTTPS, from `TLS_CERT_PATH` and `TLS_KEY_PATH` or a
+/// `[tls]` table in the configuration file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TlsPaths {
+    /// Certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// Private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+}
+
+impl TlsPaths {
+    /// Reads and validates the certificate and key, so a bad pair is reported
+    /// at startup rather than on the first handshake.
+    pub fn load(&self) -> Result<RustlsConfig, String> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| format!("TLS_CERT_PATH: cannot load {}: {err}", self.cert_path.display()))?;
+        if certs.is_empty() {
+            return Err(format!("TLS_CERT_PATH: no certificates found in {}", self.cert_path.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|err| format!("TLS_KEY_PATH: cannot load a private key from {}: {err}", self.key_path.display()))?;
+
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|err| format!("TLS_CERT_PATH/TLS_KEY_PATH: unusable certificate and key: {err}"))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(RustlsConfig::from_config(Arc::new(config)))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let limits = DecodeLimits::default();
+        Config {
+            max_concurrent_decodes: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            decode_queue_timeout: Duration::from_secs(10),
+            cache_capacity: 1000,
+            max_image_pixels: limits.max_pixels,
+            max_image_width: limits.max_width,
+            max_image_height: limits.max_height,
+            max_decode_alloc_bytes: limits.max_alloc_bytes,
+            max_unique_colors: 1 << 20,
+            max_upload_bytes: 20 * 1024 * 1024,
+            max_archive_entries: 1000,
+            max_archive_uncompressed_bytes: 256 * 1024 * 1024,
+            request_timeout: Duration::from_secs(30),
+            max_in_flight_requests: 64,
+            max_in_flight_per_client: 4,
+            api_keys: Vec::new(),
+            jwt_hs256_secret: None,
+            jwt_jwks_url: None,
+            jwt_required_scope: None,
+            upload_hmac_secret: None,
+            rate_limit_per_minute: 0,
+            rate_limit_burst: 10,
+            trust_proxy: false,
+            allow_private_fetch_urls: false,
+            bind_addr: None,
+            uds_path: None,
+            shutdown_timeout: Duration::from_secs(30),
+            upload_field_names: ["image", "file", "upload"].map(str::to_string).to_vec(),
+            tls: None,
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: vec![Method::GET, Method::POST],
+            cors_allow_credentials: false,
+            base_path: String::new(),
+            nest_base_path: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from the environment, falling back to
+    /// [`Config::default`] for unset variables.
+    pub fn from_env() -> Result<Self, String> {
+        Config::default().with_env()
+    }
+
+    /// Parses a TOML configuration file. Keys are the field names of
+    /// [`Config`], with durations given in seconds under a `_secs` name
+    /// (`request_timeout_secs = 30`); missing keys keep their defaults. Keys
+    /// that mean nothing are returned alongside, by path, so they can be
+    /// reported instead of silently ignored.
+    ///
+    /// ```
+    /// use webcalculation::server::Config;
+    ///
+    /// let (config, unknown) = Config::from_toml("cache_capacity = 10\ncache_size = 5").unwrap();
+    /// assert_eq!(config.cache_capacity, 10);
+    /// assert_eq!(config.max_in_flight_requests, Config::default().max_in_flight_requests);
+    /// assert_eq!(unknown, ["cache_size"]);
+    /// ```
+    pub fn from_toml(text: &str) -> Result<(Self, Vec<String>), String> {
+        let mut unknown = Vec::new();
+        let config = serde_ignored::deserialize(toml::Deserializer::new(text), |path| unknown.push(key_path(&path)))
+            .map_err(|err: toml::de::Error| err.to_string().trim_end().to_string())?;
+        Ok((config, unknown))
+    }
+
+    /// Overrides `self`, e.g. read [from a file](Config::from_toml), with the
+    /// environment variables that are set, then [validates](Config::validate)
+    /// the result. List variables only override when they list something.
+    pub fn with_env(self) -> Result<Self, String> {
+        let base = self;
+        let mut bind_addr = match std::env::var("BIND_ADDR") {
+            Ok(value) => Some(parse_bind_addr(&value, DEFAULT_BIND_ADDR.port()).map_err(|err| format!("BIND_ADDR: {err}"))?),
+            Err(_) => base.bind_addr,
+        };
+        if std::env::var_os("PORT").is_some() {
+            let addr = bind_addr.get_or_insert(DEFAULT_BIND_ADDR);
+            addr.set_port(env_or("PORT", addr.port())?);
+        }
+
+        // Empty values count as unset, so `TLS_CERT_PATH=` cannot pass for a path
+        let path_var = |name| std::env::var_os(name).filter(|path| !path.is_empty());
+        let tls = match (path_var("TLS_CERT_PATH"), path_var("TLS_KEY_PATH")) {
+            (Some(cert_path), Some(key_path)) => Some(TlsPaths { cert_path: cert_path.into(), key_path: key_path.into() }),
+            (None, None) => base.tls,
+            (Some(_), None) => return Err("TLS_CERT_PATH is set but TLS_KEY_PATH is not; set both to serve HTTPS".to_string()),
+            (None, Some(_)) => return Err("TLS_KEY_PATH is set but TLS_CERT_PATH is not; set both to serve HTTPS".to_string()),
+        };
+
+        let cors_allowed_origins = match env_list("CORS_ALLOWED_ORIGINS") {
+            origins if origins.is_empty() => base.cors_allowed_origins,
+            origins => origins
+                .iter()
+                .map(|origin| origin.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|err| format!("CORS_ALLOWED_ORIGINS: {err}"))?,
+        };
+        let cors_allowed_methods = match env_list("CORS_ALLOWED_METHODS") {
+            methods if methods.is_empty() => base.cors_allowed_methods,
+            methods => methods
+                .iter()
+                .map(|method| parse_method(method))
+                .collect::<Result<_, _>>()
+                .map_err(|err| format!("CORS_ALLOWED_METHODS: {err}"))?,
+        };
+        let or_base = |values: Vec<String>, base: Vec<String>| if values.is_empty() { base } else { values };
+
+        let config = Config {
+            max_concurrent_decodes: env_or("MAX_CONCURRENT_DECODES", base.max_concurrent_decodes)?,
+            decode_queue_timeout: env_secs("DECODE_QUEUE_TIMEOUT_SECS", base.decode_queue_timeout)?,
+            cache_capacity: env_or("CACHE_CAPACITY", base.cache_capacity)?,
+            max_image_pixels: env_or("MAX_IMAGE_PIXELS", base.max_image_pixels)?,
+            max_image_width: env_or("MAX_IMAGE_WIDTH", base.max_image_width)?,
+            max_image_height: env_or("MAX_IMAGE_HEIGHT", base.max_image_height)?,
+            max_decode_alloc_bytes: env_or("MAX_DECODE_ALLOC_BYTES", base.max_decode_alloc_bytes)?,
+            max_unique_colors: env_or("MAX_UNIQUE_COLORS", base.max_unique_colors)?,
+            max_upload_bytes: env_or("MAX_UPLOAD_BYTES", base.max_upload_bytes)?,
+            max_archive_entries: env_or("MAX_ARCHIVE_ENTRIES", base.max_archive_entries)?,
+            max_archive_uncompressed_bytes: env_or("MAX_ARCHIVE_UNCOMPRESSED_BYTES", base.max_archive_uncompressed_bytes)?,
+            request_timeout: env_secs("REQUEST_TIMEOUT_SECS", base.request_timeout)?,
+            max_in_flight_requests: env_or("MAX_IN_FLIGHT_REQUESTS", base.max_in_flight_requests)?,
+            max_in_flight_per_client: env_or("MAX_IN_FLIGHT_PER_CLIENT", base.max_in_flight_per_client)?,
+            api_keys: or_base(env_list("API_KEYS"), base.api_keys),
+            jwt_hs256_secret: env_string("JWT_HS256_SECRET").or(base.jwt_hs256_secret),
+            jwt_jwks_url: env_string("JWT_JWKS_URL").or(base.jwt_jwks_url),
+            jwt_required_scope: env_string("JWT_REQUIRED_SCOPE").or(base.jwt_required_scope),
+            upload_hmac_secret: env_string("UPLOAD_HMAC_SECRET").or(base.upload_hmac_secret),
+            rate_limit_per_minute: env_or("RATE_LIMIT_PER_MINUTE", base.rate_limit_per_minute)?,
+            rate_limit_burst: env_or("RATE_LIMIT_BURST", base.rate_limit_burst)?,
+            trust_proxy: env_or("TRUST_PROXY", base.trust_proxy)?,
+            allow_private_fetch_urls: env_or("ALLOW_PRIVATE_FETCH_URLS", base.allow_private_fetch_urls)?,
+            bind_addr,
+            uds_path: std::env::var_os("UDS_PATH").map(PathBuf::from).or(base.uds_path),
+            shutdown_timeout: env_secs("SHUTDOWN_TIMEOUT_SECS", base.shutdown_timeout)?,
+            upload_field_names: or_base(env_list("UPLOAD_FIELD_NAMES"), base.upload_field_names),
+            tls,
+            cors_allowed_origins,
+            cors_allowed_methods,
+            cors_allow_credentials: env_or("CORS_ALLOW_CREDENTIALS", base.cors_allow_credentials)?,
+            base_path: match std::env::var("BASE_PATH") {
+                Ok(value) => parse_base_path(&value).map_err(|err| format!("BASE_PATH: {err}"))?,
+                Err(_) => base.base_path,
+            },
+            nest_base_path: env_or("NEST_BASE_PATH", base.nest_base_path)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings the server cannot run with, naming the environment
+    /// variable (the file key is the same in lower case).
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent_decodes == 0 {
+            return Err("MAX_CONCURRENT_DECODES must be at least 1".to_string());
+        }
+        if self.max_in_flight_requests == 0 {
+            return Err("MAX_IN_FLIGHT_REQUESTS must be at least 1".to_string());
+        }
+        if self.jwt_hs256_secret.is_some() && self.jwt_jwks_url.is_some() {
+            return Err("set only one of JWT_HS256_SECRET and JWT_JWKS_URL".to_string());
+        }
+        if let Some(url) = &self.jwt_jwks_url
+            && !reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+        {
+            return Err(format!("JWT_JWKS_URL: expected an http(s) URL, got {url:?}"));
+        }
+        if self.jwt_required_scope.is_some() && !self.jwt_enabled() {
+            return Err("JWT_REQUIRED_SCOPE needs JWT_HS256_SECRET or JWT_JWKS_URL".to_string());
+        }
+        if self.rate_limit_per_minute > 0 && self.rate_limit_burst == 0 {
+            return Err("RATE_LIMIT_BURST must be at least 1 when RATE_LIMIT_PER_MINUTE is set".to_string());
+        }
+        if self.cors_allow_credentials && self.cors_allowed_origins.is_empty() {
+            return Err("CORS_ALLOW_CREDENTIALS needs CORS_ALLOWED_ORIGINS; credentials cannot be offered to every origin".to_string());
+        }
+        Ok(())
+    }
+
+    /// The configuration as TOML, in the format [`Config::from_toml`] reads,
+    /// with API keys and secrets masked so it can be logged.
+    pub fn to_redacted_toml(&self) -> String {
+        let redacted = Config {
+            api_keys: vec!["<redacted>".to_string(); self.api_keys.len()],
+            jwt_hs256_secret: self.jwt_hs256_secret.as_ref().map(|_| "<redacted>".to_string()),
+            upload_hmac_secret: self.upload_hmac_secret.as_ref().map(|_| "<redacted>".to_string()),
+            ..self.clone()
+        };
+        toml::to_string(&redacted).expect("the configuration serializes to TOML")
+    }
+
+    /// Whether bearer JWTs are accepted.
+    pub fn jwt_enabled(&self) -> bool {
+        self.jwt_hs256_secret.is_some() || self.jwt_jwks_url.is_some()
+    }
+
+    /// The TCP address to listen on: the configured one, or the default when
+    /// neither an address nor a Unix socket is configured. `None` serves on
+    /// the Unix socket only.
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        self.bind_addr.or(self.uds_path.is_none().then_some(DEFAULT_BIND_ADDR))
+    }
+
+    fn archive_limits(&self) -> ArchiveLimits {
+        ArchiveLimits {
+            max_entries: self.max_archive_entries,
+            max_uncompressed_bytes: self.max_archive_uncompressed_bytes,
+        }
+    }
+
+    fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_pixels: self.max_image_pixels,
+            max_width: self.max_image_width,
+            max_height: self.max_image_height,
+            max_alloc_bytes: self.max_decode_alloc_bytes,
+        }
+    }
+}
+
+/// Parses a listen address: an IP with port (`127.0.0.1:8080`, `[::1]:8080`)
+/// or a bare IP (`0.0.0.0`, `::`), which gets `default_port`.
+///
+/// ```
+/// use webcalculation::server::parse_bind_addr;
+///
+/// assert_eq!(parse_bind_addr("127.0.0.1", 3000).unwrap().to_string(), "127.0.0.1:3000");
+/// assert_eq!(parse_bind_addr("[::1]:8080", 3000).unwrap().to_string(), "[::1]:8080");
+/// assert!(parse_bind_addr("localhost:80", 3000).is_err());
+/// ```
+pub fn parse_bind_addr(value: &str, default_port: u16) -> Result<SocketAddr, String> {
+    let value = value.trim();
+    value
+        .parse::<SocketAddr>()
+        .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, default_port)))
+        .map_err(|_| {
+            format!("invalid address {value:?}; expected an IP such as 0.0.0.0, optionally with a port such as 127.0.0.1:8080")
+        })
+}
+
+/// Normalizes a path prefix to a leading slash and no trailing one, `/` and
+/// the empty string both meaning the root (`""`). Only unreserved URL
+/// characters are accepted, so the prefix can go into URLs and HTML as is.
+///
+/// ```
+/// use webcalculation::server::parse_base_path;
+///
+/// assert_eq!(parse_base_path("intensity/").unwrap(), "/intensity");
+/// assert_eq!(parse_base_path("/").unwrap(), "");
+/// assert!(parse_base_path("/a b").is_err());
+/// ```
+pub fn parse_base_path(value: &str) -> Result<String, String> {
+    let path = value.trim().trim_matches('/');
+    let valid = |segment: &str| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+    };
+    if path.is_empty() {
+        Ok(String::new())
+    } else if path.split('/').all(valid) {
+        Ok(format!("/{path}"))
+    } else {
+        Err(format!("invalid path prefix {value:?}; expected e.g. /intensity"))
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid value for {name}: {value:?}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// The value of `name`, `None` when unset or empty.
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Comma-separated values of `name`, empty when unset.
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn env_secs(name: &str, default: Duration) -> Result<Duration, String> {
+    env_or(name, default.as_secs()).map(Duration::from_secs)
+}
+
+fn parse_method(method: &str) -> Result<Method, String> {
+    Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes()).map_err(|_| format!("invalid method {method:?}"))
+}
+
+/// A dotted TOML key such as `tls.ca_path`.
+fn key_path(path: &serde_ignored::Path<'_>) -> String {
+    use serde_ignored::Path;
+    match path {
+        Path::Root => String::new(),
+        Path::Map { parent: Path::Root, key } => key.clone(),
+        Path::Map { parent, key } => format!("{}.{key}", key_path(parent)),
+        Path::Seq { parent, index } => format!("{}[{index}]", key_path(parent)),
+        Path::Some { parent } | Path::NewtypeStruct { parent } | Path::NewtypeVariant { parent } => key_path(parent),
+    }
+}
+
+/// Addresses in the configuration file take the same forms as `BIND_ADDR`.
+fn deserialize_bind_addr<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<SocketAddr>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_bind_addr(&value, DEFAULT_BIND_ADDR.port()).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Path prefixes in the configuration file are normalized like `BASE_PATH`.
+fn deserialize_base_path<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    parse_base_path(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Durations as whole seconds in the configuration file.
+mod secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+}
+
+/// HTTP methods as names (`"POST"`) in the configuration file.
+mod methods {
+    use axum::http::Method;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(methods: &[Method], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(methods.iter().map(Method::as_str))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Method>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|method| super::parse_method(method).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Identifies a cached result: the SHA-256 of the uploaded bytes plus every
+/// request option that influences the computed statistics.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    digest: [u8; 32],
+    /// Canonical JSON of the request options
+    options: String,
+}
+
+impl CacheKey {
+    fn new(image_data: &[u8], options: &impl Serialize) -> Self {
+        CacheKey {
+            digest: Sha256::digest(image_data).into(),
+            options: serde_json::to_string(options).unwrap_or_default(),
+        }
+    }
+}
+
+/// State shared by every handler.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    /// Gates the CPU-heavy decode/compute work shared by every analysis endpoint
+    decode_permits: Arc<Semaphore>,
+    /// Recently computed results, `None` when caching is disabled
+    result_cache: Option<Arc<Mutex<LruCache<CacheKey, IntensityResponse>>>>,
+    /// Admission control for analysis requests, see [`load_shed`]
+    in_flight_requests: Arc<Semaphore>,
+    /// Number of analysis requests turned away by [`load_shed`]
+    rejected_requests: Arc<AtomicU64>,
+    /// Accepted API keys, `None` when none are configured
+    api_keys: Option<Arc<ApiKeys>>,
+    /// Bearer JWT verification, `None` when not configured
+    jwt: Option<Arc<JwtVerifier>>,
+    /// Fetches images for `GET /calculate-intensity?url=`
+    fetcher: Arc<Fetcher>,
+    /// Per-IP token buckets, `None` when rate limiting is disabled
+    rate_limiter: Option<Arc<RateLimiter<IpAddr>>>,
+    /// Analysis requests in flight per client, see [`limit_client_in_flight`];
+    /// `None` when the cap is disabled
+    client_slots: Option<Arc<InFlightLimiter<String>>>,
+    /// Set once shutdown begins; `/ready` and `/health` then report 503
+    draining: Arc<AtomicBool>,
+    /// Counters reported by `/stats`
+    stats: Arc<RuntimeStats>,
+}
+
+/// Counters behind `GET /stats`, updated by [`record_stats`] and the upload
+/// and batch handling.
+struct RuntimeStats {
+    started: Instant,
+    requests_by_route: Mutex<BTreeMap<String, u64>>,
+    image_bytes: AtomicU64,
+    decode_failures: AtomicU64,
+}
+
+impl RuntimeStats {
+    fn new() -> Self {
+        RuntimeStats {
+            started: Instant::now(),
+            requests_by_route: Mutex::default(),
+            image_bytes: AtomicU64::new(0),
+            decode_failures: AtomicU64::new(0),
+        }
+    }
+
+    fn count_request(&self, route: &str) {
+        let mut requests = self.requests_by_route.lock().unwrap();
+        match requests.get_mut(route) {
+            Some(count) => *count += 1,
+            None => {
+                requests.insert(route.to_string(), 1);
+            }
+        }
+    }
+
+    fn count_upload(&self, bytes: usize) {
+        self.image_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn count_decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl AppState {
+    pub fn new(config: Config) -> Self {
+        AppState {
+            decode_permits: Arc::new(Semaphore::new(config.max_concurrent_decodes)),
+            result_cache: NonZeroUsize::new(config.cache_capacity)
+                .map(|capacity| Arc::new(Mutex::new(LruCache::new(capacity)))),
+            in_flight_requests: Arc::new(Semaphore::new(config.max_in_flight_requests)),
+            rejected_requests: Arc::new(AtomicU64::new(0)),
+            api_keys: ApiKeys::new(&config.api_keys).map(Arc::new),
+            jwt: jwt_verifier(&config).map(Arc::new),
+            fetcher: Arc::new(Fetcher::new(
+                config.max_upload_bytes,
+                config.request_timeout,
+                config.allow_private_fetch_urls,
+            )),
+            rate_limiter: (config.rate_limit_per_minute > 0)
+                .then(|| Arc::new(RateLimiter::with_burst(config.rate_limit_per_minute, config.rate_limit_burst))),
+            client_slots: (config.max_in_flight_per_client > 0)
+                .then(|| Arc::new(InFlightLimiter::new(config.max_in_flight_per_client))),
+            draining: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(RuntimeStats::new()),
+            config: Arc::new(config),
+        }
+    }
+
+    fn cached_result(&self, key: &CacheKey) -> Option<IntensityResponse> {
+        let cache = self.result_cache.as_ref()?;
+        let mut response = cache.lock().unwrap().get(key)?.clone();
+        response.cached = true;
+        response.processing_ms = 0.0;
+        Some(response)
+    }
+
+    fn store_result(&self, key: CacheKey, response: &IntensityResponse) {
+        if let Some(cache) = &self.result_cache {
+            cache.lock().unwrap().put(key, response.clone());
+        }
+    }
+
+    fn requests_in_flight(&self) -> usize {
+        self.config.max_in_flight_requests - self.in_flight_requests.available_permits()
+    }
+
+    fn decodes_in_flight(&self) -> usize {
+        self.config.max_concurrent_decodes - self.decode_permits.available_permits()
+    }
+
+    /// Marks the service as shutting down, so health checks fail and load
+    /// balancers stop routing new traffic here.
+    pub fn begin_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Why the service should not be sent new work right now, if it
+    /// shouldn't: it is shutting down, or every analysis slot is taken so
+    /// further requests would only be shed with a 503.
+    pub fn not_ready_reason(&self) -> Option<String> {
+        if self.is_draining() {
+            return Some("draining".to_string());
+        }
+        if self.in_flight_requests.available_permits() == 0 {
+            return Some(format!("busy: {} analysis requests in flight", self.config.max_in_flight_requests));
+        }
+        None
+    }
+
+    /// Resolves once no decode is running, including ones whose request has
+    /// already gone away.
+    async fn decodes_finished(&self) {
+        let all = u32::try_from(self.config.max_concurrent_decodes).unwrap_or(u32::MAX);
+        let _ = self.decode_permits.acquire_many(all).await;
+    }
+
+    /// Waits for a decode slot, giving up with a 503 once the queue timeout elapses.
+    async fn acquire_decode_permit(&self) -> Result<OwnedSemaphorePermit, ApiError> {
+        let acquire = self.decode_permits.clone().acquire_owned();
+        match tokio::time::timeout(self.config.decode_queue_timeout, acquire).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                tracing::warn!(
+                    decodes_in_flight = self.decodes_in_flight(),
+                    max_concurrent_decodes = self.config.max_concurrent_decodes,
+                    "decode queue timeout"
+                );
+                Err(ApiError::ServerBusy)
+            }
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        calculate_intensity,
+        calculate_intensity_from_url,
+        calculate_intensity_stream,
+        calculate_intensity_sse,
+        calculate_intensity_batch_summary,
+        calculate_intensity_zip,
+        unique_colors,
+        threshold,
+        coverage,
+        segment_stats,
+        hsv_stats,
+        color_temperature,
+        channel_correlation_matrix,
+        rgb_histogram,
+        heatmap,
+        grayscale,
+        normalize,
+        equalize,
+        diff_image,
+        metadata,
+        check_duplicate,
+        supported_formats,
+        version,
+        stats,
+        liveness,
+        readiness,
+        health_check,
+        serve_openapi,
+        serve_openapi_yaml
+    ),
+    components(schemas(
+        IntensityResponse,
+        IntensityScale,
+        Weighting,
+        Colorspace,
+        Metric,
+        PixelLocation,
+        QuadrantIntensities,
+        ExposureResponse,
+        Dimensions,
+        UniqueColorsResponse,
+        CoverageResponse,
+        SegmentStatsResponse,
+        HsvStatsResponse,
+        ColorTemperatureResponse,
+        ChannelCorrelationResponse,
+        RgbHistogramResponse,
+        SupportedFormat,
+        IntensityStreamLine,
+        BatchSummary,
+        BatchSummaryResponse,
+        BatchAggregate,
+        ArchiveIntensityResponse,
+        DuplicateCheckResponse,
+        HashMatch,
+        ProbeResponse,
+        VersionResponse,
+        StatsResponse,
+        ErrorResponse,
+        ImageUpload,
+        ImageBatchUpload,
+        ImagePairUpload,
+        ArchiveUpload,
+        DuplicateCheckUpload,
+        CaptureMetadata,
+        GpsPosition
+    )),
+    tags(
+        (name = "Image Processing", description = "Image intensity calculation API")
+    ),
+    // `info.version` is left to utoipa, which takes it from CARGO_PKG_VERSION
+    info(
+        title = "Web Image Intensity Calculator API",
+        description = "A REST API for calculating the average intensity of uploaded images"
+    )
+)]
+struct ApiDoc;
+
+#[derive(Clone, Copy, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct IntensityParams {
+    /// Percentage (0-49) of the darkest and brightest pixels to discard for the trimmed mean
+    #[param(minimum = 0.0, maximum = 49.0)]
+    trim: Option<f64>,
+    /// Range to report intensities in
+    #[serde(default)]
+    scale: IntensityScale,
+    /// Deprecated alias of `colorspace=linear`
+    #[serde(default)]
+    #[deprecated = "use `colorspace=linear`"]
+    linearize: bool,
+    /// Color space to also average in: `linear` adds `linear_average_intensity` and
+    /// `linear_average_intensity_srgb`
+    #[serde(default)]
+    colorspace: Colorspace,
+    /// Perceptual metric to also report
+    metric: Option<Metric>,
+    /// Also report the average intensity of each quadrant
+    #[serde(default)]
+    quadrants: bool,
+    /// Reject uploads whose declared content type contradicts the detected format
+    #[serde(default)]
+    strict: bool,
+    /// Average (0-255) below which an image may be a black frame
+    #[serde(default = "default_black_average")]
+    #[param(default = 2.0, minimum = 0.0, maximum = 255.0)]
+    black_average: f64,
+    /// Brightest-pixel intensity (0-255) below which an image may be a black frame
+    #[serde(default = "default_black_peak")]
+    #[param(default = 16.0, minimum = 0.0, maximum = 255.0)]
+    black_peak: f64,
+    /// Resample so the longest side is at most this many pixels before analysing
+    #[param(minimum = 1)]
+    downscale_to: Option<u32>,
+    /// How pixels contribute to `average_intensity`
+    #[serde(default)]
+    weighting: Weighting,
+    /// Gaussian sigma of `center` weighting, as a fraction of the image diagonal
+    #[serde(default = "default_center_sigma")]
+    #[param(default = 0.25, exclusive_minimum = 0.0, maximum = 10.0)]
+    center_sigma: f64,
+    /// Mean HSV saturation (0-1) below which `color_family` is `neutral`
+    #[serde(default = "default_saturation_threshold")]
+    #[param(default = 0.1, minimum = 0.0, maximum = 1.0)]
+    saturation_threshold: f64,
+    /// Largest difference (0-255) between two channels of a pixel that still counts as gray
+    #[serde(default = "default_color_threshold")]
+    #[param(default = 16.0, minimum = 0.0, maximum = 255.0)]
+    color_threshold: f64,
+    /// Percentage (0-100) of colored pixels below which the image is monochrome
+    #[serde(default = "default_monochrome_percent")]
+    #[param(default = 1.0, minimum = 0.0, maximum = 100.0)]
+    monochrome_percent: f64,
+    /// Decimal places (0-10) to round the reported values to
+    // Applied when the response is serialized, so it is left out of the cache key
+    #[param(maximum = 10)]
+    round: Option<u32>,
+}
+
+impl IntensityParams {
+    /// The requested color space, with `linearize=true` standing for `linear`.
+    #[allow(deprecated)]
+    fn colorspace(&self) -> Colorspace {
+        if self.linearize { Colorspace::Linear } else { self.colorspace }
+    }
+
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.trim.is_some_and(|trim| !(0.0..=49.0).contains(&trim)) {
+            return Err(ApiError::InvalidParameter("trim must be a percentage between 0 and 49".to_string()));
+        }
+        if ![self.black_average, self.black_peak].iter().all(|value| (0.0..=255.0).contains(value)) {
+            return Err(ApiError::InvalidParameter("black_average and black_peak must be between 0 and 255".to_string()));
+        }
+        if self.downscale_to == Some(0) {
+            return Err(ApiError::InvalidParameter("downscale_to must be at least 1".to_string()));
+        }
+        if !(self.center_sigma > 0.0 && self.center_sigma <= 10.0) {
+            return Err(ApiError::InvalidParameter("center_sigma must be above 0 and at most 10".to_string()));
+        }
+        if !(0.0..=1.0).contains(&self.saturation_threshold) {
+            return Err(ApiError::InvalidParameter("saturation_threshold must be between 0 and 1".to_string()));
+        }
+        if !(0.0..=255.0).contains(&self.color_threshold) {
+            return Err(ApiError::InvalidParameter("color_threshold must be between 0 and 255".to_string()));
+        }
+        if !(0.0..=100.0).contains(&self.monochrome_percent) {
+            return Err(ApiError::InvalidParameter("monochrome_percent must be a percentage between 0 and 100".to_string()));
+        }
+        if self.round.is_some_and(|decimals| decimals > MAX_ROUND_DECIMALS) {
+            return Err(ApiError::InvalidParameter(format!("round must be between 0 and {MAX_ROUND_DECIMALS}")));
+        }
+        Ok(())
+    }
+}
+
+/// Most decimal places `?round=` accepts.
+const MAX_ROUND_DECIMALS: u32 = 10;
+
+/// `value` serialized with every fractional number rounded to `decimals`
+/// places, or at full precision when that is `None`. Integers such as pixel
+/// counts are left alone.
+struct Rounded<T> {
+    value: T,
+    decimals: Option<u32>,
+}
+
+impl<T: Serialize> Serialize for Rounded<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(decimals) = self.decimals else {
+            return self.value.serialize(serializer);
+        };
+        let mut value = serde_json::to_value(&self.value).map_err(serde::ser::Error::custom)?;
+        round_floats(&mut value, 10f64.powi(decimals as i32));
+        value.serialize(serializer)
+    }
+}
+
+/// Rounds to a multiple of `1 / factor`. Adding zero turns a rounded -0.0
+/// into 0.0.
+fn round_to(float: f64, factor: f64) -> f64 {
+    (float * factor).round() / factor + 0.0
+}
+
+fn round_floats(value: &mut serde_json::Value, factor: f64) {
+    match value {
+        serde_json::Value::Number(number) if number.is_f64() => {
+            // Values too large to scale stay as they are
+            let rounded = number.as_f64().map(|float| round_to(float, factor));
+            if let Some(rounded) = rounded.and_then(serde_json::Number::from_f64) {
+                *number = rounded;
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| round_floats(item, factor)),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(|field| round_floats(field, factor)),
+        _ => {}
+    }
+}
+
+fn default_black_average() -> f64 {
+    2.0
+}
+
+fn default_black_peak() -> f64 {
+    16.0
+}
+
+fn default_center_sigma() -> f64 {
+    0.25
+}
+
+fn default_saturation_threshold() -> f64 {
+    0.1
+}
+
+fn default_color_threshold() -> f64 {
+    16.0
+}
+
+fn default_monochrome_percent() -> f64 {
+    1.0
+}
+
+/// How pixels contribute to `average_intensity`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Weighting {
+    /// Every pixel counts the same
+    #[default]
+    Uniform,
+    /// Pixels count by a radial Gaussian peaking at the image center
+    Center,
+}
+
+/// Color space pixel values are averaged in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Colorspace {
+    /// Gamma-encoded values as stored, the only average by default
+    #[default]
+    Srgb,
+    /// Light energy: each channel is decoded through the sRGB transfer function before averaging
+    Linear,
+}
+
+/// Perceptual metrics reported alongside the intensity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Mean CIELAB L*, in `mean_lab_lightness`
+    LabLightness,
+}
+
+/// Range in which intensity values are reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum IntensityScale {
+    /// 0-255, as stored in 8-bit images
+    #[default]
+    Byte,
+    /// 0.0-1.0
+    Unit,
+}
+
+impl IntensityScale {
+    /// Converts a 0-255 intensity into this scale.
+    pub fn apply(self, intensity: f64) -> f64 {
+        match self {
+            IntensityScale::Byte => intensity,
+            IntensityScale::Unit => intensity / 255.0,
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/calculate-intensity",
+    tag = "Image Processing",
+    params(IntensityParams),
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part). \
+            `?trim=P` (0-49) additionally reports the mean with the darkest and brightest P% of pixels discarded. \
+            `?scale=unit` reports every intensity in 0-1 instead of the default 0-255 (`byte`). \
+            `?colorspace=linear` additionally reports the average in linear light (sRGB decoded) as \
+            `linear_average_intensity`, and re-encoded to sRGB as `linear_average_intensity_srgb`, both on \
+            the `scale` of `average_intensity`; the default `srgb` adds nothing. `?linearize=true` is a \
+            deprecated alias of `?colorspace=linear`. \
+            `?metric=lab_lightness` additionally reports the mean CIELAB L* (0-100) in `mean_lab_lightness`. \
+            `?quadrants=true` additionally reports the average of each image quadrant in `quadrants`. \
+            `?black_average=A&black_peak=P` (0-255, defaults 2 and 16) tune `is_black_frame`. \
+            `?weighting=center` weights each pixel's contribution to `average_intensity` by a radial Gaussian \
+            peaking at the image center, with sigma `?center_sigma=` (default 0.25) times the image diagonal. \
+            `?downscale_to=N` (at least 1) first resamples the image, area-averaging, so its longest side \
+            is at most N pixels, bounding the cost of huge images; `effective_dimensions` reports the size analysed. \
+            `?saturation_threshold=S` (0-1, default 0.1) is the mean saturation below which \
+            `color_family` is `neutral`. \
+            `?color_threshold=T` (0-255, default 16) is the channel difference above which a pixel counts \
+            towards `color_fraction`, and `?monochrome_percent=P` (0-100, default 1) the share of such pixels \
+            below which `is_monochrome` is true. \
+            `?round=N` (0-10) rounds every fractional value in the response to N decimal places. \
+            A declared content type that contradicts the detected format is reported in `warnings`, \
+            or rejected with 422 under `?strict=true`.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Successfully calculated image intensity", body = IntensityResponse,
+            headers(("X-Average-Intensity" = f64, description = "`average_intensity` from the body, scaled and rounded alike"))),
+        (status = 400, description = "Bad request - invalid or missing image data or options", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, one exceeding the decode limits, or a content type mismatch under `?strict=true`", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn calculate_intensity(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<IntensityParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Response, ApiError> {
+    params.validate()?;
+    let upload = read_image_field(&state, multipart).await?;
+    let value = analyse_intensity(&state, &params, upload).await?;
+    Ok(intensity_response(value, params.round))
+}
+
+/// The JSON body, with `average_intensity` repeated in `X-Average-Intensity`
+/// for clients that only read headers. Both are rounded alike.
+fn intensity_response(value: IntensityResponse, decimals: Option<u32>) -> Response {
+    let average = match decimals {
+        Some(decimals) => round_to(value.average_intensity, 10f64.powi(decimals as i32)),
+        None => value.average_intensity,
+    };
+    ([(HeaderName::from_static(X_AVERAGE_INTENSITY), average.to_string())], Json(Rounded { value, decimals }))
+        .into_response()
+}
+
+/// How long shared caches may keep a `GET /calculate-intensity?url=` result.
+const URL_RESULT_MAX_AGE: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImageUrlParam {
+    /// The http or https URL of the image to analyse
+    #[param(required = true, nullable = false)]
+    url: Option<String>,
+}
+
+/// Fetches the image at `?url=` and analyses it like the POST form, taking the
+/// same query options. Nothing is read from the request body, which must be
+/// empty, so the result can be cached on the full URL; responses carry
+/// `Cache-Control: max-age=300`. The fetched image is held to
+/// MAX_UPLOAD_BYTES, and loopback and private addresses are refused unless
+/// ALLOW_PRIVATE_FETCH_URLS is set.
+#[utoipa::path(
+    get,
+    path = "/calculate-intensity",
+    tag = "Image Processing",
+    params(ImageUrlParam, IntensityParams),
+    responses(
+        (status = 200, description = "Successfully calculated image intensity", body = IntensityResponse,
+            headers(("X-Average-Intensity" = f64, description = "`average_intensity` from the body, scaled and rounded alike"))),
+        (status = 400, description = "Bad request - missing or invalid url, a non-public url, a request body, or invalid options", body = ErrorResponse),
+        (status = 408, description = "Request timeout - fetching and analysing took longer than REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - the image exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - the URL serves a non-image content type, or an image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 502, description = "Bad gateway - the URL could not be fetched or did not answer with a success", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn calculate_intensity_from_url(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<IntensityParams>,
+    ApiQuery(ImageUrlParam { url }): ApiQuery<ImageUrlParam>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    params.validate()?;
+    let has_body = headers.contains_key(header::TRANSFER_ENCODING)
+        || headers.get(header::CONTENT_LENGTH).is_some_and(|length| length != "0");
+    if has_body {
+        return Err(ApiError::InvalidParameter(
+            "GET /calculate-intensity takes no body; POST multipart uploads instead".to_string(),
+        ));
+    }
+    let url = url
+        .filter(|url| !url.is_empty())
+        .ok_or_else(|| ApiError::InvalidParameter("url is required".to_string()))?;
+
+    let fetched = state.fetcher.fetch(&url).await.map_err(|err| match err {
+        FetchError::InvalidUrl(message) | FetchError::Forbidden(message) => ApiError::InvalidParameter(message),
+        FetchError::TooLarge(max_bytes) => ApiError::TooLarge(max_bytes),
+        FetchError::Failed(message) => ApiError::FetchFailed(message),
+    })?;
+    if let Some(content_type) = &fetched.content_type
+        && !is_image_content_type(content_type)
+    {
+        return Err(ApiError::UnsupportedContentType(content_type.clone()));
+    }
+    state.stats.count_upload(fetched.data.len());
+    let span = tracing::Span::current();
+    span.record("upload_bytes", fetched.data.len());
+    if let Ok(format) = image::guess_format(&fetched.data) {
+        span.record("detected_format", format_label(format).as_str());
+    }
+
+    let upload = Upload { data: Bytes::from(fetched.data), content_type: fetched.content_type };
+    let value = analyse_intensity(&state, &params, upload).await?;
+    // Results for authenticated callers stay out of shared caches
+    let visibility = if state.api_keys.is_some() || state.jwt.is_some() { "private" } else { "public" };
+    let cache_control = format!("{visibility}, max-age={}", URL_RESULT_MAX_AGE.as_secs());
+    Ok(([(header::CACHE_CONTROL, cache_control)], intensity_response(value, params.round)).into_response())
+}
+
+/// The intensity analysis behind `/calculate-intensity` and its streaming
+/// variant, served from the result cache when possible.
+async fn analyse_intensity(
+    state: &AppState,
+    params: &IntensityParams,
+    Upload { data, content_type }: Upload,
+) -> Result<IntensityResponse, ApiError> {
+    // Sniffed before decoding so strict mode rejects without spending a decode
+    // slot. Undetectable data is left for the decoder to report.
+    let detected = image::guess_format(&data).ok();
+    let mut warnings = Vec::new();
+    if let Some(mismatch) = content_type_mismatch(content_type.as_deref(), detected) {
+        if params.strict {
+            return Err(ApiError::ContentTypeMismatch(mismatch));
+        }
+        warnings.push(mismatch);
+    }
+
+    // Warnings depend on the declared type, which is not part of the cache
+    // key, so they are attached per request
+    let colorspace = params.colorspace();
+    #[allow(deprecated)]
+    let cache_key =
+        CacheKey::new(&data, &IntensityParams { round: None, linearize: false, colorspace, ..*params });
+    if let Some(mut response) = state.cached_result(&cache_key) {
+        response.warnings = warnings;
+        return Ok(response);
+    }
+
+    let limits = state.config.decode_limits();
+    let linearize = colorspace == Colorspace::Linear;
+    let quadrants = params.quadrants;
+    let lab = params.metric == Some(Metric::LabLightness);
+    let downscale_to = params.downscale_to;
+    let center_sigma = (params.weighting == Weighting::Center).then_some(params.center_sigma);
+    let saturation_threshold = params.saturation_threshold;
+    let permit = state.acquire_decode_permit().await?;
+    let (result, processing_ms) = run_blocking(permit, move || {
+        let started = Instant::now();
+        let result = decode_image(&data, &limits).and_then(|img| {
+            let original = (img.width(), img.height());
+            let img = match downscale_to {
+                Some(max_side) => downscale(&img, max_side),
+                None => Cow::Borrowed(&img),
+            };
+            let stats = intensity_stats(&img)?;
+            let family = color_family(&img, &stats, saturation_threshold);
+            let weighted = center_sigma.and_then(|sigma| center_weighted_intensity(&img, sigma));
+            let linear = linearize.then(|| linear_intensity(&img)).flatten();
+            let lightness = lab.then(|| lab_lightness(&img)).flatten();
+            let quadrants = quadrants.then(|| quadrant_intensity(&img)).flatten();
+            Ok((original, stats, family, weighted, linear, lightness, quadrants))
+        });
+        (result, started.elapsed().as_secs_f64() * 1000.0)
+    })
+    .await?;
+
+    let ((width, height), stats, family, weighted, linear, lightness, quadrants) = result?;
+    let scale = params.scale;
+    let average_intensity = scale.apply(weighted.unwrap_or(stats.average_intensity));
+    let response = IntensityResponse {
+        average_intensity,
+        scale,
+        weighting: params.weighting,
+        message: match scale {
+            IntensityScale::Byte => format!("Average intensity calculated: {:.2}", average_intensity),
+            IntensityScale::Unit => format!("Average intensity calculated: {:.4}", average_intensity),
+        },
+        median_intensity: scale.apply(stats.median_intensity),
+        std_dev: scale.apply(stats.std_dev),
+        log_mean_intensity: scale.apply(stats.log_mean_intensity),
+        hsp_brightness: scale.apply(stats.hsp_brightness),
+        contrast_rms: stats.contrast_rms(),
+        contrast_michelson: stats.contrast_michelson().unwrap_or(0.0),
+        contrast_undefined: stats.contrast_michelson().is_none(),
+        exposure: ExposureResponse::new(&stats, scale),
+        color_family: family.as_str().to_string(),
+        color_fraction: stats.color_fraction(params.color_threshold),
+        is_monochrome: stats.is_monochrome(params.color_threshold, params.monochrome_percent),
+        processing_ms,
+        cached: false,
+        brightest_pixel: PixelLocation::new(stats.brightest_pixel, scale),
+        darkest_pixel: PixelLocation::new(stats.darkest_pixel, scale),
+        bit_depth: stats.bit_depth,
+        megapixels: f64::from(width) * f64::from(height) / 1e6,
+        aspect_ratio: f64::from(width) / f64::from(height),
+        aspect_label: aspect_label(width, height),
+        effective_dimensions: downscale_to.map(|_| Dimensions { width: stats.width, height: stats.height }),
+        is_black_frame: stats.is_black_frame(params.black_average, params.black_peak),
+        trimmed_mean_intensity: params.trim.map(|trim| scale.apply(trimmed_mean(&stats.histogram, trim))),
+        linear_average_intensity: linear.map(|linear| scale.apply(255.0 * linear)),
+        linear_average_intensity_srgb: linear.map(|linear| scale.apply(255.0 * linear_to_srgb(linear))),
+        mean_lab_lightness: lightness,
+        quadrants: quadrants.map(|quadrants| QuadrantIntensities::new(quadrants, scale)),
+        detected_format: detected.map(format_label).unwrap_or_default(),
+        warnings: Vec::new(),
+    };
+    state.store_result(cache_key, &response);
+    Ok(IntensityResponse { warnings, ..response })
+}
+
+/// Channel capacity, in events, between a batch's processing task and the
+/// response body; a slow reader pauses processing once it fills.
+const BATCH_BUFFER_EVENTS: usize = 16;
+
+/// Progress of a batch started by [`spawn_batch`].
+enum BatchEvent {
+    /// One part was analysed or failed
+    Line(Box<IntensityStreamLine>),
+    /// Every part has been processed
+    Done(BatchSummary),
+}
+
+/// Analyses every part of `multipart` in order on a background task, sending
+/// each outcome as soon as it is known and a summary at the end. The shared
+/// pipeline behind the NDJSON and SSE batch endpoints.
+///
+/// The task outlives the handler, so it applies the request timeout itself:
+/// once it passes, the part in progress is reported as a timeout and the
+/// batch ends there.
+fn spawn_batch(state: AppState, params: IntensityParams, mut multipart: Multipart) -> mpsc::Receiver<BatchEvent> {
+    let (events, receiver) = mpsc::channel(BATCH_BUFFER_EVENTS);
+    let timeout = state.config.request_timeout;
+    let deadline = tokio::time::Instant::now() + timeout;
+    tokio::spawn(async move {
+        let mut summary = BatchSummary::default();
+        let mut intensity_sum = 0.0;
+        for index in 0.. {
+            let next_part = async {
+                match multipart.next_field().await {
+                    Ok(Some(part)) => {
+                        let field = part.name().map(str::to_string);
+                        let filename = part.file_name().map(str::to_string);
+                        let outcome = match read_upload(&state, part).await {
+                            Ok(upload) => analyse_intensity(&state, &params, upload).await,
+                            Err(err) => Err(err),
+                        };
+                        Some((field, filename, outcome))
+                    }
+                    Ok(None) => None,
+                    Err(err) => Some((None, None, Err(upload_error(&state, err)))),
+                }
+            };
+            let (field, filename, outcome) = match tokio::time::timeout_at(deadline, next_part).await {
+                Ok(Some(part)) => part,
+                Ok(None) => break,
+                Err(_) => (None, None, Err(ApiError::Timeout(timeout))),
+            };
+            // Past a body-level failure or the deadline nothing more can be parsed
+            let body_failed =
+                matches!(outcome, Err(ApiError::TooLarge(_) | ApiError::BodyReadError(_) | ApiError::Timeout(_)));
+
+            summary.total += 1;
+            match &outcome {
+                Ok(result) => {
+                    summary.succeeded += 1;
+                    intensity_sum += result.average_intensity;
+                }
+                Err(err) => {
+                    summary.failed += 1;
+                    if matches!(err, ApiError::DecodeError(_)) {
+                        state.stats.count_decode_failure();
+                    }
+                }
+            }
+            let line = IntensityStreamLine {
+                index,
+                field,
+                filename,
+                result: outcome.as_ref().ok().cloned(),
+                error: outcome.err().as_ref().map(ApiError::body),
+            };
+            // A failed send means the client hung up
+            if events.send(BatchEvent::Line(Box::new(line))).await.is_err() {
+                return;
+            }
+            if body_failed {
+                break;
+            }
+        }
+
+        summary.mean_intensity = (summary.succeeded > 0).then(|| intensity_sum / summary.succeeded as f64);
+        let _ = events.send(BatchEvent::Done(summary)).await;
+    });
+    receiver
+}
+
+#[utoipa::path(
+    post,
+    path = "/calculate-intensity/stream",
+    tag = "Image Processing",
+    params(IntensityParams),
+    request_body(
+        content = ImageBatchUpload,
+        description = "Any number of image files uploaded as multipart/form-data, every part being analysed \
+            in order. Takes the same query parameters as /calculate-intensity. The whole body is bounded \
+            by the upload size limit.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Newline-delimited JSON, one line per part written as soon as it is analysed",
+            content_type = "application/x-ndjson", body = IntensityStreamLine),
+        (status = 400, description = "Bad request - not multipart form data, or invalid options", body = ErrorResponse),
+        (status = 503, description = "Server busy - too many requests in flight", body = ErrorResponse)
+    )
+)]
+async fn calculate_intensity_stream(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<IntensityParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Response, ApiError> {
+    params.validate()?;
+
+    let decimals = params.round;
+    let lines = ReceiverStream::new(spawn_batch(state, params, multipart)).filter_map(move |event| match event {
+        BatchEvent::Line(value) => {
+            let mut line = serde_json::to_vec(&Rounded { value, decimals }).expect("stream lines serialize");
+            line.push(b'\n');
+            Some(Ok::<_, Infallible>(Bytes::from(line)))
+        }
+        BatchEvent::Done(_) => None,
+    });
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(lines)).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/calculate-intensity/sse",
+    tag = "Image Processing",
+    params(IntensityParams),
+    request_body(
+        content = ImageBatchUpload,
+        description = "Any number of image files uploaded as multipart/form-data, as for /calculate-intensity/stream",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Server-sent events: a `data:` event per part carrying an IntensityStreamLine \
+            (its `id` is the part index), then an `event: done` carrying a BatchSummary",
+            content_type = "text/event-stream", body = BatchSummary),
+        (status = 400, description = "Bad request - not multipart form data, or invalid options", body = ErrorResponse),
+        (status = 503, description = "Server busy - too many requests in flight", body = ErrorResponse)
+    )
+)]
+async fn calculate_intensity_sse(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<IntensityParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    params.validate()?;
+
+    let decimals = params.round;
+    let events = ReceiverStream::new(spawn_batch(state, params, multipart)).map(move |event| match event {
+        BatchEvent::Line(line) => {
+            Event::default().id(line.index.to_string()).json_data(Rounded { value: line, decimals })
+        }
+        BatchEvent::Done(summary) => Event::default().event("done").json_data(Rounded { value: summary, decimals }),
+    });
+    // Each event is written as its own frame, and compression skips
+    // `text/event-stream`, so events reach the client as they happen
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/calculate-intensity/batch/summary",
+    tag = "Image Processing",
+    params(IntensityParams),
+    request_body(
+        content = ImageBatchUpload,
+        description = "Any number of image files uploaded as multipart/form-data, as for /calculate-intensity/stream",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Every part's result together with statistics across the images, once all are analysed",
+            body = BatchSummaryResponse),
+        (status = 400, description = "Bad request - not multipart form data, no parts, or invalid options", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 503, description = "Server busy - too many requests in flight", body = ErrorResponse)
+    )
+)]
+async fn calculate_intensity_batch_summary(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<IntensityParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<Rounded<BatchSummaryResponse>>, ApiError> {
+    params.validate()?;
+
+    let decimals = params.round;
+    let mut events = spawn_batch(state, params, multipart);
+    let (mut images, mut summary) = (Vec::new(), BatchSummary::default());
+    while let Some(event) = events.recv().await {
+        match event {
+            BatchEvent::Line(line) => images.push(*line),
+            BatchEvent::Done(done) => summary = done,
+        }
+    }
+    if images.is_empty() {
+        return Err(ApiError::EmptyBatch);
+    }
+
+    let value = BatchSummaryResponse { summary, aggregate: BatchAggregate::over(&images), images };
+    Ok(Json(Rounded { value, decimals }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/calculate-intensity/zip",
+    tag = "Image Processing",
+    params(IntensityParams),
+    request_body(
+        content = ArchiveUpload,
+        description = "A ZIP archive uploaded as multipart/form-data in a field named 'image' or 'file' (or as the \
+            only file part). Every entry with an image extension, or whose first bytes are those of an image, is \
+            analysed in archive order; directories and other entries are skipped and counted. Takes the same query \
+            parameters as /calculate-intensity. Archives listing more than MAX_ARCHIVE_ENTRIES entries, or whose \
+            images decompress to more than MAX_ARCHIVE_UNCOMPRESSED_BYTES, are rejected with 413.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Every image entry's result together with statistics across the images",
+            body = ArchiveIntensityResponse),
+        (status = 400, description = "Bad request - not multipart form data, no archive part, or invalid options", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit, or the archive \
+            exceeds the entry or decompressed size limit", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - not a readable ZIP archive", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn calculate_intensity_zip(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<IntensityParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<Rounded<ArchiveIntensityResponse>>, ApiError> {
+    params.validate()?;
+    // Any content type is taken; the archive reader tells ZIPs from the rest
+    let archive = read_file_field(&state, multipart, false).await?.data;
+
+    let limits = state.config.archive_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let ArchiveImages { entries, skipped } = run_blocking(permit, move || image_entries(&archive, limits)).await??;
+
+    let mut summary = BatchSummary::default();
+    let mut intensity_sum = 0.0;
+    let mut images = Vec::with_capacity(entries.len());
+    for (index, ArchiveEntry { name, data }) in entries.into_iter().enumerate() {
+        let outcome = match data {
+            Ok(data) => analyse_intensity(&state, &params, Upload { data: Bytes::from(data), content_type: None }).await,
+            Err(reason) => Err(ApiError::DecodeError(format!("cannot decompress {name}: {reason}"))),
+        };
+        summary.total += 1;
+        match &outcome {
+            Ok(result) => {
+                summary.succeeded += 1;
+                intensity_sum += result.average_intensity;
+            }
+            Err(err) => {
+                summary.failed += 1;
+                if matches!(err, ApiError::DecodeError(_)) {
+                    state.stats.count_decode_failure();
+                }
+            }
+        }
+        images.push(IntensityStreamLine {
+            index,
+            field: None,
+            filename: Some(name),
+            result: outcome.as_ref().ok().cloned(),
+            error: outcome.err().as_ref().map(ApiError::body),
+        });
+    }
+    summary.mean_intensity = (summary.succeeded > 0).then(|| intensity_sum / summary.succeeded as f64);
+
+    let value = ArchiveIntensityResponse { summary, aggregate: BatchAggregate::over(&images), images, skipped };
+    Ok(Json(Rounded { value, decimals: params.round }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/unique-colors",
+    tag = "Image Processing",
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part)",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Number of distinct RGB colors in the image", body = UniqueColorsResponse),
+        (status = 400, description = "Bad request - invalid or missing image data", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn unique_colors(
+    State(state): State<AppState>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<UniqueColorsResponse>, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let max_colors = state.config.max_unique_colors;
+    let permit = state.acquire_decode_permit().await?;
+    let (unique_color_count, truncated) = run_blocking(permit, move || {
+        count_unique_colors(&data, &limits, max_colors)
+    })
+    .await??;
+
+    Ok(Json(UniqueColorsResponse {
+        unique_color_count,
+        truncated,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum ThresholdMethod {
+    Otsu,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ThresholdParams {
+    /// Fixed threshold; pixels above it become white
+    value: Option<u8>,
+    /// Automatic threshold selection
+    #[param(inline)]
+    method: Option<ThresholdMethod>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/threshold",
+    tag = "Image Processing",
+    params(ThresholdParams),
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part). \
+            Pass `?value=T` (0-255) for a fixed threshold or `?method=otsu` to choose it automatically.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Grayscale PNG mask where pixels above the threshold are 255 and the rest 0",
+            content_type = "image/png", body = Vec<u8>,
+            headers(("X-Threshold" = u8, description = "Threshold that was applied"))),
+        (status = 400, description = "Bad request - invalid or missing image data or threshold", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn threshold(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<ThresholdParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Response, ApiError> {
+    let fixed = match (params.value, params.method) {
+        (Some(value), None) => Some(value),
+        (None, Some(ThresholdMethod::Otsu)) => None,
+        _ => return Err(ApiError::InvalidParameter("provide exactly one of ?value=T or ?method=otsu".to_string())),
+    };
+
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let (chosen, png) = run_blocking(permit, move || {
+        let gray = intensity_image(&decode_image(&data, &limits)?);
+        let chosen = fixed.unwrap_or_else(|| otsu(&histogram(&gray)).threshold);
+        let png = encode_png(&DynamicImage::ImageLuma8(binarize(&gray, chosen)))?;
+        Ok::<_, AnalysisError>((chosen, png))
+    })
+    .await??;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png".to_string()),
+            (HeaderName::from_static("x-threshold"), chosen.to_string()),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CoverageParams {
+    /// Intensity cut-off (0-255); pixels strictly above it count as covered
+    #[param(minimum = 0.0, maximum = 255.0)]
+    threshold: f64,
+}
+
+#[utoipa::path(
+    post,
+    path = "/coverage",
+    tag = "Image Processing",
+    params(CoverageParams),
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part). \
+            The required `?threshold=T` (0-255) sets the intensity cut-off.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Fraction of pixels brighter than the threshold", body = CoverageResponse),
+        (status = 400, description = "Bad request - invalid or missing image data or threshold", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn coverage(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<CoverageParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<CoverageResponse>, ApiError> {
+    let threshold = params.threshold;
+    if !(0.0..=255.0).contains(&threshold) {
+        return Err(ApiError::InvalidParameter("threshold must be between 0 and 255".to_string()));
+    }
+
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let (pixels_above, total) = run_blocking(permit, move || {
+        let img = decode_image(&data, &limits)?;
+        match count_above_threshold(&img, threshold) {
+            (_, 0) => Err(AnalysisError::Empty),
+            counts => Ok(counts),
+        }
+    })
+    .await??;
+
+    let fraction_above = pixels_above as f64 / total as f64;
+    Ok(Json(CoverageResponse {
+        threshold,
+        fraction_above,
+        fraction_below: 1.0 - fraction_above,
+        pixels_above,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/segment-stats",
+    tag = "Image Processing",
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part)",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Otsu foreground/background split of the intensity histogram", body = SegmentStatsResponse),
+        (status = 400, description = "Bad request - invalid or missing image data", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn segment_stats(
+    State(state): State<AppState>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<SegmentStatsResponse>, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let split = run_blocking(permit, move || {
+        let gray = intensity_image(&decode_image(&data, &limits)?);
+        if gray.is_empty() {
+            return Err(AnalysisError::Empty);
+        }
+        Ok(otsu(&histogram(&gray)))
+    })
+    .await??;
+
+    Ok(Json(SegmentStatsResponse {
+        threshold: split.threshold,
+        between_class_variance: split.between_class_variance,
+        background_fraction: split.background_fraction,
+        foreground_fraction: 1.0 - split.background_fraction,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/hsv-stats",
+    tag = "Image Processing",
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part)",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "HSV saturation and value statistics and a saturation-weighted hue histogram",
+            body = HsvStatsResponse),
+        (status = 400, description = "Bad request - invalid or missing image data", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn hsv_stats(
+    State(state): State<AppState>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<HsvStatsResponse>, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let stats = run_blocking(permit, move || {
+        crate::analysis::hsv_stats(&decode_image(&data, &limits)?).ok_or(AnalysisError::Empty)
+    })
+    .await??;
+
+    Ok(Json(HsvStatsResponse {
+        mean_saturation: stats.mean_saturation,
+        std_saturation: stats.std_saturation,
+        mean_value: stats.mean_value,
+        std_value: stats.std_value,
+        hue_histogram: stats.hue_histogram.to_vec(),
+    }))
+}
+
+/// Estimates the correlated color temperature (CCT) of the image's overall
+/// cast. The pixels are decoded from sRGB to linear light and averaged; the
+/// mean is converted to CIE XYZ with the sRGB (D65) matrix and to its `xy`
+/// chromaticity, and McCamy's cubic approximation
+/// `CCT = 449 n³ + 3525 n² + 6823.3 n + 5520.33`, with
+/// `n = (x - 0.3320) / (0.1858 - y)`, gives the temperature. McCamy's formula
+/// is a closed-form fit to the Planckian locus that needs no iteration or
+/// lookup table and is accurate to a few kelvin from about 2000 to 12500 K,
+/// plenty for a warm/neutral/cool verdict; it ignores any green-magenta tint.
+/// Near-grayscale and very dark images still get a value, flagged
+/// `confidence: low`.
+#[utoipa::path(
+    post,
+    path = "/color-temperature",
+    tag = "Image Processing",
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part)",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Estimated color temperature, its warm/neutral/cool label and how far to trust it",
+            body = ColorTemperatureResponse),
+        (status = 400, description = "Bad request - invalid or missing image data", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn color_temperature(
+    State(state): State<AppState>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<ColorTemperatureResponse>, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let estimate = run_blocking(permit, move || {
+        crate::analysis::color_temperature(&decode_image(&data, &limits)?).ok_or(AnalysisError::Empty)
+    })
+    .await??;
+
+    Ok(Json(ColorTemperatureResponse {
+        cct_kelvin: estimate.kelvin.round(),
+        label: estimate.cast().as_str().to_string(),
+        confidence: if estimate.is_reliable() { "high" } else { "low" }.to_string(),
+        chromaticity_x: estimate.chromaticity.0,
+        chromaticity_y: estimate.chromaticity.1,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/channel-correlation",
+    tag = "Image Processing",
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part)",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Pearson correlation between the R, G and B channels", body = ChannelCorrelationResponse),
+        (status = 400, description = "Bad request - invalid or missing image data", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn channel_correlation_matrix(
+    State(state): State<AppState>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<ChannelCorrelationResponse>, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let (matrix, pixel_count) = run_blocking(permit, move || {
+        let img = decode_image(&data, &limits)?;
+        let matrix = channel_correlation(&img).ok_or(AnalysisError::Empty)?;
+        Ok::<_, AnalysisError>((matrix, u64::from(img.width()) * u64::from(img.height())))
+    })
+    .await??;
+
+    Ok(Json(ChannelCorrelationResponse {
+        channels: ["r", "g", "b"].map(String::from).to_vec(),
+        correlation: matrix.iter().map(|row| row.to_vec()).collect(),
+        pixel_count,
+    }))
+}
+
+/// Upload an image to get histograms of its red, green and blue channels
+#[utoipa::path(
+    post,
+    path = "/histogram/rgb",
+    tag = "Image Processing",
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part)",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "256-bin red, green, blue and intensity histograms", body = RgbHistogramResponse),
+        (status = 400, description = "Bad request - invalid or missing image data", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn rgb_histogram(
+    State(state): State<AppState>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<RgbHistogramResponse>, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let histograms = run_blocking(permit, move || Ok::<_, AnalysisError>(rgb_histograms(&decode_image(&data, &limits)?)))
+        .await??;
+
+    Ok(Json(RgbHistogramResponse {
+        red: histograms.red.to_vec(),
+        green: histograms.green.to_vec(),
+        blue: histograms.blue.to_vec(),
+        luminance: histograms.luminance.to_vec(),
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HeatmapParams {
+    /// Color map to render intensity with
+    #[serde(default)]
+    #[param(inline)]
+    colormap: Colormap,
+}
+
+#[utoipa::path(
+    post,
+    path = "/heatmap",
+    tag = "Image Processing",
+    params(HeatmapParams),
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part). \
+            `?colormap=viridis|turbo` selects the color map (default viridis).",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "False-color PNG of the image's intensity, same size as the input",
+            content_type = "image/png", body = Vec<u8>),
+        (status = 400, description = "Bad request - invalid or missing image data or colormap", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn heatmap(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<HeatmapParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Response, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let png = run_blocking(permit, move || {
+        let gray = intensity_image(&decode_image(&data, &limits)?);
+        Ok::<_, AnalysisError>(encode_png(&DynamicImage::ImageRgb8(apply_colormap(&gray, params.colormap)))?)
+    })
+    .await??;
+
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+#[derive(Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+enum Dither {
+    /// Smooth 8-bit gray
+    #[default]
+    None,
+    FloydSteinberg,
+    Ordered,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GrayscaleParams {
+    /// Quantize the gray image to black and white with this algorithm
+    #[serde(default)]
+    #[param(inline)]
+    dither: Dither,
+}
+
+#[utoipa::path(
+    post,
+    path = "/grayscale",
+    tag = "Image Processing",
+    params(GrayscaleParams),
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part). \
+            `?dither=floyd-steinberg|ordered` quantizes the gray image to black and white with that algorithm \
+            (default none).",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "PNG of the image's intensity, same size as the input: 8-bit gray, or 1-bit when dithered",
+            content_type = "image/png", body = Vec<u8>),
+        (status = 400, description = "Bad request - invalid or missing image data or dither mode", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn grayscale(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<GrayscaleParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Response, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let png = run_blocking(permit, move || {
+        let gray = intensity_image(&decode_image(&data, &limits)?);
+        Ok::<_, AnalysisError>(match params.dither {
+            Dither::None => encode_png(&DynamicImage::ImageLuma8(gray))?,
+            Dither::FloydSteinberg => encode_bilevel_png(&floyd_steinberg(&gray))?,
+            Dither::Ordered => encode_bilevel_png(&ordered_dither(&gray))?,
+        })
+    })
+    .await??;
+
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct NormalizeParams {
+    /// Percentage (0-49) of the darkest and of the brightest pixels allowed to clip to 0 and 255
+    #[serde(default = "default_clip_percent")]
+    #[param(default = 0.5, minimum = 0.0, maximum = 49.0)]
+    clip_percent: f64,
+}
+
+fn default_clip_percent() -> f64 {
+    0.5
+}
+
+#[utoipa::path(
+    post,
+    path = "/normalize",
+    tag = "Image Processing",
+    params(NormalizeParams),
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part). \
+            `?clip_percent=P` (0-49, default 0.5) sets how much of each end of the intensity histogram may clip.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "PNG with every color channel stretched linearly so the P-th percentile of intensity \
+            becomes 0 and the (100-P)-th 255, same size and layout as the input at 8 bits per channel",
+            content_type = "image/png", body = Vec<u8>,
+            headers(
+                ("X-Black-Point" = u8, description = "Intensity that was mapped to 0"),
+                ("X-White-Point" = u8, description = "Intensity that was mapped to 255")
+            )),
+        (status = 400, description = "Bad request - invalid or missing image data or clip_percent", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn normalize(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<NormalizeParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Response, ApiError> {
+    let clip_percent = params.clip_percent;
+    if !(0.0..=49.0).contains(&clip_percent) {
+        return Err(ApiError::InvalidParameter("clip_percent must be a percentage between 0 and 49".to_string()));
+    }
+
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let ((low, high), png) = run_blocking(permit, move || {
+        let img = decode_image(&data, &limits)?;
+        let bounds = percentile_bounds(&histogram(&intensity_image(&img)), clip_percent).ok_or(AnalysisError::Empty)?;
+        let png = encode_png(&stretch_levels(&img, bounds.0, bounds.1))?;
+        Ok::<_, AnalysisError>((bounds, png))
+    })
+    .await??;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png".to_string()),
+            (HeaderName::from_static("x-black-point"), low.to_string()),
+            (HeaderName::from_static("x-white-point"), high.to_string()),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/equalize",
+    tag = "Image Processing",
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part).",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "PNG with its histogram equalized: the HSV value (the brightest channel) is remapped \
+            through its cumulative distribution and each pixel's channels scaled alike, so hue and saturation are kept. \
+            Same size and layout as the input at 8 bits per channel", content_type = "image/png", body = Vec<u8>),
+        (status = 400, description = "Bad request - invalid or missing image data", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn equalize(State(state): State<AppState>, ApiMultipart(multipart): ApiMultipart) -> Result<Response, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let png = run_blocking(permit, move || {
+        let img = decode_image(&data, &limits)?;
+        Ok::<_, AnalysisError>(encode_png(&crate::analysis::equalize(&img))?)
+    })
+    .await??;
+
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DiffImageParams {
+    /// Factor the differences are multiplied by, clamping at 255
+    #[serde(default = "default_gain")]
+    #[param(default = 1.0, exclusive_minimum = 0.0, maximum = 255.0)]
+    gain: f64,
+}
+
+fn default_gain() -> f64 {
+    1.0
+}
+
+#[utoipa::path(
+    post,
+    path = "/diff-image",
+    tag = "Image Processing",
+    params(DiffImageParams),
+    request_body(
+        content = ImagePairUpload,
+        description = "Two image files of the same size uploaded as multipart/form-data in fields named 'image_a' \
+            and 'image_b'. `?gain=N` (above 0, at most 255) multiplies the differences to make faint ones visible.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "RGB PNG, the size of the inputs, where each channel is |a - b| times the gain",
+            content_type = "image/png", body = Vec<u8>),
+        (status = 400, description = "Bad request - a missing image, images of different sizes, or an invalid gain", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn diff_image(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<DiffImageParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Response, ApiError> {
+    let gain = params.gain;
+    if !(gain > 0.0 && gain <= 255.0) {
+        return Err(ApiError::InvalidParameter("gain must be above 0 and at most 255".to_string()));
+    }
+
+    let [a, b] = read_image_fields(&state, multipart, ["image_a", "image_b"]).await?;
+
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let png = run_blocking(permit, move || {
+        let (a, b) = (decode_image(&a.data, &limits)?, decode_image(&b.data, &limits)?);
+        let Some(difference) = difference_image(&a, &b, gain) else {
+            return Err(ApiError::DimensionMismatch(format!(
+                "the images must be the same size; image_a is {}x{} and image_b {}x{}",
+                a.width(),
+                a.height(),
+                b.width(),
+                b.height()
+            )));
+        };
+        Ok(encode_png(&DynamicImage::ImageRgb8(difference)).map_err(AnalysisError::from)?)
+    })
+    .await??;
+
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MetadataParams {
+    /// Include the GPS position, left out by default for privacy
+    #[serde(default)]
+    include_gps: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/metadata",
+    tag = "Image Processing",
+    params(MetadataParams),
+    request_body(
+        content = ImageUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part). \
+            `?include_gps=true` adds the GPS position, which is otherwise never returned.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "Capture settings from the image's EXIF; tags it lacks are left out, so an image \
+            without EXIF gives an empty object", body = CaptureMetadata),
+        (status = 400, description = "Bad request - missing image data or an invalid flag", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload took longer than REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type", body = ErrorResponse)
+    )
+)]
+async fn metadata(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<MetadataParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<CaptureMetadata>, ApiError> {
+    let data = read_image_field(&state, multipart).await?.data;
+    // Only the EXIF block is parsed, never the pixels, so no decode slot is taken
+    Ok(Json(capture_metadata(&data, params.include_gps)))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DuplicateParams {
+    /// Most bits (0-64) the nearest known hash may differ in for the upload to count as a duplicate
+    #[serde(default = "default_max_distance")]
+    #[param(default = 10, maximum = 64)]
+    max_distance: u32,
+}
+
+fn default_max_distance() -> u32 {
+    10
+}
+
+/// Multipart field holding the hashes `/check-duplicate` compares against.
+const KNOWN_HASHES_FIELD: &str = "known_hashes";
+
+#[utoipa::path(
+    post,
+    path = "/check-duplicate",
+    tag = "Image Processing",
+    params(DuplicateParams),
+    request_body(
+        content = DuplicateCheckUpload,
+        description = "Image file uploaded as multipart/form-data in a field named 'image' or 'file' (or as the only file part), \
+            with a text field 'known_hashes' holding a JSON array of 64-bit pHashes as 16 hex digits each. \
+            `?max_distance=N` (0-64, default 10) is the most bits the nearest hash may differ in for a duplicate.",
+        content_type = "multipart/form-data"
+    ),
+    responses(
+        (status = 200, description = "The upload's perceptual hash and the nearest known hash", body = DuplicateCheckResponse),
+        (status = 400, description = "Bad request - missing image data or known_hashes, a malformed hash, or an invalid \
+            max_distance", body = ErrorResponse),
+        (status = 408, description = "Request timeout - reading the upload and analysing it took longer than \
+            REQUEST_TIMEOUT_SECS", body = ErrorResponse),
+        (status = 413, description = "Payload too large - upload exceeds the configured size limit", body = ErrorResponse),
+        (status = 415, description = "Unsupported media type - non-image content type, or image format not supported in this build", body = ErrorResponse),
+        (status = 422, description = "Unprocessable entity - unrecognised or corrupt image, or one exceeding the decode limits", body = ErrorResponse),
+        (status = 503, description = "Server busy - no decode slot became available in time", body = ErrorResponse)
+    )
+)]
+async fn check_duplicate(
+    State(state): State<AppState>,
+    ApiQuery(params): ApiQuery<DuplicateParams>,
+    ApiMultipart(multipart): ApiMultipart,
+) -> Result<Json<DuplicateCheckResponse>, ApiError> {
+    let max_distance = params.max_distance;
+    if max_distance > 64 {
+        return Err(ApiError::InvalidParameter("max_distance must be between 0 and 64".to_string()));
+    }
+
+    let (upload, known_hashes) = read_image_and_text(&state, multipart, KNOWN_HASHES_FIELD).await?;
+    let known_hashes = known_hashes.ok_or_else(|| {
+        ApiError::InvalidParameter(format!("send the hashes to compare with in a field named '{KNOWN_HASHES_FIELD}'"))
+    })?;
+    // Checked before decoding, so a bad list costs no decode slot
+    let known = parse_known_hashes(&known_hashes)?;
+
+    let data = upload.data;
+    let limits = state.config.decode_limits();
+    let permit = state.acquire_decode_permit().await?;
+    let hash = run_blocking(permit, move || decode_image(&data, &limits).map(|img| perceptual_hash(&img))).await??;
+
+    let closest_match = known
+        .into_iter()
+        .enumerate()
+        .map(|(index, (text, known))| HashMatch { index, hash: text, distance: hash_distance(hash, known) })
+        .min_by_key(|candidate| candidate.distance);
+    Ok(Json(DuplicateCheckResponse {
+        hash: format!("{hash:016x}"),
+        is_duplicate: closest_match.as_ref().is_some_and(|closest| closest.distance <= max_distance),
+        closest_match,
+        max_distance,
+    }))
+}
+
+/// Parses the `known_hashes` field: a JSON array of 16-hex-digit hashes,
+/// each returned as sent and as its value.
+fn parse_known_hashes(text: &str) -> Result<Vec<(String, u64)>, ApiError> {
+    let hashes: Vec<String> = serde_json::from_str(text).map_err(|err| {
+        ApiError::InvalidHash(format!("{KNOWN_HASHES_FIELD} must be a JSON array of hex strings: {err}"))
+    })?;
+    hashes
+        .into_iter()
+        .enumerate()
+        .map(|(index, hash)| {
+            // `from_str_radix` alone would also take a sign and shorter hashes
+            if hash.len() == 16
+                && hash.bytes().all(|digit| digit.is_ascii_hexdigit())
+                && let Ok(value) = u64::from_str_radix(&hash, 16)
+            {
+                return Ok((hash, value));
+            }
+            Err(ApiError::InvalidHash(format!(
+                "{KNOWN_HASHES_FIELD}[{index}] is not a pHash: expected 16 hex digits, got {hash:?}"
+            )))
+        })
+        .collect()
+}
+
+/// An uploaded image part.
+struct Upload {
+    data: Bytes,
+    /// Content type declared for the part, if any
+    content_type: Option<String>,
+}
+
+/// Returns the image part: the first field named in
+/// [`Config::upload_field_names`] or, failing that, the only file part of the
+/// form.
+async fn read_image_field(state: &AppState, multipart: Multipart) -> Result<Upload, ApiError> {
+    read_file_field(state, multipart, true).await
+}
+
+/// Returns the part [`read_image_field`] would, checking its declared
+/// content type only when it should be an `image`.
+async fn read_file_field(state: &AppState, mut multipart: Multipart, image: bool) -> Result<Upload, ApiError> {
+    let mut received = Vec::new();
+    let mut file_parts = 0;
+    let mut lone_file = None;
+    while let Some(field) = multipart.next_field().await.map_err(|err| upload_error(state, err))? {
+        let name = field.name().unwrap_or_default().to_string();
+        if state.config.upload_field_names.contains(&name) {
+            return if image { read_upload(state, field).await } else { buffer_part(state, field).await };
+        }
+
+        // Only the first file part is kept; a second one makes the choice
+        // ambiguous, so later parts are skipped unread
+        if field.file_name().is_some() {
+            file_parts += 1;
+            if file_parts == 1 {
+                lone_file = Some(if image { read_upload(state, field).await } else { buffer_part(state, field).await });
+            }
+        }
+        received.push(name);
+    }
+
+    match lone_file {
+        Some(upload) if file_parts == 1 => upload,
+        _ => Err(ApiError::MissingField {
+            accepted: state.config.upload_field_names.clone(),
+            received,
+        }),
+    }
+}
+
+/// Returns the image part, chosen as by [`read_image_field`], and the text of
+/// the field named `text` if there is one, whichever order they are sent in.
+async fn read_image_and_text(
+    state: &AppState,
+    mut multipart: Multipart,
+    text: &str,
+) -> Result<(Upload, Option<String>), ApiError> {
+    let mut received = Vec::new();
+    let mut named = None;
+    let mut value = None;
+    let mut file_parts = 0;
+    let mut lone_file = None;
+    while let Some(field) = multipart.next_field().await.map_err(|err| upload_error(state, err))? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == text && value.is_none() {
+            value = Some(field.text().await.map_err(|err| upload_error(state, err))?);
+        } else if named.is_none() && state.config.upload_field_names.contains(&name) {
+            named = Some(read_upload(state, field).await?);
+        } else if field.file_name().is_some() {
+            file_parts += 1;
+            if file_parts == 1 {
+                lone_file = Some(read_upload(state, field).await);
+            }
+        }
+        received.push(name);
+        if named.is_some() && value.is_some() {
+            break;
+        }
+    }
+
+    let upload = match (named, lone_file) {
+        (Some(upload), _) => upload,
+        (None, Some(upload)) if file_parts == 1 => upload?,
+        _ => {
+            return Err(ApiError::MissingField {
+                accepted: state.config.upload_field_names.clone(),
+                received,
+            })
+        }
+    };
+    Ok((upload, value))
+}
+
+/// Returns the parts named `names`, in that order whatever order they were
+/// sent in. Other parts are skipped unread.
+async fn read_image_fields<const N: usize>(
+    state: &AppState,
+    mut multipart: Multipart,
+    names: [&str; N],
+) -> Result<[Upload; N], ApiError> {
+    let mut uploads: [Option<Upload>; N] = std::array::from_fn(|_| None);
+    let mut received = Vec::new();
+    while uploads.iter().any(Option::is_none)
+        && let Some(field) = multipart.next_field().await.map_err(|err| upload_error(state, err))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        if let Some(index) = names.iter().position(|wanted| *wanted == name)
+            && uploads[index].is_none()
+        {
+            uploads[index] = Some(read_upload(state, field).await?);
+        }
+        received.push(name);
+    }
+
+    if uploads.iter().any(Option::is_none) {
+        return Err(ApiError::MissingImages { required: names.map(str::to_string).to_vec(), received });
+    }
+    Ok(uploads.map(|upload| upload.expect("every part was read")))
+}
+
+/// Buffers one part. Its declared content type is checked first, so a large
+/// non-image upload is turned away without reading it.
+async fn read_upload(state: &AppState, field: Field<'_>) -> Result<Upload, ApiError> {
+    if let Some(content_type) = field.content_type()
+        && !is_image_content_type(content_type)
+    {
+        return Err(ApiError::UnsupportedContentType(content_type.to_string()));
+    }
+    buffer_part(state, field).await
+}
+
+/// Buffers one part whatever its declared content type.
+async fn buffer_part(state: &AppState, field: Field<'_>) -> Result<Upload, ApiError> {
+    let content_type = field.content_type().map(str::to_string);
+    let data = field.bytes().await.map_err(|err| upload_error(state, err))?;
+    state.stats.count_upload(data.len());
+    let span = tracing::Span::current();
+    span.record("upload_bytes", data.len());
+    if let Ok(format) = image::guess_format(&data) {
+        span.record("detected_format", format_label(format).as_str());
+    }
+    Ok(Upload { data, content_type })
+}
+
+fn upload_error(state: &AppState, err: MultipartError) -> ApiError {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        ApiError::TooLarge(state.config.max_upload_bytes)
+    } else {
+        ApiError::BodyReadError(format!("failed to read upload: {}", err.body_text()))
+    }
+}
+
+/// Whether a part's declared content type may hold an image. Many clients
+/// label every file `application/octet-stream`, so that is allowed through and
+/// left to the decoder.
+fn is_image_content_type(content_type: &str) -> bool {
+    let essence = mime_essence(content_type);
+    essence.starts_with("image/") || essence == "application/octet-stream"
+}
+
+/// Describes how a declared `image/*` content type contradicts the format
+/// sniffed from the data. Generic or missing types never conflict.
+fn content_type_mismatch(declared: Option<&str>, detected: Option<ImageFormat>) -> Option<String> {
+    let (declared, detected) = (declared?, detected?);
+    let essence = mime_essence(declared);
+    if !essence.starts_with("image/") || ImageFormat::from_mime_type(&essence) == Some(detected) {
+        return None;
+    }
+    Some(format!("declared {essence} but detected {}", format_label(detected)))
+}
+
+/// The lowercase `type/subtype` of a content type, without parameters.
+fn mime_essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// Lowercase name of a format as reported to clients, e.g. `png` or `jpeg`.
+fn format_label(format: ImageFormat) -> String {
+    format!("{format:?}").to_lowercase()
+}
+
+/// Throttles each client network (see [`client_network`]) to
+/// `RATE_LIMIT_PER_MINUTE` requests, with bursts of up to `RATE_LIMIT_BURST`,
+/// answering the excess with a 429.
+async fn rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+
+    let client = client_network(client_ip(&request, state.config.trust_proxy));
+    match limiter.check(client, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            tracing::warn!(%client, "rate limit: rejecting request");
+            ApiError::RateLimited(retry_after).into_response()
+        }
+    }
+}
+
+/// The connecting peer's address or, with `trust_proxy`, the right-most
+/// `Forwarded` `for=` or `X-Forwarded-For` entry, i.e. the address our proxy
+/// saw. Requests with neither (in-process tests) share the unspecified address.
+fn client_ip(request: &Request, trust_proxy: bool) -> IpAddr {
+    let header = |name: &str| request.headers().get(name)?.to_str().ok();
+    let forwarded = trust_proxy
+        .then(|| {
+            header("forwarded")
+                .and_then(forwarded_for)
+                .or_else(|| header("x-forwarded-for")?.rsplit(',').next()?.trim().parse().ok())
+        })
+        .flatten();
+
+    forwarded
+        .or_else(|| {
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        })
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+/// The address a client is counted by: IPv4 addresses as they are, IPv6 ones
+/// by their /64 prefix, since a single host usually has a whole /64 to pick
+/// fresh addresses from. IPv4-mapped IPv6 addresses count as IPv4.
+fn client_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+/// The `for=` address of the last element of an RFC 7239 `Forwarded` header,
+/// e.g. `for=192.0.2.60;proto=https` or `for="[2001:db8::1]:4711"`. Obfuscated
+/// and `unknown` identifiers give `None`.
+fn forwarded_for(forwarded: &str) -> Option<IpAddr> {
+    let node = forwarded
+        .rsplit(',')
+        .next()?
+        .split(';')
+        .find_map(|pair| pair.trim().split_once('=').filter(|(name, _)| name.eq_ignore_ascii_case("for")))?
+        .1
+        .trim_matches('"');
+    match node.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']')?.0.parse().ok(),
+        None => node.parse().ok().or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip())),
+    }
+}
+
+/// The JWT verifier the configuration asks for, if any.
+fn jwt_verifier(config: &Config) -> Option<JwtVerifier> {
+    let verifier = match (&config.jwt_hs256_secret, &config.jwt_jwks_url) {
+        (Some(secret), _) => JwtVerifier::hs256(secret.as_bytes()),
+        (None, Some(url)) => JwtVerifier::jwks(url.parse().expect("Config::validate checks JWT_JWKS_URL")),
+        (None, None) => return None,
+    };
+    Some(match &config.jwt_required_scope {
+        Some(scope) => verifier.required_scope(scope),
+        None => verifier,
+    })
+}
+
+/// Requires a credential when authentication is enabled: a key from
+/// `API_KEYS`, sent as `Authorization: Bearer <key>` or `X-API-Key: <key>`,
+/// or else a bearer JWT the configured verifier accepts. A token's `sub` is
+/// recorded on the request span.
+async fn authenticate(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if state.api_keys.is_none() && state.jwt.is_none() {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers();
+    if let (Some(api_keys), Some(key)) = (&state.api_keys, presented_api_key(headers))
+        && api_keys.verify(key)
+    {
+        return next.run(request).await;
+    }
+    let rejection = match (&state.jwt, bearer_token(headers)) {
+        (Some(jwt), Some(token)) => match jwt.verify(token).await {
+            Ok(claims) => {
+                if let Some(sub) = &claims.sub {
+                    tracing::Span::current().record("sub", sub.as_str());
+                }
+                return next.run(request).await;
+            }
+            Err(JwtError::Invalid(reason)) if state.api_keys.is_some() => {
+                ApiError::Unauthorized(format!("not a valid API key, and {reason}"))
+            }
+            Err(JwtError::Invalid(reason)) => ApiError::Unauthorized(reason),
+            Err(err @ JwtError::MissingScope(_)) => ApiError::InsufficientScope(err.to_string()),
+            Err(JwtError::KeysUnavailable(reason)) => {
+                tracing::error!(%reason, "JWT keys unavailable");
+                ApiError::AuthUnavailable("bearer tokens cannot be verified right now".to_string())
+            }
+        },
+        _ if presented_api_key(headers).is_some() => ApiError::Unauthorized("invalid API key".to_string()),
+        (None, _) => ApiError::Unauthorized(
+            "missing API key; send 'Authorization: Bearer <key>' or 'X-API-Key: <key>'".to_string(),
+        ),
+        (Some(_), _) if state.api_keys.is_some() => ApiError::Unauthorized(
+            "missing credentials; send 'Authorization: Bearer <key or token>' or 'X-API-Key: <key>'".to_string(),
+        ),
+        (Some(_), _) => ApiError::Unauthorized("missing bearer token; send 'Authorization: Bearer <token>'".to_string()),
+    };
+    rejection.into_response()
+}
+
+/// With `UPLOAD_HMAC_SECRET` set, requires `X-Signature` to be the hex
+/// HMAC-SHA256 of the whole raw body, before any of it is parsed. The body is
+/// buffered, up to the upload limit, and handed on unchanged.
+async fn verify_signature(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    // Only uploads are signed; GET requests carry no body to sign
+    let Some(secret) = state.config.upload_hmac_secret.as_ref().filter(|_| request.method() != Method::GET) else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let Some(signature) = parts.headers.get("x-signature").and_then(|value| value.to_str().ok()) else {
+        return ApiError::InvalidSignature(
+            "missing X-Signature; send the hex HMAC-SHA256 of the request body".to_string(),
+        )
+        .into_response();
+    };
+    let body = match Limited::new(body, state.config.max_upload_bytes).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(err) if err.is::<LengthLimitError>() => return ApiError::TooLarge(state.config.max_upload_bytes).into_response(),
+        Err(err) => return ApiError::BodyReadError(format!("failed to read upload: {err}")).into_response(),
+    };
+    if !signature_matches(secret.as_bytes(), &body, signature) {
+        return ApiError::InvalidSignature("X-Signature does not match the request body".to_string()).into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+fn presented_api_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key") {
+        return key.to_str().ok();
+    }
+    bearer_token(headers)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    authorization.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// Admits at most `max_in_flight_per_client` analysis requests per client at a
+/// time, answering the rest with a 429. Streamed responses keep the slot
+/// until their body is done, since they go on reading the upload meanwhile.
+async fn limit_client_in_flight(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(slots) = &state.client_slots else {
+        return next.run(request).await;
+    };
+
+    let client = client_id(&state, &request);
+    let slot = match slots.try_acquire(client.clone()) {
+        Ok(slot) => slot,
+        Err(in_flight) => {
+            tracing::warn!(%client, in_flight, "client cap: rejecting request");
+            return ApiError::ClientBusy(in_flight).into_response();
+        }
+    };
+
+    hold_until_body_done(next.run(request).await, slot)
+}
+
+/// Keeps `guard` until the response body has been sent in full. Bodies of
+/// known length are already complete and release it straight away.
+fn hold_until_body_done<G: Send + 'static>(response: Response, guard: G) -> Response {
+    if response.body().size_hint().exact().is_some() {
+        return response;
+    }
+    response.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _held = &guard;
+            chunk
+        }))
+    })
+}
+
+/// Who a request counts against: `key:` and the fingerprint of its API key
+/// when authentication is on, otherwise `ip:` and the client's
+/// [`client_network`].
+fn client_id(state: &AppState, request: &Request) -> String {
+    match presented_api_key(request.headers()).filter(|_| state.api_keys.is_some()) {
+        Some(key) => format!("key:{}", fingerprint(key)),
+        None => format!("ip:{}", client_network(client_ip(request, state.config.trust_proxy))),
+    }
+}
+
+/// Admits at most `max_in_flight_requests` analysis requests at a time and
+/// answers the rest immediately with a 503 instead of letting them queue.
+/// Streamed responses count until their body is done, like the per-client cap.
+async fn load_shed(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Ok(admitted) = state.in_flight_requests.clone().try_acquire_owned() else {
+        let rejected = state.rejected_requests.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!(requests_in_flight = state.requests_in_flight(), rejected, "load shed: rejecting request");
+        return ApiError::ServerBusy.into_response();
+    };
+
+    hold_until_body_done(next.run(request).await, admitted)
+}
+
+/// Fails analysis requests that take longer than the configured timeout with a
+/// 408. The deadline covers reading the upload as well as the analysis.
+/// Dropping the handler future also drops its `spawn_blocking` handle: work not
+/// yet started is skipped, while a decode already running is left to finish in
+/// the background with its result discarded, and its decode permit is only
+/// released once it actually completes.
+async fn request_timeout(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let timeout = state.config.request_timeout;
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => ApiError::Timeout(timeout).into_response(),
+    }
+}
+
+/// Runs CPU-heavy image work on the blocking thread pool so large decodes can't
+/// stall the async workers. The decode permit is held until the work finishes,
+/// even if the request itself is dropped in the meantime. Work still queued
+/// for a blocking thread when its request times out is skipped altogether.
+/// The work runs in a `compute` span inside the request's, and a panic in it
+/// becomes [`ApiError::Internal`].
+pub async fn run_blocking<T: Send + 'static>(
+    permit: OwnedSemaphorePermit,
+    work: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, ApiError> {
+    let abandoned = AbandonOnDrop::default();
+    let flag = abandoned.0.clone();
+    let span = tracing::info_span!("compute");
+    let result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        (!flag.load(Ordering::Acquire)).then(|| span.in_scope(work))
+    })
+    .await;
+    result
+        // Only `None` once this future has been dropped, so nobody sees it
+        .map(|output| output.expect("work runs unless its request was dropped"))
+        .map_err(|err| match err.try_into_panic() {
+            Ok(payload) => internal_error(payload),
+            Err(err) => {
+                let correlation_id = correlation_id();
+                tracing::error!(%correlation_id, %err, "blocking task failed");
+                ApiError::Internal { correlation_id }
+            }
+        })
+}
+
+/// Raises its flag when dropped, i.e. when the future waiting on some blocking
+/// work goes away before the work has started.
+#[derive(Default)]
+struct AbandonOnDrop(Arc<AtomicBool>);
+
+impl Drop for AbandonOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// The layer that turns a panic anywhere in request handling into the JSON
+/// 500 of [`ApiError::Internal`], so a decoder bug on one malformed upload
+/// doesn't surface as a dropped connection.
+pub fn catch_panic_layer() -> CatchPanicLayer<fn(Box<dyn Any + Send + 'static>) -> Response> {
+    CatchPanicLayer::custom(|payload| internal_error(payload).into_response())
+}
+
+/// Logs a panic under a fresh correlation id, returned in the error so the
+/// client can quote it.
+fn internal_error(payload: Box<dyn Any + Send + 'static>) -> ApiError {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "non-string panic payload".to_string());
+    let correlation_id = correlation_id();
+    tracing::error!(%correlation_id, %message, "panic while handling request");
+    ApiError::Internal { correlation_id }
+}
+
+/// A random 16-digit hex id. `RandomState` is seeded randomly per process and
+/// varies its keys on every construction, which is unique enough for matching
+/// log lines.
+fn correlation_id() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
+
+/// Optional cargo features this binary was compiled with.
+const ENABLED_FEATURES: &[(&str, bool)] = &[
+    ("simd", cfg!(feature = "simd")),
+    ("exr", cfg!(feature = "exr")),
+    ("hdr", cfg!(feature = "hdr")),
+    ("webp", cfg!(feature = "webp")),
+    ("avif", cfg!(feature = "avif")),
+    ("modern-formats", cfg!(feature = "modern-formats")),
+    ("otel", cfg!(feature = "otel")),
+];
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "Health",
+    responses(
+        (status = 200, description = "Version, commit and features of the running build", body = VersionResponse)
+    )
+)]
+async fn version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("WEBCALCULATION_GIT_COMMIT").map(str::to_string),
+        build_timestamp: env!("WEBCALCULATION_BUILD_TIMESTAMP").to_string(),
+        features: ENABLED_FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "Health",
+    responses(
+        (status = 200, description = "Uptime and request counters since startup", body = StatsResponse)
+    )
+)]
+async fn stats(State(state): State<AppState>) -> Json<StatsResponse> {
+    let requests_by_route = state.stats.requests_by_route.lock().unwrap().clone();
+    Json(StatsResponse {
+        uptime_seconds: state.stats.started.elapsed().as_secs(),
+        requests_total: requests_by_route.values().sum(),
+        requests_by_route,
+        image_bytes_processed: state.stats.image_bytes.load(Ordering::Relaxed),
+        decode_failures: state.stats.decode_failures.load(Ordering::Relaxed),
+        analyses_in_flight: state.requests_in_flight(),
+        in_flight_by_client: state.client_slots.as_ref().map(|slots| slots.in_flight().into_iter().collect()).unwrap_or_default(),
+        cache_entries: state.result_cache.as_ref().map(|cache| cache.lock().unwrap().len()),
+    })
+}
+
+/// Counts every request under its route, and the undecodable uploads among
+/// the responses. Unmatched paths are not counted.
+async fn record_stats(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if let Some(route) = request.extensions().get::<MatchedPath>() {
+        state.stats.count_request(route.as_str());
+    }
+    let response = next.run(request).await;
+    if response.extensions().get::<ErrorCode>().is_some_and(|code| code.0 == "decode_error") {
+        state.stats.count_decode_failure();
+    }
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/supported-formats",
+    tag = "Image Processing",
+    responses(
+        (status = 200, description = "Every format the image library knows, and whether this build decodes it",
+            body = Vec<SupportedFormat>)
+    )
+)]
+async fn supported_formats() -> Json<Vec<SupportedFormat>> {
+    let formats = ImageFormat::all()
+        .map(|format| SupportedFormat {
+            format: format_label(format),
+            extensions: format.extensions_str().iter().map(|ext| ext.to_string()).collect(),
+            // `to_mime_type` falls back to octet-stream for formats without one
+            mime_types: Some(format.to_mime_type())
+                .filter(|mime| *mime != "application/octet-stream")
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            enabled: can_decode(format),
+            feature: decoder_feature(format).map(str::to_string),
+        })
+        .collect();
+    Json(formats)
+}
+
+#[utoipa::path(
+    get,
+    path = "/live",
+    tag = "Health",
+    responses(
+        (status = 200, description = "The process is up and serving HTTP, also while draining", body = ProbeResponse)
+    )
+)]
+async fn liveness() -> Json<ProbeResponse> {
+    Json(ProbeResponse { status: "live".to_string(), reason: None })
+}
+
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "Health",
+    responses(
+        (status = 200, description = "The service accepts new work", body = ProbeResponse),
+        (status = 503, description = "Not ready - draining for shutdown, or every analysis slot is taken", body = ProbeResponse)
+    )
+)]
+async fn readiness(State(state): State<AppState>) -> Response {
+    match state.not_ready_reason() {
+        None => Json(ProbeResponse { status: "ready".to_string(), reason: None }).into_response(),
+        Some(reason) => {
+            let body = ProbeResponse { status: "not_ready".to_string(), reason: Some(reason) };
+            (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response()
+        }
+    }
+}
+
+/// Readiness in the plain-text form `/health` has always answered with.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "Health",
+    responses(
+        (status = 200, description = "The service accepts new work; same check as /ready", body = String),
+        (status = 503, description = "Not ready; the body is the reason, e.g. `draining`", body = String)
+    )
+)]
+async fn health_check(State(state): State<AppState>) -> Response {
+    match state.not_ready_reason() {
+        None => "OK".into_response(),
+        Some(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason).into_response(),
+    }
+}
+
+/// Swagger UI's assets, embedded at build time from `assets/swagger-ui/`.
+/// Empty when they were not vendored, see `build.rs`.
+const SWAGGER_UI_ASSETS: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/swagger_ui_assets.rs"));
+
+/// Whether this build embeds Swagger UI. Without it, `/swagger-ui` lists the
+/// endpoints as plain HTML instead.
+pub const SWAGGER_UI_EMBEDDED: bool = !SWAGGER_UI_ASSETS.is_empty();
+
+async fn serve_swagger(State(state): State<AppState>) -> Html<String> {
+    let base_path = &state.config.base_path;
+    let spec_url = format!("{base_path}/api-docs/openapi.json");
+    if !SWAGGER_UI_EMBEDDED {
+        return Html(endpoint_list(&api_doc(&state.config), &spec_url));
+    }
+    Html(SWAGGER_HTML.replace("{spec_url}", &spec_url).replace("{assets}", &format!("{base_path}/swagger-ui")))
+}
+
+/// One embedded Swagger UI file. They change only with the binary, so
+/// browsers may keep them for a day.
+async fn serve_swagger_asset(Path(name): Path<String>) -> Response {
+    let Some((_, data)) = SWAGGER_UI_ASSETS.iter().find(|(asset, _)| *asset == name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let content_type = if name.ends_with(".css") { "text/css; charset=utf-8" } else { "text/javascript; charset=utf-8" };
+    (
+        [(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, "public, max-age=86400")],
+        *data,
+    )
+        .into_response()
+}
+
+/// A script-free page listing the documented operations, for builds without
+/// Swagger UI.
+fn endpoint_list(doc: &utoipa::openapi::OpenApi, spec_url: &str) -> String {
+    let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let mut rows = String::new();
+    for (path, item) in &doc.paths.paths {
+        for (method, operation) in &item.operations {
+            let method = serde_json::to_value(method).ok().and_then(|method| method.as_str().map(str::to_uppercase));
+            let method = method.unwrap_or_default();
+            let summary = operation.summary.as_deref().or(operation.description.as_deref()).unwrap_or_default();
+            let summary = summary.lines().next().unwrap_or_default();
+            rows.push_str(&format!(
+                "<tr><td><code>{method}</code></td><td><code>{}</code></td><td>{}</td></tr>\n",
+                escape(path),
+                escape(summary)
+            ));
+        }
+    }
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>API Documentation</title><meta charset=\"UTF-8\"></head>\n<body>\n\
+         <h1>{}</h1>\n<p>This build does not embed Swagger UI. The OpenAPI document is at \
+         <a href='{spec_url}'>{spec_url}</a>.</p>\n<table>\n{rows}</table>\n</body>\n</html>\n",
+        escape(&doc.info.title)
+    )
+}
+
+/// The Swagger UI page; `{spec_url}` is replaced with the OpenAPI document's
+/// URL and `{assets}` with the path the embedded assets are served under.
+const SWAGGER_HTML: &str = r#"
+<!DOCTYPE html>
+<html>
+<head>
+    <title>API Documentation</title>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <link rel="stylesheet" type="text/css" href="{assets}/swagger-ui.css" />
+    <style>
+        html { box-sizing: border-box; overflow: -moz-scrollbars-vertical; overflow-y: scroll; }
+        *, *:before, *:after { box-sizing: inherit; }
+        body { margin:0; background: #fafafa; }
+    </style>
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="{assets}/swagger-ui-bundle.js"></script>
+    <script src="{assets}/swagger-ui-standalone-preset.js"></script>
+    <script>
+        window.onload = function() {
+            const ui = SwaggerUIBundle({
+                url: '{spec_url}',
+                dom_id: '#swagger-ui',
+                deepLinking: true,
+                presets: [
+                    SwaggerUIBundle.presets.apis,
+                    SwaggerUIStandalonePreset
+                ],
+                plugins: [
+                    SwaggerUIBundle.plugins.DownloadUrl
+                ],
+                layout: "StandaloneLayout"
+            });
+        };
+    </script>
+</body>
+</html>
+"#;
+
+/// This OpenAPI document, as JSON
+#[utoipa::path(
+    get,
+    path = "/api-docs/openapi.json",
+    tag = "Documentation",
+    responses((status = 200, description = "The OpenAPI 3 document describing this deployment", body = Object))
+)]
+async fn serve_openapi(State(state): State<AppState>) -> Json<utoipa::openapi::OpenApi> {
+    Json(api_doc(&state.config))
+}
+
+/// The same document as [`serve_openapi`], as YAML.
+#[utoipa::path(
+    get,
+    path = "/api-docs/openapi.yaml",
+    tag = "Documentation",
+    responses((status = 200, description = "The OpenAPI 3 document describing this deployment",
+        content_type = "text/yaml", body = String))
+)]
+async fn serve_openapi_yaml(State(state): State<AppState>) -> Response {
+    let yaml = serde_yaml::to_string(&api_doc(&state.config)).expect("the OpenAPI document serializes");
+    ([(header::CONTENT_TYPE, "text/yaml; charset=utf-8")], yaml).into_response()
+}
+
+/// Documented paths that answer without an API key.
+const UNAUTHENTICATED_PATHS: &[&str] = &["/live", "/ready", "/health", "/api-docs/openapi.json", "/api-docs/openapi.yaml"];
+
+/// The OpenAPI document with deployment-specific details filled in.
+fn api_doc(config: &Config) -> utoipa::openapi::OpenApi {
+    use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+    use utoipa::openapi::path::{ParameterBuilder, ParameterIn};
+    use utoipa::openapi::{Content, Deprecated, ObjectBuilder, Ref, RefOr, Required, ResponseBuilder, SchemaType};
+
+    let mut doc = ApiDoc::openapi();
+
+    // Handlers are declared at their unversioned paths; each is served under
+    // /v1, and at the legacy path as a deprecated alias
+    for path in versioned_paths() {
+        let Some(mut legacy) = doc.paths.paths.remove(path) else { continue };
+        doc.paths.paths.insert(format!("{API_V1}{path}"), legacy.clone());
+        for operation in legacy.operations.values_mut() {
+            operation.deprecated = Some(Deprecated::True);
+            // Operation ids must stay unique across the document
+            operation.operation_id = operation.operation_id.take().map(|id| format!("{id}_legacy"));
+            let note = format!(
+                "Deprecated alias of `{API_V1}{path}`, answered with `Deprecation` and `Sunset: {LEGACY_SUNSET}` headers."
+            );
+            operation.description = Some(match operation.description.take() {
+                Some(description) => format!("{note}\n\n{description}"),
+                None => note,
+            });
+        }
+        doc.paths.paths.insert(path.to_string(), legacy);
+    }
+
+    for path in doc.paths.paths.values_mut() {
+        for operation in path.operations.values_mut() {
+            let Some(body) = operation.request_body.as_mut() else { continue };
+            if body.content.contains_key("multipart/form-data") {
+                let limit = format!("Uploads larger than {} bytes are rejected with 413.", config.max_upload_bytes);
+                body.description = Some(match body.description.take() {
+                    Some(description) => format!("{description} {limit}"),
+                    None => limit,
+                });
+            }
+        }
+    }
+    if !config.base_path.is_empty() {
+        doc.servers = Some(vec![utoipa::openapi::Server::new(&config.base_path)]);
+    }
+
+    // Declared only when authentication is configured, so Swagger UI offers
+    // its "Authorize" dialog exactly when requests need one
+    let keys = !config.api_keys.is_empty();
+    if keys || config.jwt_enabled() {
+        let components = doc.components.get_or_insert_with(Default::default);
+        let mut bearer = HttpBuilder::new().scheme(HttpAuthScheme::Bearer);
+        let mut schemes = Vec::new();
+        if keys {
+            components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))));
+            schemes.push("api_key");
+        }
+        if config.jwt_enabled() {
+            bearer = bearer.bearer_format("JWT");
+        }
+        components.add_security_scheme("bearer", SecurityScheme::Http(bearer.build()));
+        schemes.push("bearer");
+
+        let error_response = |description: &str| {
+            ResponseBuilder::new()
+                .description(description)
+                .content("application/json", Content::new(Ref::from_schema_name("ErrorResponse")))
+                .build()
+        };
+        let unauthorized = error_response(if config.jwt_enabled() {
+            "Unauthorized - missing or invalid credentials"
+        } else {
+            "Unauthorized - missing or invalid API key"
+        });
+        let forbidden = config
+            .jwt_required_scope
+            .as_ref()
+            .map(|scope| error_response(&format!("Forbidden - the bearer token lacks the {scope} scope")));
+        for (path, item) in doc.paths.paths.iter_mut() {
+            if UNAUTHENTICATED_PATHS.contains(&path.as_str()) {
+                continue;
+            }
+            for operation in item.operations.values_mut() {
+                // Any one scheme on its own is enough
+                operation.security = Some(
+                    schemes.iter().map(|scheme| SecurityRequirement::new(*scheme, Vec::<String>::new())).collect(),
+                );
+                operation.responses.responses.insert("401".to_string(), unauthorized.clone().into());
+                if let Some(forbidden) = &forbidden {
+                    operation.responses.responses.insert("403".to_string(), forbidden.clone().into());
+                }
+            }
+        }
+    }
+
+    if config.upload_hmac_secret.is_some() {
+        let signature = ParameterBuilder::new()
+            .name("X-Signature")
+            .parameter_in(ParameterIn::Header)
+            .required(Required::True)
+            .description(Some("Hex HMAC-SHA256 of the raw request body under UPLOAD_HMAC_SECRET"))
+            .schema(Some(ObjectBuilder::new().schema_type(SchemaType::String)))
+            .build();
+        let description = if config.api_keys.is_empty() && !config.jwt_enabled() {
+            "Unauthorized - missing or invalid X-Signature"
+        } else {
+            "Unauthorized - missing or invalid credentials or X-Signature"
+        };
+        let unauthorized = ResponseBuilder::new()
+            .description(description)
+            .content("application/json", Content::new(Ref::from_schema_name("ErrorResponse")))
+            .build();
+        for operation in doc.paths.paths.values_mut().flat_map(|item| item.operations.values_mut()) {
+            if operation.request_body.as_ref().is_some_and(|body| body.content.contains_key("multipart/form-data")) {
+                operation.parameters.get_or_insert_with(Vec::new).push(signature.clone());
+                operation.responses.responses.insert("401".to_string(), unauthorized.clone().into());
+            }
+        }
+    }
+
+    // Last, so the error responses added above get examples too
+    for operation in doc.paths.paths.values_mut().flat_map(|item| item.operations.values_mut()) {
+        let uploads = operation.request_body.as_ref().is_some_and(|body| body.content.contains_key("multipart/form-data"));
+        for (status, response) in operation.responses.responses.iter_mut() {
+            let RefOr::T(response) = response else { continue };
+            let Some(content) = response.content.get_mut("application/json") else { continue };
+            let is_error = matches!(&content.schema, RefOr::Ref(schema) if schema.ref_location.ends_with("/ErrorResponse"));
+            if let Some(error) = example_error(status, config, uploads).filter(|_| is_error) {
+                let body = ErrorResponse { request_id: Some(EXAMPLE_REQUEST_ID.to_string()), ..error.body() };
+                content.example = Some(serde_json::to_value(body).expect("error bodies serialize"));
+            }
+        }
+    }
+
+    doc
+}
+
+/// `request_id` of the example error bodies.
+const EXAMPLE_REQUEST_ID: &str = "6f1c2a7e-3f4b-4c1d-9a51-0c8e2d7b9e10";
+
+/// A typical error answered with `status`, as the example body of that
+/// response. On upload endpoints the usual 400 is a missing image part.
+fn example_error(status: &str, config: &Config, uploads: bool) -> Option<ApiError> {
+    Some(match status {
+        "400" if uploads => ApiError::MissingField {
+            accepted: config.upload_field_names.clone(),
+            received: vec!["photo".to_string()],
+        },
+        "400" => ApiError::InvalidParameter("url is required".to_string()),
+        "401" => ApiError::Unauthorized("invalid API key".to_string()),
+        "403" => ApiError::InsufficientScope(
+            JwtError::MissingScope(config.jwt_required_scope.clone().unwrap_or_default()).to_string(),
+        ),
+        "408" => ApiError::Timeout(config.request_timeout),
+        "413" => ApiError::TooLarge(config.max_upload_bytes),
+        "415" => ApiError::UnsupportedContentType("text/plain".to_string()),
+        "422" => ApiError::DecodeError(
+            "unsupported or corrupt image: The image format could not be determined; see /supported-formats".to_string(),
+        ),
+        "502" => ApiError::FetchFailed("the image URL answered 404 Not Found".to_string()),
+        "503" => ApiError::ServerBusy,
+        _ => return None,
+    })
+}
+
+/// Sockets for [`serve_until`] to accept connections on; at least one should
+/// be set.
+#[derive(Default)]
+pub struct Listeners {
+    /// TCP listener, serving HTTPS when `tls` is set
+    pub tcp: Option<TcpListener>,
+    /// Certificate and key for the TCP listener
+    pub tls: Option<RustlsConfig>,
+    /// Unix domain socket, always plain HTTP
+    #[cfg(unix)]
+    pub unix: Option<UnixSocket>,
+}
+
+/// Serves [`app`] on every listener in `listeners` until `shutdown`
+/// resolves, then shuts down gracefully: new connections are refused,
+/// `/health` reports 503, and in-flight requests and decodes get up to
+/// [`Config::shutdown_timeout`] to finish before this returns regardless.
+pub async fn serve_until(
+    listeners: Listeners,
+    state: AppState,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let timeout = state.config.shutdown_timeout;
+    let (drain_started, drain_start) = oneshot::channel();
+    let (stop, stopped) = watch::channel(false);
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            shutdown.await;
+            state.begin_draining();
+            tracing::info!(
+                requests_in_flight = state.requests_in_flight(),
+                timeout_secs = timeout.as_secs(),
+                "shutdown: draining"
+            );
+            let _ = stop.send(true);
+            let _ = drain_started.send(());
+        }
+    });
+    // One stop signal per listener, all firing together
+    let signal = || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.wait_for(|&stopped| stopped).await;
+        }
+    };
+
+    let router = app(state.clone());
+    let tcp = async {
+        let Some(listener) = listeners.tcp else {
+            return Ok(());
+        };
+        let service = router.clone().into_make_service_with_connect_info::<SocketAddr>();
+        match listeners.tls {
+            None => axum::serve(listener, service).with_graceful_shutdown(signal()).await,
+            Some(tls) => {
+                let handle = axum_server::Handle::new();
+                tokio::spawn({
+                    let handle = handle.clone();
+                    let signal = signal();
+                    async move {
+                        signal.await;
+                        handle.graceful_shutdown(None);
+                    }
+                });
+                axum_server::from_tcp_rustls(listener.into_std()?, tls).handle(handle).serve(service).await
+            }
+        }
+    };
+    #[cfg(unix)]
+    let unix = async {
+        if let Some(socket) = &listeners.unix {
+            socket.serve(router.clone(), signal()).await;
+        }
+        Ok(())
+    };
+    #[cfg(not(unix))]
+    let unix = async { Ok(()) };
+
+    let drain = async {
+        tokio::try_join!(tcp, unix)?;
+        if state.decodes_in_flight() > 0 {
+            tracing::info!(decodes_in_flight = state.decodes_in_flight(), "shutdown: connections closed, waiting for decodes");
+        }
+        state.decodes_finished().await;
+        Ok(())
+    };
+    let deadline = async {
+        match drain_start.await {
+            Ok(()) => tokio::time::sleep(timeout).await,
+            // The server failed before shutdown was requested
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = drain => {
+            if result.is_ok() {
+                tracing::info!("shutdown: complete");
+            }
+            result
+        }
+        () = deadline => {
+            tracing::warn!(
+                timeout_secs = timeout.as_secs(),
+                requests_in_flight = state.requests_in_flight(),
+                decodes_in_flight = state.decodes_in_flight(),
+                "shutdown: timed out with work still running"
+            );
+            Ok(())
+        }
+    }
+}
+
+type Routes = Vec<(&'static str, MethodRouter<AppState>)>;
+
+/// The image analysis endpoints, behind every admission-control layer.
+fn analysis_routes() -> Routes {
+    vec![
+        ("/calculate-intensity", post(calculate_intensity).get(calculate_intensity_from_url)),
+        ("/calculate-intensity/stream", post(calculate_intensity_stream)),
+        ("/calculate-intensity/sse", post(calculate_intensity_sse)),
+        ("/calculate-intensity/batch/summary", post(calculate_intensity_batch_summary)),
+        ("/calculate-intensity/zip", post(calculate_intensity_zip)),
+        ("/unique-colors", post(unique_colors)),
+        ("/threshold", post(threshold)),
+        ("/coverage", post(coverage)),
+        ("/segment-stats", post(segment_stats)),
+        ("/hsv-stats", post(hsv_stats)),
+        ("/color-temperature", post(color_temperature)),
+        ("/channel-correlation", post(channel_correlation_matrix)),
+        ("/histogram/rgb", post(rgb_histogram)),
+        ("/heatmap", post(heatmap)),
+        ("/grayscale", post(grayscale)),
+        ("/normalize", post(normalize)),
+        ("/equalize", post(equalize)),
+        ("/diff-image", post(diff_image)),
+        ("/metadata", post(metadata)),
+        ("/check-duplicate", post(check_duplicate)),
+    ]
+}
+
+/// Service information, authenticated like the analysis endpoints.
+fn info_routes() -> Routes {
+    vec![
+        ("/supported-formats", get(supported_formats)),
+        ("/version", get(version)),
+        ("/stats", get(stats)),
+    ]
+}
+
+/// Swagger UI and the OpenAPI document.
+fn docs_routes() -> Routes {
+    vec![
+        ("/swagger-ui", get(serve_swagger)),
+        ("/swagger-ui/", get(serve_swagger)),
+        ("/swagger-ui/:asset", get(serve_swagger_asset)),
+        ("/api-docs/openapi.json", get(serve_openapi)),
+        ("/api-docs/openapi.yaml", get(serve_openapi_yaml)),
+    ]
+}
+
+/// Orchestrator probes, never authenticated.
+fn probe_routes() -> Routes {
+    vec![("/live", get(liveness)), ("/ready", get(readiness)), ("/health", get(health_check))]
+}
+
+fn routed(routes: Routes) -> Router<AppState> {
+    routes.into_iter().fold(Router::new(), |router, (path, route)| router.route(path, route))
+}
+
+/// Prefix of the current API version. Probes and documentation are not
+/// versioned.
+pub const API_V1: &str = "/v1";
+
+/// `Deprecation` of the unprefixed legacy paths (RFC 9745): since 2026-10-14.
+const LEGACY_DEPRECATION: &str = "@1791936000";
+
+/// `Sunset` of the unprefixed legacy paths (RFC 8594), after which they may be
+/// removed.
+pub const LEGACY_SUNSET: &str = "Wed, 14 Apr 2027 00:00:00 GMT";
+
+/// Paths served under each API version, and as deprecated legacy aliases.
+fn versioned_paths() -> Vec<&'static str> {
+    analysis_routes().into_iter().chain(info_routes()).map(|(path, _)| path).collect()
+}
+
+/// Path pattern of every route [`app`] serves, in axum syntax and without
+/// the base path, for checking the OpenAPI document against the router.
+pub fn route_paths() -> Vec<String> {
+    let versioned = versioned_paths();
+    let unversioned = docs_routes().into_iter().chain(probe_routes()).map(|(path, _)| path);
+    versioned
+        .iter()
+        .map(|path| format!("{API_V1}{path}"))
+        .chain(versioned.iter().copied().chain(unversioned).map(str::to_string))
+        .collect()
+}
+
+/// One version of the authenticated API from its route lists, with the
+/// admission-control layers on the analysis routes and authentication on all.
+fn api_routes(state: &AppState, analysis: Routes, info: Routes) -> Router<AppState> {
+    let analysis_routes = routed(analysis)
+        .route_layer(middleware::from_fn_with_state(state.clone(), verify_signature))
+        .route_layer(middleware::from_fn_with_state(state.clone(), request_timeout))
+        .route_layer(middleware::from_fn_with_state(state.clone(), load_shed))
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit_client_in_flight))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit));
+
+    // Authentication runs first, then rate limiting, the per-client cap and
+    // load shedding, so rejected requests never take an in-flight slot
+    routed(info)
+        .merge(analysis_routes)
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+}
+
+/// Flags responses on the unprefixed legacy paths as deprecated, pointing at
+/// the `/v1` path that replaces each.
+async fn mark_legacy(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let successor = format!(
+        "<{}{API_V1}{}>; rel=\"successor-version\"",
+        state.config.base_path,
+        request.uri().path_and_query().map_or("", |path| path.as_str())
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(HeaderName::from_static("deprecation"), HeaderValue::from_static(LEGACY_DEPRECATION));
+    headers.insert(HeaderName::from_static("sunset"), HeaderValue::from_static(LEGACY_SUNSET));
+    if let Ok(link) = HeaderValue::try_from(successor) {
+        headers.insert(header::LINK, link);
+    }
+    response
+}
+
+/// Builds the service router with all routes and middleware.
+pub fn app(state: AppState) -> Router {
+    // Each version is nested from its own route lists over the shared
+    // analysis code, so a `/v2` with other response shapes adds its lists and
+    // nests them beside v1. The unprefixed legacy paths alias v1.
+    let v1 = || api_routes(&state, analysis_routes(), info_routes());
+    let legacy = v1().route_layer(middleware::from_fn_with_state(state.clone(), mark_legacy));
+
+    // The docs stay open, so Swagger UI can load the spec before the user has
+    // authorized, and so do the probes
+    let routes = routed(probe_routes())
+        .nest(API_V1, v1())
+        .merge(legacy)
+        .merge(routed(docs_routes()))
+        .route_layer(middleware::from_fn_with_state(state.clone(), record_stats));
+    let base_path = &state.config.base_path;
+    let routes = if state.config.nest_base_path && !base_path.is_empty() {
+        Router::new().nest(base_path, routes)
+    } else {
+        routes
+    };
+    with_middleware(routes, state)
+}
+
+/// Wraps `routes` in the middleware stack every [`app`] route runs under:
+/// panic recovery, body limit, compression, CORS, tracing and request ids.
+pub fn with_middleware(routes: Router<AppState>, state: AppState) -> Router {
+    routes
+        .layer(catch_panic_layer())
+        .layer(DefaultBodyLimit::max(state.config.max_upload_bytes))
+        // The default predicate leaves `image/*` responses (masks, heatmaps)
+        // alone; NDJSON is left alone too, as the encoder would hold lines back
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(NotForContentType::const_new("application/x-ndjson")),
+        ))
+        .layer(cors_layer(
+            &state.config.cors_allowed_origins,
+            &state.config.cors_allowed_methods,
+            state.config.cors_allow_credentials,
+        ))
+        .layer(middleware::from_fn(scope_request_id))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span).on_response(log_response))
+        // Outermost, so every response, errors included, carries the id the
+        // client sent, or one generated for it
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state)
+}
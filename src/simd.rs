@@ -0,0 +1,74 @@
+//! Vectorised byte summation with a portable fallback.
+//!
+//! The AVX2 path is compiled only with the `simd` cargo feature and is picked
+//! at runtime when the CPU supports it.
+
+/// Total channel sum (`r + g + b` per pixel, `3 * gray` for gray) of a buffer
+/// without padding or alpha, when a vectorised path is available. Returns
+/// `None` for other layouts or when the accelerated path is not available, in
+/// which case callers sum per pixel.
+pub fn contiguous_channel_total(samples: &[u8], channels: usize) -> Option<u64> {
+    if !accelerated() {
+        return None;
+    }
+    match channels {
+        1 => Some(3 * sum_bytes(samples)),
+        3 => Some(sum_bytes(samples)),
+        _ => None,
+    }
+}
+
+/// Whether [`sum_bytes`] uses a vector unit on this machine.
+pub fn accelerated() -> bool {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("avx2")
+    }
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
+
+/// Sum of every byte in `bytes`.
+pub fn sum_bytes(bytes: &[u8]) -> u64 {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if std::arch::is_x86_feature_detected!("avx2") {
+            // SAFETY: the CPU supports AVX2, checked just above.
+            return unsafe { sum_bytes_avx2(bytes) };
+        }
+    }
+    sum_bytes_scalar(bytes)
+}
+
+/// Portable reference implementation of [`sum_bytes`].
+pub fn sum_bytes_scalar(bytes: &[u8]) -> u64 {
+    bytes.iter().map(|&byte| u64::from(byte)).sum()
+}
+
+/// Sums 32 bytes per step with `vpsadbw`, which adds each group of eight
+/// unsigned bytes into a 64-bit lane, so the accumulator cannot overflow.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn sum_bytes_avx2(bytes: &[u8]) -> u64 {
+    use std::arch::x86_64::{
+        __m256i, _mm256_add_epi64, _mm256_loadu_si256, _mm256_sad_epu8, _mm256_setzero_si256, _mm256_storeu_si256,
+    };
+
+    let zero = _mm256_setzero_si256();
+    let mut lanes = _mm256_setzero_si256();
+    let chunks = bytes.chunks_exact(32);
+    let remainder = chunks.remainder();
+
+    for chunk in chunks {
+        // SAFETY: `chunk` is exactly 32 readable bytes; the load is unaligned.
+        let vector = unsafe { _mm256_loadu_si256(chunk.as_ptr().cast::<__m256i>()) };
+        lanes = _mm256_add_epi64(lanes, _mm256_sad_epu8(vector, zero));
+    }
+
+    let mut totals = [0u64; 4];
+    // SAFETY: `totals` is 32 writable bytes; the store is unaligned.
+    unsafe { _mm256_storeu_si256(totals.as_mut_ptr().cast::<__m256i>(), lanes) };
+    totals.iter().sum::<u64>() + sum_bytes_scalar(remainder)
+}
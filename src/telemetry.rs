@@ -0,0 +1,146 @@
+//! OpenTelemetry trace export, with the `otel` feature.
+//!
+//! When `OTEL_EXPORTER_OTLP_ENDPOINT` is set, every request span, and the
+//! `decode` and `compute` spans of its image work, are exported over
+//! OTLP/gRPC in batches. A request carrying a W3C `traceparent` header joins
+//! the caller's trace. The export runs in the background: spans for an
+//! unreachable collector are dropped rather than holding up requests.
+//! Without the feature, or with the endpoint unset, [`Telemetry`] does nothing.
+
+use axum::http::HeaderMap;
+use tracing::{Span, Subscriber};
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::TracerProvider,
+    Context,
+};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+#[cfg(feature = "otel")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Environment variable naming the collector, e.g. `http://localhost:4317`.
+pub const ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Instrumentation scope the spans are recorded under.
+#[cfg(feature = "otel")]
+const TRACER_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Handle to the span exporter, if one is running. [`shutdown`](Self::shutdown)
+/// flushes the spans still buffered.
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Exports to `OTEL_EXPORTER_OTLP_ENDPOINT` when it is set and this build
+    /// has the `otel` feature. Needs a Tokio runtime, which the gRPC client runs on.
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var(ENDPOINT_VAR) {
+            #[cfg(feature = "otel")]
+            Ok(endpoint) if !endpoint.trim().is_empty() => Self::exporting_to(endpoint.trim()),
+            _ => Ok(Telemetry::default()),
+        }
+    }
+
+    /// Exports spans in batches to the OTLP/gRPC collector at `endpoint`. The
+    /// connection is made lazily, so an unreachable collector is not an error.
+    #[cfg(feature = "otel")]
+    pub fn exporting_to(endpoint: &str) -> Result<Self, String> {
+        use opentelemetry_otlp::WithExportConfig;
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|err| format!("{ENDPOINT_VAR}: {err}"))?;
+        Ok(Telemetry::with_provider(SdkTracerProvider::builder().with_batch_exporter(exporter).with_resource(resource()).build()))
+    }
+
+    /// Exports through `provider`, e.g. one with an in-memory exporter.
+    #[cfg(feature = "otel")]
+    pub fn with_provider(provider: SdkTracerProvider) -> Self {
+        Telemetry { provider: Some(provider) }
+    }
+
+    /// Whether spans are being exported.
+    pub fn is_exporting(&self) -> bool {
+        #[cfg(feature = "otel")]
+        return self.provider.is_some();
+        #[cfg(not(feature = "otel"))]
+        false
+    }
+
+    /// The layer handing spans to the exporter, or one that does nothing.
+    pub(crate) fn layer<S>(&self) -> impl Layer<S> + Send + Sync + 'static
+    where
+        S: Subscriber + Send + Sync + for<'span> LookupSpan<'span>,
+    {
+        #[cfg(feature = "otel")]
+        return self
+            .provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(TRACER_NAME)));
+        #[cfg(not(feature = "otel"))]
+        tracing_subscriber::layer::Identity::new()
+    }
+
+    /// Exports the spans still buffered, waiting up to the exporter's timeout
+    /// for an unreachable collector.
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider
+            && let Err(err) = provider.shutdown()
+        {
+            tracing::warn!(%err, "flushing trace spans failed");
+        }
+    }
+}
+
+/// `service.name` defaults to the crate name unless `OTEL_SERVICE_NAME` (or
+/// `OTEL_RESOURCE_ATTRIBUTES`) names it.
+#[cfg(feature = "otel")]
+fn resource() -> Resource {
+    let named = std::env::var_os("OTEL_SERVICE_NAME").is_some()
+        || std::env::var("OTEL_RESOURCE_ATTRIBUTES").is_ok_and(|attributes| attributes.contains("service.name"));
+    let builder = Resource::builder();
+    if named { builder.build() } else { builder.with_service_name(TRACER_NAME).build() }
+}
+
+/// The remote trace context in `headers`' `traceparent` and `tracestate`,
+/// empty when they are absent or malformed.
+#[cfg(feature = "otel")]
+pub fn parent_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+/// Makes `span` part of the caller's trace, when the request names one.
+#[cfg(feature = "otel")]
+pub(crate) fn join_trace(span: &Span, headers: &HeaderMap) {
+    if headers.contains_key("traceparent") {
+        // Fails only for spans the filter disabled, which are not exported anyway
+        let _ = span.set_parent(parent_context(headers));
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn join_trace(_span: &Span, _headers: &HeaderMap) {}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otel")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
@@ -0,0 +1,95 @@
+//! Serving the router on a Unix domain socket, for a reverse proxy on the
+//! same host such as nginx with `proxy_pass http://unix:/path/to.sock`.
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::{
+    future::Future,
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::net::UnixListener;
+
+/// Permissions of the socket file: read and write for its owner and group,
+/// so a proxy running as another user in the same group can connect.
+const SOCKET_MODE: u32 = 0o660;
+
+/// A bound Unix domain socket. The socket file is removed when this is
+/// dropped, i.e. once the server has shut down.
+#[derive(Debug)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    /// Binds a socket at `path`, replacing a stale one left behind by a
+    /// previous run that did not shut down cleanly. A socket another process
+    /// still accepts connections on, or a path that is not a socket at all,
+    /// is left alone and reported as an error.
+    pub fn bind(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the path exists and is not a socket"));
+            }
+            Ok(_) if std::os::unix::net::UnixStream::connect(&path).is_ok() => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another process is listening on the socket"));
+            }
+            Ok(_) => std::fs::remove_file(&path)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        let socket = UnixSocket { listener, path };
+        std::fs::set_permissions(&socket.path, std::fs::Permissions::from_mode(SOCKET_MODE))?;
+        Ok(socket)
+    }
+
+    /// Location of the socket file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves `router` until `shutdown` resolves, then stops accepting and
+    /// waits for open connections to finish their current requests.
+    pub(crate) async fn serve(&self, router: Router, shutdown: impl Future<Output = ()>) {
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let graceful = GracefulShutdown::new();
+        tokio::pin!(shutdown);
+        loop {
+            let stream = tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    // Usually running out of file descriptors; back off as
+                    // `axum::serve` does rather than spinning
+                    Err(err) => {
+                        tracing::error!(%err, "unix socket accept error");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                () = &mut shutdown => break,
+            };
+            let service = TowerToHyperService::new(router.clone());
+            let connection = graceful.watch(builder.serve_connection(TokioIo::new(stream), service).into_owned());
+            tokio::spawn(async move {
+                let _ = connection.await;
+            });
+        }
+        graceful.shutdown().await;
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
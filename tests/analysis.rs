@@ -0,0 +1,723 @@
+mod common;
+
+use common::{cmyk_jpeg, forged_png};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, LumaA, Rgb, RgbImage, Rgba, RgbaImage};
+use webcalculation::analysis::{
+    accumulate_parallel, accumulate_sequential, aspect_label, binarize, calculate_image_intensity, channel_correlation,
+    center_weighted_intensity, count_above_threshold, downscale,
+    count_unique_colors, decode_image, encode_bilevel_png, encode_png, floyd_steinberg, hash_distance, histogram, histogram_median, hsv_stats, hue_stats, color_family, intensity_image, intensity_stats,
+    cie_lightness, lab_lightness, linear_intensity, ordered_dither, otsu, perceptual_hash, rgb_histograms, srgb_to_linear, trimmed_mean, unique_colors, AnalysisError, ColorFamily, DecodeLimits,
+    ExposureSuggestion,
+};
+use webcalculation::colormap::{apply_colormap, Colormap};
+use webcalculation::simd::{sum_bytes, sum_bytes_scalar};
+
+fn png(img: &DynamicImage) -> Vec<u8> {
+    encode_png(img).expect("encode test image")
+}
+
+fn gradient(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        Rgb([(x * 7 % 256) as u8, (y * 13 % 256) as u8, ((x + y) % 256) as u8])
+    }))
+}
+
+#[test]
+fn average_keeps_fractional_channel_means() {
+    let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 3, Rgb([255, 255, 254])));
+    let stats = intensity_stats(&img).unwrap();
+    assert!((stats.average_intensity - 764.0 / 3.0).abs() < 1e-9);
+    assert!((stats.brightest_pixel.intensity - 764.0 / 3.0).abs() < 1e-9);
+}
+
+#[test]
+fn extrema_report_first_occurrence() {
+    let mut img = RgbImage::from_pixel(10, 6, Rgb([40, 40, 40]));
+    img.put_pixel(7, 3, Rgb([255, 255, 255]));
+    img.put_pixel(9, 5, Rgb([255, 255, 255]));
+    img.put_pixel(2, 1, Rgb([0, 0, 0]));
+
+    let stats = intensity_stats(&DynamicImage::ImageRgb8(img)).unwrap();
+    assert_eq!((stats.brightest_pixel.x, stats.brightest_pixel.y), (7, 3));
+    assert_eq!(stats.brightest_pixel.intensity, 255.0);
+    assert_eq!((stats.darkest_pixel.x, stats.darkest_pixel.y), (2, 1));
+    assert_eq!(stats.darkest_pixel.intensity, 0.0);
+    assert_eq!(stats.median_intensity, 40.0);
+}
+
+#[test]
+fn gray_and_rgb_layouts_agree() {
+    let gray = GrayImage::from_fn(16, 9, |x, y| Luma([(x * 16 + y) as u8]));
+    let rgb = RgbImage::from_fn(16, 9, |x, y| {
+        let value = gray.get_pixel(x, y)[0];
+        Rgb([value, value, value])
+    });
+
+    let from_gray = intensity_stats(&DynamicImage::ImageLuma8(gray)).unwrap();
+    let from_rgb = intensity_stats(&DynamicImage::ImageRgb8(rgb)).unwrap();
+    assert_eq!(from_gray, from_rgb);
+}
+
+#[test]
+fn alpha_is_ignored() {
+    let rgba = RgbaImage::from_fn(5, 5, |x, _| Rgba([90, 120, 150, (x * 60) as u8]));
+    let gray_alpha = ImageBuffer::from_pixel(5, 5, LumaA([120u8, 0]));
+
+    let stats = intensity_stats(&DynamicImage::ImageRgba8(rgba)).unwrap();
+    assert_eq!(stats.average_intensity, 120.0);
+    let stats = intensity_stats(&DynamicImage::ImageLumaA8(gray_alpha)).unwrap();
+    assert_eq!(stats.average_intensity, 120.0);
+}
+
+#[test]
+fn empty_image_is_an_error() {
+    let img = DynamicImage::ImageRgb8(RgbImage::new(0, 0));
+    assert!(matches!(intensity_stats(&img), Err(AnalysisError::Empty)));
+}
+
+#[test]
+fn encoded_round_trip_matches_in_memory_stats() {
+    let img = gradient(40, 30);
+    let decoded = calculate_image_intensity(&png(&img), &DecodeLimits::default()).unwrap();
+    assert_eq!(decoded, intensity_stats(&img).unwrap());
+}
+
+#[test]
+fn corrupt_bytes_fail_to_decode() {
+    let err = decode_image(b"\x89PNG\r\n\x1a\nnot really", &DecodeLimits::default()).unwrap_err();
+    assert!(matches!(err, AnalysisError::Decode(_)));
+    assert!(std::error::Error::source(&err).is_some());
+}
+
+#[test]
+fn limits_reject_oversized_images() {
+    let data = png(&gradient(50, 20));
+
+    let pixel_cap = DecodeLimits { max_pixels: 999, ..DecodeLimits::default() };
+    assert!(matches!(
+        decode_image(&data, &pixel_cap),
+        Err(AnalysisError::TooManyPixels { width: 50, height: 20, max_pixels: 999 })
+    ));
+
+    let width_cap = DecodeLimits { max_width: 49, ..DecodeLimits::default() };
+    assert!(matches!(
+        decode_image(&data, &width_cap),
+        Err(AnalysisError::DimensionsExceeded { width: 50, height: 20, .. })
+    ));
+
+    let alloc_cap = DecodeLimits { max_alloc_bytes: 64, ..DecodeLimits::default() };
+    assert!(matches!(decode_image(&data, &alloc_cap), Err(AnalysisError::AllocationExceeded { .. })));
+
+    assert!(decode_image(&data, &DecodeLimits::default()).is_ok());
+
+    // A header claiming 10 gigapixels fails on the header alone, whatever the file holds
+    assert!(matches!(
+        decode_image(&forged_png(100_000, 100_000), &DecodeLimits::default()),
+        Err(AnalysisError::TooManyPixels { width: 100_000, height: 100_000, .. })
+    ));
+}
+
+#[test]
+fn vector_byte_sum_matches_scalar_at_every_length() {
+    // Lengths straddling the 32-byte vector width, with saturated bytes in every lane
+    let bytes: Vec<u8> = (0..300u32).map(|i| if i % 7 == 0 { 255 } else { (i * 37 % 256) as u8 }).collect();
+    for len in 0..bytes.len() {
+        assert_eq!(sum_bytes(&bytes[..len]), sum_bytes_scalar(&bytes[..len]), "length {len}");
+    }
+}
+
+#[test]
+fn hsp_brightness_weights_squared_channels_at_any_depth() {
+    let blue = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([0, 0, 255])));
+    let blue16 = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([0u16, 0, 65535])));
+    for img in [blue, blue16] {
+        assert!((intensity_stats(&img).unwrap().hsp_brightness - 0.114f64.sqrt() * 255.0).abs() < 1e-9);
+    }
+    let gray = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([90])));
+    assert_eq!(intensity_stats(&gray).unwrap().hsp_brightness, 90.0);
+}
+
+#[test]
+fn parallel_accumulation_matches_sequential() {
+    let img = gradient(1024, 700);
+    let samples = img.as_bytes();
+    assert_eq!(accumulate_parallel(samples, 3), accumulate_sequential(samples, 3, 0));
+}
+
+#[test]
+fn median_and_trimmed_mean_follow_the_histogram() {
+    let mut bins = [0u64; 256];
+    bins[0] = 10;
+    bins[50] = 80;
+    bins[255] = 10;
+
+    assert_eq!(histogram_median(&bins), 50);
+    assert_eq!(trimmed_mean(&bins, 10.0), 50.0);
+    // A 5% cut removes half of each outlier bin.
+    assert!((trimmed_mean(&bins, 5.0) - (50.0 * 80.0 + 255.0 * 5.0) / 90.0).abs() < 1e-9);
+}
+
+#[test]
+fn counts_unique_colors_with_truncation() {
+    let quad = RgbImage::from_fn(4, 4, |x, y| match (x < 2, y < 2) {
+        (true, true) => Rgb([255, 0, 0]),
+        (false, true) => Rgb([0, 255, 0]),
+        (true, false) => Rgb([0, 0, 255]),
+        (false, false) => Rgb([255, 255, 255]),
+    });
+    let quad = DynamicImage::ImageRgb8(quad);
+    assert_eq!(unique_colors(&quad, 1 << 20), (4, false));
+    assert_eq!(unique_colors(&quad, 3), (3, true));
+    assert_eq!(unique_colors(&quad, 4), (4, false));
+    assert_eq!(count_unique_colors(&png(&quad), &DecodeLimits::default(), 1 << 20).unwrap(), (4, false));
+}
+
+#[test]
+fn threshold_counts_are_strict() {
+    let img = DynamicImage::ImageLuma8(GrayImage::from_fn(10, 1, |x, _| Luma([(x * 20) as u8])));
+    assert_eq!(count_above_threshold(&img, 100.0), (4, 10));
+    assert_eq!(count_above_threshold(&img, 99.9), (5, 10));
+}
+
+#[test]
+fn otsu_splits_a_bimodal_image() {
+    let img = DynamicImage::ImageLuma8(GrayImage::from_fn(20, 10, |x, _| Luma([if x < 5 { 30 } else { 200 }])));
+    let gray = intensity_image(&img);
+    let bins = histogram(&gray);
+    assert_eq!((bins[30], bins[200]), (50, 150));
+
+    let split = otsu(&bins);
+    assert!((30..200).contains(&split.threshold));
+    assert_eq!(split.background_fraction, 0.25);
+    assert!(split.between_class_variance > 0.0);
+
+    let mask = binarize(&gray, split.threshold);
+    assert_eq!(mask.get_pixel(0, 0)[0], 0);
+    assert_eq!(mask.get_pixel(19, 9)[0], 255);
+}
+
+#[test]
+fn intensity_image_uses_the_channel_mean() {
+    let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([10, 20, 40])));
+    assert_eq!(intensity_image(&img).get_pixel(0, 0)[0], 23);
+}
+
+#[test]
+fn intensity_image_bins_like_the_histogram() {
+    let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([255, 255, 254])));
+    assert!(intensity_image(&img).pixels().all(|pixel| pixel[0] == 255));
+    assert_eq!(intensity_stats(&img).unwrap().histogram[255], 4);
+
+    let img16 = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([65535, 65535, 65278])));
+    assert!(intensity_image(&img16).pixels().all(|pixel| pixel[0] == 255));
+    assert_eq!(intensity_stats(&img16).unwrap().histogram[255], 4);
+}
+
+#[test]
+fn colormap_keeps_dimensions_and_spans_the_lut() {
+    let gray = GrayImage::from_fn(256, 2, |x, _| Luma([x as u8]));
+    for colormap in [Colormap::Viridis, Colormap::Turbo] {
+        let rendered = apply_colormap(&gray, colormap);
+        assert_eq!(rendered.dimensions(), (256, 2));
+        assert_eq!(rendered.get_pixel(0, 0).0, colormap.lut()[0]);
+        assert_eq!(rendered.get_pixel(255, 1).0, colormap.lut()[255]);
+    }
+    // Viridis runs from dark purple to yellow.
+    let [r, g, b] = Colormap::Viridis.lut()[0];
+    assert!(b > r && b > g);
+    let [r, g, b] = Colormap::Viridis.lut()[255];
+    assert!(r > b && g > b);
+}
+
+fn gradient16(width: u32, height: u32) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let value = (x * 1031 + y * 17 + 3) as u16;
+        Rgb([value, value.wrapping_add(101), value / 2])
+    })
+}
+
+fn expected_average16(img: &ImageBuffer<Rgb<u16>, Vec<u16>>) -> f64 {
+    let total: u64 = img.pixels().flat_map(|pixel| pixel.0).map(u64::from).sum();
+    total as f64 / (3.0 * img.pixels().len() as f64) * 255.0 / 65535.0
+}
+
+#[test]
+fn sixteen_bit_images_keep_full_precision() {
+    let img16 = gradient16(63, 5);
+    let expected = expected_average16(&img16);
+    let decoded = calculate_image_intensity(&png(&DynamicImage::ImageRgb16(img16.clone())), &DecodeLimits::default()).unwrap();
+
+    assert_eq!(decoded.bit_depth, 16);
+    assert!((decoded.average_intensity - expected).abs() < 1e-9);
+
+    // The same image quantized to 8 bits lands measurably elsewhere.
+    let eight = intensity_stats(&DynamicImage::ImageRgb8(DynamicImage::ImageRgb16(img16).to_rgb8())).unwrap();
+    assert_eq!(eight.bit_depth, 8);
+    assert!((eight.average_intensity - expected).abs() > 1e-3);
+}
+
+#[test]
+fn sixteen_bit_extremes_and_histogram_use_the_byte_scale() {
+    let gray = ImageBuffer::from_fn(4, 1, |x, _| Luma([[0u16, 257, 32_896, 65_535][x as usize]]));
+    let stats = intensity_stats(&DynamicImage::ImageLuma16(gray)).unwrap();
+
+    assert_eq!(stats.brightest_pixel.intensity, 255.0);
+    assert_eq!(stats.brightest_pixel.x, 3);
+    assert_eq!(stats.darkest_pixel.intensity, 0.0);
+    assert_eq!((stats.histogram[0], stats.histogram[1], stats.histogram[128], stats.histogram[255]), (1, 1, 1, 1));
+}
+
+#[test]
+fn parallel_sixteen_bit_accumulation_matches_sequential() {
+    let img = gradient16(1024, 600);
+    let samples = img.as_raw();
+    assert_eq!(accumulate_parallel(samples, 3), accumulate_sequential(samples, 3, 0));
+}
+
+#[test]
+fn linear_average_of_mid_gray_differs_from_gamma_space() {
+    let mid_gray = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([128, 128, 128])));
+    let gamma = intensity_stats(&mid_gray).unwrap().average_intensity;
+    let linear = linear_intensity(&mid_gray).unwrap();
+
+    assert_eq!(gamma, 128.0);
+    assert!((linear - srgb_to_linear(128.0 / 255.0)).abs() < 1e-12);
+    assert!((linear - 0.2158).abs() < 1e-4);
+    assert!((255.0 * linear - gamma).abs() > 70.0);
+}
+
+#[test]
+fn linearization_keeps_the_endpoints_and_handles_16_bit() {
+    assert_eq!(srgb_to_linear(0.0), 0.0);
+    assert!((srgb_to_linear(1.0) - 1.0).abs() < 1e-12);
+
+    let white = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([255])));
+    assert!((linear_intensity(&white).unwrap() - 1.0).abs() < 1e-12);
+
+    let gray16 = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(2, 2, Luma([32_896u16])));
+    let gray8 = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])));
+    assert!((linear_intensity(&gray16).unwrap() - linear_intensity(&gray8).unwrap()).abs() < 1e-12);
+
+    assert_eq!(linear_intensity(&DynamicImage::ImageRgb8(RgbImage::new(0, 0))), None);
+}
+
+#[test]
+fn adobe_cmyk_jpegs_are_converted_to_rgb() {
+    let cases = [
+        // Cyan ink leaves green and blue
+        ([255, 0, 0, 0], 170.0),
+        ([0, 0, 0, 0], 255.0),
+        ([0, 0, 0, 255], 0.0),
+    ];
+    for (ink, expected) in cases {
+        let stats = calculate_image_intensity(&cmyk_jpeg(ink, true), &DecodeLimits::default()).unwrap();
+        assert!((stats.average_intensity - expected).abs() <= 1.0, "{ink:?}: {}", stats.average_intensity);
+    }
+}
+
+#[test]
+fn cmyk_jpeg_without_adobe_marker_is_rejected() {
+    let err = decode_image(&cmyk_jpeg([255, 0, 0, 0], false), &DecodeLimits::default()).unwrap_err();
+    assert!(matches!(err, AnalysisError::AmbiguousCmyk));
+    assert!(err.to_string().starts_with("CMYK JPEG without an Adobe APP14 marker"));
+}
+
+#[test]
+fn aspect_labels_match_common_ratios() {
+    let cases = [
+        ((1920, 1080), Some("16:9")),
+        ((1080, 1920), Some("9:16")),
+        ((1024, 768), Some("4:3")),
+        ((1366, 768), Some("16:9")),
+        ((1280, 800), Some("16:10")),
+        ((500, 500), Some("1:1")),
+        ((8, 4), Some("2:1")),
+        ((1000, 123), None),
+        ((0, 10), None),
+    ];
+    for ((width, height), expected) in cases {
+        assert_eq!(aspect_label(width, height).as_deref(), expected, "{width}x{height}");
+    }
+}
+
+#[test]
+fn rms_contrast_separates_flat_and_checkerboard_images() {
+    let flat = intensity_stats(&DynamicImage::ImageRgb8(RgbImage::from_pixel(9, 7, Rgb([90, 120, 150])))).unwrap();
+    assert_eq!(flat.std_dev, 0.0);
+    assert_eq!(flat.contrast_rms(), 0.0);
+
+    let black = intensity_stats(&DynamicImage::ImageLuma8(GrayImage::new(4, 4))).unwrap();
+    assert_eq!(black.contrast_rms(), 0.0);
+
+    let checker = |x: u32, y: u32| (x + y).is_multiple_of(2);
+    let board = GrayImage::from_fn(8, 8, |x, y| Luma([if checker(x, y) { 255 } else { 0 }]));
+    let stats = intensity_stats(&DynamicImage::ImageLuma8(board)).unwrap();
+    assert!((stats.std_dev - 127.5).abs() < 1e-9);
+    assert!((stats.contrast_rms() - 1.0).abs() < 1e-9);
+
+    let board16 = ImageBuffer::from_fn(8, 8, |x, y| Luma([if checker(x, y) { u16::MAX } else { 0 }]));
+    let stats16 = intensity_stats(&DynamicImage::ImageLuma16(board16)).unwrap();
+    assert!((stats16.std_dev - 127.5).abs() < 1e-9);
+}
+
+#[test]
+fn michelson_contrast_uses_the_extremes() {
+    // Two tones, 64 and 192: (192 - 64) / (192 + 64) = 0.5 whatever their proportions
+    let two_tone = GrayImage::from_fn(10, 4, |x, _| Luma([if x < 3 { 64 } else { 192 }]));
+    let stats = intensity_stats(&DynamicImage::ImageLuma8(two_tone)).unwrap();
+    assert_eq!(stats.contrast_michelson(), Some(0.5));
+
+    let flat = intensity_stats(&DynamicImage::ImageLuma8(GrayImage::from_pixel(3, 3, Luma([40])))).unwrap();
+    assert_eq!(flat.contrast_michelson(), Some(0.0));
+    let black = intensity_stats(&DynamicImage::ImageLuma8(GrayImage::new(3, 3))).unwrap();
+    assert_eq!(black.contrast_michelson(), None);
+}
+
+#[test]
+fn dark_shadow_clipped_images_need_more_exposure() {
+    // A fifth of the frame crushed to black, the rest dim
+    let dark = GrayImage::from_fn(10, 10, |x, _| Luma([if x < 2 { 0 } else { 70 }]));
+    let exposure = intensity_stats(&DynamicImage::ImageLuma8(dark)).unwrap().exposure();
+    assert_eq!(exposure.suggestion, ExposureSuggestion::IncreaseExposure);
+    assert_eq!((exposure.shadow_clipping, exposure.highlight_clipping), (20.0, 0.0));
+    assert!(exposure.ev_offset < -1.0, "{}", exposure.ev_offset);
+}
+
+#[test]
+fn bright_highlight_clipped_images_need_less_exposure() {
+    let bright = GrayImage::from_fn(10, 10, |x, _| Luma([if x < 3 { 255 } else { 180 }]));
+    let exposure = intensity_stats(&DynamicImage::ImageLuma8(bright)).unwrap().exposure();
+    assert_eq!(exposure.suggestion, ExposureSuggestion::DecreaseExposure);
+    assert_eq!((exposure.shadow_clipping, exposure.highlight_clipping), (0.0, 30.0));
+    assert!(exposure.ev_offset > 0.5, "{}", exposure.ev_offset);
+}
+
+#[test]
+fn midtones_and_balanced_clipping_are_well_exposed() {
+    let mid = GrayImage::from_fn(10, 10, |x, y| Luma([100 + (x + y) as u8 * 2]));
+    let exposure = intensity_stats(&DynamicImage::ImageLuma8(mid)).unwrap().exposure();
+    assert_eq!(exposure.suggestion, ExposureSuggestion::WellExposed);
+    assert!(exposure.ev_offset.abs() < 1e-9, "{}", exposure.ev_offset);
+
+    // Clipped at both ends, as in a high-contrast scene, around a middle-gray mean
+    let contrasty = GrayImage::from_fn(10, 10, |x, _| Luma([[0, 118, 118, 118, 255][x as usize % 5]]));
+    let exposure = intensity_stats(&DynamicImage::ImageLuma8(contrasty)).unwrap().exposure();
+    assert_eq!((exposure.shadow_clipping, exposure.highlight_clipping), (20.0, 20.0));
+    assert_eq!(exposure.suggestion, ExposureSuggestion::WellExposed);
+
+    // Neither a slightly dark mean nor mild shadow clipping is enough alone,
+    // but together they are
+    let slightly_dark = GrayImage::from_fn(10, 10, |x, y| Luma([if x + 10 * y < 4 { 0 } else { 92 }]));
+    let exposure = intensity_stats(&DynamicImage::ImageLuma8(slightly_dark)).unwrap().exposure();
+    assert_eq!(exposure.shadow_clipping, 4.0);
+    assert_eq!(exposure.suggestion, ExposureSuggestion::IncreaseExposure);
+}
+
+#[test]
+fn center_weighting_favours_the_middle_of_the_frame() {
+    // A bright square in the middle of a dark frame
+    let spotlit = DynamicImage::ImageLuma8(GrayImage::from_fn(60, 40, |x, y| {
+        Luma([if (20..40).contains(&x) && (12..28).contains(&y) { 240 } else { 10 }])
+    }));
+    let uniform = intensity_stats(&spotlit).unwrap().average_intensity;
+    let center = center_weighted_intensity(&spotlit, 0.15).unwrap();
+    assert!(center > uniform + 50.0, "center {center}, uniform {uniform}");
+    // A narrower Gaussian looks only at the square; a very wide one at everything
+    assert!((center_weighted_intensity(&spotlit, 0.01).unwrap() - 240.0).abs() < 1e-6);
+    assert!((center_weighted_intensity(&spotlit, 10.0).unwrap() - uniform).abs() < 0.1);
+
+    // 16-bit images land on the same 0-255 scale
+    let deep = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(5, 5, Luma([65535u16])));
+    assert!((center_weighted_intensity(&deep, 0.25).unwrap() - 255.0).abs() < 1e-9);
+    assert_eq!(center_weighted_intensity(&DynamicImage::ImageLuma8(GrayImage::new(0, 0)), 0.25), None);
+}
+
+#[test]
+fn downscaling_averages_areas_at_the_source_depth() {
+    // Alternating columns of 1000 and 3000 average to 2000 in every output pixel
+    let img = DynamicImage::ImageLuma16(ImageBuffer::from_fn(64, 16, |x, _| Luma([if x % 2 == 0 { 1000u16 } else { 3000 }])));
+    let small = downscale(&img, 32);
+    assert_eq!((small.width(), small.height()), (32, 8));
+    let small = small.as_luma16().unwrap();
+    assert!(small.pixels().all(|pixel| pixel.0[0] == 2000));
+
+    // Portrait images are bound by their height; small ones are left alone
+    assert_eq!(downscale(&gradient(30, 90), 45).height(), 45);
+    assert!(matches!(downscale(&gradient(30, 90), 90), std::borrow::Cow::Borrowed(_)));
+}
+
+#[test]
+fn gray_images_have_perfectly_correlated_channels() {
+    // Equal channels, whether stored as RGB or as gray
+    let rgb = RgbImage::from_fn(16, 16, |x, y| {
+        let value = (x * 16 + y) as u8;
+        Rgb([value, value, value])
+    });
+    let gray = DynamicImage::ImageLuma16(ImageBuffer::from_fn(16, 16, |x, y| Luma([(x * 4000 + y) as u16])));
+    for img in [DynamicImage::ImageRgb8(rgb), gray] {
+        let matrix = channel_correlation(&img).unwrap();
+        for entry in matrix.iter().flatten() {
+            assert!((entry.unwrap() - 1.0).abs() < 1e-9, "{matrix:?}");
+        }
+    }
+}
+
+#[test]
+fn pure_hues_are_weakly_correlated() {
+    // Red, green and blue pixels in turn: each channel is high exactly where
+    // the others are low
+    let hues = RgbImage::from_fn(30, 10, |x, _| {
+        let mut pixel = [0; 3];
+        pixel[x as usize % 3] = 255;
+        Rgb(pixel)
+    });
+    let matrix = channel_correlation(&DynamicImage::ImageRgb8(hues)).unwrap();
+    for (row, entries) in matrix.iter().enumerate() {
+        for (column, entry) in entries.iter().enumerate() {
+            let expected = if row == column { 1.0 } else { -0.5 };
+            assert!((entry.unwrap() - expected).abs() < 1e-9, "{matrix:?}");
+        }
+    }
+
+    let flat_blue = RgbImage::from_fn(4, 4, |x, y| Rgb([(x * 60) as u8, (y * 60) as u8, 200]));
+    let matrix = channel_correlation(&DynamicImage::ImageRgb8(flat_blue)).unwrap();
+    assert_eq!(matrix[2], [None; 3]);
+    assert!(matrix[0][1].unwrap().abs() < 1e-9);
+    assert_eq!(channel_correlation(&DynamicImage::ImageRgb8(RgbImage::new(0, 0))), None);
+}
+
+#[test]
+fn rgb_histograms_bin_each_channel_and_the_intensity() {
+    let img = gradient(20, 12);
+    let histograms = rgb_histograms(&img);
+    assert_eq!(histograms.luminance, intensity_stats(&img).unwrap().histogram);
+    for histogram in [histograms.red, histograms.green, histograms.blue] {
+        assert_eq!(histogram.iter().sum::<u64>(), 240);
+    }
+    assert_eq!(histograms.red[7], 12);
+
+    // 16-bit values round to the nearest bin; gray fills all three channels
+    let gray = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(3, 3, Luma([32_896u16])));
+    let histograms = rgb_histograms(&gray);
+    for histogram in [histograms.red, histograms.green, histograms.blue, histograms.luminance] {
+        assert_eq!(histogram[128], 9);
+    }
+}
+
+#[test]
+fn primaries_map_to_their_color_family() {
+    let family = |rgb: [u8; 3]| {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 3, Rgb(rgb)));
+        hue_stats(&img).unwrap().color_family(0.1)
+    };
+    assert_eq!(family([255, 0, 0]), ColorFamily::Red);
+    assert_eq!(family([0, 255, 0]), ColorFamily::Green);
+    assert_eq!(family([0, 0, 255]), ColorFamily::Blue);
+    assert_eq!(family([255, 255, 0]), ColorFamily::Yellow);
+    assert_eq!(family([0, 255, 255]), ColorFamily::Cyan);
+    assert_eq!(family([255, 0, 255]), ColorFamily::Magenta);
+    assert_eq!(family([255, 128, 0]), ColorFamily::Orange);
+    assert_eq!(family([128, 0, 255]), ColorFamily::Purple);
+    // Just below red's wrap-around at 360 degrees
+    assert_eq!(family([255, 0, 20]), ColorFamily::Red);
+    assert_eq!(family([90, 90, 90]), ColorFamily::Neutral);
+}
+
+#[test]
+fn desaturated_or_balanced_hues_are_neutral() {
+    // Saturation 0.08: neutral by default, reddish with a lower threshold
+    let pale = hue_stats(&DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([250, 230, 230])))).unwrap();
+    assert!((pale.mean_saturation - 0.08).abs() < 1e-9);
+    assert_eq!(pale.color_family(0.1), ColorFamily::Neutral);
+    assert_eq!(pale.color_family(0.05), ColorFamily::Red);
+
+    // Red, green and blue in equal parts: saturated, but no mean hue
+    let hues = RgbImage::from_fn(3, 1, |x, _| {
+        let mut pixel = [0; 3];
+        pixel[x as usize] = 255;
+        Rgb(pixel)
+    });
+    let balanced = hue_stats(&DynamicImage::ImageRgb8(hues)).unwrap();
+    assert_eq!((balanced.mean_hue, balanced.mean_saturation), (None, 1.0));
+    assert_eq!(balanced.color_family(0.1), ColorFamily::Neutral);
+
+    let gray = DynamicImage::ImageLuma16(ImageBuffer::from_pixel(2, 2, Luma([40_000u16])));
+    assert_eq!(hue_stats(&gray).unwrap().color_family(0.0), ColorFamily::Neutral);
+    assert_eq!(hue_stats(&DynamicImage::ImageRgb8(RgbImage::new(0, 3))), None);
+}
+
+#[test]
+fn intensity_pass_saturation_matches_the_hue_pass() {
+    for img in [gradient(64, 64), DynamicImage::ImageRgb16(gradient16(32, 16)), DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 3, Rgb([250, 230, 230])))] {
+        let stats = intensity_stats(&img).unwrap();
+        let hues = hue_stats(&img).unwrap();
+        assert!((stats.mean_saturation - hues.mean_saturation).abs() < 1e-9);
+        for threshold in [0.0, 0.1, 0.5, 1.0] {
+            assert_eq!(color_family(&img, &stats, threshold), hues.color_family(threshold), "{threshold}");
+        }
+    }
+}
+
+#[test]
+fn lab_lightness_matches_reference_values() {
+    let gray = |value: u8| lab_lightness(&DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([value])))).unwrap();
+    assert!((gray(255) - 100.0).abs() < 1e-9);
+    assert_eq!(gray(0), 0.0);
+    // An 18% gray card: sRGB 118 decodes to Y = 0.181
+    assert!((gray(118) - 49.5).abs() < 0.5, "{}", gray(118));
+
+    // The interpolated table tracks the exact curve at every 8-bit level
+    for value in 0..=255u8 {
+        let exact = cie_lightness(srgb_to_linear(f64::from(value) / 255.0));
+        assert!((gray(value) - exact).abs() < 1e-3, "{value}: {} vs {exact}", gray(value));
+    }
+
+    // Green is far lighter than blue at the same channel value
+    let rgb = |pixel: [u8; 3]| lab_lightness(&DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb(pixel)))).unwrap();
+    assert!((rgb([0, 255, 0]) - 87.74).abs() < 0.01);
+    assert!((rgb([0, 0, 255]) - 32.30).abs() < 0.01);
+    let sixteen = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(1, 1, Rgb([65_535u16; 3])));
+    assert!((lab_lightness(&sixteen).unwrap() - 100.0).abs() < 1e-9);
+    assert_eq!(lab_lightness(&DynamicImage::ImageRgb8(RgbImage::new(0, 3))), None);
+}
+
+#[test]
+fn hsv_hue_histogram_is_weighted_by_saturation() {
+    // Full red, half-saturated green, and gray that must not count towards any hue
+    let img = RgbImage::from_fn(4, 1, |x, _| match x {
+        0 => Rgb([255, 0, 0]),
+        1 => Rgb([100, 200, 100]),
+        _ => Rgb([200, 200, 200]),
+    });
+    let stats = hsv_stats(&DynamicImage::ImageRgb8(img)).unwrap();
+    assert!((stats.mean_saturation - 1.5 / 4.0).abs() < 1e-12);
+    assert!((stats.mean_value - (1.0 + 3.0 * 200.0 / 255.0) / 4.0).abs() < 1e-12);
+    assert!((stats.hue_histogram[0] - 2.0 / 3.0).abs() < 1e-12);
+    assert!((stats.hue_histogram[4] - 1.0 / 3.0).abs() < 1e-12);
+    assert!((stats.hue_histogram.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+
+    // Hues just either side of 0 degrees share the red bucket
+    let reds = RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([255, 0, 30]) } else { Rgb([255, 30, 0]) });
+    assert_eq!(hsv_stats(&DynamicImage::ImageRgb8(reds)).unwrap().hue_histogram[0], 1.0);
+}
+
+#[test]
+fn gray_images_have_no_saturation_or_hue() {
+    let stripes = GrayImage::from_fn(4, 2, |x, _| Luma([if x % 2 == 0 { 0 } else { 255 }]));
+    for img in [DynamicImage::ImageLuma8(stripes.clone()), DynamicImage::ImageLuma8(stripes).to_rgb8().into()] {
+        let stats = hsv_stats(&img).unwrap();
+        assert_eq!((stats.mean_saturation, stats.std_saturation), (0.0, 0.0));
+        assert_eq!((stats.mean_value, stats.std_value), (0.5, 0.5));
+        assert_eq!(stats.hue_histogram, [0.0; 12]);
+    }
+    assert_eq!(hsv_stats(&DynamicImage::ImageRgb8(RgbImage::new(0, 3))), None);
+}
+
+/// A gray "page" of text-like stripes, optionally with a red square stamp.
+fn scanned_page(stamp_side: u32) -> RgbImage {
+    RgbImage::from_fn(200, 200, |x, y| {
+        if x >= 180 - stamp_side && x < 180 && y >= 20 && y < 20 + stamp_side {
+            Rgb([200, 30, 30])
+        } else if y % 12 < 3 && (x / 7) % 3 != 0 {
+            Rgb([40, 40, 40])
+        } else {
+            Rgb([235, 235, 235])
+        }
+    })
+}
+
+#[test]
+fn gray_scanned_in_color_is_monochrome() {
+    // Through a lossy RGB JPEG, whose chroma subsampling leaves small channel differences
+    let mut jpeg = Vec::new();
+    DynamicImage::ImageRgb8(scanned_page(0)).write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 75)).unwrap();
+    let img = decode_image(&jpeg, &DecodeLimits::default()).unwrap();
+    assert!(img.color().has_color());
+    let stats = intensity_stats(&img).unwrap();
+    assert!(stats.color_fraction(16.0) < 0.001, "{}", stats.color_fraction(16.0));
+    assert!(stats.is_monochrome(16.0, 1.0));
+    assert_eq!(intensity_stats(&DynamicImage::ImageLuma8(GrayImage::new(3, 3))).unwrap().color_fraction(0.0), 0.0);
+}
+
+#[test]
+fn photos_and_stamped_pages_are_not_monochrome() {
+    let photo = intensity_stats(&gradient(64, 64)).unwrap();
+    assert!(photo.color_fraction(16.0) > 0.5, "{}", photo.color_fraction(16.0));
+    assert!(!photo.is_monochrome(16.0, 1.0));
+
+    // A 10x10 stamp on a 200x200 page: 0.25% colored
+    let stamped = intensity_stats(&DynamicImage::ImageRgb8(scanned_page(10))).unwrap();
+    assert_eq!(stamped.color_fraction(16.0), 0.0025);
+    assert!(stamped.is_monochrome(16.0, 1.0));
+    assert!(!stamped.is_monochrome(16.0, 0.1));
+    // The stamp's channels differ by 170, so a threshold at that still counts it
+    assert_eq!(stamped.color_fraction(169.0), 0.0025);
+    assert_eq!(stamped.color_fraction(170.0), 0.0);
+}
+
+#[test]
+fn log_mean_sits_below_the_mean_of_a_bright_skewed_image() {
+    // Mostly bright, with a few dark pixels pulling the log-average down
+    let skewed = GrayImage::from_fn(10, 10, |x, y| Luma([if x + y * 10 < 5 { 4 } else { 250 }]));
+    let stats = intensity_stats(&DynamicImage::ImageLuma8(skewed)).unwrap();
+    let expected = ((5.0 * 5f64.ln() + 95.0 * 251f64.ln()) / 100.0).exp() - 1.0;
+    assert!((stats.log_mean_intensity - expected).abs() < 1e-6, "{}", stats.log_mean_intensity);
+    assert!(stats.log_mean_intensity < stats.average_intensity - 30.0);
+
+    let flat = intensity_stats(&DynamicImage::ImageLuma8(GrayImage::from_pixel(3, 3, Luma([200])))).unwrap();
+    assert!((flat.log_mean_intensity - 200.0).abs() < 1e-6);
+    let black = intensity_stats(&DynamicImage::ImageLuma8(GrayImage::new(3, 3))).unwrap();
+    assert_eq!(black.log_mean_intensity, 0.0);
+}
+
+#[test]
+fn floyd_steinberg_carries_the_error_to_all_four_neighbours() {
+    // Row 0: 100 -> 0 pushes 43.75 right, 143.75 -> 255 pulls the pixels
+    // below down; the bottom row ends at 110 and 120, both rounding to 0
+    let dithered = floyd_steinberg(&GrayImage::from_pixel(2, 2, Luma([100])));
+    assert_eq!(dithered.as_raw(), &[0, 255, 0, 0]);
+
+    for level in [32u8, 64, 128, 200] {
+        let gray = GrayImage::from_pixel(32, 32, Luma([level]));
+        for dithered in [floyd_steinberg(&gray), ordered_dither(&gray)] {
+            assert!(dithered.iter().all(|&value| value == 0 || value == 255));
+            let mean = dithered.iter().map(|&value| f64::from(value)).sum::<f64>() / 1024.0;
+            assert!((mean - f64::from(level)).abs() < 4.0, "level {level}: mean {mean}");
+        }
+    }
+}
+
+#[test]
+fn bilevel_pngs_decode_back_to_the_same_pixels() {
+    // An odd width leaves padding bits at the end of each row
+    let mut gray = GrayImage::new(11, 3);
+    for (x, y, pixel) in gray.enumerate_pixels_mut() {
+        *pixel = Luma([if (x + y) % 3 == 0 { 255 } else { 0 }]);
+    }
+    let png = encode_bilevel_png(&gray).unwrap();
+    assert_eq!(png[24], 1);
+    assert_eq!(image::load_from_memory(&png).unwrap().to_luma8(), gray);
+}
+
+/// A smooth 256x192 scene: a bright disc off-center on a diagonal ramp.
+fn scene(flip: bool) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(256, 192, |x, y| {
+        let x = if flip { 255 - x } else { x };
+        let disc = if (x as i32 - 80).pow(2) + (y as i32 - 70).pow(2) < 40 * 40 { 120 } else { 0 };
+        let level = ((x + y) / 4 + disc) as u8;
+        Rgb([level, level / 2 + 40, 255 - level])
+    }))
+}
+
+#[test]
+fn perceptual_hash_survives_rescaling_and_recompression_only() {
+    let original = perceptual_hash(&scene(false));
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 60).encode_image(&scene(false).thumbnail(128, 96)).unwrap();
+    let recompressed = perceptual_hash(&image::load_from_memory(&jpeg).unwrap());
+    assert!(hash_distance(original, recompressed) <= 8, "{original:016x} vs {recompressed:016x}");
+
+    let mirrored = perceptual_hash(&scene(true));
+    assert!(hash_distance(original, mirrored) >= 16, "{original:016x} vs {mirrored:016x}");
+}
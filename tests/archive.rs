@@ -0,0 +1,93 @@
+//! `POST /calculate-intensity/zip`, on archives written by the test.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::*;
+use image::{DynamicImage, Rgb, RgbImage};
+use std::io::{Cursor, Write};
+use webcalculation::analysis::encode_png;
+use webcalculation::archive::{image_entries, ArchiveError, ArchiveLimits};
+use webcalculation::server::{ArchiveIntensityResponse, Config};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// A deflated ZIP holding `files` by path; paths ending in `/` are directories.
+fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (path, data) in files {
+        if path.ends_with('/') {
+            writer.add_directory(*path, options).unwrap();
+        } else {
+            writer.start_file(*path, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+    }
+    writer.finish().unwrap().into_inner()
+}
+
+fn gray_png(level: u8) -> Vec<u8> {
+    encode_png(&DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([level, level, level])))).unwrap()
+}
+
+const LIMITS: ArchiveLimits = ArchiveLimits { max_entries: 100, max_uncompressed_bytes: 1 << 20 };
+
+#[test]
+fn images_are_found_by_extension_or_content() {
+    let png = gray_png(10);
+    let archive = zip_of(&[
+        ("frames/", b""),
+        ("frames/a.png", &png),
+        ("frames/b.dat", &png),
+        ("notes.txt", b"hello"),
+        ("__MACOSX/frames/._a.png", &png),
+    ]);
+    let images = image_entries(&archive, LIMITS).unwrap();
+    let names: Vec<&str> = images.entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["frames/a.png", "frames/b.dat"]);
+    assert!(images.entries.iter().all(|entry| entry.data.as_deref() == Ok(png.as_slice())));
+    assert_eq!(images.skipped, 3);
+}
+
+#[test]
+fn bombs_are_refused() {
+    let archive = zip_of(&[("a.png", &gray_png(1)), ("b.png", &gray_png(2)), ("c.png", &gray_png(3))]);
+    let few = ArchiveLimits { max_entries: 2, ..LIMITS };
+    assert_eq!(image_entries(&archive, few).unwrap_err(), ArchiveError::TooManyEntries { entries: 3, max: 2 });
+
+    // Zeros deflate to a few hundred bytes whatever the declared size
+    let archive = zip_of(&[("zeros.pgm", &vec![0; 1 << 21])]);
+    assert_eq!(image_entries(&archive, LIMITS).unwrap_err(), ArchiveError::TooLarge(1 << 20));
+    assert!(matches!(image_entries(b"not a zip", LIMITS), Err(ArchiveError::Invalid(_))));
+}
+
+#[tokio::test]
+async fn every_image_entry_is_analysed() {
+    let (dim, bright) = (gray_png(40), gray_png(200));
+    let archive = zip_of(&[("dim.png", &dim), ("readme.md", b"# frames"), ("bright.png", &bright), ("broken.jpg", b"nope")]);
+    let request = multipart_request("/calculate-intensity/zip", multipart_named_files(&[("file", "frames.zip", &archive)]));
+    let (status, body) = send(test_app(Config::default()), request).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let response: ArchiveIntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!((response.summary.total, response.summary.succeeded, response.summary.failed), (3, 2, 1));
+    assert_eq!(response.skipped, 1);
+    assert_eq!(response.images[2].filename.as_deref(), Some("broken.jpg"));
+    assert_eq!(response.images[2].error.as_ref().unwrap().code, "decode_error");
+    let aggregate = response.aggregate.unwrap();
+    assert_eq!((aggregate.min_intensity, aggregate.max_intensity), (40.0, 200.0));
+    assert_eq!(aggregate.brightest_filename.as_deref(), Some("bright.png"));
+}
+
+#[tokio::test]
+async fn oversized_and_invalid_archives_are_rejected() {
+    let archive = zip_of(&[("a.png", &gray_png(1)), ("b.png", &gray_png(2))]);
+    let config = Config { max_archive_entries: 1, ..Config::default() };
+    let (status, body) = send(test_app(config), upload("/calculate-intensity/zip", "file", &archive)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error_code(&body), "archive_too_large");
+
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity/zip", "file", &gray_png(1))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), "invalid_archive");
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use common::*;
+use webcalculation::auth::{sign, signature_matches, ApiKeys};
+use webcalculation::server::Config;
+
+fn keyed_config() -> Config {
+    Config {
+        api_keys: vec!["alpha-key".to_string(), "beta-key".to_string()],
+        ..Config::default()
+    }
+}
+
+#[test]
+fn api_keys_match_exactly() {
+    let keys = ApiKeys::new(&["secret".to_string(), "other".to_string()]).unwrap();
+    assert!(keys.verify("secret"));
+    assert!(keys.verify("other"));
+    assert!(!keys.verify("secre"));
+    assert!(!keys.verify("secret "));
+    assert!(!keys.verify(""));
+    assert!(ApiKeys::new(&[]).is_none());
+}
+
+#[tokio::test]
+async fn requests_without_a_key_are_rejected() {
+    let response = tower::ServiceExt::oneshot(test_app(keyed_config()), upload("/calculate-intensity", "image", &test_png()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+    let (status, body) = send(test_app(keyed_config()), get("/stats")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(error_message(&body).starts_with("missing API key"));
+}
+
+#[tokio::test]
+async fn wrong_keys_are_rejected() {
+    let mut request = upload("/calculate-intensity", "image", &test_png());
+    request.headers_mut().insert("x-api-key", "gamma-key".parse().unwrap());
+    let (status, body) = send(test_app(keyed_config()), request).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&body), "unauthorized");
+    assert_eq!(error_message(&body), "invalid API key");
+}
+
+#[tokio::test]
+async fn either_header_is_accepted() {
+    let mut request = upload("/calculate-intensity", "image", &test_png());
+    request.headers_mut().insert("x-api-key", "alpha-key".parse().unwrap());
+    assert_eq!(send(test_app(keyed_config()), request).await.0, StatusCode::OK);
+
+    let mut request = upload("/calculate-intensity", "image", &test_png());
+    request.headers_mut().insert(header::AUTHORIZATION, "Bearer beta-key".parse().unwrap());
+    assert_eq!(send(test_app(keyed_config()), request).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn health_and_docs_stay_open() {
+    for path in ["/health", "/live", "/ready", "/swagger-ui", "/api-docs/openapi.json", "/api-docs/openapi.yaml"] {
+        let (status, _) = send(test_app(keyed_config()), get(path)).await;
+        assert_eq!(status, StatusCode::OK, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn openapi_declares_the_key_schemes_when_keys_are_configured() {
+    let (_, body) = send(test_app(keyed_config()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let schemes = &doc["components"]["securitySchemes"];
+    assert_eq!(schemes["api_key"], serde_json::json!({"type": "apiKey", "in": "header", "name": "X-API-Key"}));
+    assert_eq!(schemes["bearer"], serde_json::json!({"type": "http", "scheme": "bearer"}));
+
+    let operation = &doc["paths"]["/calculate-intensity"]["post"];
+    assert_eq!(operation["security"], serde_json::json!([{"api_key": []}, {"bearer": []}]));
+    assert_eq!(operation["responses"]["401"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ErrorResponse");
+    assert!(doc["paths"]["/health"]["get"].get("security").is_none());
+
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(doc["components"].get("securitySchemes").is_none());
+    assert!(doc["paths"]["/calculate-intensity"]["post"].get("security").is_none());
+}
+
+#[tokio::test]
+async fn no_keys_disables_authentication() {
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+/// RFC 4231, test case 2
+const KEY: &[u8] = b"Jefe";
+const BODY: &[u8] = b"what do ya want for nothing?";
+const SIGNATURE: &str = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+#[test]
+fn signatures_match_a_known_triple() {
+    assert!(signature_matches(KEY, BODY, SIGNATURE));
+    assert!(signature_matches(KEY, BODY, &format!("sha256={SIGNATURE}")));
+    assert!(signature_matches(KEY, BODY, &SIGNATURE.to_uppercase()));
+    assert!(!signature_matches(b"jefe", BODY, SIGNATURE));
+    assert!(!signature_matches(KEY, b"what do ya want for nothing", SIGNATURE));
+    for malformed in ["", &SIGNATURE[..62], &SIGNATURE[1..], "+5bdcc146", "zz"] {
+        assert!(!signature_matches(KEY, BODY, malformed), "{malformed:?}");
+    }
+}
+
+fn signed_config() -> Config {
+    Config {
+        upload_hmac_secret: Some("webhook-secret".to_string()),
+        ..Config::default()
+    }
+}
+
+fn signed(body: Vec<u8>, signature: &str) -> axum::http::Request<axum::body::Body> {
+    let mut request = multipart_request("/calculate-intensity", body);
+    request.headers_mut().insert("x-signature", signature.parse().unwrap());
+    request
+}
+
+#[tokio::test]
+async fn uploads_must_carry_the_body_signature() {
+    let body = multipart_body("image", &test_png());
+    let signature = sign(b"webhook-secret", &body);
+    let (status, _) = send(test_app(signed_config()), signed(body.clone(), &signature)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, response) = send(test_app(signed_config()), multipart_request("/calculate-intensity", body.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(&response), "invalid_signature");
+    assert!(error_message(&response).starts_with("missing X-Signature"));
+
+    // Signed for other bytes, or with another secret
+    let mut tampered = body.clone();
+    tampered.push(b'\n');
+    for (body, signature) in [(tampered, signature.clone()), (body.clone(), sign(b"guess", &body))] {
+        let (status, response) = send(test_app(signed_config()), signed(body, &signature)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error_message(&response), "X-Signature does not match the request body");
+    }
+
+    // Only uploads are signed
+    let (status, _) = send(test_app(signed_config()), get("/supported-formats")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn oversized_signed_uploads_are_too_large() {
+    let config = Config { max_upload_bytes: 64, ..signed_config() };
+    let body = multipart_body("image", &test_png());
+    let signature = sign(b"webhook-secret", &body);
+    let (status, response) = send(test_app(config), signed(body, &signature)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error_code(&response), "too_large");
+}
+
+#[tokio::test]
+async fn openapi_declares_the_signature_header_when_a_secret_is_set() {
+    let (_, body) = send(test_app(signed_config()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let operation = &doc["paths"]["/calculate-intensity"]["post"];
+    let parameters = operation["parameters"].as_array().unwrap();
+    assert!(parameters.iter().any(|parameter| parameter["name"] == "X-Signature" && parameter["in"] == "header"));
+    assert!(operation["responses"]["401"].is_object());
+    assert!(doc["paths"]["/version"]["get"]["responses"]["401"].is_null());
+}
@@ -0,0 +1,153 @@
+//! Helpers shared by the HTTP integration tests.
+#![allow(dead_code)]
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, StatusCode},
+    Router,
+};
+use image::{DynamicImage, Rgb, RgbImage};
+use tower::ServiceExt;
+use webcalculation::analysis::encode_png;
+use webcalculation::server::{app, AppState, Config, ErrorResponse};
+
+pub const BOUNDARY: &str = "test-boundary";
+
+pub fn test_app(config: Config) -> Router {
+    app(AppState::new(config))
+}
+
+pub fn multipart_body(field: &str, data: &[u8]) -> Vec<u8> {
+    multipart_body_as(field, Some("application/octet-stream"), data)
+}
+
+/// A single-part multipart body, optionally declaring the part's content type.
+pub fn multipart_body_as(field: &str, content_type: Option<&str>, data: &[u8]) -> Vec<u8> {
+    let mut body = format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{field}\"; filename=\"upload\"\r\n");
+    if let Some(content_type) = content_type {
+        body.push_str(&format!("Content-Type: {content_type}\r\n"));
+    }
+    body.push_str("\r\n");
+    let mut body = body.into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+/// A multipart body of file parts, each labelled `application/octet-stream`.
+pub fn multipart_files(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let named: Vec<(&str, &str, &[u8])> = files.iter().map(|&(field, data)| (field, "upload", data)).collect();
+    multipart_named_files(&named)
+}
+
+/// Like [`multipart_files`], with each part's `(field, filename, data)`.
+pub fn multipart_named_files(files: &[(&str, &str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (field, filename, data) in files {
+        body.extend(format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{field}\"; filename=\"{filename}\"\r\n").as_bytes());
+        body.extend(b"Content-Type: application/octet-stream\r\n\r\n");
+        body.extend_from_slice(data);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+/// A multipart body of plain (non-file) text fields.
+pub fn text_fields_body(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut body = String::new();
+    for (name, value) in fields {
+        body.push_str(&format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"));
+    }
+    body.push_str(&format!("--{BOUNDARY}--\r\n"));
+    body.into_bytes()
+}
+
+/// A multipart body of text fields followed by file parts.
+pub fn text_fields_and_files(fields: &[(&str, &str)], files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = text_fields_body(fields);
+    // Drop the closing delimiter, which the file parts' body repeats
+    body.truncate(body.len() - format!("--{BOUNDARY}--\r\n").len());
+    body.extend(multipart_files(files));
+    body
+}
+
+pub fn multipart_request(uri: &str, body: Vec<u8>) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from(body))
+        .unwrap()
+}
+
+pub fn upload(uri: &str, field: &str, data: &[u8]) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from(multipart_body(field, data)))
+        .unwrap()
+}
+
+pub fn upload_as(uri: &str, content_type: Option<&str>, data: &[u8]) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from(multipart_body_as("image", content_type, data)))
+        .unwrap()
+}
+
+pub fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+pub async fn send(app: Router, request: Request<Body>) -> (StatusCode, Vec<u8>) {
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, body.to_vec())
+}
+
+pub fn error_body(body: &[u8]) -> ErrorResponse {
+    serde_json::from_slice(body).expect("ErrorResponse body")
+}
+
+pub fn error_message(body: &[u8]) -> String {
+    error_body(body).error
+}
+
+pub fn error_code(body: &[u8]) -> String {
+    error_body(body).code
+}
+
+/// 8x4 near-white PNG with a single black pixel at (3, 2).
+pub fn test_png() -> Vec<u8> {
+    let mut img = RgbImage::from_pixel(8, 4, Rgb([255, 255, 254]));
+    img.put_pixel(3, 2, Rgb([0, 0, 0]));
+    encode_png(&DynamicImage::ImageRgb8(img)).unwrap()
+}
+
+/// A small valid PNG whose IHDR header claims `width x height`, with the
+/// header checksum fixed up so only the dimensions give it away.
+pub fn forged_png(width: u32, height: u32) -> Vec<u8> {
+    let mut data = test_png();
+    data[16..20].copy_from_slice(&width.to_be_bytes());
+    data[20..24].copy_from_slice(&height.to_be_bytes());
+    let mut crc = flate2::Crc::new();
+    crc.update(&data[12..29]);
+    data[29..33].copy_from_slice(&crc.sum().to_be_bytes());
+    data
+}
+
+/// An 8x8 JPEG filled with one CMYK ink combination, stored the Adobe way
+/// (inverted, with an APP14 marker). With `adobe_marker` false the marker is
+/// stripped, leaving the channel convention unstated.
+pub fn cmyk_jpeg(ink: [u8; 4], adobe_marker: bool) -> Vec<u8> {
+    let pixels: Vec<u8> = (0..64).flat_map(|_| ink).collect();
+    let mut data = Vec::new();
+    jpeg_encoder::Encoder::new(&mut data, 100)
+        .encode(&pixels, 8, 8, jpeg_encoder::ColorType::Cmyk)
+        .unwrap();
+    if !adobe_marker {
+        let start = data.windows(2).position(|marker| marker == [0xFF, 0xEE]).unwrap();
+        let length = usize::from(u16::from_be_bytes([data[start + 2], data[start + 3]]));
+        data.drain(start..start + 2 + length);
+    }
+    data
+}
@@ -0,0 +1,123 @@
+//! The configuration file and how environment variables override it.
+
+use std::time::Duration;
+use webcalculation::server::Config;
+
+const FILE: &str = r#"
+max_concurrent_decodes = 3
+request_timeout_secs = 12
+bind_addr = "127.0.0.1"
+api_keys = ["first-key", "second-key"]
+upload_hmac_secret = "signing-secret"
+jwt_hs256_secret = "token-secret"
+upload_field_names = ["photo"]
+cors_allowed_origins = ["https://*.example.com"]
+cors_allowed_methods = ["post"]
+base_path = "intensity/"
+
+[tls]
+cert_path = "/etc/tls/cert.pem"
+key_path = "/etc/tls/key.pem"
+"#;
+
+#[test]
+fn files_override_the_defaults() {
+    let (config, unknown) = Config::from_toml(FILE).unwrap();
+    assert!(unknown.is_empty(), "{unknown:?}");
+    assert_eq!(config.max_concurrent_decodes, 3);
+    assert_eq!(config.request_timeout, Duration::from_secs(12));
+    assert_eq!(config.bind_addr.unwrap().to_string(), "127.0.0.1:3000");
+    assert_eq!(config.api_keys, ["first-key", "second-key"]);
+    assert_eq!(config.upload_field_names, ["photo"]);
+    assert!(config.cors_allowed_origins[0].matches("https://app.example.com"));
+    assert_eq!(config.cors_allowed_methods, [axum::http::Method::POST]);
+    assert_eq!(config.base_path, "/intensity");
+    assert_eq!(config.tls.unwrap().key_path.to_str(), Some("/etc/tls/key.pem"));
+    // Everything else keeps its default
+    assert_eq!(config.cache_capacity, Config::default().cache_capacity);
+    assert_eq!(config.shutdown_timeout, Config::default().shutdown_timeout);
+
+    let (empty, _) = Config::from_toml("").unwrap();
+    assert_eq!(empty.tcp_addr(), Config::default().tcp_addr());
+}
+
+#[test]
+fn unknown_keys_are_reported() {
+    let (_, unknown) = Config::from_toml("request_timeout = 5\n[tls]\ncert_path = \"c\"\nkey_path = \"k\"\nca_path = \"x\"").unwrap();
+    assert_eq!(unknown, ["request_timeout", "tls.ca_path"]);
+}
+
+#[test]
+fn invalid_values_are_rejected() {
+    for (file, expected) in [
+        ("cache_capacity = \"lots\"", "invalid type"),
+        ("bind_addr = \"localhost\"", "expected an IP"),
+        ("cors_allowed_origins = [\"example.com\"]", "invalid origin"),
+        ("cors_allowed_methods = [\"NOT A METHOD\"]", "invalid method"),
+        ("base_path = \"/intensity?x=1\"", "invalid path prefix"),
+        ("[tls]\ncert_path = \"cert.pem\"", "missing field `key_path`"),
+        ("max_in_flight_requests = ", "TOML parse error at line 1"),
+    ] {
+        let err = Config::from_toml(file).unwrap_err();
+        assert!(err.contains(expected), "{file}: {err}");
+    }
+
+    let (config, _) = Config::from_toml("max_concurrent_decodes = 0").unwrap();
+    assert_eq!(config.validate().unwrap_err(), "MAX_CONCURRENT_DECODES must be at least 1");
+    let (config, _) = Config::from_toml("cors_allow_credentials = true").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("CORS_ALLOW_CREDENTIALS needs CORS_ALLOWED_ORIGINS"));
+    let (config, _) = Config::from_toml("rate_limit_per_minute = 60\nrate_limit_burst = 0").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("RATE_LIMIT_BURST must be at least 1"));
+    let (config, _) = Config::from_toml("jwt_hs256_secret = \"s\"\njwt_jwks_url = \"https://idp.example/jwks\"").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("set only one of JWT_HS256_SECRET and JWT_JWKS_URL"));
+    let (config, _) = Config::from_toml("jwt_jwks_url = \"idp.example/jwks\"").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("JWT_JWKS_URL: expected an http(s) URL"));
+    let (config, _) = Config::from_toml("jwt_required_scope = \"intensity:write\"").unwrap();
+    assert!(config.validate().unwrap_err().starts_with("JWT_REQUIRED_SCOPE needs"));
+}
+
+#[test]
+fn the_logged_configuration_reads_back_without_its_keys() {
+    let (config, _) = Config::from_toml(FILE).unwrap();
+    let logged = config.to_redacted_toml();
+    assert!(!logged.contains("first-key") && !logged.contains("signing-secret") && !logged.contains("token-secret"), "{logged}");
+
+    let (reread, unknown) = Config::from_toml(&logged).unwrap();
+    assert!(unknown.is_empty(), "{unknown:?}");
+    assert_eq!(reread.api_keys, ["<redacted>", "<redacted>"]);
+    assert_eq!(reread.upload_hmac_secret.as_deref(), Some("<redacted>"));
+    assert_eq!(reread.jwt_hs256_secret.as_deref(), Some("<redacted>"));
+    assert_eq!(reread.request_timeout, config.request_timeout);
+    assert_eq!(reread.bind_addr, config.bind_addr);
+    assert_eq!(reread.cors_allowed_origins, config.cors_allowed_origins);
+}
+
+#[test]
+fn environment_variables_override_the_file() {
+    // The only test in this binary touching the environment, so nothing races it
+    unsafe {
+        std::env::set_var("REQUEST_TIMEOUT_SECS", "45");
+        std::env::set_var("PORT", "8080");
+        std::env::set_var("API_KEYS", "");
+    }
+    let (file, _) = Config::from_toml(FILE).unwrap();
+    let config = file.with_env().unwrap();
+    assert_eq!(config.request_timeout, Duration::from_secs(45));
+    assert_eq!(config.bind_addr.unwrap().to_string(), "127.0.0.1:8080");
+    // An empty list does not wipe out the file's keys
+    assert_eq!(config.api_keys, ["first-key", "second-key"]);
+    assert_eq!(config.max_concurrent_decodes, 3);
+
+    // Half a TLS configuration stops startup instead of silently serving HTTP
+    unsafe {
+        std::env::set_var("TLS_CERT_PATH", "");
+        std::env::set_var("TLS_KEY_PATH", "/etc/tls/other-key.pem");
+    }
+    let err = Config::default().with_env().unwrap_err();
+    assert_eq!(err, "TLS_KEY_PATH is set but TLS_CERT_PATH is not; set both to serve HTTPS");
+    unsafe {
+        std::env::set_var("TLS_CERT_PATH", "/etc/tls/other-cert.pem");
+    }
+    let tls = Config::default().with_env().unwrap().tls.unwrap();
+    assert_eq!(tls.key_path.to_str(), Some("/etc/tls/other-key.pem"));
+}
@@ -0,0 +1,111 @@
+//! Cross-origin policy from `CORS_ALLOWED_ORIGINS` and friends.
+
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+};
+use common::test_app;
+use tower::ServiceExt;
+use webcalculation::cors::OriginPattern;
+use webcalculation::server::Config;
+
+fn restricted(origins: &[&str]) -> Config {
+    Config {
+        cors_allowed_origins: origins.iter().map(|origin| origin.parse().unwrap()).collect(),
+        ..Config::default()
+    }
+}
+
+/// Sends a browser preflight for `POST /calculate-intensity` from `origin`.
+async fn preflight(config: Config, origin: &str) -> (StatusCode, HeaderMap) {
+    let request = Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/calculate-intensity")
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+        .body(Body::empty())
+        .unwrap();
+    let response = test_app(config).oneshot(request).await.unwrap();
+    (response.status(), response.headers().clone())
+}
+
+fn allowed_origin(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).map(|value| value.to_str().unwrap())
+}
+
+#[tokio::test]
+async fn every_origin_is_allowed_by_default() {
+    let (status, headers) = preflight(Config::default(), "https://anywhere.example").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(allowed_origin(&headers), Some("*"));
+}
+
+#[tokio::test]
+async fn listed_origins_pass_preflight() {
+    let (status, headers) = preflight(restricted(&["https://app.example.com"]), "https://app.example.com").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(allowed_origin(&headers), Some("https://app.example.com"));
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET,POST");
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+}
+
+#[tokio::test]
+async fn other_origins_are_refused() {
+    for origin in ["https://evil.example.com", "http://app.example.com", "https://app.example.com:8443"] {
+        let (_, headers) = preflight(restricted(&["https://app.example.com"]), origin).await;
+        assert_eq!(allowed_origin(&headers), None, "{origin}");
+    }
+}
+
+#[tokio::test]
+async fn wildcards_cover_subdomains_only() {
+    let config = || restricted(&["https://*.example.com"]);
+    for origin in ["https://app.example.com", "https://a.b.example.com"] {
+        let (_, headers) = preflight(config(), origin).await;
+        assert_eq!(allowed_origin(&headers), Some(origin));
+    }
+    for origin in ["https://example.com", "https://evilexample.com", "http://app.example.com"] {
+        let (_, headers) = preflight(config(), origin).await;
+        assert_eq!(allowed_origin(&headers), None, "{origin}");
+    }
+}
+
+#[tokio::test]
+async fn credentials_and_methods_are_configurable() {
+    let config = Config {
+        cors_allowed_methods: vec![Method::POST],
+        cors_allow_credentials: true,
+        ..restricted(&["https://app.example.com"])
+    };
+    let (_, headers) = preflight(config, "https://app.example.com").await;
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "POST");
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+}
+
+#[tokio::test]
+async fn listed_origins_can_read_the_request_id() {
+    let request = Request::get("/health")
+        .header(header::ORIGIN, "https://app.example.com")
+        .header("x-request-id", "gateway-42")
+        .body(Body::empty())
+        .unwrap();
+    let response = test_app(restricted(&["https://app.example.com"])).oneshot(request).await.unwrap();
+    assert_eq!(response.headers()["x-request-id"], "gateway-42");
+    let exposed = response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS].to_str().unwrap();
+    assert!(exposed.split(',').any(|name| name.trim() == "x-request-id"), "{exposed}");
+    assert!(exposed.split(',').any(|name| name.trim() == "x-average-intensity"), "{exposed}");
+}
+
+#[test]
+fn origin_patterns_are_validated() {
+    assert_eq!("HTTPS://App.Example.com/".parse(), Ok(OriginPattern::Exact("https://app.example.com".to_string())));
+    assert_eq!("http://*.local:8080".parse::<OriginPattern>().unwrap().to_string(), "http://*.local:8080");
+    for invalid in ["app.example.com", "https://", "https://*example.com", "https://app.*.com", "https://a.com/path"] {
+        let err = invalid.parse::<OriginPattern>().unwrap_err();
+        assert!(err.starts_with("invalid origin"), "{invalid}: {err}");
+    }
+}
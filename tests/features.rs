@@ -0,0 +1,45 @@
+//! The feature matrix: the crate, tests included, must build without default
+//! features and with each feature on its own. Slow, so run on demand with
+//! `cargo test --test features -- --ignored`.
+
+use std::path::Path;
+use std::process::Command;
+
+/// Features that link system libraries, checked only where those are found.
+const NEEDS_SYSTEM_LIBS: &[(&str, &str)] = &[("avif", "dav1d"), ("modern-formats", "dav1d")];
+
+fn features() -> Vec<String> {
+    let manifest = std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")).unwrap();
+    let manifest: toml::Table = manifest.parse().unwrap();
+    manifest["features"].as_table().unwrap().keys().filter(|name| *name != "default").cloned().collect()
+}
+
+fn has_system_lib(feature: &str) -> bool {
+    let Some((_, lib)) = NEEDS_SYSTEM_LIBS.iter().find(|(name, _)| *name == feature) else {
+        return true;
+    };
+    Command::new("pkg-config").args(["--exists", lib]).status().is_ok_and(|status| status.success())
+}
+
+#[test]
+#[ignore = "runs cargo clippy once per feature"]
+fn every_feature_builds_on_its_own() {
+    let mut combinations = vec![None];
+    combinations.extend(features().into_iter().filter(|feature| has_system_lib(feature)).map(Some));
+    for feature in combinations {
+        let mut clippy = Command::new(env!("CARGO"));
+        clippy.args(["clippy", "--all-targets", "--no-default-features"]);
+        if let Some(feature) = &feature {
+            clippy.args(["--features", feature]);
+        }
+        // A target directory of its own, so the main build is not invalidated
+        let target_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/feature-matrix");
+        let status = clippy
+            .args(["--", "-D", "warnings"])
+            .env("CARGO_TARGET_DIR", target_dir)
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "features: {}", feature.as_deref().unwrap_or("none"));
+    }
+}
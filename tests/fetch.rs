@@ -0,0 +1,159 @@
+//! `GET /calculate-intensity?url=`, fetching from a local image server.
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::response::{IntoResponse, Redirect};
+use axum::{routing::get as get_route, Router};
+use common::*;
+use std::net::{IpAddr, SocketAddr};
+use tower::ServiceExt;
+use webcalculation::fetch::is_public;
+use webcalculation::server::{Config, IntensityResponse};
+
+/// Serves `/image.png`, `/page.html`, `/missing` and `/redirect`, on a free
+/// loopback port.
+async fn image_server() -> SocketAddr {
+    let router = Router::new()
+        .route("/image.png", get_route(|| async { ([(header::CONTENT_TYPE, "image/png")], test_png()) }))
+        .route("/page.html", get_route(|| async { ([(header::CONTENT_TYPE, "text/html")], "<html></html>") }))
+        .route("/missing", get_route(|| async { StatusCode::NOT_FOUND.into_response() }))
+        .route("/redirect", get_route(|| async { Redirect::temporary("/image.png") }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr
+}
+
+fn private_allowed() -> Config {
+    Config {
+        allow_private_fetch_urls: true,
+        ..Config::default()
+    }
+}
+
+fn by_url(url: &str) -> Request<Body> {
+    get(&format!("/calculate-intensity?url={}", url.replace(' ', "%20")))
+}
+
+#[tokio::test]
+async fn fetched_images_are_analysed_like_uploads() {
+    let addr = image_server().await;
+    let response = test_app(private_allowed()).oneshot(by_url(&format!("http://{addr}/image.png"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=300");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let fetched: IntensityResponse = serde_json::from_slice(&body).unwrap();
+
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    let uploaded: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(fetched.average_intensity, uploaded.average_intensity);
+
+    let (status, _) = send(test_app(private_allowed()), by_url(&format!("http://{addr}/redirect"))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn authenticated_results_stay_out_of_shared_caches() {
+    let addr = image_server().await;
+    let config = Config {
+        api_keys: vec!["alpha-key".to_string()],
+        ..private_allowed()
+    };
+    let mut request = by_url(&format!("http://{addr}/image.png"));
+    request.headers_mut().insert("x-api-key", "alpha-key".parse().unwrap());
+    let response = test_app(config).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "private, max-age=300");
+}
+
+#[tokio::test]
+async fn missing_and_invalid_urls_are_rejected() {
+    for uri in [
+        "/calculate-intensity".to_string(),
+        "/calculate-intensity?url=".to_string(),
+        by_url("not a url").uri().to_string(),
+        by_url("ftp://example.com/image.png").uri().to_string(),
+    ] {
+        let (status, body) = send(test_app(private_allowed()), get(&uri)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+        assert_eq!(error_code(&body), "invalid_parameter", "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn a_body_is_refused() {
+    let mut request = by_url("http://127.0.0.1/image.png");
+    *request.body_mut() = Body::from("unexpected");
+    request.headers_mut().insert(header::CONTENT_LENGTH, "10".parse().unwrap());
+    let (status, body) = send(test_app(private_allowed()), request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error_message(&body).contains("takes no body"), "{}", error_message(&body));
+}
+
+#[tokio::test]
+async fn private_addresses_are_refused_by_default() {
+    let addr = image_server().await;
+    for (url, expected) in [
+        (format!("http://{addr}/image.png"), "non-public address 127.0.0.1"),
+        (format!("http://localhost:{}/image.png", addr.port()), "localhost has no public address"),
+    ] {
+        let (status, body) = send(test_app(Config::default()), by_url(&url)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{url}");
+        assert!(error_message(&body).contains(expected), "{url}: {}", error_message(&body));
+    }
+}
+
+#[tokio::test]
+async fn fetch_failures_are_reported() {
+    let addr = image_server().await;
+    let (status, body) = send(test_app(private_allowed()), by_url(&format!("http://{addr}/missing"))).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(error_code(&body), "fetch_failed");
+    assert!(error_message(&body).contains("404"));
+
+    let (status, body) = send(test_app(private_allowed()), by_url("http://127.0.0.1:1/image.png")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(error_code(&body), "fetch_failed");
+
+    let (status, _) = send(test_app(private_allowed()), by_url(&format!("http://{addr}/page.html"))).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let config = Config {
+        max_upload_bytes: 16,
+        ..private_allowed()
+    };
+    let (status, body) = send(test_app(config), by_url(&format!("http://{addr}/image.png"))).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error_code(&body), "too_large");
+}
+
+#[test]
+fn only_public_addresses_count_as_public() {
+    for ip in [
+        "93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946", "::ffff:93.184.216.34", "64:ff9b::5db8:d822",
+        "2002:5db8:d822::1", "192.0.1.1", "198.20.0.1", "223.255.255.255",
+    ] {
+        assert!(is_public(ip.parse::<IpAddr>().unwrap()), "{ip}");
+    }
+    for ip in [
+        "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+        "255.255.255.255", "224.0.0.1", "192.0.0.8", "198.18.0.1", "198.19.255.255", "240.0.0.1", "::1", "::",
+        "fd00::1", "fe80::1", "fec0::1", "feff::1", "::ffff:127.0.0.1", "64:ff9b::7f00:1", "64:ff9b::a9fe:a9fe",
+        "64:ff9b:1::5db8:d822", "2002:7f00:1::1", "2002:c0a8:101::1",
+    ] {
+        assert!(!is_public(ip.parse::<IpAddr>().unwrap()), "{ip}");
+    }
+}
+
+#[tokio::test]
+async fn special_purpose_literals_are_refused() {
+    for host in [
+        "192.0.0.8", "198.18.0.1", "240.0.0.1", "[fec0::1]", "[64:ff9b::7f00:1]", "[2002:7f00:1::1]",
+    ] {
+        let (status, body) = send(test_app(Config::default()), by_url(&format!("http://{host}/image.png"))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{host}");
+        assert!(error_message(&body).contains("non-public address"), "{host}: {}", error_message(&body));
+    }
+}
@@ -0,0 +1,112 @@
+//! Format support that depends on the `exr`, `hdr`, `webp` and `avif` features.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{get, send, test_app, upload as upload_request};
+use webcalculation::server::{Config, SupportedFormat};
+
+async fn upload(data: &[u8]) -> (StatusCode, Vec<u8>) {
+    send(test_app(Config::default()), upload_request("/calculate-intensity", "image", data)).await
+}
+
+#[tokio::test]
+async fn supported_formats_track_the_build() {
+    let (status, body) = send(test_app(Config::default()), get("/supported-formats")).await;
+    assert_eq!(status, StatusCode::OK);
+    let formats: Vec<SupportedFormat> = serde_json::from_slice(&body).unwrap();
+    let find = |name: &str| formats.iter().find(|format| format.format == name).unwrap();
+
+    let png = find("png");
+    assert!(png.enabled);
+    assert_eq!(png.extensions, ["png"]);
+    assert_eq!(png.mime_types, ["image/png"]);
+    assert!(find("jpeg").enabled);
+    assert!(!find("pcx").enabled);
+    assert_eq!(png.feature, None);
+    for (name, feature, enabled) in [
+        ("openexr", "exr", cfg!(feature = "exr")),
+        ("hdr", "hdr", cfg!(feature = "hdr")),
+        ("webp", "webp", cfg!(feature = "webp")),
+        ("avif", "avif", cfg!(feature = "avif")),
+    ] {
+        assert_eq!(find(name).enabled, enabled, "{name}");
+        assert_eq!(find(name).feature.as_deref(), Some(feature), "{name}");
+    }
+}
+
+#[cfg(not(all(feature = "exr", feature = "hdr", feature = "webp", feature = "avif")))]
+mod disabled {
+    use super::*;
+    use webcalculation::server::ErrorResponse;
+
+    async fn assert_unsupported(data: &[u8], name: &str, feature: &str) {
+        let (status, body) = upload(data).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, "unsupported_format");
+        assert_eq!(
+            error.error,
+            format!("{name} format not supported in this build (enable the `{feature}` cargo feature); see /supported-formats")
+        );
+    }
+
+    #[cfg(not(feature = "avif"))]
+    #[tokio::test]
+    async fn avif_is_unsupported() {
+        assert_unsupported(b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf", "AVIF", "avif").await;
+    }
+
+    #[cfg(not(feature = "webp"))]
+    #[tokio::test]
+    async fn webp_is_unsupported() {
+        assert_unsupported(b"RIFF\x1a\0\0\0WEBPVP8L\x0d\0\0\0\x2f\0\0\0\x10\x07\x10\x11\x11\x88\x88\xfe\x07\0", "WEBP", "webp").await;
+    }
+
+    #[cfg(not(feature = "hdr"))]
+    #[tokio::test]
+    async fn hdr_is_unsupported() {
+        assert_unsupported(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 1\n\0\0\0\0", "HDR", "hdr").await;
+    }
+
+    #[cfg(not(feature = "exr"))]
+    #[tokio::test]
+    async fn exr_is_unsupported() {
+        assert_unsupported(b"\x76\x2f\x31\x01\x02\0\0\0", "EXR", "exr").await;
+    }
+}
+
+#[cfg(feature = "webp")]
+mod enabled {
+    use super::*;
+    use image::{codecs::webp::WebPEncoder, ExtendedColorType, Rgb, RgbImage};
+    use webcalculation::analysis::{decode_image, DecodeLimits};
+    use webcalculation::server::IntensityResponse;
+
+    fn webp() -> Vec<u8> {
+        let img = RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 90]));
+        let mut data = Vec::new();
+        WebPEncoder::new_lossless(&mut data)
+            .encode(img.as_raw(), 6, 4, ExtendedColorType::Rgb8)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn webp_is_detected_and_decoded() {
+        let data = webp();
+        assert_eq!(image::guess_format(&data).unwrap(), image::ImageFormat::WebP);
+        let img = decode_image(&data, &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (6, 4));
+    }
+
+    #[tokio::test]
+    async fn webp_upload_is_analysed() {
+        let (status, body) = upload(&webp()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+        // Lossless, so the mean is exactly that of the source pixels.
+        let expected = (6.0 * (0.0 + 60.0 + 120.0 + 180.0) + 4.0 * (0.0 + 40.0 + 80.0 + 120.0 + 160.0 + 200.0) + 24.0 * 90.0) / 72.0;
+        assert!((response.average_intensity - expected).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,1335 @@
+mod common;
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    http::{header, Request, StatusCode},
+    routing,
+    Router,
+};
+use common::*;
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Rgb, RgbImage};
+use webcalculation::analysis::{encode_png, perceptual_hash};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_stream::StreamExt;
+use tower::ServiceExt;
+use webcalculation::server::{
+    route_paths, run_blocking, with_middleware, ApiError, AppState, BatchSummary, BatchSummaryResponse, ChannelCorrelationResponse, ColorTemperatureResponse, Config, DuplicateCheckResponse, ErrorResponse, HsvStatsResponse, IntensityResponse,
+    IntensityScale, IntensityStreamLine, RgbHistogramResponse, StatsResponse, VersionResponse, Weighting, SWAGGER_UI_EMBEDDED,
+};
+
+#[tokio::test]
+async fn health_reports_ok() {
+    let (status, body) = send(test_app(Config::default()), get("/health")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"OK");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn health_answers_while_a_large_image_decodes() {
+    // A 12-megapixel upload keeps a blocking thread busy for a while; the
+    // runtime's only worker thread must stay free for the probe meanwhile
+    let big = encode_png(&DynamicImage::ImageRgb8(RgbImage::from_fn(4000, 3000, |x, y| {
+        Rgb([(x / 16) as u8, (y / 12) as u8, 128])
+    })))
+    .unwrap();
+    let app = test_app(Config::default());
+    let slow = tokio::spawn(send(app.clone(), upload("/calculate-intensity", "image", &big)));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let started = std::time::Instant::now();
+    let (status, body) = send(app, get("/health")).await;
+    let elapsed = started.elapsed();
+    assert_eq!((status, body.as_slice()), (StatusCode::OK, b"OK".as_slice()));
+    assert!(!slow.is_finished(), "the upload finished before the probe could overlap it");
+    assert!(elapsed < Duration::from_millis(200), "/health took {elapsed:?}");
+    assert_eq!(slow.await.unwrap().0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn openapi_document_lists_the_endpoints() {
+    let (status, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let paths = doc["paths"].as_object().unwrap();
+    for path in ["/calculate-intensity", "/unique-colors", "/threshold", "/coverage", "/segment-stats", "/channel-correlation", "/histogram/rgb", "/heatmap", "/grayscale", "/calculate-intensity/batch/summary", "/live", "/ready", "/health"] {
+        assert!(paths.contains_key(path), "missing {path}");
+    }
+    assert!(doc["paths"]["/calculate-intensity"]["get"]["parameters"][0]["name"] == "url");
+    let operation = &doc["paths"]["/calculate-intensity"]["post"];
+    let description = operation["requestBody"]["description"].as_str().unwrap();
+    assert!(description.contains("20971520 bytes"));
+    for status in ["400", "408", "413", "415", "422", "503"] {
+        let schema = &operation["responses"][status]["content"]["application/json"]["schema"]["$ref"];
+        assert_eq!(schema, "#/components/schemas/ErrorResponse", "status {status}");
+    }
+}
+
+#[tokio::test]
+async fn openapi_yaml_matches_the_json_document() {
+    let response = test_app(Config::default()).oneshot(get("/api-docs/openapi.yaml")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/yaml; charset=utf-8");
+    let yaml = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let from_yaml: serde_json::Value = serde_yaml::from_slice(&yaml).unwrap();
+
+    let (_, json) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    assert_eq!(from_yaml, serde_json::from_slice::<serde_json::Value>(&json).unwrap());
+}
+
+#[tokio::test]
+async fn base_path_reaches_the_swagger_ui_and_the_openapi_servers() {
+    let (_, body) = send(test_app(Config::default()), get("/swagger-ui")).await;
+    assert!(String::from_utf8(body).unwrap().contains("'/api-docs/openapi.json'"));
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(doc.get("servers").is_none());
+
+    let config = Config { base_path: "/intensity".to_string(), ..Config::default() };
+    let (status, body) = send(test_app(config.clone()), get("/swagger-ui")).await;
+    assert_eq!(status, StatusCode::OK);
+    let page = String::from_utf8(body).unwrap();
+    assert!(page.contains("'/intensity/api-docs/openapi.json'"));
+    assert_eq!(page.contains("src=\"/intensity/swagger-ui/swagger-ui-bundle.js\""), SWAGGER_UI_EMBEDDED);
+    let (_, body) = send(test_app(config), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(doc["servers"][0]["url"], "/intensity");
+}
+
+#[tokio::test]
+async fn swagger_ui_needs_no_internet() {
+    for path in ["/swagger-ui", "/swagger-ui/"] {
+        let (status, body) = send(test_app(Config::default()), get(path)).await;
+        assert_eq!(status, StatusCode::OK, "{path}");
+        let page = String::from_utf8(body).unwrap();
+        assert!(!page.contains("https://"), "{page}");
+        assert!(page.contains("<script src=\"/swagger-ui/swagger-ui-bundle.js\"></script>"), "{page}");
+    }
+
+    let response = test_app(Config::default()).oneshot(get("/swagger-ui/swagger-ui.css")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/css; charset=utf-8");
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=86400");
+    // The assets are committed, so every checkout embeds them
+    for asset in ["swagger-ui-bundle.js", "swagger-ui-standalone-preset.js"] {
+        let (status, body) = send(test_app(Config::default()), get(&format!("/swagger-ui/{asset}"))).await;
+        assert_eq!(status, StatusCode::OK, "{asset}");
+        assert!(!body.is_empty(), "{asset}");
+    }
+    let (status, _) = send(test_app(Config::default()), get("/swagger-ui/index.js")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn routes_can_be_nested_under_the_base_path() {
+    let config = Config { base_path: "/intensity".to_string(), nest_base_path: true, ..Config::default() };
+    let (status, body) = send(test_app(config.clone()), get("/intensity/health")).await;
+    assert_eq!((status, body.as_slice()), (StatusCode::OK, b"OK".as_slice()));
+    let (status, _) = send(test_app(config.clone()), upload("/intensity/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(send(test_app(config), get("/health")).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn version_reports_the_build() {
+    let (status, body) = send(test_app(Config::default()), get("/version")).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: VersionResponse = serde_json::from_slice(&body).unwrap();
+    let parts: Vec<_> = response.version.split('.').collect();
+    assert_eq!(parts.len(), 3, "{}", response.version);
+    assert!(parts.iter().all(|part| part.split(['-', '+']).next().unwrap().parse::<u64>().is_ok()));
+    assert_eq!(response.version, env!("CARGO_PKG_VERSION"));
+    assert!(response.build_timestamp.ends_with('Z'));
+    let compiled = [
+        ("simd", cfg!(feature = "simd")),
+        ("exr", cfg!(feature = "exr")),
+        ("hdr", cfg!(feature = "hdr")),
+        ("webp", cfg!(feature = "webp")),
+        ("avif", cfg!(feature = "avif")),
+        ("modern-formats", cfg!(feature = "modern-formats")),
+        ("otel", cfg!(feature = "otel")),
+    ];
+    let expected: Vec<_> = compiled.iter().filter(|(_, on)| *on).map(|(name, _)| name.to_string()).collect();
+    assert_eq!(response.features, expected);
+
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(doc["info"]["version"], response.version);
+}
+
+#[tokio::test]
+async fn stats_count_requests_bytes_and_decode_failures() {
+    let app = test_app(Config::default());
+    let data = test_png();
+    assert_eq!(send(app.clone(), upload("/calculate-intensity", "image", &data)).await.0, StatusCode::OK);
+    let (status, _) = send(app.clone(), upload("/calculate-intensity", "image", b"not an image")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    send(app.clone(), get("/health")).await;
+    send(app.clone(), get("/no-such-route")).await;
+
+    let (status, body) = send(app, get("/stats")).await;
+    assert_eq!(status, StatusCode::OK);
+    let stats: StatsResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(stats.requests_by_route["/calculate-intensity"], 2);
+    assert_eq!(stats.requests_by_route["/health"], 1);
+    assert_eq!(stats.requests_by_route["/stats"], 1);
+    assert_eq!(stats.requests_total, 4);
+    assert_eq!(stats.image_bytes_processed, (data.len() + b"not an image".len()) as u64);
+    assert_eq!(stats.decode_failures, 1);
+    assert_eq!(stats.analyses_in_flight, 0);
+    assert_eq!(stats.cache_entries, Some(1));
+}
+
+#[tokio::test]
+async fn stalled_uploads_time_out_with_a_408() {
+    let config = Config { request_timeout: Duration::from_millis(100), ..Config::default() };
+    let mut head = multipart_body("image", &test_png());
+    head.truncate(head.len() / 2);
+    let stalled = tokio_stream::iter([Ok::<_, std::io::Error>(Bytes::from(head))]).chain(tokio_stream::pending());
+    let request = Request::post("/calculate-intensity")
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .header("x-request-id", "gateway-7")
+        .body(Body::from_stream(stalled))
+        .unwrap();
+
+    let response = test_app(config).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    assert_eq!(response.headers()["x-request-id"], "gateway-7");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(error_code(&body), "timeout");
+    assert_eq!(error_body(&body).request_id.as_deref(), Some("gateway-7"));
+}
+
+#[tokio::test]
+async fn calculates_intensity_of_an_uploaded_png() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    let expected = (31.0 * 764.0 / 3.0) / 32.0;
+    assert!((response.average_intensity - expected).abs() < 1e-9);
+    assert_eq!((response.darkest_pixel.x, response.darkest_pixel.y), (3, 2));
+    assert_eq!((response.brightest_pixel.x, response.brightest_pixel.y), (0, 0));
+    assert!(!response.cached);
+    assert_eq!(response.bit_depth, 8);
+    assert!(response.trimmed_mean_intensity.is_none());
+}
+
+#[tokio::test]
+async fn the_average_is_repeated_in_a_header() {
+    for (query, expected) in [("", None), ("?round=2", Some("246.71")), ("?scale=unit&round=3", Some("0.967"))] {
+        let uri = format!("/calculate-intensity{query}");
+        let response = test_app(Config::default()).oneshot(upload(&uri, "image", &test_png())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = response.headers()["x-average-intensity"].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // serde_json's default float parsing may be off in the last digit
+        assert!((header.parse::<f64>().unwrap() - body["average_intensity"].as_f64().unwrap()).abs() < 1e-9, "{query}");
+        if let Some(expected) = expected {
+            assert_eq!(header, expected, "{query}");
+        }
+    }
+
+    let response = test_app(Config::default()).oneshot(upload("/calculate-intensity", "image", b"not an image")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(response.headers().get("x-average-intensity").is_none());
+}
+
+#[tokio::test]
+async fn round_limits_the_decimals_of_every_stat() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?round=2", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response["average_intensity"], 246.71);
+    assert_eq!(response["brightest_pixel"]["x"], 0);
+    assert_eq!(response["bit_depth"], 8);
+    for field in ["/std_dev", "/contrast_rms", "/megapixels", "/processing_ms", "/exposure/ev_offset"] {
+        let value = response.pointer(field).unwrap().as_f64().unwrap();
+        assert_eq!(value, (value * 100.0).round() / 100.0, "{field}: {value}");
+    }
+
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity?round=0", "image", &test_png())).await;
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["average_intensity"], 247.0);
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?round=11", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_message(&body), "round must be between 0 and 10");
+}
+
+#[tokio::test]
+async fn color_family_follows_the_saturation_threshold() {
+    let pale_red = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb([250u8, 230, 230])))).unwrap();
+    for (query, family) in [("", "neutral"), ("?saturation_threshold=0.05", "red")] {
+        let uri = format!("/calculate-intensity{query}");
+        let (status, body) = send(test_app(Config::default()), upload(&uri, "image", &pale_red)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<IntensityResponse>(&body).unwrap().color_family, family, "{query}");
+    }
+
+    let uri = "/calculate-intensity?saturation_threshold=1.5";
+    let (status, body) = send(test_app(Config::default()), upload(uri, "image", &pale_red)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_message(&body), "saturation_threshold must be between 0 and 1");
+}
+
+#[tokio::test]
+async fn monochrome_detection_follows_its_thresholds() {
+    // Gray, with a quarter of the pixels faintly tinted (channels 12 apart)
+    let tinted = ImageBuffer::from_fn(4, 4, |x, _| if x == 0 { Rgb([140u8, 128, 128]) } else { Rgb([128, 128, 128]) });
+    let data = encode_png(&DynamicImage::ImageRgb8(tinted)).unwrap();
+    for (query, fraction, monochrome) in [
+        ("", 0.0, true),
+        ("?color_threshold=8", 0.25, false),
+        ("?color_threshold=8&monochrome_percent=30", 0.25, true),
+    ] {
+        let uri = format!("/calculate-intensity{query}");
+        let (status, body) = send(test_app(Config::default()), upload(&uri, "image", &data)).await;
+        assert_eq!(status, StatusCode::OK);
+        let result: IntensityResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!((result.color_fraction, result.is_monochrome), (fraction, monochrome), "{query}");
+    }
+
+    for (query, message) in [
+        ("color_threshold=256", "color_threshold must be between 0 and 255"),
+        ("monochrome_percent=-1", "monochrome_percent must be a percentage between 0 and 100"),
+    ] {
+        let (status, body) = send(test_app(Config::default()), upload(&format!("/calculate-intensity?{query}"), "image", &data)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error_message(&body), message);
+    }
+}
+
+#[tokio::test]
+async fn channel_correlation_reports_a_matrix() {
+    let (status, body) = send(test_app(Config::default()), upload("/channel-correlation", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let response: ChannelCorrelationResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response.channels, ["r", "g", "b"]);
+    assert_eq!(response.pixel_count, 32);
+    assert_eq!(response.correlation.len(), 3);
+    for (row, entries) in response.correlation.iter().enumerate() {
+        assert_eq!(entries.len(), 3);
+        assert!((entries[row].unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(entries[(row + 1) % 3], response.correlation[(row + 1) % 3][row]);
+    }
+}
+
+#[tokio::test]
+async fn rgb_histogram_of_solid_red_spikes_at_the_extremes() {
+    let red = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(5, 4, Rgb([255u8, 0, 0])))).unwrap();
+    let (status, body) = send(test_app(Config::default()), upload("/histogram/rgb", "image", &red)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let response: RgbHistogramResponse = serde_json::from_slice(&body).unwrap();
+    for (histogram, spike) in [(&response.red, 255), (&response.green, 0), (&response.blue, 0), (&response.luminance, 85)] {
+        assert_eq!(histogram.len(), 256);
+        assert_eq!(histogram[spike], 20);
+        assert_eq!(histogram.iter().sum::<u64>(), 20);
+    }
+}
+
+#[tokio::test]
+async fn json_is_gzipped_on_request_and_pngs_never_are() {
+    let with_gzip = |mut request: Request<Body>| {
+        request.headers_mut().insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+        request
+    };
+    let response = test_app(Config::default())
+        .oneshot(with_gzip(upload("/histogram/rgb", "image", &test_png())))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    let compressed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let mut json = Vec::new();
+    std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&compressed[..]), &mut json).unwrap();
+    let histograms: RgbHistogramResponse = serde_json::from_slice(&json).unwrap();
+    assert_eq!(histograms.luminance.iter().sum::<u64>(), 32);
+
+    for uri in ["/heatmap", "/grayscale", "/normalize", "/equalize", "/threshold?value=128"] {
+        let response = test_app(Config::default()).oneshot(with_gzip(upload(uri, "image", &test_png()))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png", "{uri}");
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING), "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn center_weighting_raises_the_average_of_a_spotlit_upload() {
+    let img = ImageBuffer::from_fn(30, 30, |x, y| {
+        let lit = (10..20).contains(&x) && (10..20).contains(&y);
+        Rgb(if lit { [250, 250, 250] } else { [5, 5, 5] })
+    });
+    let data = encode_png(&DynamicImage::ImageRgb8(img)).unwrap();
+    let average = |query: &'static str| {
+        let data = data.clone();
+        async move {
+            let (status, body) = send(test_app(Config::default()), upload(query, "image", &data)).await;
+            assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+            serde_json::from_slice::<IntensityResponse>(&body).unwrap()
+        }
+    };
+
+    let uniform = average("/calculate-intensity").await;
+    assert_eq!(uniform.weighting, Weighting::Uniform);
+    let center = average("/calculate-intensity?weighting=center&center_sigma=0.1").await;
+    assert_eq!(center.weighting, Weighting::Center);
+    assert!(center.average_intensity > uniform.average_intensity + 100.0);
+    // Only the mean is weighted
+    assert_eq!(center.median_intensity, uniform.median_intensity);
+
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity?center_sigma=0", "image", &data)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn downscale_to_bounds_the_analysed_size() {
+    // 2x2 blocks of one value each, so halving the size keeps the mean exactly
+    let img = ImageBuffer::from_fn(400, 200, |x, y| Rgb([((x / 2 + y / 2) % 256) as u8, 40, 200]));
+    let data = encode_png(&DynamicImage::ImageRgb8(img)).unwrap();
+
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &data)).await;
+    let full: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!(full.effective_dimensions.is_none());
+
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?downscale_to=200", "image", &data)).await;
+    assert_eq!(status, StatusCode::OK);
+    let downscaled: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    let dimensions = downscaled.effective_dimensions.unwrap();
+    assert_eq!((dimensions.width, dimensions.height), (200, 100));
+    assert!((downscaled.average_intensity - full.average_intensity).abs() < 1e-9);
+    // The size fields still describe the upload
+    assert_eq!(downscaled.megapixels, full.megapixels);
+
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?downscale_to=0", "image", &data)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "invalid_parameter");
+}
+
+#[tokio::test]
+async fn sixteen_bit_uploads_report_their_bit_depth() {
+    let img = ImageBuffer::from_fn(16, 16, |x, y| Rgb([(x * 4000 + y) as u16, 1234, (y * 4000) as u16]));
+    let total: u64 = img.pixels().flat_map(|pixel: &Rgb<u16>| pixel.0).map(u64::from).sum();
+    let expected = total as f64 / (3.0 * 256.0) * 255.0 / 65535.0;
+    let data = encode_png(&DynamicImage::ImageRgb16(img)).unwrap();
+
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &data)).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response.bit_depth, 16);
+    assert!((response.average_intensity - expected).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn repeated_uploads_are_served_from_cache() {
+    let app = test_app(Config::default());
+    let (_, first) = send(app.clone(), upload("/calculate-intensity", "image", &test_png())).await;
+    let (status, second) = send(app, upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let first: IntensityResponse = serde_json::from_slice(&first).unwrap();
+    let second: IntensityResponse = serde_json::from_slice(&second).unwrap();
+    assert!(second.cached);
+    assert_eq!(second.processing_ms, 0.0);
+    assert_eq!(first.average_intensity, second.average_intensity);
+}
+
+#[tokio::test]
+async fn corrupt_image_is_unprocessable() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", b"not an image")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), "decode_error");
+    assert!(error_message(&body).starts_with("unsupported or corrupt image"));
+    assert!(error_message(&body).ends_with("; see /supported-formats"));
+}
+
+#[tokio::test]
+async fn request_ids_round_trip_and_reach_error_bodies() {
+    let mut request = upload("/calculate-intensity", "image", b"not an image");
+    request.headers_mut().insert("x-request-id", "client-report-17".parse().unwrap());
+    let response = test_app(Config::default()).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(response.headers()["x-request-id"], "client-report-17");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(error_body(&body).request_id.as_deref(), Some("client-report-17"));
+
+    // Without one, each request gets a fresh UUID
+    let mut generated = Vec::new();
+    for _ in 0..2 {
+        let response = test_app(Config::default()).oneshot(get("/no-such-route")).await.unwrap();
+        let id = response.headers()["x-request-id"].to_str().unwrap().to_string();
+        assert_eq!((id.len(), id.matches('-').count()), (36, 4), "{id}");
+        generated.push(id);
+    }
+    assert_ne!(generated[0], generated[1]);
+}
+
+#[tokio::test]
+async fn ambiguous_cmyk_jpeg_is_unprocessable() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &cmyk_jpeg([0, 0, 0, 0], false))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), "decode_error");
+    assert!(error_message(&body).contains("CMYK"));
+
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &cmyk_jpeg([0, 0, 0, 0], true))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn default_field_names_are_accepted() {
+    for field in ["image", "file", "upload"] {
+        let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity", field, &test_png())).await;
+        assert_eq!(status, StatusCode::OK, "{field}");
+        let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response.detected_format, "png");
+    }
+}
+
+#[tokio::test]
+async fn field_names_are_configurable() {
+    let config = || Config { upload_field_names: vec!["photo".to_string()], ..Config::default() };
+    let body = text_fields_body(&[("image", "cat.png")]);
+    let (status, body) = send(test_app(config()), multipart_request("/calculate-intensity", body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error_message(&body).starts_with("no image found; send it in a field named 'photo', or"));
+    assert_eq!(error_body(&body).accepted_fields.unwrap(), ["photo"]);
+
+    let (status, _) = send(test_app(config()), upload("/calculate-intensity", "photo", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_lone_file_part_is_accepted_under_any_name() {
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity", "picture", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // With two candidates neither is guessed at
+    let body = multipart_files(&[("picture", &test_png()), ("thumbnail", &test_png())]);
+    let (status, body) = send(test_app(Config::default()), multipart_request("/calculate-intensity", body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error_message(&body).ends_with("received fields 'picture', 'thumbnail'"), "{}", error_message(&body));
+}
+
+#[tokio::test]
+async fn missing_image_field_lists_received_fields() {
+    let body = text_fields_body(&[("picture", "cat.png"), ("caption", "a cat")]);
+    let (status, body) = send(test_app(Config::default()), multipart_request("/calculate-intensity", body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "missing_field");
+    assert_eq!(
+        error_message(&body),
+        "no image found; send it in a field named 'image', 'file' or 'upload', or as the only file part; \
+         received fields 'picture', 'caption'"
+    );
+    assert_eq!(error_body(&body).accepted_fields.unwrap(), ["image", "file", "upload"]);
+
+    let (_, body) = send(test_app(Config::default()), multipart_request("/calculate-intensity", text_fields_body(&[]))).await;
+    assert!(error_message(&body).ends_with("; the request had no fields"));
+}
+
+#[tokio::test]
+async fn non_multipart_body_is_a_json_bad_request() {
+    let request = Request::post("/calculate-intensity")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from("{}"))
+        .unwrap();
+    let (status, body) = send(test_app(Config::default()), request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "body_read_error");
+    assert!(error_message(&body).contains("multipart"));
+}
+
+#[tokio::test]
+async fn truncated_multipart_body_fails_to_read() {
+    let mut body = multipart_body("image", &test_png());
+    body.truncate(body.len() - 20);
+    let request = Request::post("/calculate-intensity")
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from(body))
+        .unwrap();
+    let (status, body) = send(test_app(Config::default()), request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "body_read_error");
+    assert!(error_message(&body).starts_with("failed to read upload"));
+}
+
+#[tokio::test]
+async fn malformed_query_is_a_json_bad_request() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?trim=lots", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "invalid_parameter");
+    assert!(error_message(&body).starts_with("invalid query parameters"));
+}
+
+#[tokio::test]
+async fn out_of_range_trim_is_rejected() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?trim=50", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_message(&body), "trim must be a percentage between 0 and 49");
+}
+
+#[tokio::test]
+async fn oversized_upload_is_rejected() {
+    let config = Config { max_upload_bytes: 64, ..Config::default() };
+    let (status, body) = send(test_app(config), upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error_code(&body), "too_large");
+    assert_eq!(error_message(&body), "upload exceeds the maximum of 64 bytes");
+}
+
+#[tokio::test]
+async fn oversized_dimensions_have_their_own_code() {
+    let config = Config { max_image_width: 4, ..Config::default() };
+    let (status, body) = send(test_app(config), upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), "image_too_large");
+}
+
+#[tokio::test]
+async fn forged_huge_dimensions_are_rejected_under_the_default_cap() {
+    let forged = forged_png(100_000, 100_000);
+    assert!(forged.len() < 1024);
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &forged)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), "image_too_large");
+    assert!(error_message(&body).contains("100000x100000"), "{}", error_message(&body));
+}
+
+#[tokio::test]
+async fn non_image_content_types_are_rejected_before_decoding() {
+    for content_type in ["text/csv", "application/json", "application/pdf"] {
+        let request = upload_as("/calculate-intensity", Some(content_type), &test_png());
+        let (status, body) = send(test_app(Config::default()), request).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{content_type}");
+        assert_eq!(error_code(&body), "unsupported_content_type");
+        assert!(error_message(&body).contains(content_type));
+        assert!(error_message(&body).contains("image/*, application/octet-stream"));
+    }
+}
+
+#[tokio::test]
+async fn image_octet_stream_and_untyped_parts_are_accepted() {
+    for content_type in [Some("image/png"), Some("IMAGE/JPEG"), Some("application/octet-stream"), None] {
+        let request = upload_as("/calculate-intensity", content_type, &test_png());
+        let (status, _) = send(test_app(Config::default()), request).await;
+        assert_eq!(status, StatusCode::OK, "{content_type:?}");
+    }
+}
+
+#[tokio::test]
+async fn mismatched_content_type_is_a_warning() {
+    let app = test_app(Config::default());
+    let (status, body) = send(app.clone(), upload_as("/calculate-intensity", Some("image/jpeg"), &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response.detected_format, "png");
+    assert_eq!(response.warnings, ["declared image/jpeg but detected png"]);
+
+    // The cached result carries the warnings of the request being answered
+    let (_, body) = send(app, upload_as("/calculate-intensity", Some("image/png"), &test_png())).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!(response.cached);
+    assert!(response.warnings.is_empty());
+}
+
+#[tokio::test]
+async fn strict_mode_rejects_mismatched_content_type() {
+    let request = upload_as("/calculate-intensity?strict=true", Some("image/jpeg"), &test_png());
+    let (status, body) = send(test_app(Config::default()), request).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error_code(&body), "content_type_mismatch");
+    assert_eq!(error_message(&body), "declared image/jpeg but detected png");
+
+    for content_type in [Some("image/png"), Some("application/octet-stream"), None] {
+        let request = upload_as("/calculate-intensity?strict=true", content_type, &test_png());
+        let (status, _) = send(test_app(Config::default()), request).await;
+        assert_eq!(status, StatusCode::OK, "{content_type:?}");
+    }
+}
+
+#[tokio::test]
+async fn dimensions_are_summarised() {
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!((response.megapixels - 32e-6).abs() < 1e-12);
+    assert_eq!(response.aspect_ratio, 2.0);
+    assert_eq!(response.aspect_label.as_deref(), Some("2:1"));
+
+    let odd = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(7, 5, Rgb([9u8, 9, 9])))).unwrap();
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &odd)).await;
+    assert!(!String::from_utf8(body).unwrap().contains("aspect_label"));
+}
+
+#[tokio::test]
+async fn stream_emits_one_line_per_part() {
+    let black = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([0u8, 0, 0])))).unwrap();
+    let body = multipart_files(&[("first", &test_png()), ("broken", b"not an image"), ("third", &black)]);
+    let request = multipart_request("/calculate-intensity/stream?scale=unit", body);
+    let response = test_app(Config::default()).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let lines: Vec<IntensityStreamLine> = body
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines.iter().map(|line| line.index).collect::<Vec<_>>(), [0, 1, 2]);
+    assert_eq!(lines[0].field.as_deref(), Some("first"));
+    assert_eq!(lines[0].result.as_ref().unwrap().scale, IntensityScale::Unit);
+    assert!(lines[0].error.is_none());
+    assert_eq!(lines[1].error.as_ref().unwrap().code, "decode_error");
+    assert!(lines[1].result.is_none());
+    assert_eq!(lines[2].result.as_ref().unwrap().average_intensity, 0.0);
+}
+
+/// A batch upload whose first part is complete but whose body never ends.
+fn open_ended_batch(path: &str) -> Request<Body> {
+    let mut head = multipart_files(&[("first", &test_png())]);
+    head.truncate(head.len() - "--\r\n".len());
+    let stalled = tokio_stream::iter([Ok::<_, std::io::Error>(Bytes::from(head))]).chain(tokio_stream::pending());
+    Request::post(path)
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from_stream(stalled))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn stream_stays_in_flight_until_its_body_is_done() {
+    let app = test_app(Config { max_in_flight_requests: 1, ..Config::default() });
+    let response = app.clone().oneshot(open_ended_batch("/calculate-intensity/stream")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let (status, body) = send(app.clone(), upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(error_code(&body), "server_busy");
+
+    // Hanging up ends the stream and gives the slot back
+    drop(response);
+    assert_eq!(send(app, upload("/calculate-intensity", "image", &test_png())).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn stream_ends_with_a_timeout_at_the_request_deadline() {
+    let config = Config { request_timeout: Duration::from_millis(200), ..Config::default() };
+    let response = test_app(config).oneshot(open_ended_batch("/calculate-intensity/stream")).await.unwrap();
+    let body = tokio::time::timeout(Duration::from_secs(5), to_bytes(response.into_body(), usize::MAX))
+        .await
+        .expect("the stream ends at the deadline")
+        .unwrap();
+    let lines: Vec<IntensityStreamLine> = body
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].result.is_some());
+    assert_eq!(lines[1].error.as_ref().unwrap().code, "timeout");
+}
+
+#[tokio::test]
+async fn sse_emits_an_event_per_part_and_a_summary() {
+    let gray = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([100u8, 100, 100])))).unwrap();
+    let body = multipart_files(&[("a", &gray), ("b", b"not an image"), ("c", &test_png())]);
+    let response = test_app(Config::default())
+        .oneshot(multipart_request("/calculate-intensity/sse", body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+    let body = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+    let events: Vec<&str> = body.split("\n\n").filter(|event| !event.is_empty()).collect();
+    assert_eq!(events.len(), 4, "{body}");
+    let data = |event: &str| -> serde_json::Value {
+        serde_json::from_str(event.lines().find_map(|line| line.strip_prefix("data: ")).unwrap()).unwrap()
+    };
+    assert!(events[0].starts_with("id: 0\n"));
+    assert_eq!(data(events[0])["result"]["average_intensity"], 100.0);
+    assert_eq!(data(events[1])["error"]["code"], "decode_error");
+
+    assert!(events[3].starts_with("event: done\n"));
+    let summary: BatchSummary = serde_json::from_value(data(events[3])).unwrap();
+    assert_eq!((summary.total, summary.succeeded, summary.failed), (3, 2, 1));
+    let png_average = (31.0 * (255.0 + 255.0 + 254.0) / 3.0) / 32.0;
+    assert!((summary.mean_intensity.unwrap() - (100.0 + png_average) / 2.0).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn dithered_grayscale_is_one_bit_black_and_white() {
+    let ramp = ImageBuffer::from_fn(64, 16, |x, _| Rgb([(x * 4) as u8; 3]));
+    let ramp = encode_png(&DynamicImage::ImageRgb8(ramp)).unwrap();
+
+    let (status, body) = send(test_app(Config::default()), upload("/grayscale", "image", &ramp)).await;
+    assert_eq!(status, StatusCode::OK);
+    let smooth = image::load_from_memory(&body).unwrap().to_luma8();
+    assert!(smooth.iter().any(|&value| value != 0 && value != 255));
+
+    for mode in ["floyd-steinberg", "ordered"] {
+        let (status, body) = send(test_app(Config::default()), upload(&format!("/grayscale?dither={mode}"), "image", &ramp)).await;
+        assert_eq!(status, StatusCode::OK, "{mode}");
+        // IHDR bit depth
+        assert_eq!(body[24], 1, "{mode}");
+        let dithered = image::load_from_memory(&body).unwrap().to_luma8();
+        assert_eq!(dithered.dimensions(), (64, 16));
+        assert!(dithered.iter().all(|&value| value == 0 || value == 255), "{mode}");
+        // Dark on the left, light on the right, about half white overall
+        let white = dithered.iter().filter(|&&value| value == 255).count();
+        assert!((white as f64 / 1024.0 - 0.49).abs() < 0.05, "{mode}: {white} white pixels");
+        assert_eq!(dithered.get_pixel(0, 0).0[0], 0);
+    }
+
+    let (status, _) = send(test_app(Config::default()), upload("/grayscale?dither=random", "image", &ramp)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+/// Left half at level 40, right half at 200.
+fn two_level_png() -> Vec<u8> {
+    let img = ImageBuffer::from_fn(16, 8, |x, _| Rgb([if x < 8 { 40u8 } else { 200 }; 3]));
+    encode_png(&DynamicImage::ImageRgb8(img)).unwrap()
+}
+
+/// Posts `data` to `uri`, returning the `X-Threshold` header and the decoded
+/// mask.
+async fn threshold_mask(uri: &str, data: &[u8]) -> (u8, DynamicImage) {
+    let response = test_app(Config::default()).oneshot(upload(uri, "image", data)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK, "{uri}");
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    let chosen = response.headers()["x-threshold"].to_str().unwrap().parse().unwrap();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (chosen, image::load_from_memory(&body).unwrap())
+}
+
+#[tokio::test]
+async fn threshold_applies_a_fixed_value() {
+    let data = two_level_png();
+    for (value, left, right) in [(100, 0, 255), (40, 0, 255), (200, 0, 0), (0, 255, 255)] {
+        let (chosen, mask) = threshold_mask(&format!("/threshold?value={value}"), &data).await;
+        assert_eq!(chosen, value);
+        let DynamicImage::ImageLuma8(mask) = mask else { panic!("{value}: mask is not 1-channel") };
+        assert_eq!(mask.dimensions(), (16, 8));
+        // Only pixels strictly above the threshold are set
+        assert_eq!((mask.get_pixel(0, 0).0[0], mask.get_pixel(15, 7).0[0]), (left, right), "{value}");
+        assert!(mask.iter().all(|&level| level == 0 || level == 255), "{value}");
+    }
+}
+
+#[tokio::test]
+async fn threshold_chooses_the_value_by_otsu() {
+    let (chosen, mask) = threshold_mask("/threshold?method=otsu", &two_level_png()).await;
+    assert!((40..200).contains(&chosen), "{chosen}");
+    let DynamicImage::ImageLuma8(mask) = mask else { panic!("mask is not 1-channel") };
+    let (_, fixed) = threshold_mask("/threshold?value=100", &two_level_png()).await;
+    assert_eq!(mask, fixed.to_luma8());
+
+    for query in ["", "?value=100&method=otsu", "?value=256", "?method=mean"] {
+        let (status, body) = send(test_app(Config::default()), upload(&format!("/threshold{query}"), "image", &two_level_png())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+        assert_eq!(error_code(&body), "invalid_parameter", "{query}");
+    }
+    let (_, body) = send(test_app(Config::default()), upload("/threshold", "image", &two_level_png())).await;
+    assert_eq!(error_message(&body), "provide exactly one of ?value=T or ?method=otsu");
+}
+
+#[tokio::test]
+async fn hsv_stats_reports_saturation_value_and_hue() {
+    let half_blue = ImageBuffer::from_fn(4, 4, |x, _| if x < 2 { Rgb([0u8, 0, 255]) } else { Rgb([128, 128, 128]) });
+    let data = encode_png(&DynamicImage::ImageRgb8(half_blue)).unwrap();
+    let (status, body) = send(test_app(Config::default()), upload("/hsv-stats", "image", &data)).await;
+    assert_eq!(status, StatusCode::OK);
+    let stats: HsvStatsResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!((stats.mean_saturation, stats.std_saturation), (0.5, 0.5));
+    assert_eq!(stats.hue_histogram.len(), 12);
+    assert_eq!(stats.hue_histogram[8], 1.0);
+
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(doc["paths"]["/v1/hsv-stats"]["post"]["operationId"], "hsv_stats");
+}
+
+#[tokio::test]
+async fn normalize_stretches_a_low_contrast_image_to_the_full_range() {
+    // Levels 100-140, plus one outlier at each end for the clipping to ignore
+    let mut dim = ImageBuffer::from_fn(20, 10, |x, _| Rgb([100 + 2 * x as u8, 100 + 2 * x as u8, 110 + x as u8]));
+    dim.put_pixel(0, 0, Rgb([0, 0, 0]));
+    dim.put_pixel(19, 9, Rgb([255, 255, 255]));
+    let data = encode_png(&DynamicImage::ImageRgb8(dim)).unwrap();
+
+    let response = test_app(Config::default()).oneshot(upload("/normalize?clip_percent=1", "image", &data)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    assert_eq!(response.headers()["x-black-point"], "103");
+    assert_eq!(response.headers()["x-white-point"], "135");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let stretched = image::load_from_memory(&body).unwrap().to_rgb8();
+    assert_eq!(stretched.dimensions(), (20, 10));
+    let (min, max) = stretched.iter().fold((255, 0), |(min, max), &value| (value.min(min), value.max(max)));
+    assert_eq!((min, max), (0, 255));
+    // Mid-gray stays near the middle
+    let middle = stretched.get_pixel(10, 5).0;
+    assert!((110..150).contains(&middle[0]), "{middle:?}");
+
+    for clip in ["-1", "50"] {
+        let (status, body) = send(test_app(Config::default()), upload(&format!("/normalize?clip_percent={clip}"), "image", &data)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{clip}");
+        assert_eq!(error_code(&body), "invalid_parameter");
+    }
+}
+
+#[tokio::test]
+async fn color_temperature_tells_warm_from_cool() {
+    let estimate = |color: [u8; 3]| async move {
+        let data = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(8, 8, Rgb(color)))).unwrap();
+        let (status, body) = send(test_app(Config::default()), upload("/color-temperature", "image", &data)).await;
+        assert_eq!(status, StatusCode::OK, "{color:?}");
+        serde_json::from_slice::<ColorTemperatureResponse>(&body).unwrap()
+    };
+
+    let tungsten = estimate([255, 180, 110]).await;
+    assert_eq!((tungsten.label.as_str(), tungsten.confidence.as_str()), ("warm", "high"));
+    assert!((2000.0..4000.0).contains(&tungsten.cct_kelvin), "{}", tungsten.cct_kelvin);
+    let shade = estimate([200, 220, 255]).await;
+    assert_eq!((shade.label.as_str(), shade.confidence.as_str()), ("cool", "high"));
+    assert!(shade.cct_kelvin > 8000.0, "{}", shade.cct_kelvin);
+
+    // Gray sits on the sRGB white point, which says nothing about the lighting
+    let gray = estimate([128, 128, 128]).await;
+    assert!((gray.cct_kelvin - 6504.0).abs() <= 5.0, "{}", gray.cct_kelvin);
+    assert_eq!((gray.label.as_str(), gray.confidence.as_str()), ("neutral", "low"));
+    assert!((gray.chromaticity_x - 0.3127).abs() < 1e-3 && (gray.chromaticity_y - 0.3290).abs() < 1e-3);
+    // So does a warm tint too dark to judge, and black
+    assert_eq!(estimate([12, 8, 5]).await.confidence, "low");
+    assert_eq!(estimate([0, 0, 0]).await.confidence, "low");
+}
+
+#[tokio::test]
+async fn equalize_flattens_the_histogram_without_shifting_colors() {
+    // Values crowded into 90-130, tinted orange
+    let skewed = ImageBuffer::from_fn(32, 32, |x, y| {
+        let value = 90 + ((x * y) / 24) as u8;
+        Rgb([value, (u16::from(value) * 3 / 4) as u8, value / 2])
+    });
+    let data = encode_png(&DynamicImage::ImageRgb8(skewed.clone())).unwrap();
+
+    let response = test_app(Config::default()).oneshot(upload("/equalize", "image", &data)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let equalized = image::load_from_memory(&body).unwrap().to_rgb8();
+    assert_eq!(equalized.dimensions(), (32, 32));
+
+    // Spread of the value histogram over 8 coarse buckets: 0 when perfectly flat
+    let unevenness = |img: &RgbImage| {
+        let mut buckets = [0f64; 8];
+        for pixel in img.pixels() {
+            buckets[usize::from(pixel.0.iter().max().unwrap() / 32)] += 1.0;
+        }
+        let mean = buckets.iter().sum::<f64>() / 8.0;
+        buckets.iter().map(|count| (count - mean).powi(2)).sum::<f64>()
+    };
+    assert!(unevenness(&equalized) < unevenness(&skewed) / 4.0, "{} vs {}", unevenness(&equalized), unevenness(&skewed));
+
+    // Channel ratios, and so the hue, are kept
+    for (before, after) in skewed.pixels().zip(equalized.pixels()).step_by(37) {
+        let [r, g, b] = after.0.map(f64::from);
+        if r > 40.0 {
+            assert!((g / r - 0.75).abs() < 0.05 && (b / r - 0.5).abs() < 0.05, "{before:?} -> {after:?}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn diff_image_lights_up_where_two_images_differ() {
+    let a = ImageBuffer::from_pixel(8, 6, Rgb([100u8, 120, 140]));
+    let mut b = a.clone();
+    for (x, y) in (4..8).flat_map(|x| (0..3).map(move |y| (x, y))) {
+        b.put_pixel(x, y, Rgb([110, 100, 140]));
+    }
+    let (a, b) = (encode_png(&DynamicImage::ImageRgb8(a)).unwrap(), encode_png(&DynamicImage::ImageRgb8(b)).unwrap());
+    let pair = |uri: &str| multipart_request(uri, multipart_files(&[("image_b", &b), ("image_a", &a)]));
+
+    let (status, body) = send(test_app(Config::default()), pair("/diff-image")).await;
+    assert_eq!(status, StatusCode::OK);
+    let difference = image::load_from_memory(&body).unwrap().to_rgb8();
+    assert_eq!(difference.dimensions(), (8, 6));
+    for (x, y, pixel) in difference.enumerate_pixels() {
+        let expected = if x >= 4 && y < 3 { [10, 20, 0] } else { [0, 0, 0] };
+        assert_eq!(pixel.0, expected, "({x}, {y})");
+    }
+
+    let (status, body) = send(test_app(Config::default()), pair("/diff-image?gain=10")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(image::load_from_memory(&body).unwrap().to_rgb8().get_pixel(7, 0).0, [100, 200, 0]);
+}
+
+#[tokio::test]
+async fn diff_image_needs_two_images_of_one_size() {
+    let small = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb([0u8, 0, 0])))).unwrap();
+    let big = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 5, Rgb([0u8, 0, 0])))).unwrap();
+
+    let request = multipart_request("/diff-image", multipart_files(&[("image_a", &small), ("image_b", &big)]));
+    let (status, body) = send(test_app(Config::default()), request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "dimension_mismatch");
+    assert!(error_message(&body).contains("image_a is 4x4 and image_b 4x5"), "{}", error_message(&body));
+
+    let (status, body) = send(test_app(Config::default()), upload("/diff-image", "image_a", &small)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = error_body(&body);
+    assert_eq!(error.code, "missing_field");
+    assert_eq!(error.error, "send the images in fields named 'image_a' and 'image_b'; received fields 'image_a'");
+    assert_eq!(error.accepted_fields, Some(vec!["image_a".to_string(), "image_b".to_string()]));
+
+    for gain in ["0", "-1", "256"] {
+        let request = multipart_request(&format!("/diff-image?gain={gain}"), multipart_files(&[("image_a", &small), ("image_b", &small)]));
+        assert_eq!(send(test_app(Config::default()), request).await.0, StatusCode::BAD_REQUEST, "{gain}");
+    }
+}
+
+#[tokio::test]
+async fn check_duplicate_reports_the_nearest_known_hash() {
+    let png = test_png();
+    let hash = perceptual_hash(&image::load_from_memory(&png).unwrap());
+    let (near, far) = (format!("{:016X}", hash ^ 0b1011), format!("{:016x}", !hash));
+    let known = format!(r#"["{far}", "{near}"]"#);
+    let body = |known: &str| text_fields_and_files(&[("known_hashes", known)], &[("image", &png)]);
+
+    let (status, body_bytes) = send(test_app(Config::default()), multipart_request("/check-duplicate", body(&known))).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: DuplicateCheckResponse = serde_json::from_slice(&body_bytes).unwrap();
+    assert_eq!(response.hash, format!("{hash:016x}"));
+    let closest = response.closest_match.unwrap();
+    assert_eq!((closest.index, closest.hash, closest.distance), (1, near, 3));
+    assert!(response.is_duplicate);
+
+    let request = multipart_request("/check-duplicate?max_distance=2", body(&known));
+    let response: DuplicateCheckResponse = serde_json::from_slice(&send(test_app(Config::default()), request).await.1).unwrap();
+    assert!(!response.is_duplicate);
+
+    let response: DuplicateCheckResponse =
+        serde_json::from_slice(&send(test_app(Config::default()), multipart_request("/check-duplicate", body("[]"))).await.1).unwrap();
+    assert!(response.closest_match.is_none() && !response.is_duplicate);
+}
+
+#[tokio::test]
+async fn check_duplicate_rejects_malformed_hashes() {
+    let png = test_png();
+    for (known, message) in [
+        (r#"["0123456789abcdef", "0123"]"#, r#"known_hashes[1] is not a pHash: expected 16 hex digits, got "0123""#),
+        (r#"["+123456789abcdef"]"#, r#"known_hashes[0] is not a pHash: expected 16 hex digits, got "+123456789abcdef""#),
+        ("0123456789abcdef", "known_hashes must be a JSON array of hex strings"),
+    ] {
+        let request = multipart_request("/check-duplicate", text_fields_and_files(&[("known_hashes", known)], &[("image", &png)]));
+        let (status, body) = send(test_app(Config::default()), request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{known}");
+        assert_eq!(error_code(&body), "invalid_hash");
+        assert!(error_message(&body).starts_with(message), "{}", error_message(&body));
+    }
+
+    let (status, body) = send(test_app(Config::default()), upload("/check-duplicate", "image", &png)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "invalid_parameter");
+}
+
+#[tokio::test]
+async fn batch_summary_picks_the_brightest_and_darkest_images() {
+    let gray = |level: u8| encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, Rgb([level, level, level])))).unwrap();
+    let (dim, bright, mid) = (gray(40), gray(220), gray(100));
+    let body = multipart_named_files(&[
+        ("image", "dim.png", &dim),
+        ("image", "bright.png", &bright),
+        ("image", "broken.png", b"not an image"),
+        ("image", "mid.png", &mid),
+    ]);
+    let (status, body) = send(test_app(Config::default()), multipart_request("/calculate-intensity/batch/summary", body)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let response: BatchSummaryResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!((response.summary.total, response.summary.succeeded, response.summary.failed), (4, 3, 1));
+    assert_eq!(response.images.len(), 4);
+    assert_eq!(response.images[2].error.as_ref().unwrap().code, "decode_error");
+    let aggregate = response.aggregate.unwrap();
+    assert!((aggregate.mean_of_means - (40.0 + 220.0 + 100.0) / 3.0).abs() < 1e-9);
+    assert_eq!((aggregate.min_intensity, aggregate.max_intensity), (40.0, 220.0));
+    assert_eq!((aggregate.brightest_index, aggregate.brightest_filename.as_deref()), (1, Some("bright.png")));
+    assert_eq!((aggregate.darkest_index, aggregate.darkest_filename.as_deref()), (0, Some("dim.png")));
+}
+
+#[tokio::test]
+async fn an_empty_batch_summary_is_rejected() {
+    let (status, body) = send(test_app(Config::default()), multipart_request("/calculate-intensity/batch/summary", multipart_files(&[]))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "empty_batch");
+}
+
+#[tokio::test]
+async fn stream_validates_options_up_front() {
+    let request = multipart_request("/calculate-intensity/stream?trim=80", multipart_files(&[("a", &test_png())]));
+    let (status, body) = send(test_app(Config::default()), request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "invalid_parameter");
+}
+
+#[tokio::test]
+async fn black_frames_are_flagged() {
+    let black = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb([0u8, 0, 0])))).unwrap();
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &black)).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!(response.is_black_frame);
+    assert!(response.contrast_undefined);
+    assert_eq!((response.contrast_michelson, response.contrast_rms), (0.0, 0.0));
+
+    // Averages 1.25, but one pixel of real content lifts the peak above 16
+    let mut dim = ImageBuffer::from_pixel(4, 4, Rgb([0u8, 0, 0]));
+    dim.put_pixel(1, 2, Rgb([20, 20, 20]));
+    let dim = encode_png(&DynamicImage::ImageRgb8(dim)).unwrap();
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &dim)).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response.average_intensity, 1.25);
+    assert!(!response.is_black_frame);
+    assert!(!response.contrast_undefined);
+    assert_eq!(response.contrast_michelson, 1.0);
+
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity?black_peak=32", "image", &dim)).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!(response.is_black_frame);
+}
+
+#[tokio::test]
+async fn black_frame_thresholds_are_validated() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?black_average=300", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(&body), "invalid_parameter");
+}
+
+#[tokio::test]
+async fn linear_colorspace_reports_light_and_its_srgb_encoding() {
+    let mid_gray = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb([128u8, 128, 128])))).unwrap();
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?colorspace=linear", "image", &mid_gray)).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response.average_intensity, 128.0);
+    let linear = response.linear_average_intensity.unwrap();
+    assert!((linear - 55.04).abs() < 0.01, "{linear}");
+    assert!((response.linear_average_intensity_srgb.unwrap() - 128.0).abs() < 1e-9);
+
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity?colorspace=linear&scale=unit", "image", &mid_gray)).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    let linear = response.linear_average_intensity.unwrap();
+    assert!((linear - 0.216).abs() < 0.001, "{linear}");
+    assert!((response.linear_average_intensity_srgb.unwrap() - 128.0 / 255.0).abs() < 1e-9);
+
+    // Gray halves at 0 and 255 average to 127.5 encoded, but to half the light
+    let halves = ImageBuffer::from_fn(4, 4, |x, _| Rgb([if x < 2 { 0u8 } else { 255 }; 3]));
+    let halves = encode_png(&DynamicImage::ImageRgb8(halves)).unwrap();
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity?colorspace=linear", "image", &halves)).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(response.average_intensity, 127.5);
+    assert_eq!(response.linear_average_intensity, Some(127.5));
+    assert!((response.linear_average_intensity_srgb.unwrap() - 187.5).abs() < 0.1);
+
+    for uri in ["/calculate-intensity", "/calculate-intensity?colorspace=srgb"] {
+        let (_, body) = send(test_app(Config::default()), upload(uri, "image", &mid_gray)).await;
+        let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+        assert!(response.linear_average_intensity.is_none() && response.linear_average_intensity_srgb.is_none(), "{uri}");
+    }
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity?colorspace=lab", "image", &mid_gray)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn linearize_is_an_alias_of_the_linear_colorspace() {
+    let mid_gray = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb([128u8, 128, 128])))).unwrap();
+    let app = test_app(Config::default());
+    let (status, aliased) = send(app.clone(), upload("/calculate-intensity?linearize=true", "image", &mid_gray)).await;
+    assert_eq!(status, StatusCode::OK);
+    let aliased: IntensityResponse = serde_json::from_slice(&aliased).unwrap();
+    assert!(aliased.linear_average_intensity.is_some() && aliased.linear_average_intensity_srgb.is_some());
+
+    // Both spellings share one cache entry
+    let (_, body) = send(app, upload("/calculate-intensity?colorspace=linear", "image", &mid_gray)).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!(response.cached);
+    assert_eq!(response.linear_average_intensity, aliased.linear_average_intensity);
+    assert_eq!(response.linear_average_intensity_srgb, aliased.linear_average_intensity_srgb);
+}
+
+#[tokio::test]
+async fn lab_lightness_is_reported_on_request() {
+    let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity?metric=lab_lightness&scale=unit", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    // 31 of the 32 pixels are near-white, the last black
+    let lightness = response.mean_lab_lightness.unwrap();
+    assert!((lightness - 31.0 * 99.9 / 32.0).abs() < 0.1, "{lightness}");
+
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!(response.mean_lab_lightness.is_none());
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity?metric=luma", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn every_route_is_documented() {
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let documented = doc["paths"].as_object().unwrap();
+    // axum's `/:name` is OpenAPI's `/{name}`
+    let routes: Vec<String> = route_paths()
+        .iter()
+        .map(|path| path.split('/').map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{name}}}"),
+            None => segment.to_string(),
+        }).collect::<Vec<_>>().join("/"))
+        .collect();
+    for route in &routes {
+        // Swagger UI's page and assets are the documentation's viewer, not part of the API
+        if !route.starts_with("/swagger-ui") {
+            assert!(documented.contains_key(route), "{route} is routed but missing from the OpenAPI document");
+        }
+    }
+    for path in documented.keys() {
+        assert!(routes.contains(path), "{path} is documented but not routed");
+    }
+}
+
+#[tokio::test]
+async fn openapi_schemas_carry_examples_and_constraints() {
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let average = &doc["components"]["schemas"]["IntensityResponse"]["properties"]["average_intensity"];
+    assert_eq!((average["minimum"].as_f64(), average["maximum"].as_f64()), (Some(0.0), Some(255.0)));
+    assert!(average["example"].is_number());
+    let code = &doc["components"]["schemas"]["ErrorResponse"]["properties"]["code"];
+    assert_eq!(code["example"], "missing_field");
+
+    // Each error response shows a body that status really carries
+    let operation = &doc["paths"]["/calculate-intensity"]["post"];
+    for (status, code) in [("400", "missing_field"), ("408", "timeout"), ("413", "too_large"), ("415", "unsupported_content_type"), ("422", "decode_error"), ("503", "server_busy")] {
+        let example: ErrorResponse = serde_json::from_value(operation["responses"][status]["content"]["application/json"]["example"].clone()).unwrap();
+        assert_eq!(example.code, code, "status {status}");
+        assert!(example.request_id.is_some());
+    }
+    let too_large = &operation["responses"]["413"]["content"]["application/json"]["example"]["error"];
+    assert_eq!(too_large, "upload exceeds the maximum of 20971520 bytes");
+    let url_error = &doc["paths"]["/calculate-intensity"]["get"]["responses"]["502"]["content"]["application/json"]["example"];
+    assert_eq!(url_error["code"], "fetch_failed");
+}
+
+#[tokio::test]
+async fn openapi_describes_uploads_as_files_and_lists_query_parameters() {
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let schemas = &doc["components"]["schemas"];
+    let mut uploads = 0;
+    for (path, item) in doc["paths"].as_object().unwrap() {
+        for (method, operation) in item.as_object().unwrap() {
+            let Some(media) = operation["requestBody"]["content"].get("multipart/form-data") else { continue };
+            uploads += 1;
+            let name = media["schema"]["$ref"].as_str().unwrap().trim_start_matches("#/components/schemas/");
+            let properties = schemas[name]["properties"].as_object().unwrap();
+            let file = properties.values().next().unwrap();
+            let file = if file["type"] == "array" { &file["items"] } else { file };
+            assert_eq!((&file["type"], &file["format"]), (&"string".into(), &"binary".into()), "{method} {path}");
+        }
+    }
+    assert!(uploads >= 12, "{uploads} multipart operations");
+
+    let parameters = |path: &str| -> Vec<String> {
+        let operation = &doc["paths"][path]["post"];
+        operation["parameters"].as_array().unwrap().iter().map(|parameter| parameter["name"].as_str().unwrap().to_string()).collect()
+    };
+    for name in ["trim", "scale", "linearize", "quadrants", "strict", "downscale_to", "weighting", "round"] {
+        assert!(parameters("/calculate-intensity").iter().any(|parameter| parameter == name), "missing {name}");
+        assert!(parameters("/calculate-intensity/stream").iter().any(|parameter| parameter == name), "missing {name}");
+    }
+    let linearize = doc["paths"]["/calculate-intensity"]["post"]["parameters"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|parameter| parameter["name"] == "linearize")
+        .unwrap();
+    assert_eq!(linearize["deprecated"], true);
+    assert_eq!(parameters("/threshold"), ["value", "method"]);
+    assert_eq!(parameters("/coverage"), ["threshold"]);
+    let dither = &doc["paths"]["/grayscale"]["post"]["parameters"][0]["schema"]["enum"];
+    assert_eq!(dither, &serde_json::json!(["none", "floyd-steinberg", "ordered"]));
+
+    // Uploading through the documented property, as Swagger UI does, works
+    let property = schemas["ImageUpload"]["properties"].as_object().unwrap().keys().next().unwrap().clone();
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity", &property, &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn hsp_brightness_diverges_from_luma_on_saturated_colors() {
+    for (color, expected_hsp) in [([0u8, 0, 255], 0.114f64.sqrt()), ([255, 0, 0], 0.299f64.sqrt())] {
+        let image = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb(color)))).unwrap();
+        let (status, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &image)).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+        let [r, g, b] = color.map(f64::from);
+        let luma_601 = 0.299 * r + 0.587 * g + 0.114 * b;
+        assert!((response.hsp_brightness - expected_hsp * 255.0).abs() < 1e-9, "{color:?}: {}", response.hsp_brightness);
+        // Squaring before weighting lifts a lone channel well above its luma
+        assert!(response.hsp_brightness > 1.8 * luma_601, "{color:?}");
+    }
+
+    let gray = encode_png(&DynamicImage::ImageRgb8(ImageBuffer::from_pixel(4, 4, Rgb([90u8, 90, 90])))).unwrap();
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &gray)).await;
+    let response: IntensityResponse = serde_json::from_slice(&body).unwrap();
+    assert!((response.hsp_brightness - 90.0).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn quadrants_split_left_bright_from_right_dark() {
+    // 5x3, bright in columns 0-2: the middle column 2 belongs to the right half
+    let image = DynamicImage::ImageLuma8(GrayImage::from_fn(5, 3, |x, _| Luma([if x < 3 { 210 } else { 0 }])));
+    let (status, body) =
+        send(test_app(Config::default()), upload("/calculate-intensity?quadrants=true", "image", &encode_png(&image).unwrap())).await;
+    assert_eq!(status, StatusCode::OK);
+    let quadrants = serde_json::from_slice::<IntensityResponse>(&body).unwrap().quadrants.unwrap();
+    assert!(quadrants.top_left > quadrants.top_right);
+    assert!(quadrants.bottom_left > quadrants.bottom_right);
+    assert_eq!((quadrants.top_left, quadrants.bottom_left), (210.0, 210.0));
+    assert_eq!((quadrants.top_right, quadrants.bottom_right), (70.0, 70.0));
+
+    let (_, body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    assert!(serde_json::from_slice::<IntensityResponse>(&body).unwrap().quadrants.is_none());
+}
+
+async fn explode() -> &'static str {
+    panic!("decoder exploded")
+}
+
+async fn explode_blocking() -> Result<&'static str, ApiError> {
+    let permit = Arc::new(Semaphore::new(1)).acquire_owned().await.unwrap();
+    run_blocking(permit, || panic!("decoder exploded")).await
+}
+
+#[tokio::test]
+async fn panics_become_a_json_500_with_a_correlation_id() {
+    // Panics in a handler are caught by the layer, those in blocking work by
+    // `run_blocking`; both under the middleware stack of the real app
+    let app = Router::new()
+        .route("/explode", routing::get(explode))
+        .route("/explode-blocking", routing::get(explode_blocking));
+    for uri in ["/explode", "/explode-blocking"] {
+        let request = Request::get(uri).header("x-request-id", "panic-report-3").body(Body::empty()).unwrap();
+        let (status, body) = send(with_middleware(app.clone(), AppState::new(Config::default())), request).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{uri}");
+
+        let error = error_body(&body);
+        assert_eq!(error.code, "internal", "{uri}");
+        let correlation_id = error.correlation_id.unwrap();
+        assert_eq!(correlation_id.len(), 16, "{uri}");
+        assert_eq!(error.error, format!("internal processing error (correlation id {correlation_id})"));
+        assert_eq!(error.request_id.as_deref(), Some("panic-report-3"), "{uri}");
+    }
+}
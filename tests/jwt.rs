@@ -0,0 +1,246 @@
+//! Bearer JWTs, signed with a shared secret or by keys served from a local
+//! JWKS endpoint.
+
+mod common;
+
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::{routing::get as get_route, Json, Router};
+use base64::engine::{general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::*;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use webcalculation::jwt::{JwtError, JwtVerifier};
+use webcalculation::server::Config;
+
+const SECRET: &str = "token-secret";
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn hs256_token(secret: &str, claims: serde_json::Value) -> String {
+    encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+}
+
+fn jwt_config(required_scope: Option<&str>) -> Config {
+    Config {
+        jwt_hs256_secret: Some(SECRET.to_string()),
+        jwt_required_scope: required_scope.map(str::to_string),
+        ..Config::default()
+    }
+}
+
+async fn upload_with_token(config: Config, token: &str) -> (StatusCode, Vec<u8>) {
+    let mut request = upload("/calculate-intensity", "image", &test_png());
+    request.headers_mut().insert(header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+    send(test_app(config), request).await
+}
+
+#[tokio::test]
+async fn valid_tokens_are_accepted() {
+    let token = hs256_token(SECRET, serde_json::json!({"sub": "user-1", "exp": now() + 600}));
+    assert_eq!(upload_with_token(jwt_config(None), &token).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn expired_and_foreign_tokens_are_rejected() {
+    for token in [
+        hs256_token(SECRET, serde_json::json!({"sub": "user-1", "exp": now() - 3600})),
+        hs256_token("another-secret", serde_json::json!({"sub": "user-1", "exp": now() + 600})),
+        hs256_token(SECRET, serde_json::json!({"sub": "user-1"})),
+        "not.a.token".to_string(),
+    ] {
+        let (status, body) = upload_with_token(jwt_config(None), &token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{token}");
+        assert_eq!(error_code(&body), "unauthorized");
+    }
+
+    let (status, body) = send(test_app(jwt_config(None)), upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(error_message(&body).starts_with("missing bearer token"));
+}
+
+#[tokio::test]
+async fn the_required_scope_is_enforced() {
+    let config = || jwt_config(Some("intensity:write"));
+    let exp = now() + 600;
+    let (status, body) =
+        upload_with_token(config(), &hs256_token(SECRET, serde_json::json!({"exp": exp, "scope": "intensity:read"}))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error_code(&body), "insufficient_scope");
+
+    for claims in [
+        serde_json::json!({"exp": exp, "scope": "intensity:read intensity:write"}),
+        serde_json::json!({"exp": exp, "scp": ["intensity:write"]}),
+        serde_json::json!({"exp": exp, "scp": "intensity:write"}),
+    ] {
+        assert_eq!(upload_with_token(config(), &hs256_token(SECRET, claims.clone())).await.0, StatusCode::OK, "{claims}");
+    }
+}
+
+#[tokio::test]
+async fn api_keys_and_tokens_coexist() {
+    let config = || Config {
+        api_keys: vec!["alpha-key".to_string()],
+        ..jwt_config(None)
+    };
+    let token = hs256_token(SECRET, serde_json::json!({"exp": now() + 600}));
+    assert_eq!(upload_with_token(config(), "alpha-key").await.0, StatusCode::OK);
+    assert_eq!(upload_with_token(config(), &token).await.0, StatusCode::OK);
+
+    let mut request = upload("/calculate-intensity", "image", &test_png());
+    request.headers_mut().insert("x-api-key", "alpha-key".parse().unwrap());
+    assert_eq!(send(test_app(config()), request).await.0, StatusCode::OK);
+
+    let (status, body) = upload_with_token(config(), "beta-key").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(error_message(&body).starts_with("not a valid API key, and"), "{}", error_message(&body));
+}
+
+#[tokio::test]
+async fn openapi_declares_a_jwt_bearer_scheme() {
+    let (_, body) = send(test_app(jwt_config(Some("intensity:write"))), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let schemes = &doc["components"]["securitySchemes"];
+    assert_eq!(schemes["bearer"], serde_json::json!({"type": "http", "scheme": "bearer", "bearerFormat": "JWT"}));
+    assert!(schemes.get("api_key").is_none());
+
+    let operation = &doc["paths"]["/calculate-intensity"]["post"];
+    assert_eq!(operation["security"], serde_json::json!([{"bearer": []}]));
+    assert!(operation["responses"]["403"]["description"].as_str().unwrap().contains("intensity:write"));
+}
+
+/// A JWKS endpoint serving whatever set the test puts in, counting requests.
+#[derive(Clone, Default)]
+struct Issuer {
+    set: Arc<Mutex<serde_json::Value>>,
+    fetches: Arc<AtomicUsize>,
+}
+
+impl Issuer {
+    async fn start(&self) -> reqwest::Url {
+        async fn jwks(State(issuer): State<Issuer>) -> Json<serde_json::Value> {
+            issuer.fetches.fetch_add(1, Ordering::SeqCst);
+            Json(issuer.set.lock().unwrap().clone())
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/jwks", listener.local_addr().unwrap()).parse().unwrap();
+        let router = Router::new().route("/jwks", get_route(jwks)).with_state(self.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        url
+    }
+
+    fn publish(&self, keys: &[&SigningKey]) {
+        *self.set.lock().unwrap() = serde_json::json!({"keys": keys.iter().map(|key| key.jwk()).collect::<Vec<_>>()});
+    }
+
+    fn fetches(&self) -> usize {
+        self.fetches.load(Ordering::SeqCst)
+    }
+}
+
+/// An ES256 key pair with its `kid`.
+struct SigningKey {
+    kid: &'static str,
+    pair: rcgen::KeyPair,
+}
+
+impl SigningKey {
+    fn new(kid: &'static str) -> Self {
+        SigningKey { kid, pair: rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap() }
+    }
+
+    fn jwk(&self) -> serde_json::Value {
+        // An uncompressed point: 0x04, then x and y
+        let point = self.pair.public_key_raw();
+        serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": self.kid,
+            "use": "sig",
+            "alg": "ES256",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        })
+    }
+
+    fn token(&self, sub: &str) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(self.kid.to_string());
+        let key = EncodingKey::from_ec_pem(self.pair.serialize_pem().as_bytes()).unwrap();
+        encode(&header, &serde_json::json!({"sub": sub, "exp": now() + 600}), &key).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn jwks_keys_are_cached_and_refreshed_for_unknown_kids() {
+    let issuer = Issuer::default();
+    let (first, second) = (SigningKey::new("first"), SigningKey::new("second"));
+    issuer.publish(&[&first]);
+    let verifier = JwtVerifier::jwks(issuer.start().await).min_refresh_interval(Duration::ZERO);
+    assert_eq!(issuer.fetches(), 0);
+
+    assert_eq!(verifier.verify(&first.token("user-1")).await.unwrap().sub.as_deref(), Some("user-1"));
+    assert_eq!(verifier.verify(&first.token("user-2")).await.unwrap().sub.as_deref(), Some("user-2"));
+    assert_eq!(issuer.fetches(), 1);
+
+    // A rotated-in key is picked up on first sight
+    issuer.publish(&[&first, &second]);
+    assert!(verifier.verify(&second.token("user-1")).await.is_ok());
+    assert_eq!(issuer.fetches(), 2);
+
+    let unknown = SigningKey::new("unknown");
+    assert!(matches!(verifier.verify(&unknown.token("user-1")).await, Err(JwtError::Invalid(_))));
+    assert_eq!(issuer.fetches(), 3);
+}
+
+#[tokio::test]
+async fn unknown_kids_do_not_refetch_within_the_refresh_interval() {
+    let issuer = Issuer::default();
+    let key = SigningKey::new("only");
+    issuer.publish(&[&key]);
+    let verifier = JwtVerifier::jwks(issuer.start().await);
+
+    assert!(verifier.verify(&key.token("user-1")).await.is_ok());
+    for kid in ["made-up-1", "made-up-2", "made-up-3"] {
+        let err = verifier.verify(&SigningKey::new(kid).token("user-1")).await.unwrap_err();
+        assert_eq!(err, JwtError::Invalid(format!("the token is signed by an unknown key {kid:?}")));
+    }
+    assert_eq!(issuer.fetches(), 1);
+}
+
+#[tokio::test]
+async fn jwks_verifiers_refuse_hmac_tokens() {
+    let issuer = Issuer::default();
+    issuer.publish(&[&SigningKey::new("only")]);
+    let verifier = JwtVerifier::jwks(issuer.start().await);
+    let token = hs256_token(SECRET, serde_json::json!({"exp": now() + 600}));
+    assert!(matches!(verifier.verify(&token).await, Err(JwtError::Invalid(_))));
+    assert_eq!(issuer.fetches(), 0);
+}
+
+#[tokio::test]
+async fn an_unreachable_jwks_answers_503() {
+    let config = Config {
+        jwt_jwks_url: Some("http://127.0.0.1:1/jwks".to_string()),
+        ..Config::default()
+    };
+    let (status, body) = upload_with_token(config, &SigningKey::new("only").token("user-1")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(error_code(&body), "auth_unavailable");
+}
+
+#[tokio::test]
+async fn jwks_tokens_are_accepted_through_the_app() {
+    let issuer = Issuer::default();
+    let key = SigningKey::new("only");
+    issuer.publish(&[&key]);
+    let config = Config {
+        jwt_jwks_url: Some(issuer.start().await.to_string()),
+        ..Config::default()
+    };
+    assert_eq!(upload_with_token(config, &key.token("user-1")).await.0, StatusCode::OK);
+}
@@ -0,0 +1,126 @@
+//! Request logging, captured from an in-memory writer.
+
+mod common;
+
+use axum::http::{header, StatusCode};
+use common::{send, test_app, test_png, upload};
+use std::io;
+use std::sync::{Arc, Mutex};
+use webcalculation::logging::{subscriber, LogFormat};
+use webcalculation::server::Config;
+use webcalculation::telemetry::Telemetry;
+
+/// Log output shared with the subscriber.
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+    }
+}
+
+/// Sends `request` with logs in `format` going to the returned capture.
+async fn logged(format: LogFormat, request: axum::http::Request<axum::body::Body>) -> (StatusCode, Capture) {
+    logged_with(Config::default(), format, request).await
+}
+
+async fn logged_with(
+    config: Config,
+    format: LogFormat,
+    request: axum::http::Request<axum::body::Body>,
+) -> (StatusCode, Capture) {
+    let capture = Capture::default();
+    let writer = capture.clone();
+    let _guard = tracing::subscriber::set_default(subscriber(format, move || writer.clone(), &Telemetry::default()));
+    let (status, _) = send(test_app(config), request).await;
+    (status, capture)
+}
+
+#[tokio::test]
+async fn json_lines_carry_the_request_fields() {
+    let data = test_png();
+    let (status, capture) = logged(LogFormat::Json, upload("/calculate-intensity", "image", &data)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let finished: Vec<serde_json::Value> = capture
+        .lines()
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("one JSON object per line"))
+        .filter(|event: &serde_json::Value| event["message"] == "request finished")
+        .collect();
+    assert_eq!(finished.len(), 1, "{:?}", capture.lines());
+    let event = &finished[0];
+    assert_eq!(event["status"], 200);
+    assert!(event["latency_ms"].as_f64().unwrap() >= 0.0);
+    assert!(event["response_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(event["span"]["method"], "POST");
+    assert_eq!(event["span"]["path"], "/calculate-intensity");
+    assert_eq!(event["span"]["upload_bytes"], data.len());
+    assert_eq!(event["span"]["detected_format"], "png");
+    assert_eq!(event["span"]["request_id"].as_str().unwrap().len(), 36);
+}
+
+#[tokio::test]
+async fn decode_errors_are_logged_as_warnings() {
+    let (status, capture) = logged(LogFormat::Text, upload("/calculate-intensity", "image", b"not an image")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let lines = capture.lines();
+    let warning = lines.iter().find(|line| line.contains("WARN")).expect("a warning");
+    assert!(warning.contains("image could not be decoded"), "{warning}");
+    assert!(warning.contains("could not be determined"), "{warning}");
+    assert!(warning.contains("path=/calculate-intensity"), "{warning}");
+    assert!(lines.iter().any(|line| line.contains("request finished") && line.contains("status=422")), "{lines:?}");
+}
+
+#[tokio::test]
+async fn pretty_events_list_their_fields_and_span() {
+    let (status, capture) = logged(LogFormat::Pretty, upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let output = capture.lines().join("\n");
+    let finished = output.find("request finished").expect("a finished event");
+    let event = &output[finished..];
+    assert!(event.contains("status: 200"), "{output}");
+    assert!(event.contains("latency_ms: "), "{output}");
+    assert!(event.contains("path: /calculate-intensity"), "{output}");
+}
+
+#[tokio::test]
+async fn the_bearer_tokens_subject_is_logged() {
+    let claims = serde_json::json!({"sub": "user-42", "exp": 4_102_444_800u64});
+    let key = jsonwebtoken::EncodingKey::from_secret(b"token-secret");
+    let token = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap();
+    let mut request = upload("/calculate-intensity", "image", &test_png());
+    request.headers_mut().insert(header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+    let config = Config {
+        jwt_hs256_secret: Some("token-secret".to_string()),
+        ..Config::default()
+    };
+
+    let (status, capture) = logged_with(config, LogFormat::Text, request).await;
+    assert_eq!(status, StatusCode::OK);
+    let lines = capture.lines();
+    assert!(lines.iter().any(|line| line.contains("request finished") && line.contains("sub=\"user-42\"")), "{lines:?}");
+}
+
+#[test]
+fn log_formats_parse() {
+    assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+    assert_eq!(" text ".parse(), Ok(LogFormat::Text));
+    assert_eq!("Pretty".parse(), Ok(LogFormat::Pretty));
+    assert_eq!(LogFormat::default(), LogFormat::Pretty);
+    assert!("yaml".parse::<LogFormat>().unwrap_err().contains("expected pretty, text or json"));
+}
@@ -0,0 +1,112 @@
+//! `POST /metadata`, on JPEGs carrying EXIF written by the test.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::*;
+use exif::experimental::Writer;
+use exif::{Field, In, Rational, Tag, Value};
+use std::io::Cursor;
+use webcalculation::metadata::{capture_metadata, CaptureMetadata, GpsPosition};
+use webcalculation::server::Config;
+
+fn field(tag: Tag, value: Value) -> Field {
+    Field { tag, ifd_num: In::PRIMARY, value }
+}
+
+fn ascii(text: &str) -> Value {
+    Value::Ascii(vec![text.as_bytes().to_vec()])
+}
+
+fn rationals(values: &[(u32, u32)]) -> Value {
+    Value::Rational(values.iter().map(|&(num, denom)| Rational { num, denom }).collect())
+}
+
+/// An 8x8 gray JPEG whose APP1 segment holds `fields` as EXIF.
+fn jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false).unwrap();
+    let mut segment = b"Exif\0\0".to_vec();
+    segment.extend(tiff.into_inner());
+
+    let mut data = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut data, 90);
+    encoder.add_app_segment(1, segment).unwrap();
+    encoder.encode(&[128; 64], 8, 8, jpeg_encoder::ColorType::Luma).unwrap();
+    data
+}
+
+fn camera_jpeg() -> Vec<u8> {
+    jpeg_with_exif(&[
+        field(Tag::Make, ascii("Canon")),
+        field(Tag::Model, ascii("Canon EOS R6")),
+        field(Tag::Orientation, Value::Short(vec![6])),
+        field(Tag::PhotographicSensitivity, Value::Short(vec![400])),
+        field(Tag::ExposureTime, rationals(&[(1, 125)])),
+        field(Tag::FNumber, rationals(&[(28, 10)])),
+        field(Tag::FocalLength, rationals(&[(50, 1)])),
+        field(Tag::GPSLatitudeRef, ascii("N")),
+        field(Tag::GPSLatitude, rationals(&[(48, 1), (51, 1), (3024, 100)])),
+        field(Tag::GPSLongitudeRef, ascii("W")),
+        field(Tag::GPSLongitude, rationals(&[(2, 1), (17, 1), (4020, 100)])),
+        field(Tag::GPSAltitudeRef, Value::Byte(vec![0])),
+        field(Tag::GPSAltitude, rationals(&[(35, 1)])),
+    ])
+}
+
+async fn metadata(uri: &str, data: &[u8]) -> (StatusCode, serde_json::Value) {
+    let (status, body) = send(test_app(Config::default()), upload(uri, "image", data)).await;
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn camera_settings_are_reported_without_gps() {
+    let (status, body) = metadata("/metadata", &camera_jpeg()).await;
+    assert_eq!(status, StatusCode::OK);
+    let metadata: CaptureMetadata = serde_json::from_value(body.clone()).unwrap();
+    assert_eq!(
+        metadata,
+        CaptureMetadata {
+            make: Some("Canon".to_string()),
+            model: Some("Canon EOS R6".to_string()),
+            iso: Some(400),
+            exposure_time: Some(0.008),
+            f_number: Some(2.8),
+            focal_length: Some(50.0),
+            orientation: Some(6),
+            gps: None,
+        }
+    );
+    assert!(body.get("gps").is_none(), "{body}");
+}
+
+#[tokio::test]
+async fn gps_is_included_only_when_asked_for() {
+    let (status, body) = metadata("/metadata?include_gps=true", &camera_jpeg()).await;
+    assert_eq!(status, StatusCode::OK);
+    let GpsPosition { latitude, longitude, altitude } = serde_json::from_value(body["gps"].clone()).unwrap();
+    assert!((latitude - 48.8584).abs() < 1e-4, "{latitude}");
+    assert!((longitude + 2.2945).abs() < 1e-4, "{longitude}");
+    assert_eq!(altitude, Some(35.0));
+}
+
+#[tokio::test]
+async fn images_without_exif_give_an_empty_object() {
+    for data in [test_png(), jpeg_with_exif(&[field(Tag::Make, ascii("  "))])] {
+        let (status, body) = metadata("/metadata?include_gps=true", &data).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({}));
+    }
+}
+
+#[test]
+fn damaged_exif_reads_as_absent() {
+    let mut data = camera_jpeg();
+    let exif = data.windows(6).position(|window| window == b"Exif\0\0").unwrap();
+    data[exif + 6..exif + 14].fill(0);
+    assert_eq!(capture_metadata(&data, true), CaptureMetadata::default());
+}
@@ -0,0 +1,288 @@
+mod common;
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    Router,
+};
+use common::*;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tower::ServiceExt;
+use webcalculation::rate_limit::{InFlightLimiter, RateLimiter};
+use webcalculation::server::{app, AppState, Config, StatsResponse};
+
+/// Limited to `per_minute`, all of which may be spent at once.
+fn limited_app(per_minute: u32, trust_proxy: bool) -> Router {
+    test_app(Config {
+        rate_limit_per_minute: per_minute,
+        rate_limit_burst: per_minute,
+        trust_proxy,
+        ..Config::default()
+    })
+}
+
+/// An upload whose body never finishes, so its handler stays in flight.
+fn stalled_upload(peer: &str) -> Request<Body> {
+    let stalled = tokio_stream::StreamExt::chain(
+        tokio_stream::iter([Ok::<_, std::io::Error>(bytes::Bytes::from_static(b"--"))]),
+        tokio_stream::pending(),
+    );
+    let request = Request::post("/calculate-intensity")
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from_stream(stalled))
+        .unwrap();
+    from_peer(request, peer)
+}
+
+async fn in_flight_by_client(app: &Router) -> Vec<(String, usize)> {
+    let (_, body) = send(app.clone(), get("/stats")).await;
+    serde_json::from_slice::<StatsResponse>(&body).unwrap().in_flight_by_client.into_iter().collect()
+}
+
+fn from_peer(mut request: Request<Body>, peer: &str) -> Request<Body> {
+    request.extensions_mut().insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+    request
+}
+
+#[test]
+fn buckets_refill_over_time() {
+    let limiter = RateLimiter::new(60);
+    let start = Instant::now();
+    for _ in 0..60 {
+        assert!(limiter.check("client", start).is_ok());
+    }
+
+    let retry_after = limiter.check("client", start).unwrap_err();
+    assert!(retry_after <= Duration::from_secs(1) && retry_after > Duration::ZERO);
+    assert!(limiter.check("other", start).is_ok());
+
+    assert!(limiter.check("client", start + Duration::from_secs(1)).is_ok());
+    assert!(limiter.check("client", start + Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn burst_caps_the_bucket() {
+    let limiter = RateLimiter::with_burst(60, 3);
+    let start = Instant::now();
+    for _ in 0..3 {
+        assert!(limiter.check("client", start).is_ok());
+    }
+    assert!(limiter.check("client", start).is_err());
+
+    // A long pause refills only up to the burst
+    let later = start + Duration::from_secs(600);
+    for _ in 0..3 {
+        assert!(limiter.check("client", later).is_ok());
+    }
+    assert!(limiter.check("client", later).is_err());
+}
+
+#[test]
+fn the_least_recently_seen_client_is_evicted() {
+    let limiter = RateLimiter::with_burst(1, 1).max_keys(2);
+    let start = Instant::now();
+    assert!(limiter.check("a", start).is_ok());
+    assert!(limiter.check("b", start).is_ok());
+    // Seen again, "a" is now more recent than "b"
+    assert!(limiter.check("a", start).is_err());
+    assert!(limiter.check("c", start).is_ok());
+    assert_eq!(limiter.tracked_keys(), 2);
+
+    // "a" kept its spent bucket while "b" made room and starts afresh
+    assert!(limiter.check("a", start).is_err());
+    assert!(limiter.check("b", start).is_ok());
+}
+
+#[tokio::test]
+async fn excess_requests_get_429_with_retry_after() {
+    let app = limited_app(2, false);
+    for _ in 0..2 {
+        let request = from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.1:5000");
+        assert_eq!(send(app.clone(), request).await.0, StatusCode::OK);
+    }
+
+    let request = from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.1:5001");
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(error_code(&body), "rate_limited");
+
+    // Another client still has its own budget
+    let request = from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.2:5000");
+    assert_eq!(send(app, request).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn the_burst_is_spent_before_the_429() {
+    let app = test_app(Config {
+        rate_limit_per_minute: 60,
+        rate_limit_burst: 3,
+        ..Config::default()
+    });
+    for _ in 0..3 {
+        let request = from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.1:5000");
+        assert_eq!(send(app.clone(), request).await.0, StatusCode::OK);
+    }
+
+    let request = from_peer(upload("/unique-colors", "image", &test_png()), "10.0.0.1:5000");
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+}
+
+#[tokio::test]
+async fn only_analysis_endpoints_are_limited() {
+    let app = limited_app(1, false);
+    for path in ["/health", "/live", "/version", "/supported-formats", "/api-docs/openapi.json"] {
+        for _ in 0..3 {
+            let (status, _) = send(app.clone(), from_peer(get(path), "10.0.0.1:5000")).await;
+            assert_eq!(status, StatusCode::OK, "{path}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn forwarded_address_is_used_only_when_trusted() {
+    let forwarded = |client: &str| {
+        let mut request = from_peer(upload("/calculate-intensity", "image", &test_png()), "192.168.0.10:443");
+        request
+            .headers_mut()
+            .insert("x-forwarded-for", format!("203.0.113.99, {client}").parse().unwrap());
+        request
+    };
+
+    let trusted = limited_app(1, true);
+    assert_eq!(send(trusted.clone(), forwarded("198.51.100.1")).await.0, StatusCode::OK);
+    assert_eq!(send(trusted.clone(), forwarded("198.51.100.2")).await.0, StatusCode::OK);
+    assert_eq!(send(trusted, forwarded("198.51.100.1")).await.0, StatusCode::TOO_MANY_REQUESTS);
+
+    // Untrusted: every request counts against the proxy's own address
+    let untrusted = limited_app(1, false);
+    assert_eq!(send(untrusted.clone(), forwarded("198.51.100.1")).await.0, StatusCode::OK);
+    assert_eq!(send(untrusted, forwarded("198.51.100.2")).await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn the_forwarded_header_names_the_client() {
+    let forwarded = |value: &str| {
+        let mut request = from_peer(upload("/calculate-intensity", "image", &test_png()), "192.168.0.10:443");
+        request.headers_mut().insert(header::FORWARDED, value.parse().unwrap());
+        request
+    };
+
+    let trusted = limited_app(1, true);
+    assert_eq!(send(trusted.clone(), forwarded("for=203.0.113.99, for=198.51.100.1;proto=https")).await.0, StatusCode::OK);
+    assert_eq!(send(trusted.clone(), forwarded("for=\"[2001:db8::1]:4711\"")).await.0, StatusCode::OK);
+    assert_eq!(send(trusted.clone(), forwarded("For=198.51.100.1:8080")).await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send(trusted, forwarded("for=\"[2001:db8::1]\"")).await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn ipv6_clients_share_a_bucket_per_64() {
+    let forwarded = |client: &str| {
+        let mut request = from_peer(upload("/calculate-intensity", "image", &test_png()), "192.168.0.10:443");
+        request.headers_mut().insert("x-forwarded-for", client.parse().unwrap());
+        request
+    };
+
+    let trusted = limited_app(1, true);
+    assert_eq!(send(trusted.clone(), forwarded("2001:db8:0:7::1")).await.0, StatusCode::OK);
+    assert_eq!(send(trusted.clone(), forwarded("2001:db8:0:7:a:b:c:d")).await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send(trusted.clone(), forwarded("2001:db8:0:8::1")).await.0, StatusCode::OK);
+    // IPv4-mapped addresses count as the IPv4 client they stand for
+    assert_eq!(send(trusted.clone(), forwarded("198.51.100.7")).await.0, StatusCode::OK);
+    assert_eq!(send(trusted, forwarded("::ffff:198.51.100.7")).await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn disabled_by_default() {
+    let app = test_app(Config::default());
+    for _ in 0..5 {
+        let request = from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.1:5000");
+        assert_eq!(send(app.clone(), request).await.0, StatusCode::OK);
+    }
+}
+
+#[test]
+fn in_flight_slots_come_back_when_their_guards_drop() {
+    let limiter = InFlightLimiter::new(2);
+    let first = limiter.try_acquire("client").unwrap();
+    let second = limiter.try_acquire("client").unwrap();
+    assert_eq!(limiter.try_acquire("client").err(), Some(2));
+    assert!(limiter.try_acquire("other").is_ok());
+
+    drop(first);
+    let third = limiter.try_acquire("client").unwrap();
+    assert_eq!(limiter.in_flight(), [("client", 2)]);
+    drop((second, third));
+    assert!(limiter.in_flight().is_empty());
+}
+
+#[tokio::test]
+async fn each_client_gets_a_few_uploads_in_flight() {
+    let app = app(AppState::new(Config { max_in_flight_per_client: 2, ..Config::default() }));
+    let uploads: Vec<_> = (0..2).map(|_| tokio::spawn(send(app.clone(), stalled_upload("10.0.0.1:5000")))).collect();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(in_flight_by_client(&app).await, [("ip:10.0.0.1".to_string(), 2)]);
+
+    let (status, body) = send(app.clone(), from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.1:5001")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(error_code(&body), "client_busy");
+    assert!(error_message(&body).starts_with("2 analysis requests from this client are already in flight"));
+
+    // Other clients are unaffected
+    let request = from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.2:5000");
+    assert_eq!(send(app.clone(), request).await.0, StatusCode::OK);
+
+    // Disconnecting mid-upload gives the slots back
+    for upload in uploads {
+        upload.abort();
+        let _ = upload.await;
+    }
+    assert!(in_flight_by_client(&app).await.is_empty());
+    let request = from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.1:5000");
+    assert_eq!(send(app, request).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn failed_requests_release_their_slot() {
+    let app = test_app(Config { max_in_flight_per_client: 1, ..Config::default() });
+    for _ in 0..3 {
+        let (status, body) = send(app.clone(), upload("/calculate-intensity", "image", b"not an image")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error_code(&body), "decode_error");
+    }
+    assert!(in_flight_by_client(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn an_api_key_is_capped_across_addresses() {
+    let app = app(AppState::new(Config {
+        max_in_flight_per_client: 1,
+        api_keys: vec!["shared-key".to_string(), "other-key".to_string()],
+        ..Config::default()
+    }));
+    let with_key = |mut request: Request<Body>, key: &str| {
+        request.headers_mut().insert("x-api-key", key.parse().unwrap());
+        request
+    };
+    let stalled = tokio::spawn(send(app.clone(), with_key(stalled_upload("10.0.0.1:5000"), "shared-key")));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let request = with_key(from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.2:5000"), "shared-key");
+    assert_eq!(send(app.clone(), request).await.0, StatusCode::TOO_MANY_REQUESTS);
+    let request = with_key(from_peer(upload("/calculate-intensity", "image", &test_png()), "10.0.0.1:5000"), "other-key");
+    assert_eq!(send(app.clone(), request).await.0, StatusCode::OK);
+
+    let (_, body) = send(app.clone(), with_key(get("/stats"), "other-key")).await;
+    let stats: StatsResponse = serde_json::from_slice(&body).unwrap();
+    let clients: Vec<_> = stats.in_flight_by_client.keys().collect();
+    assert_eq!(clients.len(), 1);
+    assert!(clients[0].starts_with("key:") && !clients[0].contains("shared"), "{clients:?}");
+    stalled.abort();
+}
@@ -0,0 +1,63 @@
+//! Repeated runs over the same input give bit-identical results, on the
+//! parallel path as much as the sequential one.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::*;
+use image::{DynamicImage, Rgb, RgbImage};
+use webcalculation::analysis::{accumulate_parallel, encode_png, intensity_stats};
+use webcalculation::server::{Config, IntensityResponse};
+
+const RUNS: usize = 100;
+
+/// RGB noise from a fixed xorshift seed, so every run sees the same pixels.
+fn seeded_noise(width: u32, height: u32) -> RgbImage {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    };
+    RgbImage::from_fn(width, height, |_, _| Rgb([next(), next(), next()]))
+}
+
+#[test]
+fn parallel_accumulation_is_repeatable() {
+    // Several rayon chunks, whose partial totals must merge in buffer order
+    // whichever finishes first
+    let noise = seeded_noise(1024, 700);
+    let first = accumulate_parallel(noise.as_raw(), 3);
+    for run in 1..RUNS {
+        assert_eq!(accumulate_parallel(noise.as_raw(), 3), first, "run {run}");
+    }
+
+    let img = DynamicImage::ImageRgb8(noise);
+    let average = intensity_stats(&img).unwrap().average_intensity;
+    assert_eq!(average.to_bits(), (first.total_channel_sum as f64 / (3.0 * first.pixel_count as f64)).to_bits());
+}
+
+#[tokio::test]
+async fn the_same_upload_gives_identical_results() {
+    let data = encode_png(&DynamicImage::ImageRgb8(seeded_noise(64, 48))).unwrap();
+    // No cache, so every request is computed afresh; the options add the
+    // floating-point measures to the integer mean
+    let config = Config { cache_capacity: 0, ..Config::default() };
+    let uri = "/calculate-intensity?linearize=true&colorspace=linear&metric=lab_lightness&trim=5";
+
+    let mut first = None;
+    for run in 0..RUNS {
+        let (status, body) = send(test_app(config.clone()), upload(uri, "image", &data)).await;
+        assert_eq!(status, StatusCode::OK);
+        let result: IntensityResponse = serde_json::from_slice(&body).unwrap();
+        assert!(!result.cached);
+        let mut fields: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // The only field that may differ between runs
+        fields.as_object_mut().unwrap().remove("processing_ms");
+
+        let (average, fields_first) = first.get_or_insert((result.average_intensity, fields.clone()));
+        assert_eq!(result.average_intensity.to_bits(), average.to_bits(), "run {run}");
+        assert_eq!(&fields, fields_first, "run {run}");
+    }
+}
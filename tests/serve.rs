@@ -0,0 +1,285 @@
+//! The router served over a real socket bound to port 0.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::{get, multipart_body, send, test_png, BOUNDARY};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::sync::oneshot;
+use tokio_rustls::rustls::{self, pki_types::ServerName};
+use webcalculation::server::{
+    app, parse_bind_addr, serve_until, AppState, Config, Listeners, ProbeResponse, TlsPaths, DEFAULT_BIND_ADDR,
+};
+use webcalculation::unix_socket::UnixSocket;
+
+fn tcp(listener: TcpListener) -> Listeners {
+    Listeners { tcp: Some(listener), ..Listeners::default() }
+}
+
+/// Sends `GET /health` over `stream` and returns the raw response.
+async fn get_health(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> String {
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn serves_on_an_assigned_port() {
+    let config = Config {
+        bind_addr: Some(parse_bind_addr("127.0.0.1:0", 3000).unwrap()),
+        ..Config::default()
+    };
+    let listener = TcpListener::bind(config.tcp_addr().unwrap()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let service = app(AppState::new(config)).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+
+    let response = get_health(TcpStream::connect(addr).await.unwrap()).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.ends_with("OK"));
+}
+
+#[test]
+fn bind_addresses_default_the_port() {
+    assert_eq!(parse_bind_addr(" 0.0.0.0 ", 3000).unwrap(), SocketAddr::from(([0, 0, 0, 0], 3000)));
+    assert_eq!(parse_bind_addr("::", 8080).unwrap().to_string(), "[::]:8080");
+    assert_eq!(parse_bind_addr("10.0.0.5:0", 3000).unwrap().port(), 0);
+    for invalid in ["", "localhost", "1.2.3.4:99999", "127.0.0.1:port"] {
+        let err = parse_bind_addr(invalid, 3000).unwrap_err();
+        assert!(err.contains("expected an IP"), "{invalid}: {err}");
+    }
+}
+
+#[tokio::test]
+async fn health_fails_while_draining() {
+    let state = AppState::new(Config::default());
+    let (status, _) = send(app(state.clone()), get("/health")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    state.begin_draining();
+    let (status, body) = send(app(state), get("/health")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body, b"draining");
+}
+
+#[tokio::test]
+async fn probes_separate_liveness_from_readiness() {
+    let state = AppState::new(Config::default());
+    let probe = |path: &'static str| {
+        let state = state.clone();
+        async move {
+            let (status, body) = send(app(state), get(path)).await;
+            (status, serde_json::from_slice::<ProbeResponse>(&body).unwrap())
+        }
+    };
+    let (status, ready) = probe("/ready").await;
+    assert_eq!((status, ready.status.as_str(), ready.reason), (StatusCode::OK, "ready", None));
+
+    state.begin_draining();
+    let (status, ready) = probe("/ready").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!((ready.status.as_str(), ready.reason.as_deref()), ("not_ready", Some("draining")));
+    let (status, live) = probe("/live").await;
+    assert_eq!((status, live.status.as_str()), (StatusCode::OK, "live"));
+}
+
+#[tokio::test]
+async fn not_ready_while_every_analysis_slot_is_taken() {
+    let router = app(AppState::new(Config { max_in_flight_requests: 1, ..Config::default() }));
+    let stalled = tokio_stream::StreamExt::chain(
+        tokio_stream::iter([Ok::<_, std::io::Error>(bytes::Bytes::from_static(b"--"))]),
+        tokio_stream::pending(),
+    );
+    let request = axum::http::Request::post("/calculate-intensity")
+        .header("content-type", format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(axum::body::Body::from_stream(stalled))
+        .unwrap();
+    let upload = tokio::spawn(send(router.clone(), request));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let (status, body) = send(router.clone(), get("/health")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(String::from_utf8_lossy(&body).starts_with("busy"));
+
+    upload.abort();
+    let _ = upload.await;
+    assert_eq!(send(router, get("/ready")).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn shutdown_lets_in_flight_requests_finish() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = AppState::new(Config { shutdown_timeout: Duration::from_secs(5), ..Config::default() });
+    let (trigger, shutdown) = oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(tcp(listener), state.clone(), async {
+        let _ = shutdown.await;
+    }));
+
+    // Start an upload but hold back the end of the body until shutdown has begun.
+    let body = multipart_body("image", &test_png());
+    let (head, tail) = body.split_at(body.len() / 2);
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let headers = format!(
+        "POST /calculate-intensity HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nX-Request-Id: drain-1\r\n\
+         Content-Type: multipart/form-data; boundary={BOUNDARY}\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(headers.as_bytes()).await.unwrap();
+    stream.write_all(head).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    trigger.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(state.is_draining());
+    assert!(!server.is_finished());
+
+    stream.write_all(tail).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.contains("average_intensity"));
+    // The id survives the drain, so the gateway can match up the late response
+    assert!(response.to_ascii_lowercase().contains("x-request-id: drain-1\r\n"), "{response}");
+
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+/// Writes `contents` to a file unique to this test process.
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("webcalculation-{}-{name}", std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn self_signed(name: &str) -> (rcgen::CertifiedKey, TlsPaths) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let paths = TlsPaths {
+        cert_path: temp_file(&format!("{name}-cert.pem"), &certified.cert.pem()),
+        key_path: temp_file(&format!("{name}-key.pem"), &certified.key_pair.serialize_pem()),
+    };
+    (certified, paths)
+}
+
+#[tokio::test]
+async fn serves_https_when_tls_is_configured() {
+    let (certified, paths) = self_signed("https");
+    let tls = paths.load().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listeners = Listeners { tls: Some(tls), ..tcp(listener) };
+    tokio::spawn(serve_until(listeners, AppState::new(Config::default()), std::future::pending()));
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let client = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let mut stream = tokio_rustls::TlsConnector::from(Arc::new(client))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    // The server may close without a TLS close_notify once the response is sent
+    let _ = stream.read_to_end(&mut response).await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+}
+
+#[test]
+fn unusable_tls_files_are_reported() {
+    let (_, paths) = self_signed("invalid");
+    let load_error = |paths: &TlsPaths| paths.load().expect_err("load should fail");
+
+    let missing = TlsPaths { cert_path: "/nonexistent/cert.pem".into(), ..paths.clone() };
+    assert!(load_error(&missing).starts_with("TLS_CERT_PATH: cannot load /nonexistent/cert.pem"));
+
+    let garbage = temp_file("garbage.pem", "not a certificate");
+    let no_certs = TlsPaths { cert_path: garbage.clone(), ..paths.clone() };
+    assert!(load_error(&no_certs).starts_with("TLS_CERT_PATH: no certificates found"));
+    let no_key = TlsPaths { key_path: garbage, ..paths.clone() };
+    assert!(load_error(&no_key).starts_with("TLS_KEY_PATH: cannot load a private key"));
+
+    let (_, other) = self_signed("other");
+    let mismatched = TlsPaths { key_path: other.key_path, ..paths };
+    assert!(load_error(&mismatched).contains("unusable certificate and key"), "{}", load_error(&mismatched));
+}
+
+#[test]
+fn tcp_is_the_default_unless_only_a_socket_is_configured() {
+    assert_eq!(Config::default().tcp_addr(), Some(DEFAULT_BIND_ADDR));
+
+    let socket_only = Config { uds_path: Some("/tmp/app.sock".into()), ..Config::default() };
+    assert_eq!(socket_only.tcp_addr(), None);
+
+    let addr = parse_bind_addr("127.0.0.1:8080", 3000).unwrap();
+    let both = Config { bind_addr: Some(addr), ..socket_only };
+    assert_eq!(both.tcp_addr(), Some(addr));
+}
+
+#[tokio::test]
+async fn unix_sockets_replace_only_stale_sockets() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("webcalculation-{}-stale.sock", std::process::id()));
+    let socket = UnixSocket::bind(&path).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+    // Still accepting connections, so not stale
+    let err = UnixSocket::bind(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+    drop(socket);
+    assert!(!path.exists(), "the socket is removed on drop");
+
+    // A crashed process leaves its socket file behind, with nobody listening
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    drop(UnixSocket::bind(&path).unwrap());
+    assert!(!path.exists());
+
+    let regular = temp_file("regular.sock", "data");
+    let err = UnixSocket::bind(&regular).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read_to_string(&regular).unwrap(), "data");
+}
+
+#[tokio::test]
+async fn serves_tcp_and_a_unix_socket_together() {
+    let path = std::env::temp_dir().join(format!("webcalculation-{}-serve.sock", std::process::id()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listeners = Listeners { unix: Some(UnixSocket::bind(&path).unwrap()), ..tcp(listener) };
+    let (trigger, shutdown) = oneshot::channel::<()>();
+    let server = tokio::spawn(serve_until(listeners, AppState::new(Config::default()), async {
+        let _ = shutdown.await;
+    }));
+
+    let over_tcp = get_health(TcpStream::connect(addr).await.unwrap()).await;
+    assert!(over_tcp.starts_with("HTTP/1.1 200 OK"), "{over_tcp}");
+    let over_unix = get_health(UnixStream::connect(&path).await.unwrap()).await;
+    assert!(over_unix.starts_with("HTTP/1.1 200 OK"), "{over_unix}");
+
+    trigger.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+    assert!(!path.exists(), "the socket is cleaned up on shutdown");
+}
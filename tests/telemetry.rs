@@ -0,0 +1,87 @@
+//! Trace export, with spans collected in memory. Run with `--features otel`.
+#![cfg(feature = "otel")]
+
+mod common;
+
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use common::{send, test_app, test_png, upload};
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData, SpanExporter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webcalculation::logging::{subscriber, LogFormat};
+use webcalculation::server::Config;
+use webcalculation::telemetry::{parent_context, Telemetry};
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+/// Exported spans, kept for inspection.
+#[derive(Clone, Debug, Default)]
+struct Collected(Arc<Mutex<Vec<SpanData>>>);
+
+impl SpanExporter for Collected {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.0.lock().unwrap().extend(batch);
+        Ok(())
+    }
+}
+
+impl Collected {
+    fn named(&self, name: &str) -> SpanData {
+        let spans = self.0.lock().unwrap();
+        spans.iter().find(|span| span.name == name).unwrap_or_else(|| panic!("no {name} span in {spans:?}")).clone()
+    }
+}
+
+#[tokio::test]
+async fn request_spans_join_the_callers_trace() {
+    let collected = Collected::default();
+    let telemetry = Telemetry::with_provider(SdkTracerProvider::builder().with_simple_exporter(collected.clone()).build());
+    // Global, so the blocking threads the image work runs on report to it too
+    tracing::subscriber::set_global_default(subscriber(LogFormat::Text, std::io::sink, &telemetry)).unwrap();
+
+    let mut request = upload("/calculate-intensity", "image", &test_png());
+    request.headers_mut().insert("traceparent", HeaderValue::from_static(TRACEPARENT));
+    let (status, _) = send(test_app(Config::default()), request).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+    let request = collected.named("request");
+    assert_eq!(request.span_context.trace_id(), trace_id);
+    assert_eq!(request.parent_span_id, SpanId::from_hex("00f067aa0ba902b7").unwrap());
+    assert!(request.parent_span_is_remote);
+
+    let compute = collected.named("compute");
+    assert_eq!(compute.parent_span_id, request.span_context.span_id());
+    let decode = collected.named("decode");
+    assert_eq!(decode.parent_span_id, compute.span_context.span_id());
+    assert!([compute, decode].iter().all(|span| span.span_context.trace_id() == trace_id));
+    telemetry.shutdown();
+}
+
+#[tokio::test]
+async fn an_unreachable_collector_does_not_hold_up_requests() {
+    let telemetry = Telemetry::exporting_to("http://127.0.0.1:1").unwrap();
+    assert!(telemetry.is_exporting());
+    let _guard = tracing::subscriber::set_default(subscriber(LogFormat::Text, std::io::sink, &telemetry));
+
+    let (status, _) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let started = Instant::now();
+    tokio::task::spawn_blocking(|| telemetry.shutdown()).await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(15), "shutdown took {:?}", started.elapsed());
+}
+
+#[test]
+fn trace_context_is_read_from_the_traceparent_header() {
+    let mut headers = HeaderMap::new();
+    headers.insert("traceparent", HeaderValue::from_static(TRACEPARENT));
+    let context = parent_context(&headers);
+    assert_eq!(context.span().span_context().trace_id(), TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap());
+    assert!(context.span().span_context().is_remote());
+
+    headers.insert("traceparent", HeaderValue::from_static("not a trace"));
+    assert!(!parent_context(&headers).span().span_context().is_valid());
+}
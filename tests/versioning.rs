@@ -0,0 +1,90 @@
+//! The `/v1` routes and their deprecated unprefixed aliases.
+
+mod common;
+
+use axum::http::StatusCode;
+use common::*;
+use std::collections::HashSet;
+use tower::ServiceExt;
+use webcalculation::server::{Config, IntensityResponse, StatsResponse, LEGACY_SUNSET};
+
+#[tokio::test]
+async fn v1_and_legacy_paths_answer_alike() {
+    let v1 = test_app(Config::default()).oneshot(upload("/v1/calculate-intensity", "image", &test_png())).await.unwrap();
+    assert_eq!(v1.status(), StatusCode::OK);
+    assert!(v1.headers().get("deprecation").is_none());
+    assert!(v1.headers().get("sunset").is_none());
+
+    let (_, v1_body) = send(test_app(Config::default()), upload("/v1/calculate-intensity", "image", &test_png())).await;
+    let (_, legacy_body) = send(test_app(Config::default()), upload("/calculate-intensity", "image", &test_png())).await;
+    let v1: IntensityResponse = serde_json::from_slice(&v1_body).unwrap();
+    let legacy: IntensityResponse = serde_json::from_slice(&legacy_body).unwrap();
+    assert_eq!(v1.average_intensity, legacy.average_intensity);
+
+    for path in ["/v1/version", "/v1/supported-formats", "/v1/stats"] {
+        assert_eq!(send(test_app(Config::default()), get(path)).await.0, StatusCode::OK, "{path}");
+    }
+    // Probes and documentation are not versioned
+    assert_eq!(send(test_app(Config::default()), get("/v1/health")).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn legacy_paths_are_flagged_as_deprecated() {
+    let response = test_app(Config::default()).oneshot(upload("/calculate-intensity?round=2", "image", &test_png())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers["deprecation"], "@1791936000");
+    assert_eq!(headers["sunset"], LEGACY_SUNSET);
+    assert_eq!(headers["link"], "</v1/calculate-intensity?round=2>; rel=\"successor-version\"");
+
+    // Rejections too, and the successor includes the base path
+    let config = Config {
+        api_keys: vec!["alpha-key".to_string()],
+        base_path: "/intensity".to_string(),
+        nest_base_path: true,
+        ..Config::default()
+    };
+    let response = test_app(config).oneshot(get("/intensity/version")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["link"], "</intensity/v1/version>; rel=\"successor-version\"");
+
+    let response = test_app(Config::default()).oneshot(get("/health")).await.unwrap();
+    assert!(response.headers().get("deprecation").is_none());
+}
+
+#[tokio::test]
+async fn stats_keep_versions_apart() {
+    let app = test_app(Config::default());
+    for uri in ["/v1/version", "/v1/version", "/version"] {
+        app.clone().oneshot(get(uri)).await.unwrap();
+    }
+    let (_, body) = send(app, get("/v1/stats")).await;
+    let stats: StatsResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(stats.requests_by_route["/v1/version"], 2);
+    assert_eq!(stats.requests_by_route["/version"], 1);
+}
+
+#[tokio::test]
+async fn openapi_lists_v1_and_deprecates_the_legacy_paths() {
+    let (_, body) = send(test_app(Config::default()), get("/api-docs/openapi.json")).await;
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let paths = doc["paths"].as_object().unwrap();
+
+    let current = &paths["/v1/calculate-intensity"]["post"];
+    assert!(current["deprecated"].is_null());
+    assert_eq!(current["operationId"], "calculate_intensity");
+    let legacy = &paths["/calculate-intensity"]["post"];
+    assert_eq!(legacy["deprecated"], true);
+    assert!(legacy["description"].as_str().unwrap().starts_with("Deprecated alias of `/v1/calculate-intensity`"));
+    assert_eq!(paths["/calculate-intensity"]["get"]["deprecated"], true);
+    assert!(paths["/health"]["get"]["deprecated"].is_null());
+    assert!(!paths.contains_key("/v1/health"));
+
+    let mut operation_ids = HashSet::new();
+    for item in paths.values() {
+        for operation in item.as_object().unwrap().values() {
+            let id = operation["operationId"].as_str().unwrap();
+            assert!(operation_ids.insert(id.to_string()), "duplicate operationId {id}");
+        }
+    }
+}